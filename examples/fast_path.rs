@@ -0,0 +1,38 @@
+//! The allocation fast path of a [`Cap`] with the default features, in functions whose generated code can be inspected with `cargo rustc --release --example fast_path -- --emit asm`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+
+use cap::Cap;
+
+/// Allocate `layout` through `cap`.
+///
+/// # Safety
+///
+/// As for [`GlobalAlloc::alloc`].
+#[no_mangle]
+#[inline(never)]
+pub unsafe fn cap_fast_path_alloc(cap: &Cap<System>, layout: Layout) -> *mut u8 {
+	cap.alloc(layout)
+}
+
+/// Free `ptr`, allocated with `layout` through `cap`.
+///
+/// # Safety
+///
+/// As for [`GlobalAlloc::dealloc`].
+#[no_mangle]
+#[inline(never)]
+pub unsafe fn cap_fast_path_dealloc(cap: &Cap<System>, ptr: *mut u8, layout: Layout) {
+	cap.dealloc(ptr, layout);
+}
+
+fn main() {
+	let cap = Cap::new(System, usize::MAX);
+	let layout = Layout::new::<[u8; 64]>();
+	unsafe {
+		let ptr = cap_fast_path_alloc(&cap, layout);
+		assert!(!ptr.is_null());
+		cap_fast_path_dealloc(&cap, ptr, layout);
+	}
+	assert_eq!(cap.allocated(), 0);
+}
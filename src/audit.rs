@@ -0,0 +1,507 @@
+use std::{
+	alloc::{GlobalAlloc, Layout, System}, cell::UnsafeCell, fmt, hint, mem, ptr, sync::atomic::{AtomicBool, Ordering}, thread, time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+#[cfg(feature = "tags")]
+use crate::Tag;
+
+/// A discrepancy between the layout a pointer was allocated with and how it is being deallocated or reallocated, as found by the `audit` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditError {
+	/// The pointer was not allocated by this allocator, or was deallocated too long ago to be recognised as a double free. It is not forwarded to the wrapped allocator.
+	Unknown {
+		/// The pointer passed to `dealloc` or `realloc`.
+		ptr: usize,
+		/// The layout passed alongside it.
+		layout: Layout,
+	},
+	/// The pointer has already been deallocated, and not since reallocated. It is not forwarded to the wrapped allocator.
+	DoubleFree {
+		/// The pointer passed to `dealloc` or `realloc`.
+		ptr: usize,
+		/// The layout passed alongside it.
+		layout: Layout,
+	},
+	/// The layout passed doesn't match the layout the pointer was allocated with. It is forwarded to the wrapped allocator, and accounted, with the layout it was allocated with.
+	Mismatch {
+		/// The pointer passed to `dealloc` or `realloc`.
+		ptr: usize,
+		/// The layout the pointer was allocated with.
+		allocated: Layout,
+		/// The layout passed alongside it.
+		passed: Layout,
+	},
+}
+
+impl fmt::Display for AuditError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			AuditError::Unknown { ptr, layout } => write!(
+				f,
+				"{:#x} (size {}, align {}) was not allocated by this allocator or was already deallocated",
+				ptr,
+				layout.size(),
+				layout.align()
+			),
+			AuditError::DoubleFree { ptr, layout } => write!(
+				f,
+				"{:#x} (size {}, align {}) was already deallocated",
+				ptr,
+				layout.size(),
+				layout.align()
+			),
+			AuditError::Mismatch {
+				ptr,
+				allocated,
+				passed,
+			} => write!(
+				f,
+				"{:#x} was allocated with size {}, align {} but passed with size {}, align {}",
+				ptr,
+				allocated.size(),
+				allocated.align(),
+				passed.size(),
+				passed.align()
+			),
+		}
+	}
+}
+
+/// A live allocation, as returned by [`Cap::oldest_allocations`](crate::Cap::oldest_allocations).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LiveAllocation {
+	/// An identifier of the allocation, assigned in increasing order as allocations are made, and kept when it is reallocated.
+	pub id: u64,
+	/// The address of the allocation.
+	pub ptr: usize,
+	/// The layout it currently has.
+	pub layout: Layout,
+	/// When it was allocated.
+	pub time: SystemTime,
+	/// The tag it is attributed to, if any.
+	#[cfg(feature = "tags")]
+	pub tag: Option<Tag>,
+}
+
+/// A point in the sequence of allocations, as returned by [`Cap::checkpoint`](crate::Cap::checkpoint).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Checkpoint(u64);
+
+/// The allocations made between two checkpoints that are still live and attributed to one tag, as returned by [`Cap::heap_diff`](crate::Cap::heap_diff).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DiffGroup {
+	/// The tag the allocations are attributed to, if any.
+	#[cfg(feature = "tags")]
+	pub tag: Option<Tag>,
+	/// The number of allocations.
+	pub count: usize,
+	/// The number of bytes they occupy.
+	pub bytes: usize,
+}
+
+#[cfg(feature = "tags")]
+const GROUPS: usize = crate::tag::MAX_TAGS;
+#[cfg(not(feature = "tags"))]
+const GROUPS: usize = 1;
+
+const EMPTY: usize = 0;
+const TOMBSTONE: usize = 1;
+const INITIAL_CAPACITY: usize = 1024;
+/// The number of recently deallocated pointers remembered to recognise double frees.
+const FREED: usize = 256;
+
+#[derive(Clone, Copy)]
+struct Entry {
+	ptr: usize,
+	size: usize,
+	align: usize,
+	id: u64,
+	/// Nanoseconds since the Unix epoch.
+	time: u64,
+	#[cfg_attr(not(feature = "tags"), allow(dead_code))]
+	tag: usize,
+}
+
+impl Entry {
+	fn live(&self) -> LiveAllocation {
+		LiveAllocation {
+			id: self.id,
+			ptr: self.ptr,
+			layout: unsafe { Layout::from_size_align_unchecked(self.size, self.align) },
+			time: UNIX_EPOCH + Duration::from_nanos(self.time),
+			#[cfg(feature = "tags")]
+			tag: Tag::from_index(self.tag),
+		}
+	}
+}
+
+/// The entry of a pointer being reallocated, taken out of the [`Table`] while the reallocation is forwarded.
+pub(crate) struct Taken(Option<Entry>);
+
+struct Raw {
+	entries: *mut Entry,
+	capacity: usize,
+	len: usize,
+	used: usize,
+	overflowed: bool,
+	/// A ring of recently deallocated pointers.
+	freed: [usize; FREED],
+	freed_next: usize,
+	next_id: u64,
+}
+
+/// The number of times to spin for the lock of a [`Table`] before yielding to other threads.
+const SPINS: usize = 64;
+
+/// A map from live pointers to the layout they were allocated with.
+///
+/// Backed by an open-addressing hash table allocated directly from [`System`], so that maintaining it doesn't recurse into the global allocator. It only counts against the limit if [diagnostics are charged](crate::Cap::set_diagnostics_charged).
+pub(crate) struct Table {
+	locked: AtomicBool,
+	raw: UnsafeCell<Raw>,
+}
+
+unsafe impl Sync for Table {}
+unsafe impl Send for Table {}
+
+impl Table {
+	pub(crate) const fn new() -> Self {
+		Self {
+			locked: AtomicBool::new(false),
+			raw: UnsafeCell::new(Raw {
+				entries: ptr::null_mut(),
+				capacity: 0,
+				len: 0,
+				used: 0,
+				overflowed: false,
+				freed: [EMPTY; FREED],
+				freed_next: 0,
+				next_id: 0,
+			}),
+		}
+	}
+
+	fn with<R>(&self, f: impl FnOnce(&mut Raw) -> R) -> R {
+		let mut spins = 0;
+		while self
+			.locked
+			.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+			.is_err()
+		{
+			// The holder may have been preempted, so after a short spin give it the CPU rather than burning the rest of the time slice.
+			if spins < SPINS {
+				spins += 1;
+				hint::spin_loop();
+			} else {
+				thread::yield_now();
+			}
+		}
+		let ret = f(unsafe { &mut *self.raw.get() });
+		self.locked.store(false, Ordering::Release);
+		ret
+	}
+
+	/// Return the bytes the table occupies.
+	pub(crate) fn bytes(&self) -> usize {
+		self.with(|raw| raw.capacity * size_of::<Entry>())
+	}
+
+	/// Record a newly allocated pointer, attributed to the tag with index `tag`. The table only grows if `admit` accepts the bytes it would grow by.
+	pub(crate) fn insert(
+		&self, ptr: *mut u8, layout: Layout, tag: usize, admit: &dyn Fn(usize) -> bool,
+	) {
+		#[allow(clippy::cast_possible_truncation)]
+		let time = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |since| since.as_nanos() as u64);
+		self.with(|raw| {
+			let id = raw.next_id;
+			raw.next_id += 1;
+			raw.insert(
+				Entry {
+					ptr: ptr as usize,
+					size: layout.size(),
+					align: layout.align(),
+					id,
+					time,
+					tag,
+				},
+				admit,
+			);
+		});
+	}
+
+	/// Check `layout` against what `ptr` was allocated with, and forget it.
+	pub(crate) fn remove(&self, ptr: *mut u8, layout: Layout) -> Result<(), AuditError> {
+		self.with(|raw| raw.check(ptr as usize, layout).1)
+	}
+
+	/// Check `layout` against what `ptr` was allocated with, and take its entry out of the table until the reallocation it is passed to completes.
+	///
+	/// The entry must be taken before the reallocation is forwarded, as a moving reallocation frees `ptr`, and another thread may be allocated its address before the table is updated.
+	pub(crate) fn take(&self, ptr: *mut u8, layout: Layout) -> (Taken, Result<(), AuditError>) {
+		let (entry, checked) = self.with(|raw| raw.check(ptr as usize, layout));
+		(Taken(entry), checked)
+	}
+
+	/// Put back an entry taken for a reallocation that failed.
+	#[allow(clippy::needless_pass_by_value)] // so that an entry can't be put back twice
+	pub(crate) fn restore(&self, taken: Taken, admit: &dyn Fn(usize) -> bool) {
+		if let Taken(Some(entry)) = taken {
+			self.with(|raw| raw.insert(entry, admit));
+		}
+	}
+
+	/// Record that the pointer an entry was taken for has been reallocated to `new`, keeping its ID and time. Pointers that weren't tracked are left untracked.
+	#[allow(clippy::needless_pass_by_value)]
+	pub(crate) fn moved(
+		&self, taken: Taken, new: *mut u8, new_layout: Layout, admit: &dyn Fn(usize) -> bool,
+	) {
+		if let Taken(Some(entry)) = taken {
+			let entry = Entry {
+				ptr: new as usize,
+				size: new_layout.size(),
+				align: new_layout.align(),
+				..entry
+			};
+			self.with(|raw| raw.insert(entry, admit));
+		}
+	}
+
+	/// Attribute `ptr` to the tag with index `tag`.
+	#[cfg(feature = "tags")]
+	pub(crate) fn retag(&self, ptr: *mut u8, tag: usize) {
+		self.with(|raw| {
+			if let Some(i) = raw.find(ptr as usize) {
+				unsafe { (*raw.entries.add(i)).tag = tag };
+			}
+		});
+	}
+
+	/// Return a checkpoint before the next allocation.
+	pub(crate) fn checkpoint(&self) -> Checkpoint {
+		Checkpoint(self.with(|raw| raw.next_id))
+	}
+
+	/// Group the live allocations made between `from` and `to` by tag, largest first.
+	pub(crate) fn diff(&self, from: Checkpoint, to: Checkpoint) -> Vec<DiffGroup> {
+		let groups = self.with(|raw| {
+			let mut groups = [(0, 0); GROUPS];
+			for i in 0..raw.capacity {
+				let entry = unsafe { *raw.entries.add(i) };
+				if entry.ptr == EMPTY
+					|| entry.ptr == TOMBSTONE
+					|| !(from.0..to.0).contains(&entry.id)
+				{
+					continue;
+				}
+				#[cfg(feature = "tags")]
+				let group = entry.tag;
+				#[cfg(not(feature = "tags"))]
+				let group = 0;
+				let (count, bytes) = &mut groups[group];
+				*count += 1;
+				*bytes += entry.size;
+			}
+			groups
+		});
+		let mut diff: Vec<DiffGroup> = groups
+			.iter()
+			.enumerate()
+			.filter(|(_, &(count, _))| count != 0)
+			.map(|(tag, &(count, bytes))| {
+				#[cfg(not(feature = "tags"))]
+				let _ = tag;
+				DiffGroup {
+					#[cfg(feature = "tags")]
+					tag: Tag::from_index(tag),
+					count,
+					bytes,
+				}
+			})
+			.collect();
+		diff.sort_by_key(|group| std::cmp::Reverse(group.bytes));
+		diff
+	}
+
+	/// Return up to `n` of the live allocations with the lowest IDs, oldest first.
+	pub(crate) fn oldest(&self, n: usize) -> Vec<LiveAllocation> {
+		// Allocated up front, so that nothing is allocated while the table is locked.
+		let mut oldest: Vec<LiveAllocation> = Vec::with_capacity(n);
+		if n == 0 {
+			return oldest;
+		}
+		self.with(|raw| {
+			for i in 0..raw.capacity {
+				let entry = unsafe { *raw.entries.add(i) };
+				if entry.ptr == EMPTY || entry.ptr == TOMBSTONE {
+					continue;
+				}
+				if oldest.len() == n {
+					if oldest[n - 1].id < entry.id {
+						continue;
+					}
+					let _ = oldest.pop();
+				}
+				let at = oldest.partition_point(|live| live.id < entry.id);
+				oldest.insert(at, entry.live());
+			}
+		});
+		oldest
+	}
+}
+
+impl Raw {
+	fn index(&self, ptr: usize) -> usize {
+		// Fibonacci hashing, taking the high bits of the product
+		let hash = (ptr as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+		#[allow(clippy::cast_possible_truncation)]
+		let index = (hash >> (64 - self.capacity.trailing_zeros())) as usize;
+		index
+	}
+
+	fn find(&self, ptr: usize) -> Option<usize> {
+		if self.capacity == 0 {
+			return None;
+		}
+		let mut i = self.index(ptr);
+		loop {
+			let entry = unsafe { *self.entries.add(i) };
+			if entry.ptr == ptr {
+				break Some(i);
+			}
+			if entry.ptr == EMPTY {
+				break None;
+			}
+			i = (i + 1) & (self.capacity - 1);
+		}
+	}
+
+	fn take(&mut self, i: usize) -> Entry {
+		let entry = unsafe { &mut *self.entries.add(i) };
+		let ret = *entry;
+		entry.ptr = TOMBSTONE;
+		self.len -= 1;
+		ret
+	}
+
+	/// Check `layout` against what `ptr` was allocated with, taking its entry out.
+	fn check(&mut self, ptr: usize, layout: Layout) -> (Option<Entry>, Result<(), AuditError>) {
+		let Some(i) = self.find(ptr) else {
+			let checked = if self.freed.contains(&ptr) {
+				Err(AuditError::DoubleFree { ptr, layout })
+			} else if self.overflowed {
+				// Entries may be missing if the table couldn't grow.
+				Ok(())
+			} else {
+				Err(AuditError::Unknown { ptr, layout })
+			};
+			return (None, checked);
+		};
+		self.freed[self.freed_next] = ptr;
+		self.freed_next = (self.freed_next + 1) % FREED;
+		let entry = self.take(i);
+		let checked = if entry.size == layout.size() && entry.align == layout.align() {
+			Ok(())
+		} else {
+			Err(AuditError::Mismatch {
+				ptr,
+				allocated: unsafe { Layout::from_size_align_unchecked(entry.size, entry.align) },
+				passed: layout,
+			})
+		};
+		(Some(entry), checked)
+	}
+
+	fn insert(&mut self, new: Entry, admit: &dyn Fn(usize) -> bool) {
+		// The address has been reused, so freeing it again is no longer a double free.
+		if let Some(freed) = self.freed.iter_mut().find(|freed| **freed == new.ptr) {
+			*freed = EMPTY;
+		}
+		if (self.used + 1) * 4 > self.capacity * 3 && !self.grow(admit) {
+			self.overflowed = true;
+			return;
+		}
+		let mut i = self.index(new.ptr);
+		loop {
+			let entry = unsafe { &mut *self.entries.add(i) };
+			if entry.ptr == EMPTY || entry.ptr == TOMBSTONE {
+				if entry.ptr == EMPTY {
+					self.used += 1;
+				}
+				*entry = new;
+				self.len += 1;
+				break;
+			}
+			i = (i + 1) & (self.capacity - 1);
+		}
+	}
+
+	/// Rehash into a table sized for the live entries, returning whether it succeeded.
+	fn grow(&mut self, admit: &dyn Fn(usize) -> bool) -> bool {
+		let capacity = if self.len * 2 >= self.capacity {
+			(self.capacity * 2).max(INITIAL_CAPACITY)
+		} else {
+			self.capacity
+		};
+		let Ok(layout) = Layout::array::<Entry>(capacity) else {
+			return false;
+		};
+		#[allow(clippy::cast_ptr_alignment)] // allocated with Entry's alignment
+		let entries = unsafe { System.alloc_zeroed(layout) }.cast::<Entry>();
+		if entries.is_null() {
+			return false;
+		}
+		if !admit((capacity - self.capacity) * size_of::<Entry>()) {
+			unsafe { System.dealloc(entries.cast(), layout) };
+			return false;
+		}
+		let old = mem::replace(
+			self,
+			Raw {
+				entries,
+				capacity,
+				len: 0,
+				used: 0,
+				overflowed: self.overflowed,
+				freed: self.freed,
+				freed_next: self.freed_next,
+				next_id: self.next_id,
+			},
+		);
+		for i in 0..old.capacity {
+			let entry = unsafe { *old.entries.add(i) };
+			if entry.ptr != EMPTY && entry.ptr != TOMBSTONE {
+				self.insert(entry, &|_| true);
+			}
+		}
+		old.free();
+		true
+	}
+
+	fn free(&self) {
+		if self.capacity != 0 {
+			unsafe {
+				System.dealloc(
+					self.entries.cast(),
+					Layout::array::<Entry>(self.capacity).unwrap(),
+				);
+			}
+		}
+	}
+}
+
+impl Drop for Table {
+	fn drop(&mut self) {
+		self.raw.get_mut().free();
+	}
+}
+
+impl fmt::Debug for Table {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let len = self.with(|raw| raw.len);
+		f.debug_struct("Table").field("len", &len).finish()
+	}
+}
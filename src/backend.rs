@@ -0,0 +1,236 @@
+//! The wrapped allocator's own statistics.
+
+#[cfg(feature = "jemalloc")]
+use std::ffi::c_int;
+use std::{
+	ffi::{c_char, c_void}, mem, ptr, sync::atomic::Ordering, thread, time::Duration
+};
+
+use crate::Cap;
+
+#[cfg(unix)]
+extern "C" {
+	fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+}
+
+/// jemalloc's control interface.
+#[cfg(feature = "jemalloc")]
+type Mallctl = unsafe extern "C" fn(
+	name: *const c_char,
+	oldp: *mut c_void,
+	oldlenp: *mut usize,
+	newp: *mut c_void,
+	newlen: usize,
+) -> c_int;
+
+/// mimalloc's process statistics.
+#[cfg(feature = "mimalloc")]
+type MiProcessInfo = unsafe extern "C" fn(
+	elapsed_msecs: *mut usize,
+	user_msecs: *mut usize,
+	system_msecs: *mut usize,
+	current_rss: *mut usize,
+	peak_rss: *mut usize,
+	current_commit: *mut usize,
+	peak_commit: *mut usize,
+	page_faults: *mut usize,
+);
+
+/// Look up `symbol`, a nul-terminated name, among those the process exports, returning null if it isn't found.
+///
+/// The allocator is found this way rather than linked against, so that this crate doesn't depend on it being linked at all.
+fn lookup(symbol: &[u8]) -> *mut c_void {
+	#[cfg(unix)]
+	{
+		// `RTLD_DEFAULT`, searching the executable and the libraries it loaded.
+		#[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+		let handle = ptr::without_provenance_mut(usize::MAX - 1);
+		#[cfg(not(any(target_vendor = "apple", target_os = "freebsd")))]
+		let handle = ptr::null_mut();
+		unsafe { dlsym(handle, symbol.as_ptr().cast()) }
+	}
+	#[cfg(not(unix))]
+	{
+		let _ = symbol;
+		ptr::null_mut()
+	}
+}
+
+/// Return jemalloc's `mallctl`, as exported by `tikv-jemalloc-sys` with its default symbol prefix, if it can be found.
+#[cfg(feature = "jemalloc")]
+fn mallctl() -> Option<Mallctl> {
+	let mallctl = lookup(b"_rjem_mallctl\0");
+	(!mallctl.is_null()).then(|| unsafe { mem::transmute::<*mut c_void, Mallctl>(mallctl) })
+}
+
+/// The wrapped allocator's own view of its memory usage, as returned by [`Cap::backend_stats`].
+///
+/// Allocators track different things, so each statistic is `None` if the allocator doesn't report it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BackendStats {
+	/// The number of bytes allocated by the application.
+	pub allocated: Option<usize>,
+	/// The number of bytes in active pages, which includes fragmentation within them.
+	pub active: Option<usize>,
+	/// The number of bytes in physically resident pages mapped by the allocator.
+	pub resident: Option<usize>,
+	/// The number of bytes in chunks mapped by the allocator.
+	pub mapped: Option<usize>,
+	/// The number of bytes committed by the allocator.
+	pub committed: Option<usize>,
+	/// The number of bytes used by the allocator's own metadata.
+	pub metadata: Option<usize>,
+}
+
+/// Read the `size_t` statistic `name`, a nul-terminated string, through `mallctl`.
+#[cfg(feature = "jemalloc")]
+fn read(mallctl: Mallctl, name: &[u8]) -> Option<usize> {
+	let mut value = 0_usize;
+	let mut len = size_of::<usize>();
+	let res = unsafe {
+		mallctl(
+			name.as_ptr().cast(),
+			(&raw mut value).cast(),
+			&raw mut len,
+			ptr::null_mut(),
+			0,
+		)
+	};
+	(res == 0).then_some(value)
+}
+
+/// Return jemalloc's statistics, advancing its epoch first to refresh them.
+#[cfg(feature = "jemalloc")]
+fn jemalloc() -> Option<BackendStats> {
+	let mallctl = mallctl()?;
+	let mut epoch = 1_u64;
+	let res = unsafe {
+		mallctl(
+			b"epoch\0".as_ptr().cast(),
+			ptr::null_mut(),
+			ptr::null_mut(),
+			(&raw mut epoch).cast(),
+			size_of::<u64>(),
+		)
+	};
+	if res != 0 {
+		return None;
+	}
+	Some(BackendStats {
+		allocated: Some(read(mallctl, b"stats.allocated\0")?),
+		active: Some(read(mallctl, b"stats.active\0")?),
+		resident: Some(read(mallctl, b"stats.resident\0")?),
+		mapped: Some(read(mallctl, b"stats.mapped\0")?),
+		committed: None,
+		metadata: Some(read(mallctl, b"stats.metadata\0")?),
+	})
+}
+
+/// Return mimalloc's statistics.
+#[cfg(feature = "mimalloc")]
+fn mimalloc() -> Option<BackendStats> {
+	let mi_process_info = lookup(b"mi_process_info\0");
+	if mi_process_info.is_null() {
+		return None;
+	}
+	let mi_process_info = unsafe { mem::transmute::<*mut c_void, MiProcessInfo>(mi_process_info) };
+	let (mut resident, mut committed) = (0, 0);
+	unsafe {
+		mi_process_info(
+			ptr::null_mut(),
+			ptr::null_mut(),
+			ptr::null_mut(),
+			&raw mut resident,
+			ptr::null_mut(),
+			&raw mut committed,
+			ptr::null_mut(),
+			ptr::null_mut(),
+		);
+	}
+	Some(BackendStats {
+		resident: Some(resident),
+		committed: Some(committed),
+		..BackendStats::default()
+	})
+}
+
+impl<H> Cap<H> {
+	/// Return the wrapped allocator's own statistics, to be compared with this allocator's accounting.
+	///
+	/// These are [jemalloc's](Self::jemalloc_stats) with the `jemalloc` feature, if it can be found, otherwise [mimalloc's](Self::mimalloc_stats) with the `mimalloc` feature. `None` is returned if neither can be found, or reports statistics.
+	pub fn backend_stats(&self) -> Option<BackendStats> {
+		let stats = None;
+		#[cfg(feature = "jemalloc")]
+		let stats = stats.or_else(|| self.jemalloc_stats());
+		#[cfg(feature = "mimalloc")]
+		let stats = stats.or_else(|| self.mimalloc_stats());
+		stats
+	}
+
+	/// Return jemalloc's statistics, from `_rjem_mallctl` as exported by `tikv-jemallocator` with its default symbol prefix, which also requires its `stats` feature.
+	///
+	/// The allocator isn't linked against, but looked up among the symbols the process exports, on Unix only. Those of an allocator statically linked into an executable are only exported if it is linked with `-C link-arg=-rdynamic`, or the like. `None` is returned if jemalloc can't be found, or doesn't report statistics.
+	#[cfg(feature = "jemalloc")]
+	pub fn jemalloc_stats(&self) -> Option<BackendStats> {
+		jemalloc()
+	}
+
+	/// Return mimalloc's process statistics, from `mi_process_info`, as linked by `mimalloc`.
+	///
+	/// The allocator isn't linked against, but looked up among the symbols the process exports, on Unix only. Those of an allocator statically linked into an executable are only exported if it is linked with `-C link-arg=-rdynamic`, or the like. `None` is returned if mimalloc can't be found.
+	#[cfg(feature = "mimalloc")]
+	pub fn mimalloc_stats(&self) -> Option<BackendStats> {
+		mimalloc()
+	}
+
+	/// Sample the wrapped allocator's [metadata](BackendStats::metadata) now, updating the figure returned by [`metadata`](Self::metadata), and return it if the allocator reports it.
+	pub fn sample_metadata(&self) -> Option<usize> {
+		let metadata = self.backend_stats()?.metadata?;
+		self.metadata.store(metadata, Ordering::Relaxed);
+		Some(metadata)
+	}
+
+	/// [Sample](Self::sample_metadata) the wrapped allocator's metadata every `interval` on a background thread.
+	///
+	/// Allocator metadata, such as jemalloc's `stats.metadata`, isn't counted in [`allocated`](Self::allocated), yet can reach hundreds of MiB for allocation-heavy workloads. Tracking it lets [`allocated_with_metadata`](Self::allocated_with_metadata) be compared to the limit. The thread exits if the allocator doesn't report metadata.
+	pub fn track_metadata(&'static self, interval: Duration) -> thread::JoinHandle<()>
+	where
+		H: Sync,
+	{
+		thread::spawn(move || {
+			while self.sample_metadata().is_some() {
+				thread::sleep(interval);
+			}
+		})
+	}
+
+	/// Return the wrapped allocator's metadata in bytes, as last [sampled](Self::sample_metadata), or 0 if it hasn't been.
+	pub fn metadata(&self) -> usize {
+		self.metadata.load(Ordering::Relaxed)
+	}
+
+	/// Return the bytes allocated plus the wrapped allocator's [metadata](Self::metadata), to be compared with the limit.
+	pub fn allocated_with_metadata(&self) -> usize {
+		self.allocated().saturating_add(self.metadata())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::alloc::System;
+
+	use crate::Cap;
+
+	#[test]
+	fn backend_stats_unlinked() {
+		// Neither allocator is linked into the tests, which still link.
+		let cap = Cap::new(System, usize::MAX);
+		assert_eq!(cap.backend_stats(), None);
+		#[cfg(feature = "jemalloc")]
+		assert_eq!(cap.jemalloc_stats(), None);
+		#[cfg(feature = "mimalloc")]
+		assert_eq!(cap.mimalloc_stats(), None);
+		assert_eq!(cap.sample_metadata(), None);
+	}
+}
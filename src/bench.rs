@@ -0,0 +1,128 @@
+//! A microbenchmark of the overhead of accounting, for quantifying its cost on a given machine and set of enabled features.
+//!
+//! ```
+//! for measurement in cap::bench::measure_overhead() {
+//!     println!(
+//!         "{}: {:.1}ns/op overhead",
+//!         measurement.workload,
+//!         measurement.delta_ns()
+//!     );
+//! }
+//! ```
+
+use std::{
+	alloc::{GlobalAlloc, Layout, System}, hint::black_box, time::Instant
+};
+
+use crate::Cap;
+
+/// The number of operations each workload is timed over by [`measure_overhead`].
+pub const ITERATIONS: usize = 100_000;
+
+/// The time per operation of a workload with and without accounting, as returned by [`measure_overhead`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Measurement {
+	/// The name of the workload.
+	pub workload: &'static str,
+	/// The nanoseconds per operation directly against the system allocator.
+	pub baseline_ns: f64,
+	/// The nanoseconds per operation through a [`Cap`] wrapping it.
+	pub capped_ns: f64,
+}
+
+impl Measurement {
+	/// Return the nanoseconds per operation added by accounting. This can be negative due to noise.
+	#[must_use]
+	pub fn delta_ns(&self) -> f64 {
+		self.capped_ns - self.baseline_ns
+	}
+}
+
+/// Time a standard set of allocation workloads over [`ITERATIONS`] operations each, directly against the system allocator and through an unlimited [`Cap`] wrapping it, with whichever features are enabled.
+///
+/// The workloads are allocating and freeing a small block, allocating and freeing a large block, and growing a block by reallocation.
+#[must_use]
+pub fn measure_overhead() -> Vec<Measurement> {
+	measure_overhead_with(ITERATIONS)
+}
+
+/// Like [`measure_overhead`], but timing each workload over `iterations` operations.
+#[must_use]
+pub fn measure_overhead_with(iterations: usize) -> Vec<Measurement> {
+	let cap = Cap::new(System, usize::MAX);
+	let workloads: [(&'static str, Workload); 3] = [
+		("alloc small", |a| unsafe {
+			let layout = Layout::new::<[u64; 4]>();
+			a.dealloc(black_box(a.alloc(layout)), layout);
+		}),
+		("alloc large", |a| unsafe {
+			let layout = Layout::from_size_align_unchecked(64 * 1024, 8);
+			a.dealloc(black_box(a.alloc(layout)), layout);
+		}),
+		("realloc grow", |a| unsafe {
+			let layout = Layout::new::<[u64; 4]>();
+			let ptr = a.realloc(a.alloc(layout), layout, 256);
+			a.dealloc(black_box(ptr), Layout::from_size_align_unchecked(256, 8));
+		}),
+	];
+	workloads
+		.iter()
+		.map(|&(workload, f)| Measurement {
+			workload,
+			baseline_ns: time(iterations, || f(&System)),
+			capped_ns: time(iterations, || f(&cap)),
+		})
+		.collect()
+}
+
+/// A workload, run once per operation against the given allocator.
+type Workload = fn(&dyn Alloc);
+
+/// An allocator that workloads can be run against without being generic over it.
+trait Alloc {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8;
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8;
+}
+
+impl<A: GlobalAlloc> Alloc for A {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		GlobalAlloc::alloc(self, layout)
+	}
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		GlobalAlloc::dealloc(self, ptr, layout);
+	}
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		GlobalAlloc::realloc(self, ptr, layout, new_size)
+	}
+}
+
+/// Return the mean nanoseconds per call of `f` over `iterations` calls, after warming up.
+fn time(iterations: usize, mut f: impl FnMut()) -> f64 {
+	for _ in 0..iterations / 10 {
+		f();
+	}
+	let start = Instant::now();
+	for _ in 0..iterations {
+		f();
+	}
+	#[allow(clippy::cast_precision_loss)]
+	let ns = start.elapsed().as_nanos() as f64 / iterations.max(1) as f64;
+	ns
+}
+
+#[cfg(test)]
+mod tests {
+	use super::measure_overhead_with;
+
+	#[test]
+	fn measure_overhead() {
+		let measurements = measure_overhead_with(100);
+		assert_eq!(measurements.len(), 3);
+		for measurement in measurements {
+			assert!(measurement.baseline_ns >= 0.0 && measurement.capped_ns >= 0.0);
+			assert!(measurement.delta_ns().is_finite());
+		}
+	}
+}
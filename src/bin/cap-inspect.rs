@@ -0,0 +1,32 @@
+//! Print a snapshot written by [`Cap::export_snapshot`](cap::Cap::export_snapshot), or the changes between two.
+//!
+//! ```text
+//! cap-inspect <snapshot>
+//! cap-inspect <earlier snapshot> <later snapshot>
+//! ```
+
+use std::{env, fs::File, process};
+
+use cap::snapshot::Snapshot;
+
+fn load(path: &str) -> Snapshot {
+	match File::open(path).and_then(|mut file| Snapshot::read(&mut file)) {
+		Ok(snapshot) => snapshot,
+		Err(error) => {
+			eprintln!("cap-inspect: {}: {}", path, error);
+			process::exit(1);
+		}
+	}
+}
+
+fn main() {
+	let args = env::args().skip(1).collect::<Vec<_>>();
+	match &*args {
+		[path] => print!("{}", load(path)),
+		[before, after] => print!("{}", load(before).diff(&load(after))),
+		_ => {
+			eprintln!("usage: cap-inspect <snapshot> [<later snapshot>]");
+			process::exit(2);
+		}
+	}
+}
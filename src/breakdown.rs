@@ -0,0 +1,121 @@
+//! A breakdown of what a [`Cap`]'s budget is consumed by.
+
+use std::sync::atomic::Ordering;
+
+use crate::Cap;
+
+/// How a [`Cap`]'s limit is currently consumed, as returned by [`Cap::breakdown`].
+///
+/// `heap`, `external`, `diagnostics` and `quarantined` sum to [`allocated`](Cap::allocated), which with `remaining` makes up the limit, less any `overdraft`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Breakdown {
+	/// The limit.
+	pub limit: usize,
+	/// The bytes of live heap allocations. Estimates of open [`Transaction`](crate::Transaction)s, and the unused remainders of carve-outs, aren't tracked separately so are included.
+	pub heap: usize,
+	/// The bytes [charged](Cap::charge) by other means, including committed transactions.
+	pub external: usize,
+	/// The bytes of diagnostics, while they are [charged](Cap::set_diagnostics_charged) against the limit.
+	pub diagnostics: usize,
+	/// The bytes freed but held in quarantine. Always 0 without the `quarantine` feature.
+	pub quarantined: usize,
+	/// The bytes allocated beyond the limit while [exempt](Cap::exempt), yet to be repaid.
+	pub overdraft: usize,
+	/// The bytes kept free within the limit for the estimated overhead of the wrapped allocator. Always 0 without the `overhead` feature.
+	pub estimated_overhead: usize,
+	/// The bytes that can still be allocated, including the estimated overhead.
+	pub remaining: usize,
+	/// The bytes of address space [reserved](Cap::reserve), which count against the committed limit rather than the limit. Always 0 without the `committed` feature.
+	pub reserved: usize,
+}
+
+impl<H> Cap<H> {
+	/// Return how the limit is currently consumed, by the heap and by each of the other sources charged against it.
+	///
+	/// The counters are read one after another, so while other threads allocate they may not quite sum to [`allocated`](Self::allocated).
+	///
+	/// ```
+	/// use std::alloc;
+	/// use cap::Cap;
+	///
+	/// #[global_allocator]
+	/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+	///
+	/// fn main() {
+	///     ALLOCATOR.charge(1024).unwrap();
+	///     let breakdown = ALLOCATOR.breakdown();
+	///     assert_eq!(breakdown.external, 1024);
+	///     println!("{:?}", breakdown);
+	/// #   ALLOCATOR.uncharge(1024);
+	/// }
+	/// ```
+	pub fn breakdown(&self) -> Breakdown {
+		let allocated = self.allocated();
+		let diagnostics = self.diagnostics.load(Ordering::Relaxed);
+		let external = self.external().saturating_sub(diagnostics);
+		#[cfg(feature = "quarantine")]
+		let quarantined = self.quarantine.bytes.load(Ordering::Relaxed);
+		#[cfg(not(feature = "quarantine"))]
+		let quarantined = 0;
+		#[cfg(feature = "overhead")]
+		let estimated_overhead = self.estimated_overhead();
+		#[cfg(not(feature = "overhead"))]
+		let estimated_overhead = 0;
+		#[cfg(feature = "committed")]
+		let reserved = self.reserved();
+		#[cfg(not(feature = "committed"))]
+		let reserved = 0;
+		Breakdown {
+			limit: self.limit(),
+			heap: allocated.saturating_sub(external + diagnostics + quarantined),
+			external,
+			diagnostics,
+			quarantined,
+			overdraft: self.overdraft.load(Ordering::Relaxed),
+			estimated_overhead,
+			remaining: self.remaining(),
+			reserved,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::alloc::{GlobalAlloc, Layout, System};
+
+	use crate::Cap;
+
+	#[test]
+	fn breakdown() {
+		let cap = Cap::new(System, 10_000);
+		cap.charge(1000).unwrap();
+		#[cfg(feature = "committed")]
+		cap.reserve(500).unwrap();
+		let transaction = cap.transaction(300).unwrap();
+		transaction.commit(200).unwrap();
+		let layout = Layout::from_size_align(2000, 1).unwrap();
+		let ptr = unsafe { cap.alloc(layout) };
+		assert!(!ptr.is_null());
+		let breakdown = cap.breakdown();
+		assert_eq!(
+			(breakdown.heap, breakdown.external),
+			(cap.charged(layout), 1200)
+		);
+		#[cfg(feature = "committed")]
+		assert_eq!(breakdown.reserved, 500);
+		assert_eq!(
+			breakdown.heap
+				+ breakdown.external
+				+ breakdown.diagnostics
+				+ breakdown.quarantined
+				+ breakdown.remaining,
+			breakdown.limit
+		);
+		unsafe { cap.dealloc(ptr, layout) };
+		#[cfg(feature = "quarantine")]
+		cap.flush_quarantine();
+		cap.uncharge(1200);
+		assert_eq!(cap.breakdown().heap, 0);
+	}
+}
@@ -0,0 +1,128 @@
+//! A circuit breaker that sheds best-effort allocations during a storm of failures.
+
+use std::{
+	cell::Cell, convert::TryFrom, marker::PhantomData, ptr, sync::atomic::{AtomicU64, AtomicUsize, Ordering}, time::Duration
+};
+
+thread_local! {
+	/// The cap allocations on this thread are best-effort for.
+	static BEST_EFFORT: Cell<*const ()> = const { Cell::new(ptr::null()) };
+}
+
+/// A guard returned by [`Cap::best_effort`](crate::Cap::best_effort) that makes allocations critical again when dropped.
+#[derive(Debug)]
+pub struct BestEffort<'a> {
+	previous: *const (),
+	_cap: PhantomData<&'a ()>,
+}
+
+impl BestEffort<'_> {
+	pub(crate) fn new(cap: *const ()) -> Self {
+		Self {
+			previous: BEST_EFFORT.with(|best_effort| best_effort.replace(cap)),
+			_cap: PhantomData,
+		}
+	}
+}
+
+impl Drop for BestEffort<'_> {
+	fn drop(&mut self) {
+		BEST_EFFORT.with(|best_effort| best_effort.set(self.previous));
+	}
+}
+
+/// Return whether allocations on this thread are best-effort for `cap`.
+pub(crate) fn is_best_effort(cap: *const ()) -> bool {
+	BEST_EFFORT
+		.try_with(|best_effort| best_effort.get() == cap)
+		.unwrap_or(false)
+}
+
+/// A [`Cap`](crate::Cap)'s circuit breaker: consecutive failures within a window open it for a cool-down.
+#[derive(Debug)]
+pub(crate) struct Breaker {
+	/// The consecutive failures that open the breaker, or 0 if it is disabled.
+	threshold: AtomicUsize,
+	window: AtomicU64,
+	cool_down: AtomicU64,
+	failures: AtomicUsize,
+	/// When the current run of failures started, in nanoseconds since the epoch.
+	run_start: AtomicU64,
+	/// When the breaker closes again, in nanoseconds since the epoch.
+	open_until: AtomicU64,
+}
+
+impl Breaker {
+	pub(crate) const fn new() -> Self {
+		Self {
+			threshold: AtomicUsize::new(0),
+			window: AtomicU64::new(0),
+			cool_down: AtomicU64::new(0),
+			failures: AtomicUsize::new(0),
+			run_start: AtomicU64::new(0),
+			open_until: AtomicU64::new(0),
+		}
+	}
+
+	pub(crate) fn set(&self, failures: usize, window: Duration, cool_down: Duration) {
+		let nanos = |duration: Duration| u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+		self.window.store(nanos(window), Ordering::Relaxed);
+		self.cool_down.store(nanos(cool_down), Ordering::Relaxed);
+		self.failures.store(0, Ordering::Relaxed);
+		self.open_until.store(0, Ordering::Relaxed);
+		self.threshold.store(failures, Ordering::Relaxed);
+	}
+
+	/// Return whether a threshold is set, so that the breaker can open at all.
+	#[inline]
+	pub(crate) fn is_enabled(&self) -> bool {
+		self.threshold.load(Ordering::Relaxed) != 0
+	}
+
+	/// Return whether the breaker is open at `now`.
+	pub(crate) fn is_open(&self, now: u64) -> bool {
+		now < self.open_until.load(Ordering::Relaxed)
+	}
+
+	/// Record a successful allocation, which breaks any run of failures.
+	///
+	/// This is a single load unless a run is under way, as it is on the allocation path.
+	#[inline]
+	pub(crate) fn succeeded(&self) {
+		if self.failures.load(Ordering::Relaxed) != 0 {
+			self.break_run();
+		}
+	}
+
+	#[cold]
+	#[inline(never)]
+	fn break_run(&self) {
+		self.failures.store(0, Ordering::Relaxed);
+	}
+
+	/// Record a failed allocation at `now`, opening the breaker if it completes a run of failures.
+	///
+	/// Failures while the breaker is open, such as of the allocations it sheds, don't count towards the next run.
+	pub(crate) fn failed(&self, now: u64) {
+		let threshold = self.threshold.load(Ordering::Relaxed);
+		if threshold == 0 || self.is_open(now) {
+			return;
+		}
+		let run_start = self.run_start.load(Ordering::Relaxed);
+		let failures = if self.failures.load(Ordering::Relaxed) == 0
+			|| now.saturating_sub(run_start) > self.window.load(Ordering::Relaxed)
+		{
+			self.run_start.store(now, Ordering::Relaxed);
+			self.failures.store(1, Ordering::Relaxed);
+			1
+		} else {
+			self.failures.fetch_add(1, Ordering::Relaxed) + 1
+		};
+		if failures >= threshold {
+			let cool_down = self.cool_down.load(Ordering::Relaxed);
+			self.open_until
+				.store(now.saturating_add(cool_down), Ordering::Relaxed);
+			self.failures.store(0, Ordering::Relaxed);
+		}
+	}
+}
@@ -0,0 +1,127 @@
+//! A broadcast channel of a [`Cap`](crate::Cap)'s notable events, for components that react to them independently.
+
+use std::{
+	alloc::Layout, fmt, sync::{
+		atomic::{AtomicUsize, Ordering}, mpsc::{self, Receiver, SyncSender, TrySendError}, Mutex, PoisonError
+	}
+};
+
+use crate::PressureEvent;
+#[cfg(feature = "watch")]
+use crate::WatchEvent;
+
+/// An event published to the receivers returned by [`Cap::subscribe`](crate::Cap::subscribe).
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Notification {
+	/// A signal of memory pressure from outside the allocator, as also delivered to the [pressure callbacks](crate::Cap::on_pressure).
+	Pressure(PressureEvent),
+	/// A crossing reported by a registered [`Watch`](crate::Watch).
+	#[cfg(feature = "watch")]
+	Watch(WatchEvent),
+	/// An allocation failed.
+	Failure {
+		/// The size of the allocation.
+		size: usize,
+		/// The alignment of the allocation.
+		align: usize,
+	},
+}
+
+/// The senders to a [`Cap`](crate::Cap)'s subscribers.
+pub(crate) struct Channel {
+	senders: Mutex<Vec<SyncSender<Notification>>>,
+	subscribers: AtomicUsize,
+}
+
+impl Channel {
+	pub(crate) const fn new() -> Self {
+		Self {
+			senders: Mutex::new(Vec::new()),
+			subscribers: AtomicUsize::new(0),
+		}
+	}
+
+	pub(crate) fn subscribe(&self, capacity: usize) -> Receiver<Notification> {
+		let (sender, receiver) = mpsc::sync_channel(capacity);
+		let mut senders = self.senders.lock().unwrap_or_else(PoisonError::into_inner);
+		senders.push(sender);
+		self.subscribers.store(senders.len(), Ordering::Release);
+		receiver
+	}
+
+	/// Send `notification` to each subscriber with room for it, dropping those that have gone.
+	///
+	/// This may be called from within the allocator: the channels are bounded so sending doesn't allocate, and it gives up rather than wait for the lock.
+	pub(crate) fn publish(&self, notification: Notification) {
+		if self.subscribers.load(Ordering::Acquire) == 0 {
+			return;
+		}
+		let _ = crate::reentrancy::call(|| {
+			if let Ok(mut senders) = self.senders.try_lock() {
+				senders.retain(|sender| {
+					!matches!(
+						sender.try_send(notification),
+						Err(TrySendError::Disconnected(_))
+					)
+				});
+				self.subscribers.store(senders.len(), Ordering::Release);
+			}
+		});
+	}
+
+	pub(crate) fn publish_failure(&self, layout: Layout) {
+		self.publish(Notification::Failure {
+			size: layout.size(),
+			align: layout.align(),
+		});
+	}
+}
+
+impl fmt::Debug for Channel {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Channel")
+			.field("subscribers", &self.subscribers.load(Ordering::Relaxed))
+			.finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::alloc::{GlobalAlloc, Layout, System};
+	#[cfg(feature = "watch")]
+	use std::time::Duration;
+
+	use super::Notification;
+	#[cfg(feature = "watch")]
+	use crate::Crossing;
+	use crate::{Cap, PressureEvent};
+
+	#[test]
+	fn broadcast() {
+		let cap = Cap::new(System, 150);
+		let (a, b) = (cap.subscribe(4), cap.subscribe(4));
+		#[cfg(feature = "watch")]
+		let watch = cap.watch(50, Crossing::Rising, Duration::ZERO).unwrap();
+		let layout = Layout::new::<[u8; 100]>();
+		unsafe {
+			let x = cap.alloc(layout);
+			assert!(cap.alloc(layout).is_null());
+			cap.dealloc(x, layout);
+		}
+		drop(b);
+		cap.notify_pressure(PressureEvent::LowMemory);
+		assert_eq!(
+			a.try_iter().collect::<Vec<_>>(),
+			[
+				#[cfg(feature = "watch")]
+				Notification::Watch(watch.last_event().unwrap()),
+				Notification::Failure {
+					size: 100,
+					align: 1
+				},
+				Notification::Pressure(PressureEvent::LowMemory),
+			]
+		);
+	}
+}
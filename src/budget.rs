@@ -0,0 +1,461 @@
+//! Budgets created and destroyed at runtime, for subsystems such as plugins that come and go.
+
+use std::{
+	alloc::Layout, cell::Cell, future::Future, pin::Pin, ptr, sync::{
+		atomic::{AtomicUsize, Ordering}, Arc, Weak
+	}, task::{Context, Poll}
+};
+
+use crate::{rejection, Rejection};
+
+thread_local! {
+	static CURRENT: Current = const { Current(Cell::new(ptr::null())) };
+}
+
+/// The budget entered on this thread, of which it holds a strong reference, so that it stays alive however the guards that entered it are dropped or forgotten.
+struct Current(Cell<*const Budget>);
+
+impl Drop for Current {
+	fn drop(&mut self) {
+		release_ref(self.0.get());
+	}
+}
+
+/// Release a strong reference to `budget`, if it isn't null.
+fn release_ref(budget: *const Budget) {
+	if !budget.is_null() {
+		unsafe { Arc::decrement_strong_count(budget) };
+	}
+}
+
+/// A limit on the memory allocated by a [`Cap`](crate::Cap) while it is entered, shared through an [`Arc`].
+///
+/// Each allocation made while a budget is entered is charged to it and to each of its ancestors, and fails if that would take any of them over its limit. It stays charged to that budget until it is freed, wherever that happens, and keeps the budget alive until then:
+///
+/// ```
+/// use std::alloc;
+/// use cap::{Budget, Cap};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     let plugins = Budget::new(1024 * 1024);
+///     let plugin = plugins.child(64 * 1024);
+///     let state = {
+///         let _guard = plugin.enter();
+///         assert!(Vec::<u8>::new().try_reserve(128 * 1024).is_err());
+///         vec![0u8; 1000]
+///     };
+///     assert!(plugin.allocated() >= 1000 && plugins.allocated() == plugin.allocated());
+///     // Unloading the plugin: the budget is destroyed once its memory is freed.
+///     drop((plugin, state));
+///     assert_eq!(plugins.allocated(), 0);
+/// }
+/// ```
+///
+/// Reallocations stay charged to the budget the block was allocated under.
+#[derive(Debug)]
+pub struct Budget {
+	allocated: AtomicUsize,
+	limit: AtomicUsize,
+	parent: Option<Arc<Budget>>,
+}
+
+impl Budget {
+	/// Create a new budget of `limit` bytes.
+	#[must_use]
+	pub fn new(limit: usize) -> Arc<Self> {
+		Arc::new(Self {
+			allocated: AtomicUsize::new(0),
+			limit: AtomicUsize::new(limit),
+			parent: None,
+		})
+	}
+
+	/// Create a budget of `limit` bytes within this one, so that what is charged to it is also charged to this.
+	#[must_use]
+	pub fn child(self: &Arc<Self>, limit: usize) -> Arc<Self> {
+		Arc::new(Self {
+			allocated: AtomicUsize::new(0),
+			limit: AtomicUsize::new(limit),
+			parent: Some(Arc::clone(self)),
+		})
+	}
+
+	/// Return the budget this was created within, if any.
+	pub fn parent(&self) -> Option<&Arc<Budget>> {
+		self.parent.as_ref()
+	}
+
+	/// Return the bytes charged to this budget.
+	pub fn allocated(&self) -> usize {
+		self.allocated.load(Ordering::Relaxed)
+	}
+
+	/// Return the limit of this budget.
+	pub fn limit(&self) -> usize {
+		self.limit.load(Ordering::Relaxed)
+	}
+
+	/// Set the limit of this budget. Lowering it below what is allocated fails further allocations until enough is freed.
+	pub fn set_limit(&self, limit: usize) {
+		self.limit.store(limit, Ordering::Relaxed);
+	}
+
+	/// Charge allocations on this thread to this budget, until the returned guard is dropped.
+	///
+	/// The thread holds a reference to the budget while it is entered, so it stays alive even if the guard is forgotten.
+	#[must_use]
+	pub fn enter(self: &Arc<Self>) -> BudgetGuard {
+		let entered = Arc::into_raw(Arc::clone(self));
+		let previous = CURRENT.with(|current| current.0.replace(entered));
+		BudgetGuard { previous }
+	}
+
+	/// Return a handle to this budget that doesn't keep it alive, for observers that may outlive it.
+	#[must_use]
+	pub fn downgrade(self: &Arc<Self>) -> WeakCapHandle {
+		WeakCapHandle {
+			budget: Arc::downgrade(self),
+		}
+	}
+
+	/// Wrap `future` so that it is charged to this budget each time it is polled.
+	pub fn instrument<F>(self: Arc<Self>, future: F) -> Budgeted<F>
+	where
+		F: Future,
+	{
+		Budgeted {
+			budget: self,
+			future,
+		}
+	}
+
+	/// Charge `size` bytes to this budget and its ancestors, returning whether they fit.
+	fn charge(&self, size: usize) -> bool {
+		let allocated = self.allocated.fetch_add(size, Ordering::Relaxed);
+		if allocated.saturating_add(size) > self.limit()
+			|| !self
+				.parent
+				.as_ref()
+				.is_none_or(|parent| parent.charge(size))
+		{
+			let _ = self.allocated.fetch_sub(size, Ordering::Relaxed);
+			return false;
+		}
+		true
+	}
+
+	fn release(&self, size: usize) {
+		let _ = self.allocated.fetch_sub(size, Ordering::Relaxed);
+		if let Some(parent) = &self.parent {
+			parent.release(size);
+		}
+	}
+}
+
+/// A handle to a [`Budget`], returned by [`Budget::downgrade`], that reports on it without keeping it alive.
+///
+/// This is for long-lived observers, such as a metrics registry, of budgets created at runtime: holding the budget itself would leak it, and everything it parents, after the subsystem it was created for has gone. Once the budget is destroyed, which is when it has been dropped and its last allocation freed, the handle reports it as gone.
+///
+/// ```
+/// use std::alloc;
+/// use cap::{Budget, Cap};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     let plugin = Budget::new(64 * 1024);
+///     let handle = plugin.downgrade();
+///     assert_eq!(handle.limit(), Some(64 * 1024));
+///     drop(plugin);
+///     assert!(handle.is_gone());
+///     assert_eq!(handle.allocated(), None);
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct WeakCapHandle {
+	budget: Weak<Budget>,
+}
+
+impl WeakCapHandle {
+	/// Return the bytes charged to the budget, or `None` if it is gone.
+	#[must_use]
+	pub fn allocated(&self) -> Option<usize> {
+		self.budget.upgrade().map(|budget| budget.allocated())
+	}
+
+	/// Return the limit of the budget, or `None` if it is gone.
+	#[must_use]
+	pub fn limit(&self) -> Option<usize> {
+		self.budget.upgrade().map(|budget| budget.limit())
+	}
+
+	/// Return whether the budget has been destroyed.
+	#[must_use]
+	pub fn is_gone(&self) -> bool {
+		self.budget.strong_count() == 0
+	}
+
+	/// Return the budget, if it isn't gone.
+	#[must_use]
+	pub fn upgrade(&self) -> Option<Arc<Budget>> {
+		self.budget.upgrade()
+	}
+}
+
+/// A guard returned by [`Budget::enter`] that restores the previously entered budget when dropped.
+#[derive(Debug)]
+pub struct BudgetGuard {
+	/// The budget entered before, of which the guard holds the thread's reference.
+	previous: *const Budget,
+}
+
+impl Drop for BudgetGuard {
+	fn drop(&mut self) {
+		// What is replaced is whatever is entered now, which if guards are dropped out of order isn't the budget this guard entered.
+		match CURRENT.try_with(|current| current.0.replace(self.previous)) {
+			Ok(entered) => release_ref(entered),
+			// The thread is exiting, and has released what was entered.
+			Err(_) => release_ref(self.previous),
+		}
+	}
+}
+
+/// A future returned by [`Budget::instrument`] that runs its wrapped future within a budget.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Budgeted<F> {
+	budget: Arc<Budget>,
+	future: F,
+}
+
+impl<F> Future for Budgeted<F>
+where
+	F: Future,
+{
+	type Output = F::Output;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+		let _guard = self.budget.enter();
+		// Safe as `future` is structurally pinned: it is never moved out of `self`.
+		unsafe { self.map_unchecked_mut(|budgeted| &mut budgeted.future) }.poll(cx)
+	}
+}
+
+/// The budget entered on this thread, or null if none.
+pub(crate) fn current() -> *const Budget {
+	CURRENT
+		.try_with(|current| current.0.get())
+		.unwrap_or(ptr::null())
+}
+
+/// Charge `size` bytes to `budget`, if it isn't null, returning whether they fit.
+pub(crate) fn charge(budget: *const Budget, size: usize) -> bool {
+	// Safe as the budget is kept alive by the thread that entered it, or by the block being resized.
+	let Some(budget) = (unsafe { budget.as_ref() }) else {
+		return true;
+	};
+	if budget.charge(size) {
+		true
+	} else {
+		rejection::reject(Rejection::Budget);
+		false
+	}
+}
+
+/// Release `size` bytes charged to `budget`, if it isn't null.
+pub(crate) fn release(budget: *const Budget, size: usize) {
+	if let Some(budget) = unsafe { budget.as_ref() } {
+		budget.release(size);
+	}
+}
+
+const TRAILER: usize = size_of::<usize>();
+
+/// The number of bytes the suffix recording the budget adds to a block of `size` bytes, including the padding to align it.
+pub(crate) fn trailer_size(size: usize) -> usize {
+	size.next_multiple_of(TRAILER) - size + TRAILER
+}
+
+/// The layout requested of the wrapped allocator: `layout` with a suffix recording the budget.
+pub(crate) fn inner_layout(layout: Layout) -> Option<Layout> {
+	let size = layout.size().checked_add(trailer_size(layout.size()))?;
+	Layout::from_size_align(size, layout.align().max(align_of::<usize>())).ok()
+}
+
+/// Record `budget`, which has been charged for it, in the suffix of the block `base` of the inner layout `layout`, keeping the budget alive until it is [detached](detach).
+pub(crate) unsafe fn attach(base: *mut u8, layout: Layout, budget: *const Budget) {
+	if !budget.is_null() {
+		Arc::increment_strong_count(budget);
+	}
+	write(base, layout, budget);
+}
+
+/// Return the budget recorded in the suffix of the block `base` of the inner layout `layout`.
+#[allow(clippy::cast_ptr_alignment)] // the suffix is a multiple of usize's alignment
+pub(crate) unsafe fn read(base: *mut u8, layout: Layout) -> *const Budget {
+	base.add(layout.size() - TRAILER)
+		.cast::<*const Budget>()
+		.read()
+}
+
+/// Record `budget` in the suffix of a block that has been moved or resized, which already holds a reference to it.
+#[allow(clippy::cast_ptr_alignment)]
+pub(crate) unsafe fn write(base: *mut u8, layout: Layout, budget: *const Budget) {
+	base.add(layout.size() - TRAILER)
+		.cast::<*const Budget>()
+		.write(budget);
+}
+
+/// Release the `size` bytes a block being freed was charged to its budget, and its reference to it.
+pub(crate) unsafe fn detach(base: *mut u8, layout: Layout, size: usize) {
+	let budget = read(base, layout);
+	if !budget.is_null() {
+		(*budget).release(size);
+		Arc::decrement_strong_count(budget);
+	}
+}
+
+/// Move the block `base` of the inner layout `layout`, charged `size` bytes, from its budget to `to`, returning `Err` if it doesn't fit within `to`.
+pub(crate) unsafe fn transfer(
+	base: *mut u8, layout: Layout, size: usize, to: *const Budget,
+) -> Result<(), ()> {
+	let from = read(base, layout);
+	if ptr::eq(from, to) {
+		return Ok(());
+	}
+	if let Some(to) = to.as_ref() {
+		if !to.charge(size) {
+			return Err(());
+		}
+	}
+	attach(base, layout, to);
+	if !from.is_null() {
+		(*from).release(size);
+		Arc::decrement_strong_count(from);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, sync::Arc
+	};
+
+	use super::Budget;
+	use crate::{Cap, Rejection};
+
+	#[test]
+	fn budget() {
+		let cap = Cap::new(System, usize::MAX);
+		let layout = Layout::new::<[u8; 100]>();
+		let charged = cap.charged(layout) + cap.charged(Layout::new::<[u8; 150]>());
+		let parent = Budget::new(charged);
+		let child = parent.child(usize::MAX);
+		unsafe {
+			let (a, b) = {
+				let _guard = child.enter();
+				let a = cap.alloc(layout);
+				let b = cap.alloc(layout);
+				assert!(cap.alloc(layout).is_null());
+				assert_eq!(crate::last_rejection(), Some(Rejection::Budget));
+				(a, cap.realloc(b, layout, 150))
+			};
+			assert_eq!((child.allocated(), parent.allocated()), (charged, charged));
+			// Outside the budget, so neither charged nor limited by it.
+			let c = cap.alloc(layout);
+			let weak = Arc::downgrade(&child);
+			drop(child);
+			cap.dealloc(a, layout);
+			assert!(weak.upgrade().is_some());
+			cap.dealloc(b, Layout::new::<[u8; 150]>());
+			assert!(weak.upgrade().is_none());
+			assert_eq!(parent.allocated(), 0);
+			cap.dealloc(c, layout);
+		}
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	#[test]
+	fn guards_misused() {
+		let cap = Cap::new(System, usize::MAX);
+		let layout = Layout::new::<[u8; 100]>();
+		let (a, b) = (Budget::new(usize::MAX), Budget::new(usize::MAX));
+		unsafe {
+			// The thread keeps a forgotten guard's budget alive.
+			std::mem::forget(a.enter());
+			let weak = Arc::downgrade(&a);
+			drop(a);
+			let x = cap.alloc(layout);
+			assert_eq!(weak.upgrade().unwrap().allocated(), cap.charged(layout));
+			cap.dealloc(x, layout);
+			// Dropped out of order, which leaves entered what the first guard dropped had replaced.
+			let child = b.child(usize::MAX);
+			let (outer, inner) = (b.enter(), child.enter());
+			drop(outer);
+			drop(child);
+			let x = cap.alloc(layout);
+			drop(inner);
+			assert_eq!(weak.upgrade().unwrap().allocated(), cap.charged(layout));
+			cap.dealloc(x, layout);
+			assert!(weak.upgrade().is_none());
+			assert_eq!(Arc::strong_count(&b), 2);
+		}
+	}
+
+	#[test]
+	fn weak_handle() {
+		let cap = Cap::new(System, usize::MAX);
+		let layout = Layout::new::<[u8; 100]>();
+		let parent = Budget::new(usize::MAX);
+		let child = parent.child(1000);
+		let handle = child.downgrade();
+		unsafe {
+			let x = {
+				let _guard = child.enter();
+				cap.alloc(layout)
+			};
+			drop(child);
+			// Kept alive by its allocation, though not by the handle.
+			assert_eq!(
+				(handle.allocated(), handle.limit()),
+				(Some(cap.charged(layout)), Some(1000))
+			);
+			cap.dealloc(x, layout);
+		}
+		assert!(handle.is_gone());
+		assert_eq!((handle.allocated(), handle.limit()), (None, None));
+		assert!(handle.upgrade().is_none());
+		assert_eq!(Arc::strong_count(&parent), 1);
+	}
+
+	#[test]
+	fn transfer() {
+		let cap = Cap::new(System, usize::MAX);
+		let layout = Layout::new::<[u8; 100]>();
+		let (producer, consumer) = (Budget::new(usize::MAX), Budget::new(50));
+		unsafe {
+			let x = {
+				let _guard = producer.enter();
+				cap.alloc(layout)
+			};
+			assert!(cap.transfer_budget(x, layout, Some(&consumer)).is_err());
+			consumer.set_limit(usize::MAX);
+			cap.transfer_budget(x, layout, Some(&consumer)).unwrap();
+			assert_eq!(
+				(producer.allocated(), consumer.allocated()),
+				(0, cap.charged(layout))
+			);
+			assert_eq!(
+				(Arc::strong_count(&producer), Arc::strong_count(&consumer)),
+				(1, 2)
+			);
+			cap.dealloc(x, layout);
+		}
+		assert_eq!(consumer.allocated(), 0);
+	}
+}
@@ -0,0 +1,268 @@
+use std::{
+	alloc::{GlobalAlloc, Layout}, cell::Cell, ptr
+};
+
+/// The smallest size class, as a power of two.
+const MIN_CLASS: u32 = 3;
+/// The number of size classes: 8, 16, 32, 64, 128 and 256 bytes.
+const CLASSES: usize = 6;
+/// The number of blocks each magazine can hold.
+const CAPACITY: usize = 32;
+/// The largest alignment of cached blocks.
+const MAX_ALIGN: usize = 16;
+
+type Release = unsafe fn(*const (), *mut u8, Layout);
+
+struct Magazine {
+	len: Cell<usize>,
+	blocks: [Cell<*mut u8>; CAPACITY],
+}
+
+/// The calling thread's magazines, which serve at most one [`ThreadCache`] at a time.
+struct Magazines {
+	owner: Cell<*const ()>,
+	release: Cell<Option<Release>>,
+	classes: [Magazine; CLASSES],
+}
+
+impl Drop for Magazines {
+	fn drop(&mut self) {
+		let Some(release) = self.release.get() else {
+			return;
+		};
+		for (class, magazine) in self.classes.iter().enumerate() {
+			for block in &magazine.blocks[..magazine.len.get()] {
+				unsafe { release(self.owner.get(), block.get(), class_layout(class)) };
+			}
+		}
+	}
+}
+
+thread_local! {
+	static MAGAZINES: Magazines = const {
+		Magazines {
+			owner: Cell::new(ptr::null()),
+			release: Cell::new(None),
+			classes: [const {
+				Magazine {
+					len: Cell::new(0),
+					blocks: [const { Cell::new(ptr::null_mut()) }; CAPACITY],
+				}
+			}; CLASSES],
+		}
+	};
+}
+
+/// The size class an allocation of `layout` is served from, if it is small enough to be cached.
+fn class(layout: Layout) -> Option<usize> {
+	if layout.align() > MAX_ALIGN {
+		return None;
+	}
+	// Blocks are aligned to their size, up to `MAX_ALIGN`, so over-aligned layouts take a larger class.
+	let size = layout.size().max(layout.align());
+	let class = (size.max(1).next_power_of_two().trailing_zeros()).max(MIN_CLASS) - MIN_CLASS;
+	Some(class as usize).filter(|&class| class < CLASSES)
+}
+
+fn class_layout(class: usize) -> Layout {
+	let size = 1 << (class + MIN_CLASS as usize);
+	Layout::from_size_align(size, size.min(MAX_ALIGN)).unwrap()
+}
+
+unsafe fn release<H: GlobalAlloc>(owner: *const (), ptr: *mut u8, layout: Layout) {
+	(*owner.cast::<ThreadCache<H>>()).inner.dealloc(ptr, layout);
+}
+
+/// An allocator that keeps small freed blocks in per-thread magazines, to be reused without touching the wrapped allocator.
+///
+/// Allocations of up to 256 bytes with alignment of at most 16 are rounded up to a power-of-two size class, of at least their alignment. Up to 32 freed blocks per class are retained by each thread, and handed out again by subsequent allocations on that thread.
+///
+/// Wrapping a [`Cap`](crate::Cap) means its accounting is done at the cache boundary: blocks are only charged and released as they enter and leave the cache, rather than on every allocation, and [`allocated`](crate::Cap::allocated) includes blocks that are cached. For workloads dominated by tiny allocations this avoids most of the atomic operations.
+///
+/// ```
+/// use std::alloc;
+/// use cap::{Cap, ThreadCache};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: ThreadCache<Cap<alloc::System>> =
+///     unsafe { ThreadCache::new(Cap::new(alloc::System, usize::MAX)) };
+///
+/// fn main() {
+///     println!("Currently allocated: {}B", ALLOCATOR.inner().allocated());
+/// }
+/// ```
+///
+/// Each thread's magazines serve one `ThreadCache` at a time; allocations through any other pass straight through to its wrapped allocator. Cached blocks are returned to the wrapped allocator when the thread exits.
+///
+/// Under Miri or a sanitizer nothing is cached, so that use-after-free and leaks of small blocks are still detected.
+#[derive(Debug)]
+pub struct ThreadCache<H> {
+	inner: H,
+}
+
+impl<H> ThreadCache<H> {
+	/// Create a new allocator, caching small allocations in front of `inner`.
+	///
+	/// # Safety
+	///
+	/// The cache must outlive every thread that allocates through it, as blocks cached by a thread are returned to it when the thread exits. This holds for a `static`.
+	pub const unsafe fn new(inner: H) -> Self {
+		Self { inner }
+	}
+
+	/// Return a reference to the wrapped allocator.
+	pub fn inner(&self) -> &H {
+		&self.inner
+	}
+}
+
+impl<H> ThreadCache<H>
+where
+	H: GlobalAlloc,
+{
+	/// Run `f` with this thread's magazines, if they are or can be claimed by this cache.
+	fn with<R>(&self, f: impl FnOnce(&Magazines) -> R) -> Option<R> {
+		if crate::SANITIZED {
+			return None;
+		}
+		let owner = ptr::from_ref(self).cast::<()>();
+		MAGAZINES
+			.try_with(|magazines| {
+				if magazines.owner.get().is_null() {
+					magazines.owner.set(owner);
+					magazines.release.set(Some(release::<H>));
+				}
+				(magazines.owner.get() == owner).then(|| f(magazines))
+			})
+			.ok()
+			.flatten()
+	}
+}
+
+unsafe impl<H> GlobalAlloc for ThreadCache<H>
+where
+	H: GlobalAlloc,
+{
+	unsafe fn alloc(&self, l: Layout) -> *mut u8 {
+		let Some(class) = class(l) else {
+			return self.inner.alloc(l);
+		};
+		let cached = self.with(|magazines| {
+			let magazine = &magazines.classes[class];
+			let len = magazine.len.get();
+			(len != 0).then(|| {
+				magazine.len.set(len - 1);
+				magazine.blocks[len - 1].get()
+			})
+		});
+		match cached.flatten() {
+			Some(block) => block,
+			None => self.inner.alloc(class_layout(class)),
+		}
+	}
+	unsafe fn dealloc(&self, ptr: *mut u8, l: Layout) {
+		let Some(class) = class(l) else {
+			return self.inner.dealloc(ptr, l);
+		};
+		let cached = self.with(|magazines| {
+			let magazine = &magazines.classes[class];
+			let len = magazine.len.get();
+			if len == CAPACITY {
+				return false;
+			}
+			magazine.blocks[len].set(ptr);
+			magazine.len.set(len + 1);
+			true
+		});
+		if cached != Some(true) {
+			self.inner.dealloc(ptr, class_layout(class));
+		}
+	}
+	unsafe fn realloc(&self, ptr: *mut u8, old_l: Layout, new_s: usize) -> *mut u8 {
+		let new_l = Layout::from_size_align_unchecked(new_s, old_l.align());
+		match (class(old_l), class(new_l)) {
+			(None, None) => self.inner.realloc(ptr, old_l, new_s),
+			(Some(old), Some(new)) if old == new => ptr,
+			_ => {
+				let res = self.alloc(new_l);
+				if !res.is_null() {
+					ptr::copy_nonoverlapping(ptr, res, old_l.size().min(new_s));
+					self.dealloc(ptr, old_l);
+				}
+				res
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, thread
+	};
+
+	use super::ThreadCache;
+	use crate::Cap;
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn thread_cache() {
+		let cache: &'static ThreadCache<Cap<System>> = Box::leak(Box::new(unsafe {
+			ThreadCache::new(Cap::new(System, usize::MAX))
+		}));
+		let cap = cache.inner();
+		thread::spawn(move || unsafe {
+			let a = cache.alloc(Layout::new::<[u8; 20]>());
+			let class = cap.charged(Layout::from_size_align(32, 16).unwrap());
+			assert_eq!(cap.allocated(), class);
+			cache.dealloc(a, Layout::new::<[u8; 20]>());
+			assert_eq!(cap.allocated(), class);
+			let b = cache.alloc(Layout::new::<[u8; 30]>());
+			assert_eq!(a, b);
+			let b = cache.realloc(b, Layout::new::<[u8; 30]>(), 1000);
+			assert_eq!(
+				cap.allocated(),
+				class + cap.charged(Layout::from_size_align(1000, 1).unwrap())
+			);
+			cache.dealloc(b, Layout::from_size_align(1000, 1).unwrap());
+		})
+		.join()
+		.unwrap();
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	/// An allocator whose blocks are aligned to exactly what they are required to be, and no more.
+	#[derive(Debug)]
+	struct Loose;
+	unsafe impl GlobalAlloc for Loose {
+		unsafe fn alloc(&self, l: Layout) -> *mut u8 {
+			let padded = Layout::from_size_align(l.size() + 32, 32).unwrap();
+			System.alloc(padded).add(l.align())
+		}
+		unsafe fn dealloc(&self, ptr: *mut u8, l: Layout) {
+			let padded = Layout::from_size_align(l.size() + 32, 32).unwrap();
+			System.dealloc(ptr.sub(l.align()), padded);
+		}
+	}
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn thread_cache_aligned() {
+		let cache: &'static ThreadCache<Loose> =
+			Box::leak(Box::new(unsafe { ThreadCache::new(Loose) }));
+		thread::spawn(move || unsafe {
+			for size in 1..=16 {
+				let layout = Layout::from_size_align(size, 16).unwrap();
+				let ptrs = (0..4).map(|_| cache.alloc(layout)).collect::<Vec<_>>();
+				for &ptr in &ptrs {
+					assert_eq!(ptr as usize % 16, 0, "{layout:?}");
+				}
+				for &ptr in &ptrs {
+					cache.dealloc(ptr, layout);
+				}
+			}
+		})
+		.join()
+		.unwrap();
+	}
+}
@@ -0,0 +1,38 @@
+/* The layout of cap::ffi::Counters, as exported by cap::export_counters!. */
+
+#ifndef CAP_COUNTERS_H
+#define CAP_COUNTERS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define CAP_COUNTERS_MAGIC "cap\0ctrs"
+#define CAP_COUNTERS_VERSION 1
+
+struct cap_counters {
+	/* Always CAP_COUNTERS_MAGIC, compared with memcmp over 8 bytes. */
+	char magic[8];
+	/* The version of this layout; fields are only ever appended, growing size. */
+	uint32_t version;
+	/* The size in bytes of this layout. */
+	uint32_t size;
+	const volatile size_t *limit;
+	const volatile size_t *remaining;
+	/* The bytes allocated beyond the limit while exempt. */
+	const volatile size_t *overdraft;
+	/* The number of live allocations; null unless the cap crate's overhead feature is enabled. */
+	const volatile size_t *live;
+	/* Null unless the cap crate's stats-counts feature is enabled. */
+	const volatile size_t *total_allocated;
+	/* Null unless the cap crate's stats-peaks feature is enabled. */
+	const volatile size_t *max_allocated;
+};
+
+extern const struct cap_counters cap_counters;
+
+/* The bytes currently allocated through the cap. */
+static inline size_t cap_allocated(const struct cap_counters *counters) {
+	return *counters->limit - *counters->remaining + *counters->overdraft;
+}
+
+#endif
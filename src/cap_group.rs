@@ -0,0 +1,92 @@
+//! A combined view of, and ceiling on, several caps.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A set of [`Cap`](crate::Cap)s, such as the global allocator and an arena wrapped for a subsystem, whose usage is aggregated and can be held to a combined limit.
+///
+/// Caps join a group when they are created, with [`Cap::with_group`](crate::Cap::with_group). An allocation through any of them must then fit within both its own cap's limit and the group's.
+///
+/// ```
+/// use std::alloc;
+/// use cap::{Cap, CapGroup};
+///
+/// static GROUP: CapGroup = CapGroup::new(64 * 1024 * 1024);
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX).with_group(&GROUP);
+/// static ARENA: Cap<alloc::System> = Cap::new(alloc::System, 16 * 1024 * 1024).with_group(&GROUP);
+///
+/// fn main() {
+///     assert_eq!(GROUP.allocated(), ALLOCATOR.allocated() + ARENA.allocated());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct CapGroup {
+	limit: AtomicUsize,
+	allocated: AtomicUsize,
+	max_allocated: AtomicUsize,
+}
+
+impl CapGroup {
+	/// Create a group with the specified combined limit. For no limit, use `usize::MAX`.
+	#[must_use]
+	pub const fn new(limit: usize) -> Self {
+		Self {
+			limit: AtomicUsize::new(limit),
+			allocated: AtomicUsize::new(0),
+			max_allocated: AtomicUsize::new(0),
+		}
+	}
+
+	/// Get the combined limit in bytes.
+	pub fn limit(&self) -> usize {
+		self.limit.load(Ordering::Relaxed)
+	}
+
+	/// Set the combined limit in bytes.
+	///
+	/// This method will return `Err` if the specified limit is less than the number of bytes already allocated across the group.
+	pub fn set_limit(&self, limit: usize) -> Result<(), ()> {
+		if self.allocated() > limit {
+			return Err(());
+		}
+		self.limit.store(limit, Ordering::Relaxed);
+		Ok(())
+	}
+
+	/// Get the number of bytes currently allocated across the group's caps.
+	pub fn allocated(&self) -> usize {
+		self.allocated.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of bytes that can still be allocated across the group's caps before the combined limit is reached.
+	pub fn remaining(&self) -> usize {
+		self.limit().saturating_sub(self.allocated())
+	}
+
+	/// Get the most bytes that have been allocated across the group's caps at once.
+	pub fn max_allocated(&self) -> usize {
+		self.max_allocated.load(Ordering::Relaxed)
+	}
+
+	/// Charge `size` bytes, returning whether they fit within the combined limit.
+	pub(crate) fn charge(&self, size: usize) -> bool {
+		let allocated = self.allocated.fetch_add(size, Ordering::Relaxed) + size;
+		if allocated > self.limit() {
+			let _ = self.allocated.fetch_sub(size, Ordering::Relaxed);
+			return false;
+		}
+		let _ = self.max_allocated.fetch_max(allocated, Ordering::Relaxed);
+		true
+	}
+
+	/// Charge `size` bytes regardless of the combined limit, for allocations exempt from it.
+	pub(crate) fn overdraw(&self, size: usize) {
+		let allocated = self.allocated.fetch_add(size, Ordering::Relaxed) + size;
+		let _ = self.max_allocated.fetch_max(allocated, Ordering::Relaxed);
+	}
+
+	pub(crate) fn release(&self, size: usize) {
+		let _ = self.allocated.fetch_sub(size, Ordering::Relaxed);
+	}
+}
@@ -0,0 +1,173 @@
+//! Per-thread carve-outs of a [`Cap`]'s budget.
+
+use std::{cell::Cell, fmt, marker::PhantomData, ptr};
+
+use crate::Cap;
+
+/// This thread's carve-out: the cap it is of, the bytes of it unused, and its size.
+struct State {
+	owner: Cell<*const ()>,
+	remaining: Cell<usize>,
+	capacity: Cell<usize>,
+}
+
+thread_local! {
+	static CARVE_OUT: State = const {
+		State {
+			owner: Cell::new(ptr::null()),
+			remaining: Cell::new(0),
+			capacity: Cell::new(0),
+		}
+	};
+}
+
+/// A private share of a [`Cap`]'s budget claimed by a thread, created by [`Cap::carve_out`]. Its unused remainder is returned to the shared budget when it is dropped.
+///
+/// Allocations on the thread draw from the carve-out first, and memory the thread frees refills it, up to its size, without touching the cap's shared counters. A worker thread with predictable needs is thereby isolated from other threads exhausting the limit, and most of its allocations avoid contended atomics.
+///
+/// The unused remainder of a carve-out counts as [allocated](Cap::allocated).
+///
+/// ```
+/// use std::alloc;
+/// use cap::Cap;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     let _carve_out = ALLOCATOR.carve_out(1024 * 1024).unwrap();
+///     for _ in 0..1000 {
+///         drop(vec![0u8; 1000]);
+///     }
+/// }
+/// ```
+#[must_use = "dropping a carve-out returns it to the shared budget"]
+pub struct CarveOut<'a, H> {
+	cap: &'a Cap<H>,
+	_not_send: PhantomData<*const ()>,
+}
+
+impl<H> CarveOut<'_, H> {
+	/// Return the bytes of the carve-out not currently in use.
+	#[must_use]
+	pub fn remaining(&self) -> usize {
+		CARVE_OUT.with(|state| state.remaining.get())
+	}
+
+	/// Return the size of the carve-out.
+	#[must_use]
+	pub fn capacity(&self) -> usize {
+		CARVE_OUT.with(|state| state.capacity.get())
+	}
+}
+
+impl<H> Drop for CarveOut<'_, H> {
+	fn drop(&mut self) {
+		let remaining = CARVE_OUT.with(|state| {
+			state.owner.set(ptr::null());
+			state.capacity.set(0);
+			state.remaining.replace(0)
+		});
+		self.cap.release(remaining);
+	}
+}
+
+impl<H> fmt::Debug for CarveOut<'_, H> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("CarveOut")
+			.field("remaining", &self.remaining())
+			.field("capacity", &self.capacity())
+			.finish()
+	}
+}
+
+impl<H> Cap<H> {
+	/// Claim `bytes` of the limit for this thread's private use, until the returned [`CarveOut`] is dropped.
+	///
+	/// This method will return `Err` if `bytes` aren't remaining within the limit, or this thread already holds a carve-out.
+	pub fn carve_out(&self, bytes: usize) -> Result<CarveOut<'_, H>, ()> {
+		let held = CARVE_OUT
+			.try_with(|state| !state.owner.get().is_null())
+			.unwrap_or(true);
+		if held || !self.charge_bytes(bytes) {
+			return Err(());
+		}
+		CARVE_OUT.with(|state| {
+			state.owner.set(ptr::from_ref(self).cast());
+			state.remaining.set(bytes);
+			state.capacity.set(bytes);
+		});
+		Ok(CarveOut {
+			cap: self,
+			_not_send: PhantomData,
+		})
+	}
+}
+
+/// Draw `size` bytes from this thread's carve-out of `owner`, returning whether there were enough.
+pub(crate) fn draw(owner: *const (), size: usize) -> bool {
+	CARVE_OUT
+		.try_with(|state| {
+			let remaining = state.remaining.get();
+			let drawn = state.owner.get() == owner && remaining >= size;
+			if drawn {
+				state.remaining.set(remaining - size);
+			}
+			drawn
+		})
+		.unwrap_or(false)
+}
+
+/// Return as much of `size` freed bytes to this thread's carve-out of `owner` as fits, returning the rest, which is to be released to the shared budget.
+pub(crate) fn refill(owner: *const (), size: usize) -> usize {
+	CARVE_OUT
+		.try_with(|state| {
+			if state.owner.get() != owner {
+				return size;
+			}
+			let remaining = state.remaining.get();
+			let refilled = size.min(state.capacity.get() - remaining);
+			state.remaining.set(remaining + refilled);
+			size - refilled
+		})
+		.unwrap_or(size)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, thread
+	};
+
+	use crate::Cap;
+
+	#[test]
+	fn carve_out() {
+		let cap = Cap::new(System, usize::MAX);
+		let (layout, small) = (Layout::new::<[u8; 600]>(), Layout::new::<[u8; 500]>());
+		// Room outside the carve-out for exactly one small allocation.
+		let shared = cap.charged(small);
+		cap.set_limit(1000 + shared).unwrap();
+		let carve_out = cap.carve_out(1000).unwrap();
+		assert!(cap.carve_out(100).is_err());
+		unsafe {
+			let a = cap.alloc(layout);
+			let carved = 1000 - cap.charged(layout);
+			assert_eq!((carve_out.remaining(), cap.remaining()), (carved, shared));
+			// Other threads can't use the carve-out, nor exceed what's left outside it.
+			thread::scope(|scope| {
+				let _ = scope.spawn(|| assert!(cap.alloc(layout).is_null()));
+			});
+			// Beyond the carve-out, allocations draw from the shared budget.
+			let b = cap.alloc(small);
+			assert_eq!((carve_out.remaining(), cap.remaining()), (carved, 0));
+			// Frees refill the carve-out first.
+			cap.dealloc(a, layout);
+			cap.dealloc(b, small);
+			assert_eq!((carve_out.remaining(), cap.remaining()), (1000, shared));
+		}
+		assert_eq!(cap.allocated(), 1000);
+		drop(carve_out);
+		assert_eq!(cap.allocated(), 0);
+	}
+}
@@ -0,0 +1,103 @@
+//! Watching of a cgroup v2's `memory.events`.
+
+use std::{fs, io, time::Duration};
+
+use crate::{os, Cap, CgroupEvents, Monitor, PressureEvent};
+
+fn parse(text: &str) -> CgroupEvents {
+	let mut events = CgroupEvents::default();
+	for line in text.lines() {
+		let mut fields = line.split_whitespace();
+		let (Some(key), Some(Ok(value))) = (fields.next(), fields.next().map(str::parse)) else {
+			continue;
+		};
+		match key {
+			"low" => events.low = value,
+			"high" => events.high = value,
+			"max" => events.max = value,
+			"oom" => events.oom = value,
+			"oom_kill" => events.oom_kill = value,
+			_ => (),
+		}
+	}
+	events
+}
+
+/// The increase in each counter from `previous` to `current`.
+fn delta(previous: CgroupEvents, current: CgroupEvents) -> CgroupEvents {
+	CgroupEvents {
+		low: current.low.saturating_sub(previous.low),
+		high: current.high.saturating_sub(previous.high),
+		max: current.max.saturating_sub(previous.max),
+		oom: current.oom.saturating_sub(previous.oom),
+		oom_kill: current.oom_kill.saturating_sub(previous.oom_kill),
+	}
+}
+
+impl<H> Cap<H> {
+	/// Check this process's cgroup's `memory.events` every `interval` on a background thread, delivering any increase in its counters to the [pressure callbacks](Self::on_pressure) as a [`PressureEvent::Cgroup`].
+	///
+	/// Increases in `high` mean the kernel is throttling the cgroup, and in `max` or `oom` that the OOM killer is imminent or has been invoked, letting the application shed memory in response.
+	///
+	/// This method will return `Err` if the process isn't in a cgroup v2 hierarchy with the memory controller enabled. Otherwise checking continues until the returned [`Monitor`] is dropped.
+	pub fn monitor_cgroup_events(&'static self, interval: Duration) -> io::Result<Monitor>
+	where
+		H: Sync,
+	{
+		let path = os::cgroup_file("memory.events")
+			.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no cgroup v2 memory.events"))?;
+		let mut previous = parse(&fs::read_to_string(&path)?);
+		Ok(Monitor::spawn(move |stop| {
+			while stop.sleep(interval) {
+				let mut buf = [0; 256];
+				let Ok(text) = os::read_small(&path, &mut buf) else {
+					continue;
+				};
+				let current = parse(text);
+				let delta = delta(previous, current);
+				previous = current;
+				if delta != CgroupEvents::default() {
+					self.notify_pressure(PressureEvent::Cgroup(delta));
+				}
+			}
+		}))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::System, time::{Duration, Instant}
+	};
+
+	use crate::{Cap, CgroupEvents};
+
+	#[test]
+	fn delta() {
+		let previous = super::parse("low 0\nhigh 3\nmax 1\noom 0\noom_kill 0\noom_group_kill 0\n");
+		let current = super::parse("low 0\nhigh 5\nmax 1\noom 1\noom_kill 1\noom_group_kill 0\n");
+		assert_eq!(
+			super::delta(previous, current),
+			CgroupEvents {
+				high: 2,
+				oom: 1,
+				oom_kill: 1,
+				..CgroupEvents::default()
+			}
+		);
+	}
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn monitor_cgroup_events() {
+		let cap: &'static Cap<System> = Box::leak(Box::new(Cap::new(System, usize::MAX)));
+		let Ok(monitor) = cap.monitor_cgroup_events(Duration::from_secs(100)) else {
+			// The process isn't in a cgroup v2 hierarchy.
+			return;
+		};
+		// Stopped promptly, rather than after the next check.
+		let stopping = Instant::now();
+		drop(monitor);
+		assert!(stopping.elapsed() < Duration::from_secs(10));
+	}
+}
@@ -0,0 +1,56 @@
+//! Detection of containers that are repeatedly copied as they grow.
+
+use std::{cell::Cell, ptr};
+
+thread_local! {
+	/// Where this thread's most recent moving grow left its allocation, and the lineage of moves that led there.
+	static LINEAGE: (Cell<*mut u8>, Cell<Churn>) = const {
+		(
+			Cell::new(ptr::null_mut()),
+			Cell::new(Churn {
+				copies: 0,
+				copied: 0,
+				size: 0,
+			}),
+		)
+	};
+}
+
+/// A lineage of reallocations of an allocation, each growing it by moving it to a new block, as passed to the hook set by [`Cap::set_churn_hook`](crate::Cap::set_churn_hook).
+///
+/// This is typical of a container grown an element at a time without reserving capacity up front, which costs a copy of its contents on every move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Churn {
+	/// The number of times the allocation has been moved.
+	pub copies: usize,
+	/// The number of bytes copied by those moves.
+	pub copied: usize,
+	/// The size the allocation has grown to.
+	pub size: usize,
+}
+
+/// Record that a grow on this thread moved an allocation from `old` to `new`, copying `copied` bytes and leaving it `size` bytes, returning its lineage.
+///
+/// The lineage continues if `old` is where this thread's previous moving grow left an allocation, and restarts otherwise.
+pub(crate) fn record(old: *mut u8, new: *mut u8, copied: usize, size: usize) -> Option<Churn> {
+	LINEAGE
+		.try_with(|(last, churn)| {
+			let mut lineage = if last.get() == old {
+				churn.get()
+			} else {
+				Churn {
+					copies: 0,
+					copied: 0,
+					size: 0,
+				}
+			};
+			lineage.copies += 1;
+			lineage.copied = lineage.copied.saturating_add(copied);
+			lineage.size = size;
+			last.set(new);
+			churn.set(lineage);
+			lineage
+		})
+		.ok()
+}
@@ -0,0 +1,58 @@
+//! A comparison of a [`Cap`]'s accounting with the operating system's view of the process.
+
+use crate::{os, Cap};
+
+/// The bytes tracked by a [`Cap`] alongside the operating system's measurements of the process, as returned by [`Cap::os_comparison`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct OsComparison {
+	/// The number of bytes allocated through the [`Cap`].
+	pub allocated: usize,
+	/// The resident set size of the process in bytes, if it can be determined on this platform.
+	pub rss: Option<usize>,
+	/// The virtual memory size of the process in bytes, if it can be determined on this platform.
+	pub virtual_size: Option<usize>,
+	/// The percentage of the resident set that isn't accounted for by `allocated`, e.g. fragmentation, allocator metadata, stacks, code and memory mapped by other means. This is negative if memory has been allocated but not yet touched.
+	pub drift: Option<f64>,
+}
+
+impl<H> Cap<H> {
+	/// Compare the bytes allocated through this allocator with the resident set and virtual memory sizes the operating system reports for the process.
+	///
+	/// This answers the common question of why a process uses more memory, according to the OS or a container runtime, than its allocator reports. Currently the OS's measurements are only available on Linux.
+	pub fn os_comparison(&self) -> OsComparison {
+		let allocated = self.allocated();
+		let rss = os::rss();
+		#[allow(clippy::cast_precision_loss)]
+		let drift = rss
+			.filter(|&rss| rss != 0)
+			.map(|rss| (rss as f64 - allocated as f64) / rss as f64 * 100.0);
+		OsComparison {
+			allocated,
+			rss,
+			virtual_size: os::virtual_size(),
+			drift,
+		}
+	}
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use std::alloc::System;
+
+	use crate::Cap;
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn os_comparison() {
+		let cap = Cap::new(System, usize::MAX);
+		let rss = crate::os::rss().unwrap();
+		cap.charge(rss / 2).unwrap();
+		let comparison = cap.os_comparison();
+		assert_eq!(comparison.allocated, rss / 2);
+		assert!(comparison.rss.unwrap() > 0);
+		assert!(comparison.virtual_size.unwrap() >= comparison.rss.unwrap());
+		let drift = comparison.drift.unwrap();
+		assert!(drift > 0.0 && drift < 100.0, "{}", drift);
+	}
+}
@@ -0,0 +1,136 @@
+use std::{
+	fmt, mem, process, ptr, sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering}
+};
+
+/// A violated invariant of a [`Cap`](crate::Cap)'s accounting, as found by the `consistency` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Inconsistency {
+	/// More bytes are remaining within the limit than the limit itself, so more has been released than was charged.
+	RemainingExceedsLimit {
+		/// The bytes remaining.
+		remaining: usize,
+		/// The limit.
+		limit: usize,
+	},
+	/// More bytes are [charged externally](crate::Cap::charge) than are allocated in total, so more has been [uncharged](crate::Cap::uncharge) than was charged.
+	ExternalExceedsAllocated {
+		/// The bytes charged externally.
+		external: usize,
+		/// The bytes allocated.
+		allocated: usize,
+	},
+	/// Fewer bytes have been allocated in total than are allocated now, excluding those charged externally.
+	#[cfg(feature = "stats-counts")]
+	TotalBelowAllocated {
+		/// The [total](crate::Cap::total_allocated) bytes allocated.
+		total_allocated: usize,
+		/// The bytes allocated, excluding those charged externally.
+		allocated: usize,
+	},
+}
+
+impl fmt::Display for Inconsistency {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Inconsistency::RemainingExceedsLimit { remaining, limit } => write!(
+				f,
+				"inconsistent accounting: {remaining} bytes remaining exceeds the limit of {limit}"
+			),
+			Inconsistency::ExternalExceedsAllocated {
+				external,
+				allocated,
+			} => write!(
+				f,
+				"inconsistent accounting: {external} bytes charged externally exceeds the {allocated} allocated"
+			),
+			#[cfg(feature = "stats-counts")]
+			Inconsistency::TotalBelowAllocated {
+				total_allocated,
+				allocated,
+			} => write!(
+				f,
+				"inconsistent accounting: {total_allocated} bytes allocated in total is below the {allocated} allocated now"
+			),
+		}
+	}
+}
+
+impl std::error::Error for Inconsistency {}
+
+/// What to do when the `consistency` feature finds an [`Inconsistency`], as set by [`Cap::set_inconsistency_action`](crate::Cap::set_inconsistency_action).
+#[derive(Clone, Copy, Debug)]
+pub enum InconsistencyAction {
+	/// Print it to stderr. This is the default.
+	Log,
+	/// Call the function with it, [from within the allocator](crate#hooks).
+	Hook(fn(&Inconsistency)),
+	/// Print it to stderr and abort the process. Panicking isn't an option, as unwinding out of an allocator is undefined behaviour.
+	Abort,
+	/// In debug builds, panic with it, so that the bug, such as a foreign pointer being freed, is caught where it happens rather than distorting all subsequent accounting. In release builds, print it to stderr, as with [`Log`](Self::Log).
+	///
+	/// The panic doesn't unwind, for the reason above: once the panic hook has reported it, with a backtrace if enabled, the process aborts.
+	Panic,
+}
+
+const LOG: u8 = 0;
+const HOOK: u8 = 1;
+const ABORT: u8 = 2;
+const PANIC: u8 = 3;
+
+/// A [`Cap`](crate::Cap)'s response to inconsistencies.
+#[derive(Debug)]
+pub(crate) struct Checker {
+	action: AtomicU8,
+	hook: AtomicPtr<()>,
+	found: AtomicUsize,
+}
+
+/// Panic with `inconsistency`, aborting rather than unwinding out of the allocator.
+#[cfg(debug_assertions)]
+extern "C" fn panic_without_unwinding(inconsistency: &Inconsistency) -> ! {
+	panic!("cap: {}", inconsistency);
+}
+
+impl Checker {
+	pub(crate) const fn new() -> Self {
+		Self {
+			action: AtomicU8::new(LOG),
+			hook: AtomicPtr::new(ptr::null_mut()),
+			found: AtomicUsize::new(0),
+		}
+	}
+
+	pub(crate) fn set_action(&self, action: InconsistencyAction) {
+		match action {
+			InconsistencyAction::Log => self.action.store(LOG, Ordering::Release),
+			InconsistencyAction::Hook(hook) => {
+				self.hook.store(hook as *mut (), Ordering::Release);
+				self.action.store(HOOK, Ordering::Release);
+			}
+			InconsistencyAction::Abort => self.action.store(ABORT, Ordering::Release),
+			InconsistencyAction::Panic => self.action.store(PANIC, Ordering::Release),
+		}
+	}
+
+	pub(crate) fn found(&self) -> usize {
+		self.found.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn report(&self, inconsistency: &Inconsistency) {
+		let _ = self.found.fetch_add(1, Ordering::Relaxed);
+		match self.action.load(Ordering::Acquire) {
+			HOOK => {
+				let hook = self.hook.load(Ordering::Acquire);
+				let hook = unsafe { mem::transmute::<*mut (), fn(&Inconsistency)>(hook) };
+				let _ = crate::reentrancy::call(|| hook(inconsistency));
+			}
+			ABORT => {
+				eprintln!("cap: {inconsistency}");
+				process::abort();
+			}
+			#[cfg(debug_assertions)]
+			PANIC => panic_without_unwinding(inconsistency),
+			_ => eprintln!("cap: {inconsistency}"),
+		}
+	}
+}
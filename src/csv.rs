@@ -0,0 +1,103 @@
+//! Periodic logging of usage as CSV.
+
+use std::{
+	io::{self, Write}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+
+use crate::{os, Cap};
+
+impl<H> Cap<H> {
+	/// Append a sample of this allocator's usage to `writer` as a line of CSV every `interval`, on a background thread.
+	///
+	/// A header line is written first, followed by lines of:
+	///
+	/// * `timestamp`: seconds since the Unix epoch, with millisecond precision;
+	/// * `allocated`: the result of [`allocated`](Self::allocated);
+	/// * `rss`: the process's resident set size in bytes, or empty where it can't be determined (currently anywhere but Linux);
+	/// * `rate`: the change in `allocated` since the previous sample, in bytes per second.
+	///
+	/// This is convenient for loading into pandas or a spreadsheet for one-off investigations. Logging continues until writing fails, and the returned handle yields the error.
+	pub fn log_csv<W>(
+		&'static self, mut writer: W, interval: Duration,
+	) -> thread::JoinHandle<io::Result<()>>
+	where
+		H: Sync,
+		W: Write + Send + 'static,
+	{
+		thread::spawn(move || {
+			writeln!(writer, "timestamp,allocated,rss,rate")?;
+			let mut previous: Option<(Instant, usize)> = None;
+			loop {
+				let (now, allocated) = (Instant::now(), self.allocated());
+				let timestamp = SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.unwrap_or_default();
+				let rate = previous.map_or(0.0, |(then, previous)| {
+					#[allow(clippy::cast_precision_loss)]
+					let delta = allocated as f64 - previous as f64;
+					delta / (now - then).as_secs_f64()
+				});
+				write!(
+					writer,
+					"{}.{:03},{},",
+					timestamp.as_secs(),
+					timestamp.subsec_millis(),
+					allocated
+				)?;
+				if let Some(rss) = os::rss() {
+					write!(writer, "{rss}")?;
+				}
+				writeln!(writer, ",{rate:.0}")?;
+				writer.flush()?;
+				previous = Some((now, allocated));
+				thread::sleep(interval);
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::System, io::{self, Write}, sync::{Arc, Mutex}, thread, time::Duration
+	};
+
+	use crate::Cap;
+
+	/// A buffer that, once taken, fails writes so that logging stops.
+	#[derive(Clone)]
+	struct Shared(Arc<Mutex<Option<Vec<u8>>>>);
+
+	impl Write for Shared {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			match &mut *self.0.lock().unwrap() {
+				Some(out) => out.write(buf),
+				None => Err(io::ErrorKind::BrokenPipe.into()),
+			}
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn log_csv() {
+		let cap: &'static Cap<System> = Box::leak(Box::new(Cap::new(System, usize::MAX)));
+		cap.charge(100).unwrap();
+		let out = Shared(Arc::new(Mutex::new(Some(Vec::new()))));
+		let logger = cap.log_csv(out.clone(), Duration::from_millis(10));
+		thread::sleep(Duration::from_millis(100));
+		let out = String::from_utf8(out.0.lock().unwrap().take().unwrap()).unwrap();
+		assert_eq!(
+			logger.join().unwrap().unwrap_err().kind(),
+			io::ErrorKind::BrokenPipe
+		);
+		let mut lines = out.lines();
+		assert_eq!(lines.next(), Some("timestamp,allocated,rss,rate"));
+		let sample = lines.next().unwrap().split(',').collect::<Vec<_>>();
+		assert_eq!((sample.len(), sample[1], sample[3]), (4, "100", "0"));
+		#[cfg(target_os = "linux")]
+		assert!(sample[2].parse::<usize>().unwrap() > 0);
+	}
+}
@@ -0,0 +1,80 @@
+//! macOS's memory pressure dispatch source.
+
+use std::{ffi::c_void, io};
+
+use crate::{Cap, MemoryPressureLevel, PressureEvent};
+
+#[repr(C)]
+struct SourceType {
+	_private: [u8; 0],
+}
+
+extern "C" {
+	static _dispatch_source_type_memorypressure: SourceType;
+	fn dispatch_get_global_queue(identifier: isize, flags: usize) -> *mut c_void;
+	fn dispatch_source_create(
+		source_type: *const SourceType, handle: usize, mask: usize, queue: *mut c_void,
+	) -> *mut c_void;
+	fn dispatch_set_context(object: *mut c_void, context: *mut c_void);
+	fn dispatch_source_set_event_handler_f(
+		source: *mut c_void, handler: unsafe extern "C" fn(*mut c_void),
+	);
+	fn dispatch_source_get_data(source: *mut c_void) -> usize;
+	fn dispatch_resume(object: *mut c_void);
+}
+
+const DISPATCH_MEMORYPRESSURE_NORMAL: usize = 0x1;
+const DISPATCH_MEMORYPRESSURE_WARN: usize = 0x2;
+const DISPATCH_MEMORYPRESSURE_CRITICAL: usize = 0x4;
+const DISPATCH_QUEUE_PRIORITY_DEFAULT: isize = 0;
+
+struct Context<H: 'static> {
+	source: *mut c_void,
+	cap: &'static Cap<H>,
+}
+
+unsafe extern "C" fn handler<H>(context: *mut c_void) {
+	let context = &*context.cast::<Context<H>>();
+	let data = dispatch_source_get_data(context.source);
+	let level = if data & DISPATCH_MEMORYPRESSURE_CRITICAL != 0 {
+		MemoryPressureLevel::Critical
+	} else if data & DISPATCH_MEMORYPRESSURE_WARN != 0 {
+		MemoryPressureLevel::Warning
+	} else {
+		MemoryPressureLevel::Normal
+	};
+	context
+		.cap
+		.notify_pressure(PressureEvent::MemoryPressure(level));
+}
+
+impl<H> Cap<H> {
+	/// Subscribe to macOS's memory pressure notifications, delivering each change of level to the [pressure callbacks](Self::on_pressure) as a [`PressureEvent::MemoryPressure`].
+	///
+	/// These are the warnings the system gives before jetsam starts terminating processes, which well-behaved macOS apps respond to by freeing caches. The notifications are handled on a global dispatch queue, so no thread is spawned.
+	pub fn monitor_memory_pressure(&'static self) -> io::Result<()>
+	where
+		H: Sync,
+	{
+		unsafe {
+			let queue = dispatch_get_global_queue(DISPATCH_QUEUE_PRIORITY_DEFAULT, 0);
+			let source = dispatch_source_create(
+				&raw const _dispatch_source_type_memorypressure,
+				0,
+				DISPATCH_MEMORYPRESSURE_NORMAL
+					| DISPATCH_MEMORYPRESSURE_WARN
+					| DISPATCH_MEMORYPRESSURE_CRITICAL,
+				queue,
+			);
+			if source.is_null() {
+				return Err(io::Error::other("failed to create dispatch source"));
+			}
+			// The source, and so its context, live for the rest of the process.
+			let context = Box::into_raw(Box::new(Context { source, cap: self }));
+			dispatch_set_context(source, context.cast());
+			dispatch_source_set_event_handler_f(source, handler::<H>);
+			dispatch_resume(source);
+		}
+		Ok(())
+	}
+}
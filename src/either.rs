@@ -0,0 +1,240 @@
+use std::{
+	alloc::{GlobalAlloc, Layout}, ptr, sync::atomic::{AtomicBool, Ordering}
+};
+
+const TAG_A: u8 = 0;
+const TAG_B: u8 = 1;
+
+/// An allocator that serves allocations from one of two backends, chosen at run time.
+///
+/// This allows one binary to choose, for example, between the system allocator and jemalloc under the same [`Cap`](crate::Cap) based on an environment variable:
+///
+/// ```
+/// use std::alloc;
+/// use cap::{Cap, Either};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<Either<alloc::System, alloc::System>> =
+///     Cap::new(Either::new(alloc::System, alloc::System), usize::MAX);
+///
+/// fn main() {
+///     if std::env::var_os("USE_ALTERNATIVE_ALLOCATOR").is_some() {
+///         ALLOCATOR.allocator().select_b();
+///     }
+///     // ...
+/// }
+/// ```
+///
+/// The backend can be switched at any time, including after allocations have been made (the Rust runtime allocates before `main`). Each allocation is tagged with the backend that served it, in a prefix of `layout.align()` bytes, so that it is always reallocated and deallocated by the same backend.
+#[derive(Debug)]
+pub struct Either<A, B> {
+	a: A,
+	b: B,
+	use_b: AtomicBool,
+	fallback: AtomicBool,
+}
+
+impl<A, B> Either<A, B> {
+	/// Create a new allocator wrapping the two supplied allocators, initially serving allocations from `a`.
+	pub const fn new(a: A, b: B) -> Self {
+		Self {
+			a,
+			b,
+			use_b: AtomicBool::new(false),
+			fallback: AtomicBool::new(false),
+		}
+	}
+
+	/// Serve subsequent allocations from `a`.
+	pub fn select_a(&self) {
+		self.use_b.store(false, Ordering::Relaxed);
+	}
+
+	/// Serve subsequent allocations from `b`.
+	pub fn select_b(&self) {
+		self.use_b.store(true, Ordering::Relaxed);
+	}
+
+	/// Return whether subsequent allocations will be served from `b`.
+	pub fn is_b(&self) -> bool {
+		self.use_b.load(Ordering::Relaxed)
+	}
+
+	/// Set whether allocations and reallocations that the selected backend fails are served from the other one instead. Defaults to false.
+	///
+	/// Under a [`Cap`](crate::Cap) this distinguishes the selected backend running out of memory, which is then handled by the other, from the limit being reached, which still fails.
+	pub fn set_fallback(&self, fallback: bool) {
+		self.fallback.store(fallback, Ordering::Relaxed);
+	}
+
+	/// Return a reference to `a`.
+	pub fn a(&self) -> &A {
+		&self.a
+	}
+
+	/// Return a reference to `b`.
+	pub fn b(&self) -> &B {
+		&self.b
+	}
+}
+
+/// The layout actually requested of a backend: `layout` with a prefix for the tag.
+fn padded(layout: Layout) -> Option<Layout> {
+	let size = layout.size().checked_add(layout.align())?;
+	Layout::from_size_align(size, layout.align()).ok()
+}
+
+unsafe fn tag(ptr: *mut u8, layout: Layout, tag: u8) -> *mut u8 {
+	if ptr.is_null() {
+		return ptr;
+	}
+	let ptr = ptr.add(layout.align());
+	*ptr.sub(1) = tag;
+	ptr
+}
+
+impl<A, B> Either<A, B>
+where
+	A: GlobalAlloc,
+	B: GlobalAlloc,
+{
+	unsafe fn alloc_with(&self, l: Layout, zeroed: bool) -> *mut u8 {
+		let res = self.alloc_from(self.is_b(), l, zeroed);
+		if res.is_null() && self.fallback.load(Ordering::Relaxed) {
+			return self.alloc_from(!self.is_b(), l, zeroed);
+		}
+		res
+	}
+
+	unsafe fn alloc_from(&self, b: bool, l: Layout, zeroed: bool) -> *mut u8 {
+		let Some(padded) = padded(l) else {
+			return ptr::null_mut();
+		};
+		if b {
+			let ptr = if zeroed {
+				self.b.alloc_zeroed(padded)
+			} else {
+				self.b.alloc(padded)
+			};
+			tag(ptr, l, TAG_B)
+		} else {
+			let ptr = if zeroed {
+				self.a.alloc_zeroed(padded)
+			} else {
+				self.a.alloc(padded)
+			};
+			tag(ptr, l, TAG_A)
+		}
+	}
+}
+
+unsafe impl<A, B> GlobalAlloc for Either<A, B>
+where
+	A: GlobalAlloc,
+	B: GlobalAlloc,
+{
+	unsafe fn alloc(&self, l: Layout) -> *mut u8 {
+		self.alloc_with(l, false)
+	}
+	unsafe fn dealloc(&self, ptr: *mut u8, l: Layout) {
+		let padded = padded(l).unwrap();
+		let base = ptr.sub(l.align());
+		if *ptr.sub(1) == TAG_B {
+			self.b.dealloc(base, padded);
+		} else {
+			self.a.dealloc(base, padded);
+		}
+	}
+	unsafe fn alloc_zeroed(&self, l: Layout) -> *mut u8 {
+		self.alloc_with(l, true)
+	}
+	unsafe fn realloc(&self, ptr: *mut u8, old_l: Layout, new_s: usize) -> *mut u8 {
+		let new_l = Layout::from_size_align_unchecked(new_s, old_l.align());
+		let (Some(old_padded), Some(new_padded)) = (padded(old_l), padded(new_l)) else {
+			return ptr::null_mut();
+		};
+		let base = ptr.sub(old_l.align());
+		// The tag is in the prefix, so is preserved by the backend's realloc.
+		let is_b = *ptr.sub(1) == TAG_B;
+		let res = if is_b {
+			self.b.realloc(base, old_padded, new_padded.size())
+		} else {
+			self.a.realloc(base, old_padded, new_padded.size())
+		};
+		if !res.is_null() {
+			return res.add(old_l.align());
+		}
+		if !self.fallback.load(Ordering::Relaxed) {
+			return res;
+		}
+		let res = self.alloc_from(!is_b, new_l, false);
+		if !res.is_null() {
+			ptr::copy_nonoverlapping(ptr, res, old_l.size().min(new_s));
+			self.dealloc(ptr, old_l);
+		}
+		res
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::alloc::{GlobalAlloc, Layout, System};
+
+	use super::{padded, Either};
+	use crate::Cap;
+
+	/// The bytes charged to the wrapped [`Cap`] for an allocation of `size` bytes aligned to 16, including the tag prefixed by [`Either`].
+	fn charged(cap: &Cap<System>, size: usize) -> usize {
+		cap.charged(padded(Layout::from_size_align(size, 16).unwrap()).unwrap())
+	}
+
+	#[test]
+	fn either() {
+		let either = Either::new(Cap::new(System, usize::MAX), Cap::new(System, usize::MAX));
+		let layout = Layout::from_size_align(100, 16).unwrap();
+		unsafe {
+			let a = either.alloc(layout);
+			either.select_b();
+			let b = either.alloc_zeroed(layout);
+			assert_eq!(a as usize % 16, 0);
+			assert_eq!(*b.add(99), 0);
+			assert_eq!(either.a().allocated(), charged(either.a(), 100));
+			assert_eq!(either.b().allocated(), charged(either.b(), 100));
+			either.select_a();
+			let a = either.realloc(a, layout, 200);
+			let b = either.realloc(b, layout, 200);
+			assert_eq!(either.a().allocated(), charged(either.a(), 200));
+			assert_eq!(either.b().allocated(), charged(either.b(), 200));
+			either.dealloc(a, Layout::from_size_align(200, 16).unwrap());
+			either.dealloc(b, Layout::from_size_align(200, 16).unwrap());
+		}
+		assert_eq!(either.a().allocated(), 0);
+		assert_eq!(either.b().allocated(), 0);
+	}
+
+	#[test]
+	fn fallback() {
+		let either = Either::new(Cap::new(System, 150), Cap::new(System, usize::MAX));
+		either.set_fallback(true);
+		let layout = Layout::from_size_align(100, 16).unwrap();
+		unsafe {
+			let a = either.alloc(layout);
+			*a = 7;
+			let b = either.alloc(layout);
+			let small = charged(either.b(), 100);
+			assert_eq!(
+				(either.a().allocated(), either.b().allocated()),
+				(small, small)
+			);
+			let a = either.realloc(a, layout, 200);
+			assert_eq!(*a, 7);
+			assert_eq!(
+				(either.a().allocated(), either.b().allocated()),
+				(0, small + charged(either.b(), 200))
+			);
+			either.dealloc(a, Layout::from_size_align(200, 16).unwrap());
+			either.dealloc(b, layout);
+		}
+		assert_eq!(either.b().allocated(), 0);
+	}
+}
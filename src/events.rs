@@ -0,0 +1,166 @@
+#[cfg(any(feature = "events", feature = "stats-peaks", feature = "chaos"))]
+use std::ptr;
+#[cfg(feature = "events")]
+use std::{
+	fmt, sync::{
+		atomic::{AtomicUsize, Ordering}, OnceLock
+	}
+};
+
+#[cfg(all(feature = "events", feature = "tags"))]
+use crate::Tag;
+
+/// A receiver of allocation events, registered with [`Cap::set_event_sink`](crate::Cap::set_event_sink).
+///
+/// This lets external crates build exporters and profilers. [`event`](Self::event) is called from within the allocator, so it shouldn't block on anything that might allocate; typically it copies the event into a preallocated buffer to be processed elsewhere. Allocations it makes are exempt from the limits, and events aren't delivered for them.
+#[cfg(feature = "events")]
+pub trait EventSink: Sync {
+	/// Receive an event.
+	fn event(&self, event: &Event);
+}
+
+/// What happened, as reported in an [`Event`](crate::Event).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "events"), allow(dead_code))]
+pub enum EventKind {
+	/// A successful allocation.
+	Alloc,
+	/// A deallocation.
+	Dealloc,
+	/// A successful reallocation, from an allocation of `old_size` bytes.
+	Realloc {
+		/// The size of the allocation before it was reallocated.
+		old_size: usize,
+	},
+	/// A failed allocation or reallocation.
+	Failure,
+}
+
+/// An allocation event delivered to an [`EventSink`].
+#[cfg(feature = "events")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Event {
+	/// What happened.
+	pub kind: EventKind,
+	/// The size in bytes of the allocation, or of the requested allocation for failures.
+	pub size: usize,
+	/// The alignment of the allocation.
+	pub align: usize,
+	/// The tag the allocation is attributed to, if any.
+	#[cfg(feature = "tags")]
+	pub tag: Option<Tag>,
+	/// An opaque identifier of the thread the event happened on, unique among live threads.
+	pub thread: usize,
+}
+
+#[cfg(any(feature = "events", feature = "stats-peaks", feature = "chaos"))]
+thread_local! {
+	static THREAD: u8 = const { 0 };
+}
+
+/// An identifier of the calling thread, obtained without allocating.
+///
+/// This is the address of a thread-local, which is distinct for each live thread.
+#[cfg(any(feature = "events", feature = "stats-peaks", feature = "chaos"))]
+pub(crate) fn thread_id() -> usize {
+	THREAD
+		.try_with(|thread| ptr::from_ref(thread) as usize)
+		.unwrap_or(0)
+}
+
+/// A [`Cap`](crate::Cap)'s registered sink, and its sampling state.
+#[cfg(feature = "events")]
+pub(crate) struct Sink {
+	/// The sink, and how many events it receives one of.
+	registered: OnceLock<(&'static dyn EventSink, usize)>,
+	count: AtomicUsize,
+}
+
+#[cfg(feature = "events")]
+impl Sink {
+	pub(crate) const fn new() -> Self {
+		Self {
+			registered: OnceLock::new(),
+			count: AtomicUsize::new(0),
+		}
+	}
+
+	pub(crate) fn set(&self, sink: &'static dyn EventSink, every: usize) -> Result<(), ()> {
+		self.registered.set((sink, every.max(1))).map_err(|_| ())
+	}
+
+	/// Deliver the event built by `event`, if a sink is registered and it is sampled. Failures are always delivered.
+	pub(crate) fn emit(&self, kind: EventKind, event: impl FnOnce() -> Event) {
+		let Some(&(sink, every)) = self.registered.get() else {
+			return;
+		};
+		if kind != EventKind::Failure
+			&& !self
+				.count
+				.fetch_add(1, Ordering::Relaxed)
+				.is_multiple_of(every)
+		{
+			return;
+		}
+		let _ = crate::reentrancy::call(|| sink.event(&event()));
+	}
+}
+
+#[cfg(feature = "events")]
+impl fmt::Debug for Sink {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Sink")
+			.field("registered", &self.registered.get().is_some())
+			.field("count", &self.count.load(Ordering::Relaxed))
+			.finish()
+	}
+}
+
+#[cfg(all(test, feature = "events"))]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, sync::atomic::{AtomicUsize, Ordering}
+	};
+
+	use super::{Event, EventKind, EventSink};
+	use crate::Cap;
+
+	#[derive(Debug)]
+	struct Counts([AtomicUsize; 4]);
+
+	impl EventSink for Counts {
+		fn event(&self, event: &Event) {
+			let i = match event.kind {
+				EventKind::Alloc => 0,
+				EventKind::Dealloc => 1,
+				EventKind::Realloc { .. } => 2,
+				EventKind::Failure => 3,
+			};
+			let _ = self.0[i].fetch_add(event.size, Ordering::Relaxed);
+		}
+	}
+
+	static COUNTS: Counts = Counts([const { AtomicUsize::new(0) }; 4]);
+
+	#[test]
+	fn events() {
+		let cap = Cap::new(System, 100);
+		cap.set_event_sink(&COUNTS, 2).unwrap();
+		assert!(cap.set_event_sink(&COUNTS, 1).is_err());
+		unsafe {
+			let a = cap.alloc(Layout::new::<[u8; 10]>());
+			let b = cap.alloc(Layout::new::<[u8; 20]>());
+			assert!(cap.alloc(Layout::new::<[u8; 200]>()).is_null());
+			let a = cap.realloc(a, Layout::new::<[u8; 10]>(), 40);
+			cap.dealloc(a, Layout::new::<[u8; 40]>());
+			cap.dealloc(b, Layout::new::<[u8; 20]>());
+		}
+		let counts = COUNTS
+			.0
+			.each_ref()
+			.map(|count| count.load(Ordering::Relaxed));
+		// Every other event is sampled, except failures.
+		assert_eq!(counts, [10, 20, 40, 200]);
+	}
+}
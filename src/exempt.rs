@@ -0,0 +1,35 @@
+use std::{cell::Cell, marker::PhantomData, ptr};
+
+thread_local! {
+	/// The cap allocations on this thread are exempted from the limits of.
+	static EXEMPT: Cell<*const ()> = const { Cell::new(ptr::null()) };
+}
+
+/// A guard returned by [`Cap::exempt`](crate::Cap::exempt) that ends the exemption when dropped.
+#[derive(Debug)]
+pub struct Exemption<'a> {
+	previous: *const (),
+	_cap: PhantomData<&'a ()>,
+}
+
+impl Exemption<'_> {
+	pub(crate) fn new(cap: *const ()) -> Self {
+		Self {
+			previous: EXEMPT.with(|exempt| exempt.replace(cap)),
+			_cap: PhantomData,
+		}
+	}
+}
+
+impl Drop for Exemption<'_> {
+	fn drop(&mut self) {
+		EXEMPT.with(|exempt| exempt.set(self.previous));
+	}
+}
+
+/// Return whether allocations on this thread are exempted from the limits of `cap`.
+pub(crate) fn is_exempt(cap: *const ()) -> bool {
+	EXEMPT
+		.try_with(|exempt| exempt.get() == cap)
+		.unwrap_or(false)
+}
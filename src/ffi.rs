@@ -0,0 +1,361 @@
+//! C-compatible allocation functions, so that memory allocated by C and C++ libraries can be tracked and limited too.
+//!
+//! Many libraries accept custom allocation functions — for example OpenSSL's `CRYPTO_set_mem_functions` and zstd's `ZSTD_customMem` — which can be pointed at the functions generated by [`c_allocator!`](crate::c_allocator):
+//!
+//! ```
+//! use std::alloc;
+//! use cap::Cap;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+//!
+//! // Defines `cap_malloc`, `cap_calloc`, `cap_realloc`, `cap_free` and `cap_posix_memalign`.
+//! cap::c_allocator!(ALLOCATOR);
+//!
+//! fn main() {
+//!     let ptr = unsafe { cap_malloc(100) };
+//!     assert!(ALLOCATOR.allocated() >= 100);
+//!     unsafe { cap_free(ptr) };
+//! }
+//! ```
+//!
+//! Allocations are prefixed with a header recording their size, as C's `free` isn't passed it. So memory allocated by these functions must only be resized and freed by them, and theirs must only be given memory they allocated; they can't be exported as `malloc`, `free` and so on to replace the C allocator, as memory from its other entry points, such as `aligned_alloc` and `strdup`, or allocated before they took effect, would then be passed to them.
+//!
+//! C code can also read a cap's counters directly, through the [`Counters`] exported by [`export_counters!`](crate::export_counters) and declared in [`Counters::HEADER`].
+
+use std::{
+	alloc::{GlobalAlloc, Layout}, ffi::{c_int, c_void}, ptr, sync::atomic::AtomicUsize
+};
+
+use crate::Cap;
+
+/// The alignment guaranteed by `malloc`, and the minimum header size.
+const MIN_ALIGN: usize = 2 * size_of::<usize>();
+
+const EINVAL: c_int = 22;
+const ENOMEM: c_int = 12;
+
+fn header_size(align: usize) -> usize {
+	align.max(MIN_ALIGN)
+}
+
+fn layout(size: usize, align: usize) -> Option<Layout> {
+	let size = size.checked_add(header_size(align))?;
+	Layout::from_size_align(size, align.max(MIN_ALIGN)).ok()
+}
+
+/// The layout of an existing allocation, which was valid when it was allocated.
+unsafe fn allocated_layout(size: usize, align: usize) -> Layout {
+	Layout::from_size_align_unchecked(size + header_size(align), align.max(MIN_ALIGN))
+}
+
+#[allow(clippy::cast_ptr_alignment)] // the header is a multiple of MIN_ALIGN
+unsafe fn attach(base: *mut u8, size: usize, align: usize) -> *mut c_void {
+	if base.is_null() {
+		return ptr::null_mut();
+	}
+	let ptr = base.add(header_size(align));
+	ptr.cast::<[usize; 2]>().sub(1).write([size, align]);
+	ptr.cast()
+}
+
+#[allow(clippy::cast_ptr_alignment)]
+unsafe fn detach(ptr: *mut c_void) -> (*mut u8, usize, usize) {
+	let ptr = ptr.cast::<u8>();
+	let [size, align] = ptr.cast::<[usize; 2]>().sub(1).read();
+	(ptr.sub(header_size(align)), size, align)
+}
+
+unsafe fn alloc<A: GlobalAlloc>(
+	allocator: &A, size: usize, align: usize, zeroed: bool,
+) -> *mut c_void {
+	let Some(layout) = layout(size, align) else {
+		return ptr::null_mut();
+	};
+	let base = if zeroed {
+		allocator.alloc_zeroed(layout)
+	} else {
+		allocator.alloc(layout)
+	};
+	attach(base, size, align)
+}
+
+/// Allocate `size` bytes, like C's `malloc`.
+///
+/// # Safety
+///
+/// The returned pointer must only be freed with [`free`] or resized with [`realloc`], passing the same allocator.
+pub unsafe fn malloc<A: GlobalAlloc>(allocator: &A, size: usize) -> *mut c_void {
+	alloc(allocator, size, MIN_ALIGN, false)
+}
+
+/// Allocate zeroed memory for `count` objects of `size` bytes, like C's `calloc`.
+///
+/// # Safety
+///
+/// See [`malloc`].
+pub unsafe fn calloc<A: GlobalAlloc>(allocator: &A, count: usize, size: usize) -> *mut c_void {
+	let Some(size) = count.checked_mul(size) else {
+		return ptr::null_mut();
+	};
+	alloc(allocator, size, MIN_ALIGN, true)
+}
+
+/// Resize an allocation to `size` bytes, like C's `realloc`.
+///
+/// A null `ptr` allocates; a `size` of zero frees `ptr` and returns null.
+///
+/// # Safety
+///
+/// `ptr` must be null or have been returned by these functions with the same allocator, and not yet freed.
+pub unsafe fn realloc<A: GlobalAlloc>(allocator: &A, ptr: *mut c_void, size: usize) -> *mut c_void {
+	if ptr.is_null() {
+		return malloc(allocator, size);
+	}
+	if size == 0 {
+		free(allocator, ptr);
+		return ptr::null_mut();
+	}
+	let (base, old_size, align) = detach(ptr);
+	let Some(new_layout) = layout(size, align) else {
+		return ptr::null_mut();
+	};
+	let base = allocator.realloc(base, allocated_layout(old_size, align), new_layout.size());
+	attach(base, size, align)
+}
+
+/// Free an allocation, like C's `free`. Freeing null does nothing.
+///
+/// # Safety
+///
+/// `ptr` must be null or have been returned by these functions with the same allocator, and not yet freed.
+pub unsafe fn free<A: GlobalAlloc>(allocator: &A, ptr: *mut c_void) {
+	if ptr.is_null() {
+		return;
+	}
+	let (base, size, align) = detach(ptr);
+	allocator.dealloc(base, allocated_layout(size, align));
+}
+
+/// Allocate `size` bytes aligned to `align`, like POSIX's `posix_memalign`, returning 0 on success or an error number.
+///
+/// # Safety
+///
+/// `memptr` must be valid for writes. See also [`malloc`].
+pub unsafe fn posix_memalign<A: GlobalAlloc>(
+	allocator: &A, memptr: *mut *mut c_void, align: usize, size: usize,
+) -> c_int {
+	if !align.is_power_of_two() || !align.is_multiple_of(size_of::<usize>()) {
+		return EINVAL;
+	}
+	let ptr = alloc(allocator, size, align, false);
+	if ptr.is_null() {
+		return ENOMEM;
+	}
+	*memptr = ptr;
+	0
+}
+
+/// Define C ABI allocation functions that forward to the given allocator static.
+///
+/// By default they are named `cap_malloc`, `cap_calloc`, `cap_realloc`, `cap_free` and `cap_posix_memalign`; alternative names can be given in that order. See the [`ffi`](crate::ffi) module for details.
+#[macro_export]
+macro_rules! c_allocator {
+	($allocator:path) => {
+		$crate::c_allocator!(
+			$allocator,
+			cap_malloc,
+			cap_calloc,
+			cap_realloc,
+			cap_free,
+			cap_posix_memalign
+		);
+	};
+	($allocator:path, $malloc:ident, $calloc:ident, $realloc:ident, $free:ident, $posix_memalign:ident) => {
+		/// Allocate memory through the Rust allocator, like C's `malloc`.
+		#[no_mangle]
+		pub unsafe extern "C" fn $malloc(size: usize) -> *mut ::std::ffi::c_void {
+			$crate::ffi::malloc(&$allocator, size)
+		}
+		/// Allocate zeroed memory through the Rust allocator, like C's `calloc`.
+		#[no_mangle]
+		pub unsafe extern "C" fn $calloc(count: usize, size: usize) -> *mut ::std::ffi::c_void {
+			$crate::ffi::calloc(&$allocator, count, size)
+		}
+		/// Resize memory allocated through the Rust allocator, like C's `realloc`.
+		#[no_mangle]
+		pub unsafe extern "C" fn $realloc(
+			ptr: *mut ::std::ffi::c_void, size: usize,
+		) -> *mut ::std::ffi::c_void {
+			$crate::ffi::realloc(&$allocator, ptr, size)
+		}
+		/// Free memory allocated through the Rust allocator, like C's `free`.
+		#[no_mangle]
+		pub unsafe extern "C" fn $free(ptr: *mut ::std::ffi::c_void) {
+			$crate::ffi::free(&$allocator, ptr)
+		}
+		/// Allocate aligned memory through the Rust allocator, like POSIX's `posix_memalign`.
+		#[no_mangle]
+		pub unsafe extern "C" fn $posix_memalign(
+			memptr: *mut *mut ::std::ffi::c_void, align: usize, size: usize,
+		) -> ::std::ffi::c_int {
+			$crate::ffi::posix_memalign(&$allocator, memptr, align, size)
+		}
+	};
+}
+
+/// A C-compatible view of a [`Cap`]'s counters, pointing at them so that C code and debuggers read them live, without any thread updating a copy.
+///
+/// The layout is declared for C by [`Counters::HEADER`]. Readers should check that `magic` is [`Counters::MAGIC`] and `version` one they understand; fields are only ever appended, growing `size`. Each counter is a `size_t` updated atomically. The bytes allocated are `limit - remaining + overdraft`, which may be momentarily inconsistent if the limit is changed as they are read.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Counters {
+	/// Always [`Counters::MAGIC`].
+	pub magic: [u8; 8],
+	/// The version of this layout, currently [`Counters::VERSION`].
+	pub version: u32,
+	/// The size in bytes of this layout.
+	pub size: u32,
+	/// The limit, as returned by [`Cap::limit`].
+	pub limit: *const AtomicUsize,
+	/// The bytes remaining within the limit, as returned by [`Cap::remaining`].
+	pub remaining: *const AtomicUsize,
+	/// The bytes allocated beyond the limit while [exempt](Cap::exempt).
+	pub overdraft: *const AtomicUsize,
+	/// The number of live allocations, as returned by `Cap::live_allocations`, or null without the `overhead` feature.
+	pub live: *const AtomicUsize,
+	/// The total bytes ever allocated, as returned by `Cap::total_allocated`, or null without the `stats-counts` feature.
+	pub total_allocated: *const AtomicUsize,
+	/// The most bytes allocated at once, as returned by `Cap::max_allocated`, or null without the `stats-peaks` feature.
+	pub max_allocated: *const AtomicUsize,
+}
+
+// Safe as the pointers are to atomics within a static.
+unsafe impl Sync for Counters {}
+
+impl Counters {
+	/// The bytes `cap\0ctrs`.
+	pub const MAGIC: [u8; 8] = *b"cap\0ctrs";
+	/// The current version of the layout.
+	pub const VERSION: u32 = 1;
+	/// A C header declaring the layout, the symbol exported by [`export_counters!`](crate::export_counters) with its default name, and a function computing the bytes allocated.
+	pub const HEADER: &'static str = include_str!("cap_counters.h");
+
+	/// Point at the counters of `cap`.
+	#[must_use]
+	pub const fn new<H>(cap: &'static Cap<H>) -> Self {
+		#[cfg(feature = "stats-counts")]
+		let total_allocated = ptr::from_ref(&cap.total_allocated);
+		#[cfg(not(feature = "stats-counts"))]
+		let total_allocated = ptr::null();
+		#[cfg(feature = "stats-peaks")]
+		let max_allocated = ptr::from_ref(&cap.max_allocated);
+		#[cfg(not(feature = "stats-peaks"))]
+		let max_allocated = ptr::null();
+		#[cfg(feature = "overhead")]
+		let live = ptr::from_ref(&cap.live);
+		#[cfg(not(feature = "overhead"))]
+		let live = ptr::null();
+		#[allow(clippy::cast_possible_truncation)] // it is a few words
+		let size = size_of::<Self>() as u32;
+		Self {
+			magic: Self::MAGIC,
+			version: Self::VERSION,
+			size,
+			limit: ptr::from_ref(&cap.limit),
+			remaining: ptr::from_ref(&cap.remaining),
+			overdraft: ptr::from_ref(&cap.overdraft),
+			live,
+			total_allocated,
+			max_allocated,
+		}
+	}
+}
+
+/// Export the [`Counters`] of the given allocator static as a C symbol, by default named `cap_counters`, as declared in [`Counters::HEADER`].
+///
+/// ```
+/// use std::alloc;
+/// use cap::Cap;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// cap::export_counters!(ALLOCATOR);
+///
+/// fn main() {
+///     // E.g. in a build script, for C components to include.
+///     let _ = std::fs::write("cap_counters.h", cap::ffi::Counters::HEADER);
+/// #   let _ = std::fs::remove_file("cap_counters.h");
+/// }
+/// ```
+#[macro_export]
+macro_rules! export_counters {
+	($allocator:path) => {
+		$crate::export_counters!($allocator, cap_counters);
+	};
+	($allocator:path, $name:ident) => {
+		/// The counters of the Rust allocator, for C code to read.
+		#[no_mangle]
+		#[allow(non_upper_case_globals)]
+		pub static $name: $crate::ffi::Counters = $crate::ffi::Counters::new(&$allocator);
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, ptr, sync::atomic::{AtomicUsize, Ordering}
+	};
+
+	use super::Counters;
+	use crate::Cap;
+
+	#[test]
+	fn counters() {
+		static CAP: Cap<System> = Cap::new(System, 1000);
+		static COUNTERS: Counters = Counters::new(&CAP);
+		let read = |counter: *const AtomicUsize| unsafe { (*counter).load(Ordering::Relaxed) };
+		let allocated =
+			|| read(COUNTERS.limit) - read(COUNTERS.remaining) + read(COUNTERS.overdraft);
+		let layout = Layout::new::<[u8; 100]>();
+		unsafe {
+			let ptr = CAP.alloc(layout);
+			assert_eq!(allocated(), CAP.charged(layout));
+			#[cfg(feature = "overhead")]
+			assert_eq!(read(COUNTERS.live), 1);
+			CAP.dealloc(ptr, layout);
+		}
+		assert_eq!(allocated(), 0);
+		assert_eq!(COUNTERS.size as usize, size_of::<Counters>());
+		assert!(Counters::HEADER.contains("struct cap_counters {"));
+	}
+
+	#[test]
+	fn ffi() {
+		let cap = Cap::new(System, usize::MAX);
+		unsafe {
+			let a = super::malloc(&cap, 100);
+			let b = super::calloc(&cap, 10, 10);
+			assert_eq!(*b.cast::<u8>().add(99), 0);
+			let a = super::realloc(&cap, a, 1000);
+			assert_eq!(
+				cap.allocated(),
+				cap.charged(super::layout(1000, super::MIN_ALIGN).unwrap())
+					+ cap.charged(super::layout(100, super::MIN_ALIGN).unwrap())
+			);
+			let mut c = ptr::null_mut();
+			assert_eq!(super::posix_memalign(&cap, &raw mut c, 4096, 10), 0);
+			assert_eq!(c as usize % 4096, 0);
+			assert_eq!(
+				super::posix_memalign(&cap, &raw mut c, 3, 10),
+				super::EINVAL
+			);
+			super::free(&cap, a);
+			super::free(&cap, b);
+			super::free(&cap, c);
+			super::free(&cap, ptr::null_mut());
+		}
+		assert_eq!(cap.allocated(), 0);
+	}
+}
@@ -0,0 +1,97 @@
+use std::{fmt, sync::atomic::Ordering};
+
+use crate::{Cap, Tag, TagGuard};
+
+/// A named group with a weight, that receives a proportional share of a [`Cap`]'s limit under pressure. Created by [`Cap::group`].
+///
+/// Each group's share is `limit * weight / total weight of registered groups`, recomputed as groups are registered and dropped. A group may exceed its share while the cap isn't under pressure, that is while no more than a fraction of the limit is allocated (see [`Cap::set_group_pressure`]).
+///
+/// Allocations are attributed to a group while it is [entered](Group::enter).
+///
+/// ```
+/// # use std::alloc;
+/// # use cap::Cap;
+/// # #[global_allocator]
+/// # static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+/// let ingest = ALLOCATOR.group("ingest", 2);
+/// let query = ALLOCATOR.group("query", 5);
+/// let background = ALLOCATOR.group("background", 1);
+/// assert_eq!(background.share(), usize::MAX / 8);
+///
+/// let _guard = query.enter();
+/// // ...
+/// ```
+pub struct Group<'a, H> {
+	cap: &'a Cap<H>,
+	tag: Tag,
+	weight: usize,
+}
+
+impl<'a, H> Group<'a, H> {
+	pub(crate) fn new(cap: &'a Cap<H>, tag: Tag, weight: usize) -> Self {
+		assert_ne!(weight, 0, "cap: group {:?} has a weight of 0", tag.name());
+		let slot = &cap.tags.slots[tag.index()];
+		// Leaving the weight of a group already registered as it is.
+		if slot
+			.weight
+			.compare_exchange(0, weight, Ordering::Relaxed, Ordering::Relaxed)
+			.is_err()
+		{
+			panic!("cap: group {:?} is already registered", tag.name());
+		}
+		let _ = cap.tags.total_weight.fetch_add(weight, Ordering::Relaxed);
+		Self { cap, tag, weight }
+	}
+
+	/// Return the tag allocations in this group are attributed to.
+	#[must_use]
+	pub fn tag(&self) -> Tag {
+		self.tag
+	}
+
+	/// Return this group's weight.
+	#[must_use]
+	pub fn weight(&self) -> usize {
+		self.weight
+	}
+
+	/// Return this group's current share of the limit in bytes.
+	#[must_use]
+	pub fn share(&self) -> usize {
+		self.cap.share(self.weight)
+	}
+
+	/// Return the number of bytes currently allocated by this group.
+	#[must_use]
+	pub fn allocated(&self) -> usize {
+		self.cap.tag_allocated(self.tag)
+	}
+
+	/// Attribute allocations made on this thread to this group, until the returned guard is dropped.
+	#[must_use = "the group is exited when the guard is dropped"]
+	pub fn enter(&self) -> TagGuard {
+		self.tag.enter()
+	}
+}
+
+impl<H> Drop for Group<'_, H> {
+	fn drop(&mut self) {
+		self.cap.tags.slots[self.tag.index()]
+			.weight
+			.store(0, Ordering::Relaxed);
+		let _ = self
+			.cap
+			.tags
+			.total_weight
+			.fetch_sub(self.weight, Ordering::Relaxed);
+	}
+}
+
+impl<H> fmt::Debug for Group<'_, H> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Group")
+			.field("tag", &self.tag)
+			.field("weight", &self.weight)
+			.finish()
+	}
+}
@@ -0,0 +1,61 @@
+//! A histogram of the sizes of allocations and reallocations.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// One bucket for each power of two a size can round up to, from 1 to 2<sup>`usize::BITS`</sup>.
+const BUCKETS: usize = usize::BITS as usize + 1;
+
+/// The state behind [`Cap::size_histogram`](crate::Cap::size_histogram).
+#[derive(Debug)]
+pub(crate) struct Histogram([AtomicUsize; BUCKETS]);
+
+impl Histogram {
+	pub(crate) const fn new() -> Self {
+		Self([const { AtomicUsize::new(0) }; BUCKETS])
+	}
+
+	/// Count an allocation or reallocation to `size` bytes, in the bucket of the power of two it rounds up to.
+	#[inline]
+	pub(crate) fn record(&self, size: usize) {
+		let bucket = (usize::BITS - size.saturating_sub(1).leading_zeros()) as usize;
+		let _ = self.0[bucket].fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn buckets(&self) -> Vec<(usize, usize)> {
+		self.0
+			.iter()
+			.enumerate()
+			.map(|(bucket, count)| {
+				#[allow(clippy::cast_possible_truncation)]
+				let bound = 1_usize.checked_shl(bucket as u32).unwrap_or(usize::MAX);
+				(bound, count.load(Ordering::Relaxed))
+			})
+			.filter(|&(_, count)| count != 0)
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Histogram;
+
+	#[test]
+	fn buckets() {
+		let histogram = Histogram::new();
+		for size in [0, 1, 2, 3, 4, 5, 1000, 1024, 1025, usize::MAX] {
+			histogram.record(size);
+		}
+		assert_eq!(
+			histogram.buckets(),
+			[
+				(1, 2),
+				(2, 1),
+				(4, 2),
+				(8, 1),
+				(1024, 2),
+				(2048, 1),
+				(usize::MAX, 1)
+			]
+		);
+	}
+}
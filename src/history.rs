@@ -0,0 +1,304 @@
+//! Hours of usage history at coarse granularity, delta and run-length encoded in a bounded buffer.
+
+use std::{
+	collections::VecDeque, io::{self, Write}, sync::{Arc, Mutex, PoisonError}, thread, time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+use crate::Cap;
+
+/// A long-duration record of the bytes allocated, sampled at a fixed interval and kept within a bounded buffer, for post-incident analysis of leaks that unfold over hours.
+///
+/// Samples are rounded to a multiple of a resolution, and stored as the change from the previous sample, with runs of unchanged samples collapsed, so a steady process costs a few bytes per hour. Once the buffer is full the oldest samples are discarded.
+///
+/// ```
+/// use std::{alloc, sync::Arc, time::Duration};
+/// use cap::{Cap, History};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     // A day of history at one sample a second, to the nearest 64 KiB, in at most 64 KiB.
+///     let history = Arc::new(History::new(Duration::from_secs(1), 64 * 1024, 64 * 1024));
+///     let _ = ALLOCATOR.record_history(Arc::clone(&history));
+///     // ...after an incident:
+///     history.export_csv(std::io::stdout()).unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct History {
+	interval: Duration,
+	resolution: usize,
+	capacity: usize,
+	inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+	/// When the oldest retained sample was taken.
+	start: SystemTime,
+	/// The oldest retained sample, in units of the resolution.
+	base: u64,
+	/// The newest sample, in units of the resolution.
+	last: u64,
+	/// The number of samples retained.
+	len: usize,
+	/// Entries since `base`: varints of either a zigzag-encoded change shifted left once, or a run of unchanged samples shifted left once with its low bit set.
+	encoded: VecDeque<u8>,
+}
+
+impl History {
+	/// Create an empty history of samples taken every `interval`, rounded to the nearest multiple of `resolution` bytes, occupying at most `capacity` bytes.
+	#[must_use]
+	pub fn new(interval: Duration, resolution: usize, capacity: usize) -> Self {
+		Self {
+			interval,
+			resolution: resolution.max(1),
+			capacity,
+			inner: Mutex::new(Inner {
+				start: UNIX_EPOCH,
+				base: 0,
+				last: 0,
+				len: 0,
+				encoded: VecDeque::new(),
+			}),
+		}
+	}
+
+	/// Return the interval samples are taken at.
+	#[must_use]
+	pub fn interval(&self) -> Duration {
+		self.interval
+	}
+
+	/// Record a sample of `allocated` bytes, taken now.
+	pub fn record(&self, allocated: usize) {
+		self.record_at(SystemTime::now(), allocated);
+	}
+
+	fn record_at(&self, now: SystemTime, allocated: usize) {
+		let value = (allocated.saturating_add(self.resolution / 2) / self.resolution) as u64;
+		let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+		if inner.len == 0 {
+			*inner = Inner {
+				start: now,
+				base: value,
+				last: value,
+				len: 1,
+				encoded: VecDeque::new(),
+			};
+			return;
+		}
+		let delta = value.wrapping_sub(inner.last).cast_signed();
+		if delta == 0 {
+			// Extend the run of unchanged samples the newest entry records, if it does.
+			let run = match inner.newest_entry() {
+				Some((at, Entry::Run(run))) => {
+					inner.encoded.truncate(at);
+					run + 1
+				}
+				_ => 1,
+			};
+			push_varint(&mut inner.encoded, run << 1 | 1);
+		} else {
+			push_varint(&mut inner.encoded, zigzag(delta) << 1);
+		}
+		inner.last = value;
+		inner.len += 1;
+		while inner.encoded.len() > self.capacity {
+			inner.drop_oldest(self.interval);
+		}
+	}
+
+	/// Return the retained samples, oldest first, as the time each was taken, assuming a steady interval, and the bytes allocated to the resolution.
+	#[must_use]
+	pub fn samples(&self) -> Vec<(SystemTime, usize)> {
+		let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+		let mut samples = Vec::with_capacity(inner.len);
+		if inner.len == 0 {
+			return samples;
+		}
+		let (mut value, mut time) = (inner.base, inner.start);
+		let mut push = |value: u64| {
+			#[allow(clippy::cast_possible_truncation)]
+			samples.push((time, (value as usize).saturating_mul(self.resolution)));
+			time += self.interval;
+		};
+		push(value);
+		for entry in Entries(inner.encoded.iter().copied()) {
+			match entry {
+				Entry::Delta(delta) => {
+					value = value.wrapping_add(delta.cast_unsigned());
+					push(value);
+				}
+				Entry::Run(run) => {
+					for _ in 0..run {
+						push(value);
+					}
+				}
+			}
+		}
+		samples
+	}
+
+	/// Return the number of bytes the encoded samples occupy.
+	#[must_use]
+	pub fn encoded_len(&self) -> usize {
+		self.inner
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.encoded
+			.len()
+	}
+
+	/// Write the retained samples to `writer` as CSV, with a header line followed by lines of `timestamp`, in seconds since the Unix epoch with millisecond precision, and `allocated`.
+	pub fn export_csv(&self, mut writer: impl Write) -> io::Result<()> {
+		writeln!(writer, "timestamp,allocated")?;
+		for (time, allocated) in self.samples() {
+			let timestamp = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+			writeln!(
+				writer,
+				"{}.{:03},{}",
+				timestamp.as_secs(),
+				timestamp.subsec_millis(),
+				allocated
+			)?;
+		}
+		writer.flush()
+	}
+}
+
+impl Inner {
+	/// Return the newest entry and its offset, if there is one.
+	fn newest_entry(&self) -> Option<(usize, Entry)> {
+		// Each byte of a varint but its last has its high bit set.
+		let mut at = self.encoded.len().checked_sub(1)?;
+		while at > 0 && self.encoded[at - 1] & 0x80 != 0 {
+			at -= 1;
+		}
+		Entries(self.encoded.range(at..).copied())
+			.next()
+			.map(|entry| (at, entry))
+	}
+
+	/// Discard the oldest sample, so that the one after it becomes `base`.
+	fn drop_oldest(&mut self, interval: Duration) {
+		let mut entries = Entries(self.encoded.iter().copied());
+		let Some(entry) = entries.next() else {
+			return;
+		};
+		let consumed = self.encoded.len() - entries.0.len();
+		let _ = self.encoded.drain(..consumed);
+		match entry {
+			Entry::Delta(delta) => self.base = self.base.wrapping_add(delta.cast_unsigned()),
+			Entry::Run(run) if run > 1 => {
+				let mut shorter = VecDeque::new();
+				push_varint(&mut shorter, (run - 1) << 1 | 1);
+				for byte in shorter.into_iter().rev() {
+					self.encoded.push_front(byte);
+				}
+			}
+			Entry::Run(_) => (),
+		}
+		self.start += interval;
+		self.len -= 1;
+	}
+}
+
+#[derive(Debug)]
+enum Entry {
+	Delta(i64),
+	Run(u64),
+}
+
+struct Entries<I>(I);
+
+impl<I> Iterator for Entries<I>
+where
+	I: Iterator<Item = u8>,
+{
+	type Item = Entry;
+
+	fn next(&mut self) -> Option<Entry> {
+		let (mut value, mut shift) = (0_u64, 0);
+		loop {
+			let byte = self.0.next()?;
+			value |= u64::from(byte & 0x7f) << shift;
+			if byte & 0x80 == 0 {
+				break;
+			}
+			shift += 7;
+		}
+		Some(if value & 1 == 0 {
+			Entry::Delta(unzigzag(value >> 1))
+		} else {
+			Entry::Run(value >> 1)
+		})
+	}
+}
+
+fn zigzag(value: i64) -> u64 {
+	((value << 1) ^ (value >> 63)).cast_unsigned()
+}
+
+fn unzigzag(value: u64) -> i64 {
+	(value >> 1).cast_signed() ^ -((value & 1).cast_signed())
+}
+
+fn push_varint(encoded: &mut VecDeque<u8>, mut value: u64) {
+	while value >= 0x80 {
+		#[allow(clippy::cast_possible_truncation)]
+		encoded.push_back(value as u8 | 0x80);
+		value >>= 7;
+	}
+	#[allow(clippy::cast_possible_truncation)]
+	encoded.push_back(value as u8);
+}
+
+impl<H> Cap<H> {
+	/// [Record](History::record) the bytes allocated into `history` at its interval, on a background thread, for as long as anything else holds a reference to it.
+	pub fn record_history(&'static self, history: Arc<History>) -> thread::JoinHandle<()>
+	where
+		H: Sync,
+	{
+		thread::spawn(move || {
+			while Arc::strong_count(&history) > 1 {
+				history.record(self.allocated());
+				thread::sleep(history.interval);
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, UNIX_EPOCH};
+
+	use super::History;
+
+	#[test]
+	fn history() {
+		let history = History::new(Duration::from_secs(1), 10, 8);
+		let values = [100, 104, 108, 200, 200, 200, 200, 150, 1_000_000];
+		for (i, &value) in values.iter().enumerate() {
+			history.record_at(UNIX_EPOCH + Duration::from_secs(i as u64), value);
+		}
+		let samples = history.samples();
+		assert!(samples
+			.iter()
+			.map(|&(_, allocated)| allocated)
+			.eq([100, 100, 110, 200, 200, 200, 200, 150, 1_000_000]));
+		assert_eq!(history.encoded_len(), 8);
+		// The run extends in place, and the oldest samples are discarded to make room for it.
+		for i in 9..1009 {
+			history.record_at(UNIX_EPOCH + Duration::from_secs(i), 1_000_000);
+		}
+		let samples = history.samples();
+		assert_eq!((samples.len(), history.encoded_len()), (1007, 8));
+		assert_eq!(samples[0], (UNIX_EPOCH + Duration::from_secs(2), 110));
+		assert_eq!(
+			samples[1006],
+			(UNIX_EPOCH + Duration::from_secs(1008), 1_000_000)
+		);
+	}
+}
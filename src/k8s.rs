@@ -0,0 +1,194 @@
+//! One-call configuration for running in a container, such as a Kubernetes pod.
+//!
+//! ```no_run
+//! use std::alloc;
+//! use cap::Cap;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+//!
+//! fn main() {
+//!     // Keep 64 MiB of the container's memory limit free for the kernel and the rest of the process.
+//!     let configuration = cap::k8s::auto_configure(&ALLOCATOR, 64 * 1024 * 1024);
+//!     eprintln!("{:?}", configuration);
+//! }
+//! ```
+
+use std::time::{Duration, Instant};
+
+use crate::{os, Cap};
+
+/// How often the resident set size is measured to calibrate the limit.
+const INTERVAL: Duration = Duration::from_secs(1);
+/// The fraction of the cgroup's limit in use, in percent, above which the OOM killer is considered imminent.
+const DANGER_USAGE: usize = 95;
+/// The `full` memory pressure, in percent, above which the OOM killer is considered imminent.
+const DANGER_PRESSURE: f64 = 10.0;
+/// The estimated time to the OOM killer below which it is considered imminent.
+const DANGER_TIME: Duration = Duration::from_secs(30);
+
+/// What [`auto_configure`] configured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Configuration {
+	/// The memory limit of the process's cgroup, if it has one.
+	pub cgroup_limit: Option<usize>,
+	/// The resident set size the limit is being [calibrated](Cap::control_rss) to, if it can be measured.
+	pub rss_target: Option<usize>,
+	/// The limit, as initially set.
+	pub limit: usize,
+	/// The soft limit.
+	pub soft_limit: usize,
+}
+
+/// Configure `cap` for the memory limit of the container it is running in, leaving `headroom` bytes of it free.
+///
+/// If the process's cgroup has a memory limit, this:
+///
+/// * sets the limit to the cgroup's limit less `headroom`;
+/// * if the resident set size can be measured, calibrates the limit every second on a background thread, with [`control_rss`](Cap::control_rss), so that the whole process rather than just its heap stays within that;
+/// * sets the [soft limit](Cap::set_soft_limit) to seven eighths of it, so that caches shed memory before allocations begin to fail.
+///
+/// Otherwise `cap` is left unchanged. Calling this again doesn't add to the background threads, as setting the limit makes the previous one exit at its next step.
+pub fn auto_configure<H>(cap: &'static Cap<H>, headroom: usize) -> Configuration
+where
+	H: Sync,
+{
+	let cgroup_limit = os::cgroup_memory_max();
+	let mut rss_target = None;
+	if let Some(cgroup_limit) = cgroup_limit {
+		let target = cgroup_limit.saturating_sub(headroom);
+		let _ = cap.set_limit_with_reason(target.max(cap.allocated()), "k8s");
+		if os::rss().is_some() {
+			let _ = cap.control_rss(target, INTERVAL);
+			rss_target = Some(target);
+		}
+		cap.set_soft_limit_with_reason(target / 8 * 7, "k8s");
+	}
+	Configuration {
+		cgroup_limit,
+		rss_target,
+		limit: cap.limit(),
+		soft_limit: cap.soft_limit(),
+	}
+}
+
+/// An estimate of how soon the kernel's OOM killer will be invoked, as returned by [`OomEstimator::estimate`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct OomEstimate {
+	/// The memory used by the process's cgroup, or its resident set size if it isn't in one.
+	pub usage: Option<usize>,
+	/// The memory limit of the process's cgroup, if it has one.
+	pub limit: Option<usize>,
+	/// The percentage of the last 10 seconds in which all non-idle tasks were stalled waiting for memory, if pressure stall information is available.
+	pub pressure: Option<f64>,
+	/// How long until usage reaches the limit at its recent rate of growth, if it is growing fast enough for that to be represented as a `Duration`.
+	pub time_to_oom: Option<Duration>,
+	/// Whether the OOM killer looks imminent: usage is within 5% of the limit, tasks are stalled on memory over 10% of the time, or the limit will be reached within 30 seconds.
+	pub danger: bool,
+}
+
+/// A crude estimator of how soon the kernel's OOM killer will be invoked, from the cgroup's usage and limit, the resident set size and pressure stall information.
+///
+/// Each [`estimate`](Self::estimate) samples usage, and extrapolates its rate of growth since the previous samples. Calling it periodically lets an application checkpoint its state before the kernel kills it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OomEstimator {
+	previous: Option<(Instant, usize)>,
+	/// The smoothed rate of growth of usage, in bytes per second.
+	rate: f64,
+}
+
+impl OomEstimator {
+	/// Create a new estimator, with no previous samples.
+	#[must_use]
+	pub const fn new() -> Self {
+		Self {
+			previous: None,
+			rate: 0.0,
+		}
+	}
+
+	/// Sample usage, and estimate how soon the OOM killer will be invoked.
+	pub fn estimate(&mut self) -> OomEstimate {
+		let usage = os::cgroup_memory_current().or_else(os::rss);
+		let now = Instant::now();
+		if let Some(usage) = usage {
+			if let Some((then, previous)) = self.previous {
+				let elapsed = now.duration_since(then).as_secs_f64();
+				if elapsed > 0.0 {
+					#[allow(clippy::cast_precision_loss)]
+					let rate = (usage as f64 - previous as f64) / elapsed;
+					self.rate = if self.rate == 0.0 {
+						rate
+					} else {
+						self.rate.midpoint(rate)
+					};
+				}
+			}
+			self.previous = Some((now, usage));
+		}
+		assess(
+			usage,
+			os::cgroup_memory_max(),
+			os::memory_pressure_full(),
+			self.rate,
+		)
+	}
+
+	/// Return the [estimated](Self::estimate) time until usage reaches the limit, if it is growing towards one.
+	pub fn time_to_oom_estimate(&mut self) -> Option<Duration> {
+		self.estimate().time_to_oom
+	}
+
+	/// Return whether the [estimate](Self::estimate) is that the OOM killer is imminent.
+	pub fn danger(&mut self) -> bool {
+		self.estimate().danger
+	}
+}
+
+fn assess(
+	usage: Option<usize>, limit: Option<usize>, pressure: Option<f64>, rate: f64,
+) -> OomEstimate {
+	let time_to_oom = match (usage, limit) {
+		// Too far off to represent when growth is negligible.
+		#[allow(clippy::cast_precision_loss)]
+		(Some(usage), Some(limit)) if rate > 0.0 => {
+			Duration::try_from_secs_f64(limit.saturating_sub(usage) as f64 / rate).ok()
+		}
+		_ => None,
+	};
+	let near_limit =
+		matches!((usage, limit), (Some(usage), Some(limit)) if usage >= limit / 100 * DANGER_USAGE);
+	let danger = near_limit
+		|| pressure.is_some_and(|pressure| pressure >= DANGER_PRESSURE)
+		|| time_to_oom.is_some_and(|time| time < DANGER_TIME);
+	OomEstimate {
+		usage,
+		limit,
+		pressure,
+		time_to_oom,
+		danger,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::assess;
+
+	#[test]
+	fn assess_danger() {
+		let calm = assess(Some(500), Some(1000), Some(0.0), 10.0);
+		assert_eq!(calm.time_to_oom, Some(Duration::from_secs(50)));
+		assert!(!calm.danger);
+		assert!(assess(Some(500), Some(1000), None, 20.0).danger);
+		assert!(assess(Some(960), Some(1000), None, 0.0).danger);
+		assert!(assess(Some(500), Some(1000), Some(25.0), -5.0).danger);
+		let unlimited = assess(Some(500), None, None, 10.0);
+		assert!(unlimited.time_to_oom.is_none() && !unlimited.danger);
+		let negligible = assess(Some(0), Some(usize::MAX), None, f64::MIN_POSITIVE);
+		assert!(negligible.time_to_oom.is_none() && !negligible.danger);
+	}
+}
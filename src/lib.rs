@@ -22,11 +22,15 @@
 //!     println!("Currently allocated: {}B", ALLOCATOR.allocated());
 //! }
 //! ```
+//!
+//! # Hooks
+//!
+//! Functions set to be called on events such as a failed allocation, like [`Cap::set_inner_failure_hook`], are called from within the allocator, on the thread allocating or freeing. While one runs, allocations it makes on that thread are exempt from the limits, so that it can neither fail for lack of memory nor recurse into itself through the allocator, and hooks aren't called again on that thread until it returns, other than the [limit change hook](Cap::on_limit_change) for changes it makes.
 
-#![cfg_attr(feature = "nightly", feature(allocator_api))]
+#![cfg_attr(feature = "nightly", feature(allocator_api, cfg_sanitize))]
 #![cfg_attr(
 	all(test, feature = "nightly"),
-	feature(try_reserve, test, custom_test_frameworks)
+	feature(try_reserve_kind, test, custom_test_frameworks)
 )]
 #![cfg_attr(all(test, feature = "nightly"), test_runner(tests::runner))]
 #![warn(
@@ -46,38 +50,624 @@
 	clippy::missing_errors_doc
 )]
 
+#[cfg(feature = "audit")]
+mod audit;
+#[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+mod backend;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod breakdown;
+#[cfg(feature = "breaker")]
+mod breaker;
+#[cfg(feature = "broadcast")]
+mod broadcast;
+#[cfg(feature = "budget")]
+mod budget;
+mod cache;
+#[cfg(feature = "cap-group")]
+mod cap_group;
+#[cfg(feature = "carve")]
+mod carve;
+#[cfg(all(feature = "cgroup", target_os = "linux"))]
+mod cgroup;
+#[cfg(feature = "stats-churn")]
+mod churn;
+#[cfg(feature = "compare")]
+mod compare;
+#[cfg(feature = "consistency")]
+mod consistency;
+mod csv;
+#[cfg(all(feature = "memory-pressure", target_os = "macos"))]
+mod dispatch;
+mod either;
+mod events;
+mod exempt;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "tags")]
+mod group;
+#[cfg(feature = "stats-histograms")]
+mod histogram;
+#[cfg(feature = "history")]
+mod history;
+#[cfg(feature = "k8s")]
+pub mod k8s;
+#[cfg(all(feature = "low-memory", windows))]
+mod low_memory;
+#[cfg(any(
+	all(any(feature = "cgroup", feature = "psi"), target_os = "linux"),
+	all(any(feature = "reload", feature = "shm", feature = "uds"), unix),
+	all(feature = "low-memory", windows)
+))]
+mod monitor;
+mod os;
+#[cfg(feature = "stats-peaks")]
+mod peak;
+#[cfg(all(feature = "perfcounters", windows))]
+mod perf;
+mod pressure;
+#[cfg(all(feature = "psi", target_os = "linux"))]
+mod psi;
+#[cfg(feature = "quarantine")]
+mod quarantine;
+#[cfg(feature = "recent")]
+mod recent;
+mod reentrancy;
+mod rejection;
+#[cfg(all(feature = "reload", unix))]
+mod reload;
+#[cfg(feature = "reserve")]
+mod reserve;
+#[cfg(feature = "scope")]
+mod scope;
+mod self_test;
+#[cfg(all(feature = "shm", unix))]
+mod shm;
+#[cfg(feature = "signal")]
+mod signal;
+#[cfg(feature = "sites")]
+mod site;
+mod size;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+mod stats;
+#[cfg(feature = "summary")]
+mod summary;
+#[cfg(feature = "tags")]
+mod tag;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "stats-threads")]
+mod threads;
+mod transaction;
+mod tune;
+#[cfg(all(feature = "uds", unix))]
+mod uds;
+mod wait;
+#[cfg(feature = "watch")]
+mod watch;
+
+#[cfg(feature = "audit")]
+pub use audit::{AuditError, Checkpoint, DiffGroup, LiveAllocation};
+#[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+pub use backend::BackendStats;
+pub use breakdown::Breakdown;
+#[cfg(feature = "breaker")]
+pub use breaker::BestEffort;
+#[cfg(feature = "broadcast")]
+pub use broadcast::Notification;
+#[cfg(feature = "budget")]
+pub use budget::{Budget, BudgetGuard, Budgeted, WeakCapHandle};
+pub use cache::ThreadCache;
+#[cfg(feature = "cap-group")]
+pub use cap_group::CapGroup;
+#[cfg(feature = "carve")]
+pub use carve::CarveOut;
+#[cfg(feature = "stats-churn")]
+pub use churn::Churn;
+#[cfg(feature = "compare")]
+pub use compare::OsComparison;
+#[cfg(feature = "consistency")]
+pub use consistency::{Inconsistency, InconsistencyAction};
+pub use either::Either;
+#[cfg(feature = "events")]
+pub use events::{Event, EventKind, EventSink};
+pub use exempt::Exemption;
+#[cfg(feature = "tags")]
+pub use group::Group;
+#[cfg(feature = "history")]
+pub use history::History;
+#[cfg(any(
+	all(any(feature = "cgroup", feature = "psi"), target_os = "linux"),
+	all(any(feature = "reload", feature = "shm", feature = "uds"), unix),
+	all(feature = "low-memory", windows)
+))]
+pub use monitor::Monitor;
+#[cfg(feature = "stats-peaks")]
+pub use peak::PeakInfo;
+pub use pressure::{CgroupEvents, MemoryPressureLevel, PressureEvent};
+#[cfg(feature = "recent")]
+pub use recent::{LimitChange, LimitKind, RecentEvent, RecentEventKind};
+pub use rejection::{last_rejection, Rejection};
+#[cfg(feature = "reserve")]
+pub use reserve::{HookAllocation, RESERVE_SIZE};
+#[cfg(feature = "scope")]
+pub use scope::{MemoryScope, PeakScope, ScopeGuard, Scoped};
+pub use self_test::{SelfTest, SelfTestFailure};
+#[cfg(all(feature = "shm", unix))]
+pub use shm::SharedStats;
+#[cfg(feature = "signal")]
+pub use signal::signal_handler;
+#[cfg(feature = "sites")]
+pub use site::{Site, SiteGuard};
+pub use size::parse_size;
+pub use stats::Stats;
+#[cfg(feature = "summary")]
+pub use summary::PanicReport;
+#[cfg(feature = "tags")]
+pub use tag::{
+	capture_tag, current_tag, spawn_tagged, tag, Tag, TagGuard, TagNode, TagStats, Tagged
+};
+#[cfg(feature = "stats-threads")]
+pub use threads::ThreadStats;
+pub use transaction::Transaction;
+pub use wait::{wait_for_budget, WaitGuard};
+#[cfg(feature = "watch")]
+pub use watch::{Crossing, Watch, WatchEvent};
+
+#[cfg(not(feature = "events"))]
+use events::EventKind;
 #[cfg(feature = "nightly")]
-use std::alloc::{Alloc, AllocErr, CannotReallocInPlace};
+use std::alloc::{AllocError, Allocator};
+#[cfg(feature = "audit")]
+use std::backtrace::{Backtrace, BacktraceStatus};
+#[cfg(feature = "redzone")]
+use std::slice;
+#[cfg(feature = "zeroize")]
+use std::sync::atomic;
+#[cfg(feature = "chaos")]
+use std::sync::atomic::AtomicU64;
 use std::{
-	alloc::{GlobalAlloc, Layout}, ptr, sync::atomic::{AtomicUsize, Ordering}
+	alloc::{GlobalAlloc, Layout}, mem, ptr, sync::{
+		atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering}, Arc
+	}, thread, time::{Duration, SystemTime, UNIX_EPOCH}
 };
 
+/// Whether this is being built for Miri or, with the `nightly` feature, a sanitizer, which instrumentation that reuses or defers freeing memory would confuse.
+///
+/// In this mode such instrumentation is disabled, so that code using it tests cleanly under these tools without swapping out the global allocator.
+#[cfg_attr(
+	feature = "nightly",
+	cfg(any(
+		miri,
+		sanitize = "address",
+		sanitize = "memory",
+		sanitize = "hwaddress"
+	))
+)]
+#[cfg_attr(not(feature = "nightly"), cfg(miri))]
+pub(crate) const SANITIZED: bool = true;
+/// Whether this is being built for Miri or, with the `nightly` feature, a sanitizer.
+#[cfg_attr(
+	feature = "nightly",
+	cfg(not(any(
+		miri,
+		sanitize = "address",
+		sanitize = "memory",
+		sanitize = "hwaddress"
+	)))
+)]
+#[cfg_attr(not(feature = "nightly"), cfg(not(miri)))]
+pub(crate) const SANITIZED: bool = false;
+
 /// A struct that wraps another allocator and limits the number of bytes that can be allocated.
 #[derive(Debug)]
 pub struct Cap<H> {
 	allocator: H,
 	remaining: AtomicUsize,
 	limit: AtomicUsize,
-	#[cfg(feature = "stats")]
+	soft_limit: AtomicUsize,
+	external: AtomicUsize,
+	#[cfg(feature = "committed")]
+	reserved: AtomicUsize,
+	#[cfg(feature = "committed")]
+	committed_limit: AtomicUsize,
+	#[cfg(feature = "overhead")]
+	overhead: AtomicUsize,
+	#[cfg(feature = "overhead")]
+	live: AtomicUsize,
+	/// The number of failures in the current streak, each within `RETRY_MAX` of the last.
+	failure_streak: AtomicUsize,
+	#[cfg(feature = "breaker")]
+	breaker: breaker::Breaker,
+	/// When the last failure happened, in [milliseconds](now_millis), so that it fits in a `usize` on targets without 64-bit atomics.
+	last_failure: AtomicUsize,
+	overdraft: AtomicUsize,
+	granularity: usize,
+	#[cfg(feature = "cap-group")]
+	group: Option<&'static CapGroup>,
+	stats: Stats,
+	#[cfg(feature = "stats-counts")]
 	total_allocated: AtomicUsize,
-	#[cfg(feature = "stats")]
+	#[cfg(feature = "stats-peaks")]
 	max_allocated: AtomicUsize,
+	#[cfg(feature = "stats-peaks")]
+	peak: peak::Peak,
+	#[cfg(feature = "stats-peaks")]
+	window_peak: AtomicUsize,
+	#[cfg(feature = "stats-histograms")]
+	histogram: histogram::Histogram,
+	#[cfg(feature = "stats-counts")]
+	grow_count: AtomicUsize,
+	#[cfg(feature = "stats-counts")]
+	grow_zeroed_count: AtomicUsize,
+	#[cfg(feature = "stats-counts")]
+	shrink_count: AtomicUsize,
+	#[cfg(feature = "stats-counts")]
+	realloc_copied: AtomicUsize,
+	/// The number of moves in a lineage at which the churn hook is called.
+	#[cfg(feature = "stats-churn")]
+	churn_threshold: AtomicUsize,
+	#[cfg(feature = "stats-churn")]
+	churn_hook: AtomicPtr<()>,
+	#[cfg(feature = "stats-counts")]
+	failure_count: AtomicUsize,
+	#[cfg(feature = "stats-counts")]
+	inner_failure_count: AtomicUsize,
+	#[cfg(feature = "stats-counts")]
+	exempted: AtomicUsize,
+	#[cfg(feature = "stats-counts")]
+	pressure: pressure::Pressure,
+	#[cfg(feature = "stats-threads")]
+	threads: threads::Threads,
+	#[cfg(feature = "chaos")]
+	failure_threshold: AtomicU64,
+	#[cfg(feature = "chaos")]
+	failure_min_size: AtomicUsize,
+	#[cfg(feature = "chaos")]
+	failure_rng: AtomicU64,
+	/// The index of the tag failures are confined to, or 0 for any.
+	#[cfg(feature = "chaos")]
+	failure_tag: AtomicUsize,
+	/// The identifier of the thread failures are confined to, or 0 for any.
+	#[cfg(feature = "chaos")]
+	failure_thread: AtomicUsize,
+	#[cfg(feature = "chaos")]
+	injected_failures: AtomicUsize,
+	#[cfg(feature = "testing")]
+	testing: testing::State,
+	pressure_callbacks: pressure::Callbacks,
+	#[cfg(feature = "watch")]
+	watches: watch::Table,
+	#[cfg(feature = "broadcast")]
+	broadcast: broadcast::Channel,
+	advisor: pressure::Advisor,
+	inner_retries: AtomicUsize,
+	inner_failure_hook: AtomicPtr<()>,
+	limit_change_hook: AtomicPtr<()>,
+	jumbo_threshold: AtomicUsize,
+	/// Whether a jumbo allocation is gathering budget.
+	jumbo_admitting: AtomicBool,
+	#[cfg(feature = "audit")]
+	audit: audit::Table,
+	#[cfg(feature = "audit")]
+	audit_errors: AtomicUsize,
+	#[cfg(feature = "audit")]
+	audit_hook: AtomicPtr<()>,
+	diagnostics_charged: AtomicBool,
+	/// The wrapped allocator's metadata, as last sampled, or 0 if it hasn't been.
+	#[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+	metadata: AtomicUsize,
+	/// The bytes of diagnostics charged against the limit.
+	diagnostics: AtomicUsize,
+	/// The index of the tag diagnostics are attributed to while charged, or 0 for none.
+	#[cfg(feature = "tags")]
+	diagnostics_tag: AtomicUsize,
+	#[cfg(feature = "tags")]
+	tags: tag::Table,
+	#[cfg(feature = "events")]
+	sink: events::Sink,
+	#[cfg(feature = "recent")]
+	recent: recent::Ring,
+	#[cfg(feature = "recent")]
+	limit_log: recent::LimitLog,
+	#[cfg(feature = "redzone")]
+	redzone: usize,
+	#[cfg(feature = "redzone")]
+	redzone_errors: AtomicUsize,
+	#[cfg(feature = "quarantine")]
+	quarantine: quarantine::Quarantine,
+	#[cfg(feature = "zeroize")]
+	zero_on_free: AtomicBool,
+	#[cfg(feature = "consistency")]
+	consistency: consistency::Checker,
+	#[cfg(feature = "reserve")]
+	reserve: reserve::Arena,
+	#[cfg(feature = "signal")]
+	signals: signal::Detector,
+}
+
+/// The byte redzones are filled with.
+const CANARY: u8 = 0xfd;
+/// The back-off [`Cap::retry_after`] advises after an isolated failure, doubling with each failure in a streak.
+const RETRY_BASE: Duration = Duration::from_millis(1);
+/// The longest back-off [`Cap::retry_after`] advises; failures further apart than this start a new streak.
+const RETRY_MAX: Duration = Duration::from_secs(1);
+
+/// Zero the `len` bytes at `ptr`, in a way the compiler won't elide even though they are about to be freed.
+#[cfg(feature = "zeroize")]
+unsafe fn zeroize(ptr: *mut u8, len: usize) {
+	for i in 0..len {
+		ptr.add(i).write_volatile(0);
+	}
+	atomic::compiler_fence(Ordering::SeqCst);
+}
+/// Return the time in nanoseconds since the Unix epoch, or 0 if the clock is before it.
+pub(crate) fn now() -> u64 {
+	#[allow(clippy::cast_possible_truncation)]
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |since| since.as_nanos() as u64);
+	now
+}
+/// Return the time in milliseconds since the Unix epoch, truncated to a `usize`; differences between two of these are right with `wrapping_sub` as long as they're within 49 days of each other.
+fn now_millis() -> usize {
+	#[allow(clippy::cast_possible_truncation)]
+	let now = (now() / 1_000_000) as usize;
+	now
+}
+
+/// The byte freed memory is filled with by the `poison` feature, so that use-after-free bugs fail fast and recognisably.
+///
+/// Memory is poisoned when it is deallocated and when an allocation shrinks, before it is returned to the wrapped allocator. Nothing is poisoned under Miri or a sanitizer.
+#[cfg(feature = "poison")]
+pub const POISON: u8 = 0xdd;
+
+/// Which allocations failure injection is confined to, as set by [`Cap::set_failure_scope`].
+#[cfg(feature = "chaos")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FailureScope {
+	/// All allocations. This is the default.
+	All,
+	/// Allocations made while the tag is [entered](Tag::enter), so that one subsystem's out-of-memory handling can be tested while the rest of the program is unaffected.
+	#[cfg(feature = "tags")]
+	Tag(Tag),
+	/// Allocations made by the thread that sets the scope.
+	CurrentThread,
 }
 
 impl<H> Cap<H> {
 	/// Create a new allocator, wrapping the supplied allocator and enforcing the specified limit.
 	///
 	/// For no limit, simply set the limit to the theoretical maximum `usize::max_value()`.
+	#[allow(clippy::too_many_lines)] // a line per field
 	pub const fn new(allocator: H, limit: usize) -> Self {
 		Self {
 			allocator,
 			remaining: AtomicUsize::new(limit),
 			limit: AtomicUsize::new(limit),
-			#[cfg(feature = "stats")]
+			soft_limit: AtomicUsize::new(usize::MAX),
+			external: AtomicUsize::new(0),
+			#[cfg(feature = "committed")]
+			reserved: AtomicUsize::new(0),
+			#[cfg(feature = "committed")]
+			committed_limit: AtomicUsize::new(usize::MAX),
+			#[cfg(feature = "overhead")]
+			overhead: AtomicUsize::new(0),
+			#[cfg(feature = "overhead")]
+			live: AtomicUsize::new(0),
+			failure_streak: AtomicUsize::new(0),
+			#[cfg(feature = "breaker")]
+			breaker: breaker::Breaker::new(),
+			last_failure: AtomicUsize::new(0),
+			overdraft: AtomicUsize::new(0),
+			granularity: 1,
+			#[cfg(feature = "cap-group")]
+			group: None,
+			stats: Stats::ALL,
+			#[cfg(feature = "stats-counts")]
 			total_allocated: AtomicUsize::new(0),
-			#[cfg(feature = "stats")]
+			#[cfg(feature = "stats-peaks")]
 			max_allocated: AtomicUsize::new(0),
+			#[cfg(feature = "stats-peaks")]
+			peak: peak::Peak::new(),
+			#[cfg(feature = "stats-peaks")]
+			window_peak: AtomicUsize::new(0),
+			#[cfg(feature = "stats-histograms")]
+			histogram: histogram::Histogram::new(),
+			#[cfg(feature = "stats-counts")]
+			grow_count: AtomicUsize::new(0),
+			#[cfg(feature = "stats-counts")]
+			grow_zeroed_count: AtomicUsize::new(0),
+			#[cfg(feature = "stats-counts")]
+			shrink_count: AtomicUsize::new(0),
+			#[cfg(feature = "stats-counts")]
+			realloc_copied: AtomicUsize::new(0),
+			#[cfg(feature = "stats-churn")]
+			churn_threshold: AtomicUsize::new(usize::MAX),
+			#[cfg(feature = "stats-churn")]
+			churn_hook: AtomicPtr::new(ptr::null_mut()),
+			#[cfg(feature = "stats-counts")]
+			failure_count: AtomicUsize::new(0),
+			#[cfg(feature = "stats-counts")]
+			inner_failure_count: AtomicUsize::new(0),
+			#[cfg(feature = "stats-counts")]
+			exempted: AtomicUsize::new(0),
+			#[cfg(feature = "stats-counts")]
+			pressure: pressure::Pressure::new(),
+			#[cfg(feature = "stats-threads")]
+			threads: threads::Threads::new(),
+			#[cfg(feature = "chaos")]
+			failure_threshold: AtomicU64::new(0),
+			#[cfg(feature = "chaos")]
+			failure_min_size: AtomicUsize::new(0),
+			#[cfg(feature = "chaos")]
+			failure_rng: AtomicU64::new(0),
+			#[cfg(feature = "chaos")]
+			failure_tag: AtomicUsize::new(0),
+			#[cfg(feature = "chaos")]
+			failure_thread: AtomicUsize::new(0),
+			#[cfg(feature = "chaos")]
+			injected_failures: AtomicUsize::new(0),
+			#[cfg(feature = "testing")]
+			testing: testing::State::new(),
+			pressure_callbacks: pressure::Callbacks::new(),
+			#[cfg(feature = "watch")]
+			watches: watch::Table::new(),
+			#[cfg(feature = "broadcast")]
+			broadcast: broadcast::Channel::new(),
+			advisor: pressure::Advisor::new(),
+			inner_retries: AtomicUsize::new(0),
+			inner_failure_hook: AtomicPtr::new(ptr::null_mut()),
+			limit_change_hook: AtomicPtr::new(ptr::null_mut()),
+			jumbo_threshold: AtomicUsize::new(usize::MAX),
+			jumbo_admitting: AtomicBool::new(false),
+			#[cfg(feature = "audit")]
+			audit: audit::Table::new(),
+			#[cfg(feature = "audit")]
+			audit_errors: AtomicUsize::new(0),
+			#[cfg(feature = "audit")]
+			audit_hook: AtomicPtr::new(ptr::null_mut()),
+			diagnostics_charged: AtomicBool::new(false),
+			#[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+			metadata: AtomicUsize::new(0),
+			diagnostics: AtomicUsize::new(0),
+			#[cfg(feature = "tags")]
+			diagnostics_tag: AtomicUsize::new(0),
+			#[cfg(feature = "tags")]
+			tags: tag::Table::new(),
+			#[cfg(feature = "events")]
+			sink: events::Sink::new(),
+			#[cfg(feature = "recent")]
+			recent: recent::Ring::new(),
+			#[cfg(feature = "recent")]
+			limit_log: recent::LimitLog::new(),
+			#[cfg(feature = "redzone")]
+			redzone: 0,
+			#[cfg(feature = "redzone")]
+			redzone_errors: AtomicUsize::new(0),
+			#[cfg(feature = "quarantine")]
+			quarantine: quarantine::Quarantine::new(),
+			#[cfg(feature = "zeroize")]
+			zero_on_free: AtomicBool::new(false),
+			#[cfg(feature = "consistency")]
+			consistency: consistency::Checker::new(),
+			#[cfg(feature = "reserve")]
+			reserve: reserve::Arena::new(),
+			#[cfg(feature = "signal")]
+			signals: signal::Detector::new(),
+		}
+	}
+
+	/// Like [`new`](Self::new), but checking that `limit` is plausible: nonzero, and either `usize::MAX` for no limit or no more than the address space can hold.
+	///
+	/// Used to initialise a static, a misconfigured limit fails the build rather than the first allocation:
+	///
+	/// ```compile_fail
+	/// use std::alloc;
+	/// use cap::Cap;
+	///
+	/// static ALLOCATOR: Cap<alloc::System> = Cap::new_checked(alloc::System, 0);
+	/// ```
+	///
+	/// # Panics
+	///
+	/// Panics if `limit` is implausible.
+	#[must_use]
+	pub const fn new_checked(allocator: H, limit: usize) -> Self {
+		assert!(limit != 0, "cap: the limit must be nonzero");
+		assert!(
+			limit == usize::MAX || limit <= isize::MAX as usize,
+			"cap: the limit exceeds the address space; use usize::MAX for no limit"
+		);
+		Self::new(allocator, limit)
+	}
+
+	/// Count each allocation against the limit in units of `granularity` bytes, rounding its size up, to approximate the size-class rounding of the wrapped allocator without querying it.
+	///
+	/// For example most allocators round small allocations up to a multiple of 16 bytes. Defaults to 1.
+	///
+	/// # Panics
+	///
+	/// Panics if `granularity` is 0.
+	#[must_use]
+	pub const fn with_granularity(mut self, granularity: usize) -> Self {
+		assert!(granularity != 0, "cap: granularity must be nonzero");
+		self.granularity = granularity;
+		self
+	}
+
+	/// Keep only the groups of statistics in `stats`, of those compiled in, so that those not needed cost nothing on the allocation path. Defaults to [`Stats::ALL`].
+	#[must_use]
+	pub const fn with_stats(mut self, stats: Stats) -> Self {
+		self.stats = stats;
+		self
+	}
+
+	/// Return the groups of statistics [kept](Self::with_stats).
+	pub fn stats(&self) -> Stats {
+		self.stats
+	}
+
+	/// Join `group`, so that allocations must also fit within its combined limit, and are included in its totals.
+	#[cfg(feature = "cap-group")]
+	#[must_use]
+	pub const fn with_group(mut self, group: &'static CapGroup) -> Self {
+		self.group = Some(group);
+		self
+	}
+
+	/// Surround each allocation with `bytes` of guard bytes on either side, filled with a canary that is checked when the allocation is deallocated or reallocated.
+	///
+	/// Overwritten guard bytes, as left by buffer overflows and underflows, are reported on stderr along with the allocation's size and tag, and counted by [`redzone_errors`](Self::redzone_errors). The guard bytes count against the limit. They are omitted under Miri or a sanitizer, which detect overflows themselves.
+	#[cfg(feature = "redzone")]
+	#[must_use]
+	pub const fn with_redzone(mut self, bytes: usize) -> Self {
+		self.redzone = bytes;
+		self
+	}
+
+	/// Get the number of allocations found with overwritten [redzones](Self::with_redzone).
+	#[cfg(feature = "redzone")]
+	pub fn redzone_errors(&self) -> usize {
+		self.redzone_errors.load(Ordering::Relaxed)
+	}
+
+	/// Set whether memory is zeroed before it is freed, so that secrets aren't left behind in freed heap memory.
+	///
+	/// Memory is zeroed on deallocation and when an allocation shrinks, with writes the compiler can't elide. Reallocations copy the allocation to a new block and zero the old one themselves, rather than leaving the wrapped allocator to move it.
+	#[cfg(feature = "zeroize")]
+	pub fn set_zero_on_free(&self, zero_on_free: bool) {
+		self.zero_on_free.store(zero_on_free, Ordering::Relaxed);
+	}
+
+	#[cfg(feature = "zeroize")]
+	fn zeroing(&self) -> bool {
+		self.zero_on_free.load(Ordering::Relaxed)
+	}
+
+	/// Return whether resizing a block from `old_l` to `new_l` must move it ourselves, so that the bytes freed can be [scrubbed](Self::scrub) while a failure still leaves the block intact: always if [zeroing on free](Self::set_zero_on_free), and when shrinking if [poisoning](POISON).
+	#[cfg_attr(
+		not(any(feature = "zeroize", feature = "poison")),
+		allow(clippy::unused_self)
+	)]
+	fn scrubs_resize(&self, old_l: Layout, new_l: Layout) -> bool {
+		#[cfg(feature = "zeroize")]
+		if self.zeroing() {
+			return true;
+		}
+		#[cfg(feature = "poison")]
+		if !SANITIZED && new_l.size() < old_l.size() {
+			return true;
 		}
+		let _ = (self, old_l, new_l);
+		false
+	}
+
+	/// Return a reference to the wrapped allocator.
+	pub fn allocator(&self) -> &H {
+		&self.allocator
 	}
 
 	/// Return the number of bytes remaining within the limit.
@@ -87,19 +677,198 @@ impl<H> Cap<H> {
 		self.remaining.load(Ordering::Relaxed)
 	}
 
+	/// Return whether an allocation of `layout` would currently fit within the limit.
+	///
+	/// This accounts for everything the allocation would be [charged](Self::allocated), and with the `overhead`, `committed` and `cap-group` features for the other bounds it would be checked against. It doesn't reserve anything, so a concurrent allocation may still take the space first.
+	pub fn can_allocate(&self, layout: Layout) -> bool {
+		self.would_exceed(layout).is_none()
+	}
+
+	/// Return by how many bytes an allocation of `layout` would currently exceed the limit, or the tightest of the other bounds it would be checked against, or `None` if it would fit.
+	///
+	/// This doesn't reserve anything, so a concurrent allocation may still take the space first.
+	pub fn would_exceed(&self, layout: Layout) -> Option<usize> {
+		let size = self.charged(layout);
+		let remaining = self.remaining();
+		let excess = size.saturating_sub(remaining);
+		#[cfg(feature = "overhead")]
+		let excess = if self.overhead_per_allocation() == 0 {
+			excess
+		} else {
+			excess.max(
+				size.saturating_add(self.estimated_overhead())
+					.saturating_sub(remaining),
+			)
+		};
+		#[cfg(feature = "committed")]
+		let excess = excess.max(
+			self.committed()
+				.saturating_add(size)
+				.saturating_sub(self.committed_limit()),
+		);
+		#[cfg(feature = "cap-group")]
+		let excess = self.group.map_or(excess, |group| {
+			excess.max(size.saturating_sub(group.remaining()))
+		});
+		(excess != 0).then_some(excess)
+	}
+
 	/// Return the limit in bytes.
 	pub fn limit(&self) -> usize {
 		self.limit.load(Ordering::Relaxed)
 	}
 
+	/// Set the number of times an allocation or reallocation is retried when the wrapped allocator fails it despite being within the limit. Defaults to 0.
+	///
+	/// To serve such allocations from another allocator instead, wrap the two in an [`Either`] with [fallback](Either::set_fallback) enabled.
+	pub fn set_inner_retries(&self, retries: usize) {
+		self.inner_retries.store(retries, Ordering::Relaxed);
+	}
+
+	/// Set a function to be called when the wrapped allocator fails an allocation or reallocation of `layout` within the limit, after any [retries](Self::set_inner_retries). If it returns true, for example having released memory held elsewhere, it is tried once more.
+	///
+	/// The function is [called from within the allocator](crate#hooks).
+	pub fn set_inner_failure_hook(&self, hook: fn(Layout) -> bool) {
+		self.inner_failure_hook
+			.store(hook as *mut (), Ordering::Release);
+	}
+
+	/// Call `f` to allocate from the wrapped allocator, retrying as set by [`set_inner_retries`](Self::set_inner_retries) and [`set_inner_failure_hook`](Self::set_inner_failure_hook) if it fails.
+	#[inline]
+	fn retry_inner<T>(&self, layout: Layout, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+		let res = f();
+		if res.is_some() {
+			return res;
+		}
+		self.retry_failed_inner(layout, f)
+	}
+
+	/// The rest of [`retry_inner`](Self::retry_inner), out of line as it is only reached when the wrapped allocator fails.
+	#[cold]
+	#[inline(never)]
+	fn retry_failed_inner<T>(&self, layout: Layout, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+		for _ in 0..self.inner_retries.load(Ordering::Relaxed) {
+			let res = f();
+			if res.is_some() {
+				return res;
+			}
+		}
+		let mut res = None;
+		let hook = self.inner_failure_hook.load(Ordering::Acquire);
+		if !hook.is_null() {
+			let hook = unsafe { mem::transmute::<*mut (), fn(Layout) -> bool>(hook) };
+			if reentrancy::call(|| hook(layout)) == Some(true) {
+				res = f();
+			}
+		}
+		if res.is_none() {
+			rejection::reject(Rejection::Inner);
+			#[cfg(feature = "stats-counts")]
+			let _ = self.inner_failure_count.fetch_add(1, Ordering::Relaxed);
+		}
+		res
+	}
+
+	/// Exempt allocations on this thread from the limit, and any tag limits, until the returned guard is dropped.
+	///
+	/// This is for paths that must not fail, such as panic handling, crash reporting and flushing logs on exit. Exempted allocations are still counted in [`allocated`](Self::allocated); those beyond the limit are repaid from the first memory freed afterwards, and totalled by [`exempted_bytes`](Self::exempted_bytes) so that overuse is visible.
+	///
+	/// ```
+	/// use std::alloc;
+	/// use cap::Cap;
+	///
+	/// #[global_allocator]
+	/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+	///
+	/// fn main() {
+	///     std::panic::set_hook(Box::new(|info| {
+	///         let _exempt = ALLOCATOR.exempt();
+	///         eprintln!("{}", info);
+	///     }));
+	/// }
+	/// ```
+	#[must_use = "the exemption ends when the guard is dropped"]
+	pub fn exempt(&self) -> Exemption<'_> {
+		Exemption::new(ptr::from_ref(self).cast())
+	}
+
+	/// Measure the peak bytes allocated through this cap by this thread, until the returned guard is dropped.
+	#[cfg(feature = "scope")]
+	#[must_use = "the scope ends when the guard is dropped"]
+	pub fn peak_scope(&self) -> PeakScope<'_> {
+		PeakScope::new(ptr::from_ref(self).cast())
+	}
+
+	/// Check the invariants of the accounting, returning the first found to be violated.
+	///
+	/// Allocations concurrent with the check may make it fail spuriously, so call it when the program is quiescent, such as in tests or before exiting. Invariants that are robust to concurrency are also checked as memory is released, as set by [`set_inconsistency_action`](Self::set_inconsistency_action).
+	#[cfg(feature = "consistency")]
+	pub fn check_consistency(&self) -> Result<(), Inconsistency> {
+		let (limit, remaining) = (self.limit(), self.remaining());
+		if remaining > limit {
+			return Err(Inconsistency::RemainingExceedsLimit { remaining, limit });
+		}
+		let (allocated, external) = (self.allocated(), self.external());
+		if external > allocated {
+			return Err(Inconsistency::ExternalExceedsAllocated {
+				external,
+				allocated,
+			});
+		}
+		#[cfg(feature = "stats-counts")]
+		if self.stats.contains(Stats::COUNTS) && self.total_allocated() < allocated - external {
+			return Err(Inconsistency::TotalBelowAllocated {
+				total_allocated: self.total_allocated(),
+				allocated: allocated - external,
+			});
+		}
+		Ok(())
+	}
+
+	/// Set what to do when an [`Inconsistency`] is found as memory is released. Defaults to [`InconsistencyAction::Log`].
+	///
+	/// [`InconsistencyAction::Abort`] suits staging environments, where silent drift in the accounting should be loud, and [`InconsistencyAction::Panic`] debug builds, where a dealloc that would take the bytes remaining above the limit, freeing bytes never charged, is then a panic.
+	#[cfg(feature = "consistency")]
+	pub fn set_inconsistency_action(&self, action: InconsistencyAction) {
+		self.consistency.set_action(action);
+	}
+
+	/// Get the number of inconsistencies found as memory is released.
+	#[cfg(feature = "consistency")]
+	pub fn inconsistencies(&self) -> usize {
+		self.consistency.found()
+	}
+
+	/// Admit allocations of at least `bytes` in two phases when they don't fit and this thread is [waiting for budget](wait_for_budget): first gather the budget, taking all memory as it is freed, then allocate. Defaults to `usize::MAX`, admitting none this way.
+	///
+	/// Otherwise a large allocation waiting for memory races the many small allocations that take it as soon as it is freed, and may never fit. While a jumbo allocation is gathering budget, smaller allocations find none remaining; jumbo allocations themselves queue, gathering one at a time.
+	pub fn set_jumbo_threshold(&self, bytes: usize) {
+		self.jumbo_threshold.store(bytes, Ordering::Relaxed);
+	}
+
 	/// Set the limit in bytes.
 	///
 	/// For no limit, simply set the limit to the theoretical maximum `usize::max_value()`.
 	///
 	/// This method will return `Err` if the specified limit is less than the number of bytes already allocated.
 	pub fn set_limit(&self, limit: usize) -> Result<(), ()> {
-		loop {
+		self.change_limit(limit, None, None)
+	}
+
+	/// Like [`set_limit`](Self::set_limit), recording `reason` alongside the change in the [limit history](Self::limit_history).
+	pub fn set_limit_with_reason(&self, limit: usize, reason: &'static str) -> Result<(), ()> {
+		self.change_limit(limit, None, Some(reason))
+	}
+
+	/// Set the limit to `limit`, failing if it is currently other than `expected`, where given.
+	fn change_limit(
+		&self, limit: usize, expected: Option<usize>, reason: Option<&'static str>,
+	) -> Result<(), ()> {
+		let limit_old = loop {
 			let limit_old = self.limit.load(Ordering::Relaxed);
+			if expected.is_some_and(|expected| expected != limit_old) {
+				return Err(());
+			}
 			if limit < limit_old {
 				if self
 					.remaining
@@ -109,7 +878,7 @@ impl<H> Cap<H> {
 					let _ = self
 						.remaining
 						.fetch_add(limit_old - limit, Ordering::Relaxed);
-					break Err(());
+					return Err(());
 				}
 				if self
 					.limit
@@ -130,349 +899,3758 @@ impl<H> Cap<H> {
 					.remaining
 					.fetch_add(limit - limit_old, Ordering::Relaxed);
 			}
-			break Ok(());
+			break limit_old;
+		};
+		if limit == limit_old {
+			return Ok(());
+		}
+		#[cfg(feature = "recent")]
+		self.limit_log
+			.record(LimitKind::Limit, limit_old, limit, reason);
+		#[cfg(not(feature = "recent"))]
+		let _ = reason;
+		let hook = self.limit_change_hook.load(Ordering::Acquire);
+		if !hook.is_null() {
+			let hook = unsafe { mem::transmute::<*mut (), fn(usize, usize)>(hook) };
+			reentrancy::call_limit_hook(|| hook(limit_old, limit));
 		}
+		Ok(())
 	}
 
-	/// Return the number of bytes allocated. Always less than the limit.
-	pub fn allocated(&self) -> usize {
-		// Make reasonable effort to get valid output
-		loop {
-			let limit_old = self.limit.load(Ordering::SeqCst);
-			let remaining = self.remaining.load(Ordering::SeqCst);
-			let limit = self.limit.load(Ordering::SeqCst);
-			if limit_old == limit && limit >= remaining {
-				break limit - remaining;
-			}
-		}
+	/// Set a function to be called with the old and new limit each time the limit changes, so that caches, pools and admission control can recompute their budgets immediately rather than polling [`limit`](Self::limit).
+	///
+	/// It is called on the thread that changed the limit, after the change, and replaces any function previously set. It is called as [hooks](crate#hooks) are, including for changes made by other hooks, except that changes to the limit it makes itself don't call it again.
+	pub fn on_limit_change(&self, hook: fn(usize, usize)) {
+		self.limit_change_hook
+			.store(hook as *mut (), Ordering::Release);
 	}
 
-	/// Get total amount of allocated memory. This includes already deallocated memory.
-	#[cfg(feature = "stats")]
-	pub fn total_allocated(&self) -> usize {
-		self.total_allocated.load(Ordering::Relaxed)
+	/// Return the soft limit in bytes.
+	pub fn soft_limit(&self) -> usize {
+		self.soft_limit.load(Ordering::Relaxed)
 	}
 
-	/// Get maximum amount of memory that was allocated at any point in time.
-	#[cfg(feature = "stats")]
-	pub fn max_allocated(&self) -> usize {
-		self.max_allocated.load(Ordering::Relaxed)
+	/// Set the soft limit in bytes, which defaults to `usize::MAX`.
+	///
+	/// The soft limit isn't enforced; instead [`over_soft_limit`](Self::over_soft_limit) lets caches and the like shed memory before allocations begin to fail.
+	pub fn set_soft_limit(&self, soft_limit: usize) {
+		self.change_soft_limit(soft_limit, None);
 	}
 
-	fn update_stats(&self, size: usize) {
-		#[cfg(feature = "stats")]
-		{
-			let _ = self.total_allocated.fetch_add(size, Ordering::Relaxed);
-			// If max_allocated is less than currently allocated, then it will be updated to limit - remaining.
-			// Otherwise, it will remain unchanged.
-			let _ = self
-				.max_allocated
-				.fetch_max(self.allocated(), Ordering::Relaxed);
-		}
-		#[cfg(not(feature = "stats"))]
-		{
-			let _ = (self, size);
-		}
+	/// Like [`set_soft_limit`](Self::set_soft_limit), recording `reason` alongside the change in the [limit history](Self::limit_history).
+	pub fn set_soft_limit_with_reason(&self, soft_limit: usize, reason: &'static str) {
+		self.change_soft_limit(soft_limit, Some(reason));
 	}
-}
 
-unsafe impl<H> GlobalAlloc for Cap<H>
-where
-	H: GlobalAlloc,
-{
-	unsafe fn alloc(&self, l: Layout) -> *mut u8 {
-		let size = l.size();
-		let res = if self.remaining.fetch_sub(size, Ordering::Acquire) >= size {
-			self.allocator.alloc(l)
-		} else {
-			ptr::null_mut()
-		};
-		if res.is_null() {
-			let _ = self.remaining.fetch_add(size, Ordering::Release);
-		} else {
-			self.update_stats(size);
+	fn change_soft_limit(&self, soft_limit: usize, reason: Option<&'static str>) {
+		let old = self.soft_limit.swap(soft_limit, Ordering::Relaxed);
+		#[cfg(feature = "recent")]
+		if soft_limit != old {
+			self.limit_log
+				.record(LimitKind::SoftLimit, old, soft_limit, reason);
 		}
-		res
+		#[cfg(not(feature = "recent"))]
+		let _ = (old, reason);
 	}
-	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-		let size = layout.size();
-		self.allocator.dealloc(ptr, layout);
-		let _ = self.remaining.fetch_add(size, Ordering::Release);
+
+	/// Return whether more bytes are allocated than the soft limit.
+	pub fn over_soft_limit(&self) -> bool {
+		self.allocated() > self.soft_limit()
 	}
-	unsafe fn alloc_zeroed(&self, l: Layout) -> *mut u8 {
-		let size = l.size();
-		let res = if self.remaining.fetch_sub(size, Ordering::Acquire) >= size {
-			self.allocator.alloc_zeroed(l)
-		} else {
-			ptr::null_mut()
-		};
-		if res.is_null() {
-			let _ = self.remaining.fetch_add(size, Ordering::Release);
-		} else {
-			self.update_stats(size);
-		}
-		res
+
+	/// Recommend how many more bytes a cache may occupy: `fraction` of the headroom below the soft limit, or the limit if it is lower, less a margin for how much usage has recently varied.
+	///
+	/// The margin is the largest change in the bytes allocated between calls, decaying by an eighth each call, so calling this periodically lets the advice track conditions. A cache should hold at most its current size plus the advice; if it is 0 the cache shouldn't grow, and if usage is over the soft limit it should [shrink](Self::over_soft_limit).
+	pub fn advise_cache_budget(&self, fraction: f64) -> usize {
+		let allocated = self.allocated();
+		let volatility = self.advisor.volatility(allocated);
+		let headroom = self
+			.limit()
+			.min(self.soft_limit())
+			.saturating_sub(allocated)
+			.saturating_sub(volatility);
+		#[allow(
+			clippy::cast_possible_truncation,
+			clippy::cast_precision_loss,
+			clippy::cast_sign_loss
+		)]
+		let budget = (headroom as f64 * fraction.clamp(0.0, 1.0)) as usize;
+		budget
 	}
-	unsafe fn realloc(&self, ptr: *mut u8, old_l: Layout, new_s: usize) -> *mut u8 {
-		let new_l = Layout::from_size_align_unchecked(new_s, old_l.align());
-		let (old_size, new_size) = (old_l.size(), new_l.size());
-		let res = if new_size > old_size {
-			let res = if self
-				.remaining
-				.fetch_sub(new_size - old_size, Ordering::Acquire)
-				>= new_size - old_size
-			{
-				self.allocator.realloc(ptr, old_l, new_s)
-			} else {
-				ptr::null_mut()
-			};
-			if res.is_null() {
-				let _ = self
-					.remaining
-					.fetch_add(new_size - old_size, Ordering::Release);
-			}
-			res
-		} else {
-			let res = self.allocator.realloc(ptr, old_l, new_s);
-			if !res.is_null() {
-				let _ = self
-					.remaining
-					.fetch_add(old_size - new_size, Ordering::Release);
+
+	/// Gradually move the limit to `to` over the duration `over`, on a background thread.
+	///
+	/// The limit is stepped linearly from its current value, letting tests observe how a service degrades as memory tightens rather than hitting a cliff. If a step would put the limit below the number of bytes already allocated, that step is skipped.
+	///
+	/// The returned handle yields the result of setting the final limit.
+	pub fn ramp_limit(
+		&'static self, to: usize, over: Duration,
+	) -> thread::JoinHandle<Result<(), ()>>
+	where
+		H: Sync,
+	{
+		const STEPS: u32 = 100;
+		thread::spawn(move || {
+			let from = self.limit();
+			for step in 1..STEPS {
+				thread::sleep(over / STEPS);
+				let delta =
+					u128::from(step) * (from.max(to) - from.min(to)) as u128 / u128::from(STEPS);
+				#[allow(clippy::cast_possible_truncation)]
+				let limit = if to > from {
+					from + delta as usize
+				} else {
+					from - delta as usize
+				};
+				let _ = self.set_limit_with_reason(limit, "ramp_limit");
 			}
-			// Although this might just deaalocate, I will still update the stats as if it allocates to be on "the safe side"
-			res
-		};
-		if !res.is_null() {
-			self.update_stats(new_size);
-		}
-		res
+			thread::sleep(over / STEPS);
+			self.set_limit_with_reason(to, "ramp_limit")
+		})
 	}
-}
 
-#[cfg(feature = "nightly")]
-unsafe impl<H> Alloc for Cap<H>
-where
-	H: Alloc,
-{
-	unsafe fn alloc(&mut self, l: Layout) -> Result<ptr::NonNull<u8>, AllocErr> {
-		let size = self.allocator.usable_size(&l).1;
-		let res = if self.remaining.fetch_sub(size, Ordering::Acquire) >= size {
-			self.allocator.alloc(l)
-		} else {
-			Err(AllocErr)
-		};
-		if res.is_err() {
-			let _ = self.remaining.fetch_add(size, Ordering::Release);
-		} else {
-			self.update_stats(size);
-		}
-		res
+	/// Register `callback` to be called with each [`PressureEvent`], signalling memory pressure from outside the allocator.
+	///
+	/// Callbacks are called from the background threads that monitor these signals, as [hooks](crate#hooks) are, so may allocate, and register further callbacks.
+	pub fn on_pressure(&self, callback: impl Fn(&PressureEvent) + Send + Sync + 'static) {
+		self.pressure_callbacks.push(Arc::new(callback));
 	}
-	unsafe fn dealloc(&mut self, item: ptr::NonNull<u8>, l: Layout) {
-		let size = self.allocator.usable_size(&l).1;
-		self.allocator.dealloc(item, l);
-		let _ = self.remaining.fetch_add(size, Ordering::Release);
+
+	#[cfg_attr(
+		not(any(
+			all(any(feature = "cgroup", feature = "psi"), target_os = "linux"),
+			all(feature = "low-memory", windows),
+			all(feature = "memory-pressure", target_os = "macos"),
+			all(test, feature = "broadcast")
+		)),
+		allow(dead_code)
+	)]
+	fn notify_pressure(&self, event: PressureEvent) {
+		self.pressure_callbacks.notify(&event);
+		#[cfg(feature = "broadcast")]
+		self.broadcast.publish(Notification::Pressure(event));
 	}
-	fn usable_size(&self, layout: &Layout) -> (usize, usize) {
-		self.allocator.usable_size(layout)
+
+	/// Subscribe to this allocator's [`Notification`]s: signals of memory pressure, crossings reported by [watches](Self::watch) with the `watch` feature, and allocation failures.
+	///
+	/// Each subscriber receives every notification published after it subscribes, so independent components can each react without coordinating a single callback. Notifications arriving while `capacity` are already queued for a subscriber are dropped for it rather than block the allocator. Dropping the receiver unsubscribes.
+	#[cfg(feature = "broadcast")]
+	pub fn subscribe(&self, capacity: usize) -> std::sync::mpsc::Receiver<Notification> {
+		self.broadcast.subscribe(capacity)
 	}
-	unsafe fn realloc(
-		&mut self, ptr: ptr::NonNull<u8>, old_l: Layout, new_s: usize,
-	) -> Result<ptr::NonNull<u8>, AllocErr> {
-		let new_l = Layout::from_size_align_unchecked(new_s, old_l.align());
-		let (old_size, new_size) = (
-			self.allocator.usable_size(&old_l).1,
-			self.allocator.usable_size(&new_l).1,
-		);
-		let res = if new_size > old_size {
-			let res = if self
-				.remaining
-				.fetch_sub(new_size - old_size, Ordering::Acquire)
-				>= new_size - old_size
-			{
-				self.allocator.realloc(ptr, old_l, new_s)
-			} else {
-				Err(AllocErr)
-			};
-			if res.is_err() {
-				let _ = self
-					.remaining
-					.fetch_add(new_size - old_size, Ordering::Release);
+
+	/// Watch for the bytes allocated crossing `threshold` in the direction `crossing`, until the returned [`Watch`] is dropped, reporting crossings no more often than once per `debounce`.
+	///
+	/// This lets components register interest in memory usage as they start, and unregister as they stop. Returns `Err` if 16 watches are already registered.
+	#[cfg(feature = "watch")]
+	pub fn watch(
+		&self, threshold: usize, crossing: Crossing, debounce: Duration,
+	) -> Result<Watch<'_>, ()> {
+		self.watches
+			.register(threshold, crossing, debounce, self.allocated())
+	}
+
+	/// Continuously adjust the limit, every `interval` on a background thread, so that the process's resident set size converges to `target` bytes.
+	///
+	/// The bytes allocated through this allocator are only part of the resident set, alongside fragmentation, allocator metadata, stacks and code. Each step estimates the limit that would put the resident set at `target` were that overhead to stay constant, and moves the limit halfway towards it, never below the bytes already allocated.
+	///
+	/// The thread exits, leaving the limit as it is, once the limit is set by other means, such as by [`set_limit`](Self::set_limit) or another call to this. The resident set size can currently only be measured on Linux; elsewhere the thread exits straight away, leaving the limit unchanged.
+	pub fn control_rss(&'static self, target: usize, interval: Duration) -> thread::JoinHandle<()>
+	where
+		H: Sync,
+	{
+		thread::spawn(move || {
+			let mut set = None;
+			while let Some(rss) = os::rss() {
+				let allocated = self.allocated();
+				let desired = allocated.saturating_add(target).saturating_sub(rss);
+				let limit = set
+					.map_or(desired, |set: usize| set.midpoint(desired))
+					.max(allocated);
+				// Only if it hasn't been set by other means since it was last adjusted.
+				if self.change_limit(limit, set, Some("control_rss")).is_ok() {
+					set = Some(limit);
+				} else if set.is_some_and(|set| self.limit() != set) {
+					break;
+				}
+				thread::sleep(interval);
 			}
-			res
-		} else {
-			let res = self.allocator.realloc(ptr, old_l, new_s);
-			if res.is_ok() {
-				let _ = self
-					.remaining
-					.fetch_add(old_size - new_size, Ordering::Release);
+		})
+	}
+
+	/// Return the number of bytes allocated.
+	///
+	/// Each allocation counts the bytes requested of the wrapped allocator for it, so with the `tags` and `budget` features this includes the prefix recording its tag and the suffix recording its budget. This is at most the limit, unless allocations [exempt](Self::exempt) from it, such as those made by hooks, have overdrawn it. The bytes beyond the limit are the [overdraft](Breakdown::overdraft), which is repaid first as memory is freed.
+	pub fn allocated(&self) -> usize {
+		// Make reasonable effort to get valid output
+		loop {
+			let limit_old = self.limit.load(Ordering::SeqCst);
+			let remaining = self.remaining.load(Ordering::SeqCst);
+			let limit = self.limit.load(Ordering::SeqCst);
+			if limit_old == limit && limit >= remaining {
+				break limit - remaining + self.overdraft.load(Ordering::SeqCst);
 			}
-			res
-		};
-		if res.is_ok() {
-			self.update_stats(new_size);
 		}
-		res
 	}
-	unsafe fn alloc_zeroed(&mut self, l: Layout) -> Result<ptr::NonNull<u8>, AllocErr> {
-		let size = self.allocator.usable_size(&l).1;
-		let res = if self.remaining.fetch_sub(size, Ordering::Acquire) >= size {
-			self.allocator.alloc_zeroed(l)
-		} else {
-			Err(AllocErr)
-		};
-		if res.is_err() {
-			let _ = self.remaining.fetch_add(size, Ordering::Release);
+
+	/// Charge `bytes` of memory allocated by other means, such as `mmap`, against the limit.
+	///
+	/// This method will return `Err` if fewer than `bytes` bytes remain within the limit.
+	pub fn charge(&self, bytes: usize) -> Result<(), ()> {
+		if self.charge_bytes(bytes) {
+			let _ = self.external.fetch_add(bytes, Ordering::Relaxed);
+			Ok(())
 		} else {
-			self.update_stats(size);
+			Err(())
 		}
-		res
 	}
-	unsafe fn grow_in_place(
-		&mut self, ptr: ptr::NonNull<u8>, old_l: Layout, new_s: usize,
-	) -> Result<(), CannotReallocInPlace> {
-		let new_l = Layout::from_size_align(new_s, old_l.align()).unwrap();
-		let (old_size, new_size) = (
-			self.allocator.usable_size(&old_l).1,
-			self.allocator.usable_size(&new_l).1,
-		);
-		let res = if self
-			.remaining
-			.fetch_sub(new_size - old_size, Ordering::Acquire)
-			>= new_size - old_size
-		{
-			self.allocator.grow_in_place(ptr, old_l, new_s)
-		} else {
-			Err(CannotReallocInPlace)
-		};
-		if res.is_err() {
-			let _ = self
-				.remaining
-				.fetch_add(new_size - old_size, Ordering::Release);
-		} else {
-			self.update_stats(new_size - old_size);
+
+	/// Release `bytes` previously charged with [`charge`](Self::charge) or committed by a [`Transaction`].
+	pub fn uncharge(&self, bytes: usize) {
+		let external = self.external.fetch_sub(bytes, Ordering::Relaxed);
+		#[cfg(feature = "consistency")]
+		if external < bytes {
+			self.consistency
+				.report(&Inconsistency::ExternalExceedsAllocated {
+					external: external.wrapping_sub(bytes),
+					allocated: self.allocated(),
+				});
 		}
-		res
+		#[cfg(not(feature = "consistency"))]
+		let _ = external;
+		self.release(bytes);
 	}
-	unsafe fn shrink_in_place(
-		&mut self, ptr: ptr::NonNull<u8>, old_l: Layout, new_s: usize,
-	) -> Result<(), CannotReallocInPlace> {
-		let new_l = Layout::from_size_align(new_s, old_l.align()).unwrap();
-		let (old_size, new_size) = (
-			self.allocator.usable_size(&old_l).1,
-			self.allocator.usable_size(&new_l).1,
-		);
-		let res = self.allocator.shrink_in_place(ptr, old_l, new_s);
-		if res.is_ok() {
-			let _ = self
-				.remaining
-				.fetch_add(old_size - new_size, Ordering::Release);
-		}
-		res
+
+	/// Return the number of bytes currently charged with [`charge`](Self::charge) or committed by a [`Transaction`]. These are included in [`allocated`](Self::allocated).
+	pub fn external(&self) -> usize {
+		self.external.load(Ordering::Relaxed)
 	}
-}
 
-#[cfg(test)]
-mod tests {
-	#[cfg(all(test, feature = "nightly"))]
-	extern crate test;
-	#[cfg(all(test, feature = "nightly"))]
-	use std::collections::TryReserveError;
-	use std::{alloc, thread};
-	#[cfg(all(test, feature = "nightly"))]
-	use test::{TestDescAndFn, TestFn};
+	/// Record a reservation of `bytes` of address space that isn't yet backed by memory, such as an `mmap` mapping that is committed lazily, against the [committed limit](Self::set_committed_limit) but not the limit.
+	///
+	/// This method will return `Err` if it would take the [committed](Self::committed) bytes over the committed limit.
+	#[cfg(feature = "committed")]
+	pub fn reserve(&self, bytes: usize) -> Result<(), ()> {
+		let committed_limit = self.committed_limit();
+		let allocated = self.allocated();
+		self.reserved
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reserved| {
+				reserved
+					.checked_add(bytes)
+					.filter(|&reserved| allocated.saturating_add(reserved) <= committed_limit)
+			})
+			.map(drop)
+			.map_err(drop)
+	}
 
-	use super::Cap;
+	/// Release `bytes` previously recorded with [`reserve`](Self::reserve).
+	#[cfg(feature = "committed")]
+	pub fn unreserve(&self, bytes: usize) {
+		let _ = self.reserved.fetch_sub(bytes, Ordering::Relaxed);
+	}
 
-	#[global_allocator]
+	/// Return the number of bytes currently recorded with [`reserve`](Self::reserve).
+	#[cfg(feature = "committed")]
+	pub fn reserved(&self) -> usize {
+		self.reserved.load(Ordering::Relaxed)
+	}
+
+	/// Return the bytes [allocated](Self::allocated), including those [charged](Self::charge) externally, plus those [reserved](Self::reserve).
+	#[cfg(feature = "committed")]
+	pub fn committed(&self) -> usize {
+		self.allocated().saturating_add(self.reserved())
+	}
+
+	/// Set the maximum number of bytes that may be [committed](Self::committed), so that code that reserves much address space outside of the heap can't exceed the real budget. Allocations, charges and reservations that would exceed it fail.
+	///
+	/// For no limit, simply set the limit to the theoretical maximum `usize::MAX`, which is the default.
+	///
+	/// This method will return `Err`, leaving the committed limit unchanged, if the specified limit is less than the number of bytes already committed.
+	#[cfg(feature = "committed")]
+	pub fn set_committed_limit(&self, limit: usize) -> Result<(), ()> {
+		let limit_old = self.committed_limit.swap(limit, Ordering::Relaxed);
+		if self.committed() > limit {
+			self.committed_limit.store(limit_old, Ordering::Relaxed);
+			return Err(());
+		}
+		Ok(())
+	}
+
+	/// Return the maximum number of bytes that may be [committed](Self::committed).
+	#[cfg(feature = "committed")]
+	pub fn committed_limit(&self) -> usize {
+		self.committed_limit.load(Ordering::Relaxed)
+	}
+
+	/// Set the number of bytes the wrapped allocator is estimated to use beyond each allocation, in headers and rounding up to its size classes. Defaults to 0. See [`calibrate_overhead`](Self::calibrate_overhead) to measure it.
+	///
+	/// The estimated overhead of the live allocations is kept free within the limit, so that it bounds what the allocator actually uses rather than just what was requested of it.
+	#[cfg(feature = "overhead")]
+	pub fn set_overhead(&self, per_allocation: usize) {
+		self.overhead.store(per_allocation, Ordering::Relaxed);
+	}
+
+	/// Return the number of bytes the wrapped allocator is estimated to use beyond each allocation.
+	#[cfg(feature = "overhead")]
+	pub fn overhead_per_allocation(&self) -> usize {
+		self.overhead.load(Ordering::Relaxed)
+	}
+
+	/// Return the estimated overhead of the live allocations: the [per-allocation overhead](Self::set_overhead) times the number of them.
+	#[cfg(feature = "overhead")]
+	pub fn estimated_overhead(&self) -> usize {
+		self.live_allocations()
+			.saturating_mul(self.overhead_per_allocation())
+	}
+
+	/// Return the number of allocations currently live.
+	#[cfg(feature = "overhead")]
+	pub fn live_allocations(&self) -> usize {
+		self.live.load(Ordering::Relaxed)
+	}
+
+	/// Begin a [`Transaction`], charging `estimate` bytes against the limit until it is committed or rolled back.
+	///
+	/// This method will return `Err` if fewer than `estimate` bytes remain within the limit.
+	pub fn transaction(&self, estimate: usize) -> Result<Transaction<'_, H>, ()> {
+		if self.charge_bytes(estimate) {
+			Ok(Transaction::new(self, estimate))
+		} else {
+			Err(())
+		}
+	}
+
+	/// Return the number of bytes currently allocated while attributed to `tag`.
+	#[cfg(feature = "tags")]
+	pub fn tag_allocated(&self, tag: Tag) -> usize {
+		self.tags.slots[tag.index()]
+			.allocated
+			.load(Ordering::Relaxed)
+	}
+
+	/// Return the most bytes that have been allocated while attributed to `tag` at once, as also reported, with when it was reached, by [`stats_by_tag`](Self::stats_by_tag).
+	#[cfg(all(feature = "tags", feature = "stats-tags"))]
+	pub fn tag_peak(&self, tag: Tag) -> usize {
+		self.tags.slots[tag.index()].peak.load(Ordering::Relaxed)
+	}
+
+	/// Set the maximum number of bytes that may be allocated while attributed to `tag`, independently of the overall limit.
+	///
+	/// For no limit, simply set the limit to the theoretical maximum `usize::MAX`, which is the default.
+	///
+	/// This method will return `Err`, leaving the tag's limit unchanged, if the specified limit is less than the number of bytes already allocated while attributed to `tag`.
+	#[cfg(feature = "tags")]
+	pub fn set_tag_limit(&self, tag: Tag, limit: usize) -> Result<(), ()> {
+		let slot = &self.tags.slots[tag.index()];
+		let limit_old = slot.limit.swap(limit, Ordering::Relaxed);
+		if slot.allocated.load(Ordering::Relaxed) > limit {
+			slot.limit.store(limit_old, Ordering::Relaxed);
+			return Err(());
+		}
+		Ok(())
+	}
+
+	/// Return the maximum number of bytes that may be allocated while attributed to `tag`.
+	#[cfg(feature = "tags")]
+	pub fn tag_limit(&self, tag: Tag) -> usize {
+		self.tags.slots[tag.index()].limit.load(Ordering::Relaxed)
+	}
+
+	/// Move the allocation at `ptr` from the tag it was allocated under to `to`, or to no tag, so that it stays attributed to `to` until it is freed.
+	///
+	/// This suits pipelines in which buffers are produced under one subsystem and owned long-term by another. This method will return `Err`, leaving the allocation where it was, if it would exceed the limit of `to`.
+	///
+	/// # Safety
+	///
+	/// `ptr` must have been allocated by this cap with `layout`, and not yet freed.
+	#[cfg(feature = "tags")]
+	pub unsafe fn transfer_tag(
+		&self, ptr: *mut u8, layout: Layout, to: Option<Tag>,
+	) -> Result<(), ()> {
+		let size = self.charged(layout);
+		let (base, from) = tag::detach(ptr.sub(self.redzones(layout).0), layout);
+		let to = to.map_or(0, Tag::index);
+		if to == from {
+			return Ok(());
+		}
+		if to != 0 && !self.charge_tag(size, to) {
+			return Err(());
+		}
+		if from != 0 {
+			let _ = self.tags.slots[from]
+				.allocated
+				.fetch_sub(size, Ordering::Relaxed);
+		}
+		let _ = tag::attach(base, layout, to);
+		#[cfg(feature = "audit")]
+		self.audit.retag(ptr, to);
+		Ok(())
+	}
+
+	/// Move the allocation at `ptr` from the [budget](Budget) it was allocated under to `to`, or to no budget, so that it is charged to `to` until it is freed.
+	///
+	/// This method will return `Err`, leaving the allocation where it was, if it would exceed the limit of `to` or one of its ancestors.
+	///
+	/// # Safety
+	///
+	/// `ptr` must have been allocated by this cap with `layout`, and not yet freed.
+	#[cfg(feature = "budget")]
+	pub unsafe fn transfer_budget(
+		&self, ptr: *mut u8, layout: Layout, to: Option<&Arc<Budget>>,
+	) -> Result<(), ()> {
+		let base = ptr.sub(self.redzones(layout).0);
+		#[cfg(feature = "tags")]
+		let base = tag::detach(base, layout).0;
+		let inner_layout = self.inner_layout(layout).ok_or(())?;
+		budget::transfer(
+			base,
+			inner_layout,
+			self.charged(layout),
+			to.map_or(ptr::null(), Arc::as_ptr),
+		)
+	}
+
+	/// Return statistics for each registered tag.
+	#[cfg(feature = "tags")]
+	pub fn stats_by_tag(&self) -> Vec<TagStats> {
+		self.tags.stats()
+	}
+
+	/// Return the registered tags as a tree, each under the tag that was current when it was first [entered](Tag::enter), so that reports mirror the program's structure.
+	///
+	/// Tags that haven't been entered, or whose nesting is cyclic, appear at the top level.
+	#[cfg(feature = "tags")]
+	pub fn tag_tree(&self) -> Vec<TagNode> {
+		self.tags.tree()
+	}
+
+	/// Write the 10 tags with the most bytes allocated, largest first and omitting those with none, into `buf` as lines of `name: bytes`, returning the part of `buf` written.
+	///
+	/// This doesn't allocate, so can be called from an [`EventSink`](crate::EventSink) when it receives a [`Failure`](crate::EventKind::Failure) event, which is when knowing what is holding the memory is most valuable. The report is truncated if `buf` is too small; 1 KiB usually suffices.
+	#[cfg(feature = "tags")]
+	pub fn top_tags_report<'a>(&self, buf: &'a mut [u8]) -> &'a str {
+		self.tags.report(10, buf)
+	}
+
+	/// Set the fraction of the limit above which the cap is considered under pressure, and groups are held to their shares. Defaults to `0.875`.
+	#[cfg(feature = "tags")]
+	pub fn set_group_pressure(&self, fraction: f64) {
+		#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+		let pressure = (fraction.clamp(0.0, 1.0) * f64::from(tag::PRESSURE_SCALE)) as usize;
+		self.tags.pressure.store(pressure, Ordering::Relaxed);
+	}
+
+	/// Register a [`Group`] named `name` with the given weight, which receives a proportional share of the limit under pressure until dropped.
+	///
+	/// # Panics
+	///
+	/// Panics if `weight` is 0, or if a group of this name is already registered with this allocator.
+	#[cfg(feature = "tags")]
+	pub fn group(&self, name: &'static str, weight: usize) -> Group<'_, H> {
+		Group::new(self, Tag::new(name), weight)
+	}
+
+	/// Get total amount of allocated memory. This includes already deallocated memory.
+	#[cfg(feature = "stats-counts")]
+	pub fn total_allocated(&self) -> usize {
+		self.total_allocated.load(Ordering::Relaxed)
+	}
+
+	/// Get maximum amount of memory that was allocated at any point in time.
+	#[cfg(feature = "stats-peaks")]
+	pub fn max_allocated(&self) -> usize {
+		self.max_allocated.load(Ordering::Relaxed)
+	}
+
+	/// Get the maximum amount of memory that was allocated at any point since this method was last called, or since creation.
+	///
+	/// Unlike [`max_allocated`](Self::max_allocated), which saturates after the first spike, this gives a peak per monitoring interval when called on each scrape. Each call starts a new window, so there should only be one such caller.
+	#[cfg(feature = "stats-peaks")]
+	pub fn peak_since_last_call(&self) -> usize {
+		let allocated = self.allocated();
+		self.window_peak
+			.swap(allocated, Ordering::Relaxed)
+			.max(allocated)
+	}
+
+	/// Get when, and in what context, the [maximum amount of memory](Self::max_allocated) was allocated, or `None` if nothing has been.
+	///
+	/// The fields are updated independently, so if peaks are reached concurrently they may describe different ones.
+	#[cfg(feature = "stats-peaks")]
+	pub fn peak_info(&self) -> Option<PeakInfo> {
+		Some(self.max_allocated())
+			.filter(|&allocated| allocated != 0)
+			.map(|allocated| self.peak.info(allocated))
+	}
+
+	/// Return a histogram of the sizes of allocations and reallocations: for each power of two that sizes have rounded up to, that power of two and how many have, in increasing order.
+	///
+	/// Sizes over `usize::MAX / 2 + 1` are counted with `usize::MAX`.
+	#[cfg(feature = "stats-histograms")]
+	pub fn size_histogram(&self) -> Vec<(usize, usize)> {
+		self.histogram.buckets()
+	}
+
+	/// Register the calling thread as `name`, so that its own peak is tracked and reported by [`thread_stats`](Self::thread_stats).
+	///
+	/// Up to 64 threads can be registered with each allocator, for the lifetime of the allocator; this method will return `Err` if that many already have been. It suits long-lived workers, to size per-thread arenas and find the worst offender.
+	#[cfg(feature = "stats-threads")]
+	pub fn register_thread(&self, name: &'static str) -> Result<(), ()> {
+		self.threads.register(name)
+	}
+
+	/// Return statistics for each [registered](Self::register_thread) thread, including its peak live bytes.
+	#[cfg(feature = "stats-threads")]
+	pub fn thread_stats(&self) -> Vec<ThreadStats> {
+		self.threads.stats()
+	}
+
+	/// Get the number of reallocations that have grown an allocation, excluding those that zeroed the new memory.
+	#[cfg(feature = "stats-counts")]
+	pub fn grow_count(&self) -> usize {
+		self.grow_count.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of reallocations that have grown an allocation and zeroed the new memory.
+	///
+	/// These are only performed through the [`Allocator`](std::alloc::Allocator) API.
+	#[cfg(feature = "stats-counts")]
+	pub fn grow_zeroed_count(&self) -> usize {
+		self.grow_zeroed_count.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of reallocations that have shrunk an allocation, or left its size unchanged.
+	#[cfg(feature = "stats-counts")]
+	pub fn shrink_count(&self) -> usize {
+		self.shrink_count.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of bytes copied by reallocations that moved an allocation to a new block.
+	///
+	/// Every move copies the allocation's contents, a cost that doesn't show up in the bytes allocated.
+	#[cfg(feature = "stats-counts")]
+	pub fn realloc_copied_bytes(&self) -> usize {
+		self.realloc_copied.load(Ordering::Relaxed)
+	}
+
+	/// Set a function to be called when an allocation has been moved `copies` times by grows on the same thread, such as a container grown an element at a time without reserving capacity.
+	///
+	/// Lineages are followed heuristically: a moving grow continues the lineage if it moves the allocation this thread's previous moving grow left behind. The function is called once per lineage, [from within the allocator](crate#hooks).
+	#[cfg(feature = "stats-churn")]
+	pub fn set_churn_hook(&self, copies: usize, hook: fn(&Churn)) {
+		self.churn_threshold.store(copies, Ordering::Relaxed);
+		self.churn_hook.store(hook as *mut (), Ordering::Release);
+	}
+
+	/// Get the number of allocations and reallocations that have failed, whether because of the limit, failure injection or the wrapped allocator.
+	#[cfg(feature = "stats-counts")]
+	pub fn failure_count(&self) -> usize {
+		self.failure_count.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of allocations and reallocations within the limit that the wrapped allocator has failed, after any [retries](Self::set_inner_retries).
+	///
+	/// The rest of the [`failure_count`](Self::failure_count) were rejected by the cap itself.
+	#[cfg(feature = "stats-counts")]
+	pub fn inner_failure_count(&self) -> usize {
+		self.inner_failure_count.load(Ordering::Relaxed)
+	}
+
+	/// Advise how long a caller whose allocation was just refused should wait before retrying, or `None` if it needn't.
+	///
+	/// The back-off starts at a millisecond after an isolated failure and doubles with each further failure in quick succession, up to a second, and is doubled while usage is over the [soft limit](Self::set_soft_limit). It counts down from the most recent failure, so retry loops across threads need not coordinate: each waits out what's left of it.
+	///
+	/// ```
+	/// use std::{alloc, thread};
+	/// use cap::Cap;
+	///
+	/// #[global_allocator]
+	/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+	///
+	/// fn main() {
+	///     let mut buffer = Vec::<u8>::new();
+	///     while buffer.try_reserve(1024).is_err() {
+	///         if let Some(delay) = ALLOCATOR.retry_after() {
+	///             thread::sleep(delay);
+	///         }
+	///     }
+	/// }
+	/// ```
+	pub fn retry_after(&self) -> Option<Duration> {
+		let streak = self.failure_streak.load(Ordering::Relaxed);
+		if streak == 0 {
+			return None;
+		}
+		#[allow(clippy::cast_possible_truncation)]
+		let exponent = (streak - 1).min(10) as u32;
+		let mut backoff = (RETRY_BASE * (1 << exponent)).min(RETRY_MAX);
+		if self.over_soft_limit() {
+			backoff = (backoff * 2).min(RETRY_MAX);
+		}
+		let elapsed = now_millis().wrapping_sub(self.last_failure.load(Ordering::Relaxed));
+		backoff
+			.checked_sub(Duration::from_millis(elapsed as u64))
+			.filter(|remaining| !remaining.is_zero())
+	}
+
+	/// Open a circuit breaker once `failures` consecutive allocations have failed within `window`, so that for the following `cool_down` [best-effort](Self::best_effort) allocations fail immediately while the rest are let through.
+	///
+	/// This sheds optional work during a storm of rejections right at the limit, rather than letting it thrash the system. Setting `failures` to 0, the default, disables the breaker.
+	#[cfg(feature = "breaker")]
+	pub fn set_circuit_breaker(&self, failures: usize, window: Duration, cool_down: Duration) {
+		self.breaker.set(failures, window, cool_down);
+	}
+
+	/// Return whether the [circuit breaker](Self::set_circuit_breaker) is open, shedding best-effort allocations.
+	#[cfg(feature = "breaker")]
+	pub fn circuit_open(&self) -> bool {
+		self.breaker.is_open(now())
+	}
+
+	/// Mark allocations through this cap on this thread as best-effort, until the returned guard is dropped, so that they are shed while the [circuit breaker](Self::set_circuit_breaker) is open.
+	///
+	/// ```
+	/// use std::{alloc, time::Duration};
+	/// use cap::Cap;
+	///
+	/// #[global_allocator]
+	/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+	///
+	/// fn main() {
+	///     ALLOCATOR.set_circuit_breaker(100, Duration::from_secs(1), Duration::from_secs(5));
+	///     let thumbnail = {
+	///         let _best_effort = ALLOCATOR.best_effort();
+	///         let mut thumbnail = Vec::<u8>::new();
+	///         thumbnail.try_reserve(64 * 1024).ok().map(|()| thumbnail)
+	///     };
+	/// #   drop(thumbnail);
+	/// }
+	/// ```
+	#[cfg(feature = "breaker")]
+	#[must_use = "allocations are critical again when the guard is dropped"]
+	pub fn best_effort(&self) -> BestEffort<'_> {
+		BestEffort::new(ptr::from_ref(self).cast())
+	}
+
+	/// Return whether an allocation on this thread is to be shed by the [circuit breaker](Self::set_circuit_breaker).
+	#[inline]
+	fn shed(&self) -> bool {
+		#[cfg(feature = "breaker")]
+		{
+			self.breaker.is_enabled() && self.shed_enabled()
+		}
+		#[cfg(not(feature = "breaker"))]
+		{
+			let _ = self;
+			false
+		}
+	}
+
+	/// [`shed`](Self::shed) once the breaker is enabled, out of line from the allocation path.
+	#[cfg(feature = "breaker")]
+	#[inline(never)]
+	fn shed_enabled(&self) -> bool {
+		let shed =
+			breaker::is_best_effort(ptr::from_ref(self).cast()) && self.breaker.is_open(now());
+		if shed {
+			rejection::reject(Rejection::Shed);
+		}
+		shed
+	}
+
+	/// Get the total number of bytes allocated beyond the limit, or a tag's limit, while [exempt](Self::exempt).
+	#[cfg(feature = "stats-counts")]
+	pub fn exempted_bytes(&self) -> usize {
+		self.exempted.load(Ordering::Relaxed)
+	}
+
+	/// Get a score from 0 to 100 of how much memory pressure the allocator is under, for application code to branch on when deciding whether to degrade.
+	///
+	/// This is a weighted average of three components, each from 0 to 1:
+	///
+	/// * usage: the fraction of the limit that is allocated;
+	/// * rate: the fraction of the headroom remaining at the previous call that has since been consumed;
+	/// * failures: 1 if any allocation has failed since the previous call, else 0.
+	///
+	/// As the latter two are relative to the previous call, this should be called periodically by one caller. The weights can be tuned with [`set_pressure_weights`](Self::set_pressure_weights).
+	#[cfg(feature = "stats-counts")]
+	pub fn pressure(&self) -> u8 {
+		self.pressure
+			.score(self.allocated(), self.limit(), self.failure_count())
+	}
+
+	/// Set the weights of the usage, rate and failure components of [`pressure`](Self::pressure). They default to 60, 20 and 20 respectively.
+	#[cfg(feature = "stats-counts")]
+	pub fn set_pressure_weights(&self, usage: u8, rate: u8, failures: u8) {
+		self.pressure.set_weights(usage, rate, failures);
+	}
+
+	/// Make allocations fail with the given probability, to exercise out-of-memory handling.
+	///
+	/// Only allocations (and reallocations) of at least `min_size` bytes are candidates for failure. A probability of `0.0` disables failure injection.
+	#[cfg(feature = "chaos")]
+	pub fn set_failure_probability(&self, probability: f64, min_size: usize) {
+		#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+		let threshold = (probability.clamp(0.0, 1.0) * 2_f64.powi(64)) as u64;
+		self.failure_min_size.store(min_size, Ordering::Relaxed);
+		self.failure_threshold.store(threshold, Ordering::Relaxed);
+	}
+
+	/// Confine [failure injection](Self::set_failure_probability) to the allocations in `scope`. Allocations outside it are never failed.
+	#[cfg(feature = "chaos")]
+	pub fn set_failure_scope(&self, scope: FailureScope) {
+		let (tag, thread) = match scope {
+			FailureScope::All => (0, 0),
+			#[cfg(feature = "tags")]
+			FailureScope::Tag(tag) => (tag.index(), 0),
+			FailureScope::CurrentThread => (0, events::thread_id()),
+		};
+		self.failure_tag.store(tag, Ordering::Relaxed);
+		self.failure_thread.store(thread, Ordering::Relaxed);
+	}
+
+	/// Get the number of allocations that have been failed by failure injection.
+	#[cfg(feature = "chaos")]
+	pub fn injected_failures(&self) -> usize {
+		self.injected_failures.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of mismatched or unknown layouts passed to `dealloc` or `realloc` that have been found by the audit mode.
+	#[cfg(feature = "audit")]
+	pub fn audit_errors(&self) -> usize {
+		self.audit_errors.load(Ordering::Relaxed)
+	}
+
+	/// Set a function to be called with each error found by the audit mode, instead of printing it to stderr.
+	///
+	/// Errors printed to stderr are followed by a backtrace if they are enabled with `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`, identifying the code passing the wrong layout. Errors found while a hook is running are counted, but neither printed nor passed to the function.
+	///
+	/// The function is [called from within the allocator](crate#hooks).
+	#[cfg(feature = "audit")]
+	pub fn set_audit_hook(&self, hook: fn(&AuditError)) {
+		self.audit_hook.store(hook as *mut (), Ordering::Release);
+	}
+
+	/// Get up to `n` of the live allocations tracked by the audit mode, oldest first.
+	///
+	/// Long-lived allocations that outlast the phase of the program that made them are typically exactly the leaked ones.
+	#[cfg(feature = "audit")]
+	pub fn oldest_allocations(&self, n: usize) -> Vec<LiveAllocation> {
+		self.audit.oldest(n)
+	}
+
+	/// Get a checkpoint in the sequence of allocations tracked by the audit mode, to be passed to [`heap_diff`](Self::heap_diff).
+	#[cfg(feature = "audit")]
+	pub fn checkpoint(&self) -> Checkpoint {
+		self.audit.checkpoint()
+	}
+
+	/// Get the allocations made between the checkpoints `from` and `to` that are still live, grouped by tag and largest first.
+	///
+	/// Taking a checkpoint before and after handling a request, and later diffing them once the request should have been cleaned up after, finds what it leaked.
+	///
+	/// ```
+	/// use std::alloc;
+	/// use cap::Cap;
+	///
+	/// #[global_allocator]
+	/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+	///
+	/// fn main() {
+	///     let before = ALLOCATOR.checkpoint();
+	///     let leaked = Box::leak(Box::new([0u8; 100]));
+	///     let freed = vec![0u8; 1000];
+	///     drop(freed);
+	///     let after = ALLOCATOR.checkpoint();
+	///     let diff = ALLOCATOR.heap_diff(before, after);
+	///     assert_eq!((diff[0].count, diff[0].bytes), (1, 100));
+	/// #   let _ = leaked;
+	/// }
+	/// ```
+	#[cfg(feature = "audit")]
+	pub fn heap_diff(&self, from: Checkpoint, to: Checkpoint) -> Vec<DiffGroup> {
+		self.audit.diff(from, to)
+	}
+
+	/// Return the bytes of heap occupied by this allocator's own diagnostics, such as the audit mode's table of live allocations, which is allocated directly from the system rather than through the wrapped allocator.
+	///
+	/// Fixed-size state, such as the tables of the `tags` and `recent` features, is held inline in the `Cap` and not included.
+	pub fn diagnostics_allocated(&self) -> usize {
+		#[cfg(feature = "audit")]
+		let bytes = self.audit.bytes();
+		#[cfg(not(feature = "audit"))]
+		let bytes = 0;
+		bytes
+	}
+
+	/// Set whether the memory occupied by diagnostics is charged against the limit, as though [`charge`](Self::charge)d, so that they can't silently eat the budget they are meant to protect. Defaults to false.
+	///
+	/// While charged, diagnostics that would take the allocator over its limit degrade instead: the audit mode stops tracking further allocations. With the `tags` feature, the memory is attributed to the tag `cap::diagnostics`.
+	///
+	/// Returns `Err` if the memory already occupied doesn't fit within the limit.
+	///
+	/// # Panics
+	///
+	/// With the `tags` feature, panics if diagnostics are being charged and 63 distinct tags have already been registered.
+	pub fn set_diagnostics_charged(&self, charged: bool) -> Result<(), ()> {
+		if charged {
+			if self.diagnostics_charged.load(Ordering::Relaxed) {
+				return Ok(());
+			}
+			let bytes = self.diagnostics_allocated();
+			self.charge(bytes)?;
+			let _ = self.diagnostics.fetch_add(bytes, Ordering::Relaxed);
+			#[cfg(feature = "tags")]
+			{
+				let tag = Tag::new("cap::diagnostics").index();
+				self.add_to_tag(tag, bytes);
+				self.diagnostics_tag.store(tag, Ordering::Relaxed);
+			}
+			self.diagnostics_charged.store(true, Ordering::Release);
+		} else if self.diagnostics_charged.swap(false, Ordering::Acquire) {
+			let bytes = self.diagnostics.swap(0, Ordering::Relaxed);
+			self.uncharge(bytes);
+			#[cfg(feature = "tags")]
+			{
+				let tag = self.diagnostics_tag.swap(0, Ordering::Relaxed);
+				let _ = self.tags.slots[tag]
+					.allocated
+					.fetch_sub(bytes, Ordering::Relaxed);
+			}
+		}
+		Ok(())
+	}
+
+	/// Return whether the memory occupied by diagnostics is [charged](Self::set_diagnostics_charged) against the limit.
+	pub fn diagnostics_charged(&self) -> bool {
+		self.diagnostics_charged.load(Ordering::Relaxed)
+	}
+
+	/// Return whether diagnostics may grow by `bytes`, charging them if diagnostics are charged against the limit.
+	#[cfg(feature = "audit")]
+	fn admit_diagnostics(&self, bytes: usize) -> bool {
+		if !self.diagnostics_charged.load(Ordering::Acquire) {
+			return true;
+		}
+		if self.charge(bytes).is_err() {
+			return false;
+		}
+		let _ = self.diagnostics.fetch_add(bytes, Ordering::Relaxed);
+		#[cfg(feature = "tags")]
+		{
+			let tag = self.diagnostics_tag.load(Ordering::Relaxed);
+			self.add_to_tag(tag, bytes);
+		}
+		true
+	}
+
+	/// Register `sink` to receive allocation events, sampling one in every `every` allocations, reallocations and deallocations. Failures are always delivered.
+	///
+	/// Only one sink can be registered; this method will return `Err` if one already has been.
+	#[cfg(feature = "events")]
+	pub fn set_event_sink(&self, sink: &'static dyn EventSink, every: usize) -> Result<(), ()> {
+		self.sink.set(sink, every)
+	}
+
+	/// Record successful allocations and reallocations of at least `bytes` bytes in the [recent events](Self::recent_events). Defaults to `usize::MAX`, recording none.
+	#[cfg(feature = "recent")]
+	pub fn set_large_threshold(&self, bytes: usize) {
+		self.recent.large_threshold.store(bytes, Ordering::Relaxed);
+	}
+
+	/// Call `f` with each of the last 64 significant events, oldest first: failures, [large allocations](Self::set_large_threshold) and soft limit crossings.
+	///
+	/// This neither allocates nor takes locks, so can be called from a crash handler to see the allocator's recent history.
+	#[cfg(feature = "recent")]
+	pub fn recent_events(&self, f: impl FnMut(RecentEvent)) {
+		self.recent.for_each(f);
+	}
+
+	/// Return the last 32 changes to the limit and soft limit, oldest first, so that an incident review can see when and why they changed.
+	///
+	/// Changes made by [`ramp_limit`](Self::ramp_limit), [`control_rss`](Self::control_rss) and the like are included, with reasons naming them.
+	#[cfg(feature = "recent")]
+	pub fn limit_history(&self) -> Vec<LimitChange> {
+		self.limit_log.history()
+	}
+
+	#[cfg(feature = "recent")]
+	fn record_recent(&self, kind: EventKind, size: usize) {
+		let allocated = self.allocated();
+		match kind {
+			EventKind::Failure => self
+				.recent
+				.record(RecentEventKind::Failure, size, allocated),
+			EventKind::Alloc | EventKind::Realloc { .. }
+				if size >= self.recent.large_threshold.load(Ordering::Relaxed) =>
+			{
+				self.recent.record(RecentEventKind::Large, size, allocated);
+			}
+			_ => (),
+		}
+		let over = allocated > self.soft_limit();
+		let flag = &self.recent.over_soft_limit;
+		if flag.load(Ordering::Relaxed) != over && flag.swap(over, Ordering::Relaxed) != over {
+			let kind = if over {
+				RecentEventKind::SoftLimitExceeded
+			} else {
+				RecentEventKind::SoftLimitRestored
+			};
+			self.recent.record(kind, 0, allocated);
+		}
+	}
+
+	fn event(&self, kind: EventKind, layout: Layout, tag: usize) {
+		#[cfg(feature = "signal")]
+		if kind != EventKind::Dealloc {
+			self.check_signal(layout);
+		}
+		#[cfg(feature = "overhead")]
+		match kind {
+			EventKind::Alloc => {
+				let _ = self.live.fetch_add(1, Ordering::Relaxed);
+			}
+			EventKind::Dealloc => {
+				let _ = self.live.fetch_sub(1, Ordering::Relaxed);
+			}
+			EventKind::Realloc { .. } | EventKind::Failure => (),
+		}
+		if kind == EventKind::Failure {
+			#[cfg(feature = "broadcast")]
+			self.broadcast.publish_failure(layout);
+		} else {
+			#[cfg(all(feature = "watch", feature = "broadcast"))]
+			self.watches.check(
+				|| self.allocated(),
+				|event| {
+					self.broadcast.publish(Notification::Watch(event));
+				},
+			);
+			#[cfg(all(feature = "watch", not(feature = "broadcast")))]
+			self.watches.check(|| self.allocated(), |_| ());
+		}
+		#[cfg(feature = "sites")]
+		match kind {
+			EventKind::Alloc => site::record(true, layout.size()),
+			EventKind::Realloc { old_size } => {
+				site::record(false, layout.size().saturating_sub(old_size));
+			}
+			EventKind::Dealloc | EventKind::Failure => (),
+		}
+		#[cfg(feature = "recent")]
+		self.record_recent(kind, layout.size());
+		#[cfg(feature = "stats-threads")]
+		if self.stats.contains(Stats::THREADS) {
+			match kind {
+				EventKind::Alloc => self.threads.record(0, layout.size()),
+				EventKind::Dealloc => self.threads.record(layout.size(), 0),
+				EventKind::Realloc { old_size } => self.threads.record(old_size, layout.size()),
+				EventKind::Failure => (),
+			}
+		}
+		#[cfg(feature = "scope")]
+		{
+			let (freed, allocated) = match kind {
+				EventKind::Alloc => (0, layout.size()),
+				EventKind::Dealloc => (layout.size(), 0),
+				EventKind::Realloc { old_size } => (old_size, layout.size()),
+				EventKind::Failure => (0, 0),
+			};
+			scope::record(freed, allocated);
+			scope::record_peak(ptr::from_ref(self).cast(), freed, allocated);
+		}
+		#[cfg(feature = "events")]
+		self.sink.emit(kind, || Event {
+			kind,
+			size: layout.size(),
+			align: layout.align(),
+			#[cfg(feature = "tags")]
+			tag: Tag::from_index(tag),
+			thread: events::thread_id(),
+		});
+		#[cfg(all(feature = "events", not(feature = "tags")))]
+		let _ = tag;
+		#[cfg(not(feature = "events"))]
+		{
+			let _ = (self, kind, layout, tag);
+		}
+	}
+
+	#[cfg(feature = "audit")]
+	fn audit_error(&self, error: &AuditError) {
+		let _ = self.audit_errors.fetch_add(1, Ordering::Relaxed);
+		let hook = self.audit_hook.load(Ordering::Acquire);
+		// The default report is run as a hook too, as capturing the backtrace and printing allocate.
+		let _ = reentrancy::call(|| {
+			if hook.is_null() {
+				// Only captured if enabled by `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`, to identify the offending crate.
+				let backtrace = Backtrace::capture();
+				if backtrace.status() == BacktraceStatus::Captured {
+					eprintln!("cap: {error}\n{backtrace}");
+				} else {
+					eprintln!("cap: {error}");
+				}
+			} else {
+				let hook = unsafe { mem::transmute::<*mut (), fn(&AuditError)>(hook) };
+				hook(error);
+			}
+		});
+	}
+
+	fn audit_alloc(&self, ptr: *mut u8, layout: Layout, tag: usize) {
+		#[cfg(feature = "audit")]
+		if !ptr.is_null() {
+			self.audit
+				.insert(ptr, layout, tag, &|bytes| self.admit_diagnostics(bytes));
+		}
+		#[cfg(not(feature = "audit"))]
+		{
+			let _ = (self, ptr, layout, tag);
+		}
+	}
+
+	/// Check the layout passed to `dealloc`, returning the layout the deallocation should be forwarded with, which is that the pointer was allocated with, or `None` if it shouldn't be forwarded.
+	#[cfg_attr(not(feature = "audit"), allow(clippy::unnecessary_wraps))]
+	fn audit_dealloc(&self, ptr: *mut u8, layout: Layout) -> Option<Layout> {
+		#[cfg(feature = "audit")]
+		if let Err(error) = self.audit.remove(ptr, layout) {
+			self.audit_error(&error);
+			return match error {
+				AuditError::Mismatch { allocated, .. } => Some(allocated),
+				AuditError::Unknown { .. } | AuditError::DoubleFree { .. } => None,
+			};
+		}
+		#[cfg(not(feature = "audit"))]
+		{
+			let _ = (self, ptr);
+		}
+		Some(layout)
+	}
+
+	/// Forward a reallocation of `ptr` with `realloc`, checking the layout passed and moving the pointer's entry in the audit table to the new pointer.
+	///
+	/// `realloc` is passed the layout the pointer was allocated with, and returns the result, the new pointer, or null if it failed, and the new layout. Pointers that are unknown or already freed aren't forwarded, `failed` being returned instead.
+	#[inline]
+	fn audit_realloc<R>(
+		&self, ptr: *mut u8, old_l: Layout, failed: R,
+		realloc: impl FnOnce(Layout) -> (R, *mut u8, Layout),
+	) -> R {
+		#[cfg(feature = "audit")]
+		{
+			let (taken, checked) = self.audit.take(ptr, old_l);
+			let mut old_l = old_l;
+			if let Err(error) = checked {
+				self.audit_error(&error);
+				match error {
+					AuditError::Mismatch { allocated, .. } => old_l = allocated,
+					AuditError::Unknown { .. } | AuditError::DoubleFree { .. } => return failed,
+				}
+			}
+			let (res, new, new_l) = realloc(old_l);
+			let admit = |bytes: usize| self.admit_diagnostics(bytes);
+			if new.is_null() {
+				self.audit.restore(taken, &admit);
+			} else {
+				self.audit.moved(taken, new, new_l, &admit);
+			}
+			res
+		}
+		#[cfg(not(feature = "audit"))]
+		{
+			let _ = (self, ptr, failed);
+			realloc(old_l).0
+		}
+	}
+
+	/// The number of guard bytes before and after an allocation of `layout`.
+	#[inline]
+	fn redzones(&self, layout: Layout) -> (usize, usize) {
+		#[cfg(feature = "redzone")]
+		if self.redzone != 0 && !SANITIZED {
+			return (self.redzone.next_multiple_of(layout.align()), self.redzone);
+		}
+		let _ = (self, layout);
+		(0, 0)
+	}
+
+	/// The number of bytes an allocation of `layout` counts against the limit, including its redzones and the prefix recording its tag and suffix recording its budget, and rounded up to the [granularity](Self::with_granularity).
+	#[inline]
+	fn charged(&self, layout: Layout) -> usize {
+		let (front, back) = self.redzones(layout);
+		let size = layout.size() + front + back;
+		#[cfg(feature = "tags")]
+		let size = size + tag::header_size(layout.align());
+		#[cfg(feature = "budget")]
+		let size = size + budget::trailer_size(size);
+		size.next_multiple_of(self.granularity)
+	}
+
+	/// `layout` extended by its redzones.
+	fn padded(&self, layout: Layout) -> Option<Layout> {
+		let (front, back) = self.redzones(layout);
+		let size = layout.size().checked_add(front)?.checked_add(back)?;
+		Layout::from_size_align(size, layout.align()).ok()
+	}
+
+	/// The layout to request of the wrapped allocator for an allocation of `layout`.
+	fn inner_layout(&self, layout: Layout) -> Option<Layout> {
+		let padded = self.padded(layout);
+		#[cfg(feature = "tags")]
+		let padded = padded.and_then(tag::inner_layout);
+		#[cfg(feature = "budget")]
+		let padded = padded.and_then(budget::inner_layout);
+		if padded.is_none() {
+			rejection::reject(Rejection::TooLarge);
+		}
+		padded
+	}
+
+	/// Convert a block returned by the wrapped allocator to the pointer to hand out, recording `tag` and filling its redzones.
+	unsafe fn attach(&self, base: *mut u8, layout: Layout, tag: usize) -> *mut u8 {
+		#[cfg(feature = "tags")]
+		let base = tag::attach(base, layout, tag);
+		#[cfg(not(feature = "tags"))]
+		let _ = tag;
+		let (front, back) = self.redzones(layout);
+		ptr::write_bytes(base, CANARY, front);
+		ptr::write_bytes(base.add(front + layout.size()), CANARY, back);
+		base.add(front)
+	}
+
+	/// Recover the block to pass to the wrapped allocator, and the tag, from a pointer returned by [`attach`](Self::attach), checking its redzones.
+	unsafe fn detach(&self, ptr: *mut u8, layout: Layout) -> (*mut u8, usize) {
+		let (front, back) = self.redzones(layout);
+		let start = ptr.sub(front);
+		#[cfg(feature = "tags")]
+		let (base, tag) = tag::detach(start, layout);
+		#[cfg(not(feature = "tags"))]
+		let (base, tag) = (start, 0);
+		if front + back != 0 {
+			self.check_redzones(ptr, layout, tag, (front, back));
+		}
+		(base, tag)
+	}
+
+	/// Overwrite the `len` bytes at `ptr` that are being freed, if [zeroing](Self::set_zero_on_free) or [poisoning](POISON) is enabled.
+	unsafe fn scrub(&self, ptr: *mut u8, len: usize) {
+		#[cfg(feature = "zeroize")]
+		if self.zeroing() {
+			zeroize(ptr, len);
+		}
+		#[cfg(feature = "poison")]
+		if !SANITIZED {
+			ptr::write_bytes(ptr, POISON, len);
+		}
+		let _ = (self, ptr, len);
+	}
+
+	/// Check that the redzones of `ptr` still hold the canary, reporting it if not.
+	#[cfg_attr(not(feature = "redzone"), allow(clippy::unused_self))]
+	unsafe fn check_redzones(
+		&self, ptr: *mut u8, layout: Layout, tag: usize, (front, back): (usize, usize),
+	) {
+		#[cfg(feature = "redzone")]
+		{
+			let before = slice::from_raw_parts(ptr.sub(front), front);
+			let after = slice::from_raw_parts(ptr.add(layout.size()), back);
+			let (bytes, side) = if let Some(i) = before.iter().position(|&byte| byte != CANARY) {
+				(front - i, "before")
+			} else if let Some(i) = after.iter().rposition(|&byte| byte != CANARY) {
+				(i + 1, "after")
+			} else {
+				return;
+			};
+			let _ = self.redzone_errors.fetch_add(1, Ordering::Relaxed);
+			#[cfg(feature = "tags")]
+			let tag = Tag::from_index(tag).map_or("none", Tag::name);
+			#[cfg(not(feature = "tags"))]
+			let tag = {
+				let _ = tag;
+				"none"
+			};
+			eprintln!(
+				"cap: {} bytes {} {:#x} (size {}, tag {}) were overwritten",
+				bytes,
+				side,
+				ptr as usize,
+				layout.size(),
+				tag
+			);
+		}
+		#[cfg(not(feature = "redzone"))]
+		let _ = (ptr, layout, tag, front, back);
+	}
+
+	#[inline]
+	fn current_tag() -> usize {
+		#[cfg(feature = "tags")]
+		{
+			tag::current()
+		}
+		#[cfg(not(feature = "tags"))]
+		{
+			0
+		}
+	}
+
+	/// Like [`charge_tagged`](Self::charge_tagged), [waiting](wait_for_budget) and retrying if it doesn't fit and this thread does.
+	#[cfg(feature = "nightly")]
+	fn charge_or_wait(&self, size: usize, tag: usize) -> bool {
+		self.charge_tagged(size, tag) || self.wait_to_charge(size, tag)
+	}
+
+	/// [Wait](wait_for_budget) for `size` bytes to fit, if this thread does, admitting [jumbo](Self::set_jumbo_threshold) allocations in two phases.
+	fn wait_to_charge(&self, size: usize, tag: usize) -> bool {
+		if size < self.jumbo_threshold.load(Ordering::Relaxed) {
+			return wait::wait(|| self.charge_tagged(size, tag));
+		}
+		// Queue behind any other jumbo allocation, then gather what is freed until there is enough.
+		let (mut admitting, mut gathered) = (false, 0);
+		let admitted = wait::wait(|| {
+			admitting = admitting
+				|| self
+					.jumbo_admitting
+					.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+					.is_ok();
+			if admitting {
+				// Leave bytes remaining that are momentarily wrapped by a charge that didn't fit, as they are about to be refunded.
+				if let Ok(remaining) =
+					self.remaining
+						.fetch_update(Ordering::Acquire, Ordering::Relaxed, |remaining| {
+							(remaining <= self.limit.load(Ordering::Relaxed)).then_some(0)
+						}) {
+					gathered += remaining;
+				}
+			}
+			gathered >= size
+		});
+		if admitting {
+			self.jumbo_admitting.store(false, Ordering::Release);
+		}
+		if !admitted {
+			self.release_remaining(gathered);
+			return false;
+		}
+		self.release_remaining(gathered - size);
+		if !(self.fits_overhead(self.remaining())
+			&& self.fits_committed()
+			&& self.charge_group(size))
+		{
+			self.release_remaining(size);
+			return false;
+		}
+		#[cfg(feature = "tags")]
+		if tag != 0 && !self.charge_tag(size, tag) {
+			self.release(size);
+			rejection::reject(Rejection::TagLimit);
+			return false;
+		}
+		#[cfg(not(feature = "tags"))]
+		let _ = tag;
+		true
+	}
+
+	/// Charge `size` bytes against the limit and the budget of the tag with index `tag`, returning whether it fit.
+	///
+	/// This is forced inline, like [`charge_bytes`](Self::charge_bytes), so that a successful allocation doesn't call anything but the wrapped allocator.
+	#[allow(clippy::inline_always)]
+	#[inline(always)]
+	fn charge_tagged(&self, size: usize, tag: usize) -> bool {
+		#[cfg(feature = "carve")]
+		let charged = carve::draw(ptr::from_ref(self).cast(), size) || self.charge_bytes(size);
+		#[cfg(not(feature = "carve"))]
+		let charged = self.charge_bytes(size);
+		if !charged && !self.charge_exempt(size) {
+			return false;
+		}
+		#[cfg(feature = "tags")]
+		if tag != 0 && !self.charge_tag(size, tag) {
+			if self.is_exempt() {
+				self.add_to_tag(tag, size);
+				#[cfg(feature = "stats-counts")]
+				let _ = self.exempted.fetch_add(size, Ordering::Relaxed);
+				return true;
+			}
+			self.release(size);
+			rejection::reject(Rejection::TagLimit);
+			return false;
+		}
+		#[cfg(not(feature = "tags"))]
+		let _ = tag;
+		true
+	}
+
+	#[inline]
+	fn release_tagged(&self, size: usize, tag: usize) {
+		#[cfg(feature = "carve")]
+		self.release(carve::refill(ptr::from_ref(self).cast(), size));
+		#[cfg(not(feature = "carve"))]
+		self.release(size);
+		#[cfg(feature = "tags")]
+		if tag != 0 {
+			let _ = self.tags.slots[tag]
+				.allocated
+				.fetch_sub(size, Ordering::Relaxed);
+		}
+		#[cfg(not(feature = "tags"))]
+		let _ = tag;
+	}
+
+	/// Attribute `size` bytes to the tag with index `tag`, regardless of its limit.
+	#[cfg(feature = "tags")]
+	fn add_to_tag(&self, tag: usize, size: usize) {
+		let slot = &self.tags.slots[tag];
+		let allocated = slot.allocated.fetch_add(size, Ordering::Relaxed) + size;
+		self.record_tag_peak(slot, allocated);
+	}
+
+	/// Record that `allocated` bytes are attributed to the tag of `slot`, which may be a new peak.
+	#[cfg(feature = "tags")]
+	#[inline]
+	fn record_tag_peak(&self, slot: &tag::Slot, allocated: usize) {
+		#[cfg(feature = "stats-tags")]
+		if self.stats.contains(Stats::TAGS) {
+			slot.record_peak(allocated);
+		}
+		#[cfg(not(feature = "stats-tags"))]
+		let _ = (self, slot, allocated);
+	}
+
+	#[cfg(feature = "tags")]
+	fn charge_tag(&self, size: usize, tag: usize) -> bool {
+		let slot = &self.tags.slots[tag];
+		let allocated = slot.allocated.fetch_add(size, Ordering::Relaxed) + size;
+		if allocated > slot.limit.load(Ordering::Relaxed) {
+			let _ = slot.allocated.fetch_sub(size, Ordering::Relaxed);
+			return false;
+		}
+		let weight = slot.weight.load(Ordering::Relaxed);
+		if weight == 0 || allocated <= self.share(weight) {
+			self.record_tag_peak(slot, allocated);
+			return true;
+		}
+		// Over its share, which is only allowed while the cap isn't under pressure.
+		let limit = self.limit();
+		#[allow(clippy::cast_possible_truncation)]
+		let threshold = (limit as u128 * self.tags.pressure.load(Ordering::Relaxed) as u128
+			/ u128::from(tag::PRESSURE_SCALE)) as usize;
+		if limit - self.remaining().min(limit) <= threshold {
+			self.record_tag_peak(slot, allocated);
+			true
+		} else {
+			let _ = slot.allocated.fetch_sub(size, Ordering::Relaxed);
+			false
+		}
+	}
+
+	/// The share of the limit of a group of weight `weight`.
+	#[cfg(feature = "tags")]
+	fn share(&self, weight: usize) -> usize {
+		let total_weight = self.tags.total_weight.load(Ordering::Relaxed).max(weight);
+		#[allow(clippy::cast_possible_truncation)]
+		let share = (self.limit() as u128 * weight as u128 / total_weight as u128) as usize;
+		share
+	}
+
+	/// Try to subtract `size` bytes from the remaining budget, returning whether it fit within it, less the estimated overhead of the wrapped allocator, and the [committed limit](Self::set_committed_limit).
+	///
+	/// This sits under every allocation, so the case of it fitting falls straight through, with the refund out of line.
+	#[allow(clippy::inline_always)]
+	#[inline(always)]
+	fn charge_bytes(&self, size: usize) -> bool {
+		let remaining = self.remaining.fetch_sub(size, Ordering::Acquire);
+		if remaining >= size
+			&& self.fits_overhead(remaining - size)
+			&& self.fits_committed()
+			&& self.charge_group(size)
+		{
+			return true;
+		}
+		self.refund(size);
+		false
+	}
+
+	/// Return whether the [committed](Self::committed) bytes are within the committed limit.
+	#[inline]
+	fn fits_committed(&self) -> bool {
+		#[cfg(feature = "committed")]
+		{
+			let committed_limit = self.committed_limit();
+			committed_limit == usize::MAX || self.committed() <= committed_limit
+		}
+		#[cfg(not(feature = "committed"))]
+		{
+			let _ = self;
+			true
+		}
+	}
+
+	/// Return whether `remaining` bytes leave room for the estimated overhead of the live allocations.
+	#[inline]
+	fn fits_overhead(&self, remaining: usize) -> bool {
+		#[cfg(feature = "overhead")]
+		{
+			let overhead = self.overhead_per_allocation();
+			overhead == 0 || remaining >= self.estimated_overhead()
+		}
+		#[cfg(not(feature = "overhead"))]
+		{
+			let _ = (self, remaining);
+			true
+		}
+	}
+
+	/// Overdraw `size` bytes that didn't fit if this thread is [exempt](Self::exempt), returning whether it was, out of line from the allocation path.
+	#[cold]
+	#[inline(never)]
+	fn charge_exempt(&self, size: usize) -> bool {
+		if !self.is_exempt() {
+			rejection::reject(Rejection::Limit);
+			return false;
+		}
+		self.overdraw(size);
+		true
+	}
+
+	/// Undo the subtraction of `size` bytes from the remaining budget by a [`charge_bytes`](Self::charge_bytes) that didn't fit.
+	#[cold]
+	#[inline(never)]
+	fn refund(&self, size: usize) {
+		let _ = self.remaining.fetch_add(size, Ordering::Release);
+	}
+
+	/// Charge `size` bytes against the combined limit of the [group](Self::with_group), if any.
+	#[inline]
+	fn charge_group(&self, size: usize) -> bool {
+		#[cfg(feature = "cap-group")]
+		{
+			self.group.is_none_or(|group| group.charge(size))
+		}
+		#[cfg(not(feature = "cap-group"))]
+		{
+			let _ = (self, size);
+			true
+		}
+	}
+
+	#[inline]
+	fn release(&self, size: usize) {
+		#[cfg(feature = "cap-group")]
+		if let Some(group) = self.group {
+			group.release(size);
+		}
+		self.release_remaining(size);
+	}
+
+	/// Return `size` bytes to the remaining budget, without touching the group's.
+	fn release_remaining(&self, mut size: usize) {
+		// Repay any overdraft first, so that the limit is restored as exempted memory is freed.
+		if self.overdraft.load(Ordering::Relaxed) != 0 {
+			if let Ok(overdraft) =
+				self.overdraft
+					.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |overdraft| {
+						(overdraft != 0).then(|| overdraft - overdraft.min(size))
+					}) {
+				size -= overdraft.min(size);
+			}
+		}
+		#[cfg(feature = "consistency")]
+		let limit = self.limit();
+		let remaining = self.remaining.fetch_add(size, Ordering::Release);
+		// Only if this release took the bytes remaining over a limit that stayed the same throughout. Were they over it already, they were either reported then, or momentarily wrapped by a charge that didn't fit.
+		#[cfg(feature = "consistency")]
+		if remaining <= limit && size > limit - remaining && self.limit() == limit {
+			self.consistency
+				.report(&Inconsistency::RemainingExceedsLimit {
+					remaining: remaining.wrapping_add(size),
+					limit,
+				});
+		}
+		#[cfg(not(feature = "consistency"))]
+		let _ = remaining;
+	}
+
+	fn is_exempt(&self) -> bool {
+		exempt::is_exempt(ptr::from_ref(self).cast()) || reentrancy::in_hook()
+	}
+
+	/// Charge `size` bytes while exempt: what remains within the limit, and the rest beyond it.
+	fn overdraw(&self, size: usize) {
+		#[cfg(feature = "cap-group")]
+		if let Some(group) = self.group {
+			group.overdraw(size);
+		}
+		// Take only what's needed, rather than momentarily leaving nothing for other threads, and nothing while momentarily wrapped by a charge that didn't fit.
+		let taken = self
+			.remaining
+			.fetch_update(Ordering::Acquire, Ordering::Relaxed, |remaining| {
+				(remaining <= self.limit.load(Ordering::Relaxed))
+					.then(|| remaining.saturating_sub(size))
+			})
+			.map_or(0, |remaining| remaining.min(size));
+		if taken == size {
+			return;
+		}
+		let _ = self.overdraft.fetch_add(size - taken, Ordering::Relaxed);
+		#[cfg(feature = "stats-counts")]
+		let _ = self.exempted.fetch_add(size - taken, Ordering::Relaxed);
+	}
+
+	fn inject_failure(&self, size: usize) -> bool {
+		#[cfg(feature = "testing")]
+		if self.testing.fail(self.allocated()) {
+			rejection::reject(Rejection::Injected);
+			return true;
+		}
+		#[cfg(feature = "chaos")]
+		{
+			let threshold = self.failure_threshold.load(Ordering::Relaxed);
+			if threshold == 0 || size < self.failure_min_size.load(Ordering::Relaxed) {
+				return false;
+			}
+			let (tag, thread) = (
+				self.failure_tag.load(Ordering::Relaxed),
+				self.failure_thread.load(Ordering::Relaxed),
+			);
+			if (tag != 0 && tag != Self::current_tag())
+				|| (thread != 0 && thread != events::thread_id())
+			{
+				return false;
+			}
+			// splitmix64, stepped atomically so concurrent allocations draw distinct values
+			let mut z = self
+				.failure_rng
+				.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+			z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+			z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+			z ^= z >> 31;
+			if z <= threshold {
+				let _ = self.injected_failures.fetch_add(1, Ordering::Relaxed);
+				rejection::reject(Rejection::Injected);
+				return true;
+			}
+			false
+		}
+		#[cfg(not(feature = "chaos"))]
+		{
+			let _ = (self, size);
+			false
+		}
+	}
+
+	fn count_resize(&self, resize: Resize) {
+		#[cfg(feature = "stats-counts")]
+		if self.stats.contains(Stats::COUNTS) {
+			let count = match resize {
+				Resize::Grow => &self.grow_count,
+				Resize::GrowZeroed => &self.grow_zeroed_count,
+				Resize::Shrink => &self.shrink_count,
+			};
+			let _ = count.fetch_add(1, Ordering::Relaxed);
+		}
+		#[cfg(not(feature = "stats-counts"))]
+		{
+			let _ = (self, resize);
+		}
+	}
+
+	/// Count the bytes copied if a reallocation from `old_l` to `new_l` moved the allocation from `old` to `new`, calling the churn hook if it continues a lineage of moving grows.
+	fn count_moved(&self, old: *mut u8, new: *mut u8, old_l: Layout, new_l: Layout) {
+		#[cfg(any(feature = "stats-counts", feature = "stats-churn"))]
+		if old != new {
+			let copied = old_l.size().min(new_l.size());
+			#[cfg(feature = "stats-counts")]
+			if self.stats.contains(Stats::COUNTS) {
+				let _ = self.realloc_copied.fetch_add(copied, Ordering::Relaxed);
+			}
+			#[cfg(feature = "stats-churn")]
+			if new_l.size() > old_l.size() && self.stats.contains(Stats::CHURN) {
+				let churn = churn::record(old, new, copied, new_l.size());
+				let hook = self.churn_hook.load(Ordering::Acquire);
+				let threshold = self.churn_threshold.load(Ordering::Relaxed);
+				if let Some(churn) =
+					churn.filter(|churn| churn.copies == threshold && !hook.is_null())
+				{
+					let hook = unsafe { mem::transmute::<*mut (), fn(&Churn)>(hook) };
+					let _ = reentrancy::call(|| hook(&churn));
+				}
+			}
+		}
+		#[cfg(not(all(feature = "stats-counts", feature = "stats-churn")))]
+		{
+			let _ = (self, old, new, old_l, new_l);
+		}
+	}
+
+	#[inline]
+	fn count_failure(&self, failed: bool, layout: Layout) {
+		if failed {
+			self.count_failed(layout);
+		} else {
+			#[cfg(feature = "breaker")]
+			self.breaker.succeeded();
+		}
+	}
+
+	/// Record a failure, out of line from the successful path.
+	#[cold]
+	#[inline(never)]
+	fn count_failed(&self, layout: Layout) {
+		#[cfg(feature = "breaker")]
+		self.breaker.failed(now());
+		let now = now_millis();
+		let last = self.last_failure.swap(now, Ordering::Relaxed);
+		if Duration::from_millis(now.wrapping_sub(last) as u64) > RETRY_MAX {
+			self.failure_streak.store(1, Ordering::Relaxed);
+		} else {
+			let _ = self.failure_streak.fetch_add(1, Ordering::Relaxed);
+		}
+		#[cfg(feature = "stats-counts")]
+		let _ = self.failure_count.fetch_add(1, Ordering::Relaxed);
+		self.event(EventKind::Failure, layout, Self::current_tag());
+	}
+
+	#[inline]
+	fn update_stats(&self, size: usize) {
+		#[cfg(feature = "stats-counts")]
+		if self.stats.contains(Stats::COUNTS) {
+			let _ = self.total_allocated.fetch_add(size, Ordering::Relaxed);
+		}
+		#[cfg(feature = "stats-peaks")]
+		if self.stats.contains(Stats::PEAKS) {
+			// If max_allocated is less than currently allocated, then it will be updated to limit - remaining.
+			// Otherwise, it will remain unchanged.
+			let allocated = self.allocated();
+			if self.max_allocated.fetch_max(allocated, Ordering::Relaxed) < allocated {
+				self.peak.record(Self::current_tag());
+			}
+			let _ = self.window_peak.fetch_max(allocated, Ordering::Relaxed);
+		}
+		#[cfg(feature = "stats-histograms")]
+		if self.stats.contains(Stats::HISTOGRAMS) {
+			self.histogram.record(size);
+		}
+		#[cfg(not(all(
+			feature = "stats-counts",
+			feature = "stats-peaks",
+			feature = "stats-histograms"
+		)))]
+		{
+			let _ = (self, size);
+		}
+	}
+}
+
+#[derive(Clone, Copy)]
+enum Resize {
+	Grow,
+	#[cfg_attr(not(feature = "nightly"), allow(dead_code))]
+	GrowZeroed,
+	Shrink,
+}
+
+unsafe impl<H> GlobalAlloc for Cap<H>
+where
+	H: GlobalAlloc,
+{
+	#[inline]
+	unsafe fn alloc(&self, l: Layout) -> *mut u8 {
+		#[cfg(feature = "reserve")]
+		let res = self.alloc_with_reserve(l, false);
+		#[cfg(not(feature = "reserve"))]
+		let res = self.alloc_with(l, false);
+		self.count_failure(res.is_null(), l);
+		res
+	}
+	#[inline]
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		#[cfg(feature = "reserve")]
+		if self.dealloc_reserved(ptr) {
+			return;
+		}
+		let Some(layout) = self.audit_dealloc(ptr, layout) else {
+			return;
+		};
+		let size = self.charged(layout);
+		let (base, tag) = self.detach(ptr, layout);
+		self.scrub(ptr, layout.size());
+		let inner_layout = self.inner_layout(layout).unwrap();
+		#[cfg(feature = "budget")]
+		budget::detach(base, inner_layout, size);
+		self.quarantine(base, inner_layout, size, tag);
+		self.event(EventKind::Dealloc, layout, tag);
+	}
+	#[inline]
+	unsafe fn alloc_zeroed(&self, l: Layout) -> *mut u8 {
+		#[cfg(feature = "reserve")]
+		let res = self.alloc_with_reserve(l, true);
+		#[cfg(not(feature = "reserve"))]
+		let res = self.alloc_with(l, true);
+		self.count_failure(res.is_null(), l);
+		res
+	}
+	#[inline]
+	unsafe fn realloc(&self, ptr: *mut u8, old_l: Layout, new_s: usize) -> *mut u8 {
+		#[cfg(feature = "reserve")]
+		let res = self.realloc_with_reserve(ptr, old_l, new_s);
+		#[cfg(not(feature = "reserve"))]
+		let res = self.realloc_with(ptr, old_l, new_s);
+		self.count_failure(
+			res.is_null(),
+			Layout::from_size_align_unchecked(new_s, old_l.align()),
+		);
+		res
+	}
+}
+
+impl<H> Cap<H>
+where
+	H: GlobalAlloc,
+{
+	/// Estimate the [per-allocation overhead](Self::set_overhead) of the wrapped allocator from probe allocations across size classes, and install it, returning it.
+	///
+	/// Allocators usually place consecutive blocks of the same size next to each other, so the most common distance between them less the size requested is the overhead of each. The probes are made directly of the wrapped allocator, so aren't counted, and the estimate is the mean over the size classes for which a consistent distance is found.
+	#[cfg(feature = "overhead")]
+	pub fn calibrate_overhead(&self) -> usize {
+		const SIZES: [usize; 9] = [16, 32, 48, 64, 96, 128, 256, 512, 1024];
+		const PROBES: usize = 64;
+		let (mut total, mut classes) = (0, 0);
+		for size in SIZES {
+			// Safe as the sizes are small and the alignment a power of two.
+			let layout = unsafe { Layout::from_size_align_unchecked(size, align_of::<usize>()) };
+			let mut blocks = [ptr::null_mut(); PROBES];
+			for block in &mut blocks {
+				*block = unsafe { self.allocator.alloc(layout) };
+			}
+			let mut strides = [0; PROBES - 1];
+			for (stride, pair) in strides.iter_mut().zip(blocks.windows(2)) {
+				*stride = (pair[1] as usize).abs_diff(pair[0] as usize);
+			}
+			strides.sort_unstable();
+			// The most common distance, as blocks reused from a fragmented heap are scattered.
+			let (stride, count) = strides
+				.chunk_by(|a, b| a == b)
+				.map(|run| (run[0], run.len()))
+				.max_by_key(|&(_, count)| count)
+				.unwrap_or((0, 0));
+			// Blocks that weren't placed together say nothing about the overhead.
+			if !blocks.contains(&ptr::null_mut())
+				&& count >= PROBES / 4
+				&& (size..=size * 4).contains(&stride)
+			{
+				total += stride - size;
+				classes += 1;
+			}
+			for &block in blocks.iter().filter(|block| !block.is_null()) {
+				unsafe { self.allocator.dealloc(block, layout) };
+			}
+		}
+		let overhead = total.checked_div(classes).unwrap_or(0);
+		self.set_overhead(overhead);
+		overhead
+	}
+
+	/// Hold freed blocks of up to `bytes` in total in a quarantine before releasing them, so that use-after-free is more likely to be caught while they are still [poisoned](POISON).
+	///
+	/// Quarantined blocks count against the limit. Once the quarantine is full the oldest blocks are released, and it is flushed if an allocation would otherwise fail. A budget of 0, the default, disables the quarantine. Blocks freed through the `Allocator` API, and under Miri or a sanitizer, aren't quarantined.
+	#[cfg(feature = "quarantine")]
+	pub fn set_quarantine(&self, bytes: usize) {
+		self.quarantine.budget.store(bytes, Ordering::Relaxed);
+		let _ = self.quarantine.shrink_to(bytes, |entry| self.evict(entry));
+	}
+
+	/// Release all quarantined blocks.
+	#[cfg(feature = "quarantine")]
+	pub fn flush_quarantine(&self) {
+		let _ = self.quarantine.shrink_to(0, |entry| self.evict(entry));
+	}
+
+	/// Get the number of bytes held in quarantine.
+	#[cfg(feature = "quarantine")]
+	pub fn quarantined(&self) -> usize {
+		self.quarantine.bytes.load(Ordering::Relaxed)
+	}
+
+	#[cfg(feature = "quarantine")]
+	fn evict(&self, entry: quarantine::Entry) {
+		unsafe { self.allocator.dealloc(entry.base, entry.layout) };
+		self.release_tagged(entry.charged, entry.tag);
+	}
+
+	/// Release the block `base`, or hold it in quarantine.
+	unsafe fn quarantine(&self, base: *mut u8, inner_layout: Layout, charged: usize, tag: usize) {
+		#[cfg(feature = "quarantine")]
+		if !SANITIZED {
+			let entry = quarantine::Entry {
+				base,
+				layout: inner_layout,
+				charged,
+				tag,
+			};
+			if let Some(entry) = self.quarantine.push(entry, |entry| self.evict(entry)) {
+				self.evict(entry);
+			}
+			return;
+		}
+		self.allocator.dealloc(base, inner_layout);
+		self.release_tagged(charged, tag);
+	}
+
+	/// Like [`charge_tagged`](Self::charge_tagged), flushing the quarantine and retrying if it doesn't fit, then [waiting](wait_for_budget) if this thread does.
+	#[inline]
+	fn charge_or_flush(&self, size: usize, tag: usize) -> bool {
+		self.charge_tagged(size, tag) || self.flush_and_charge(size, tag)
+	}
+
+	/// The rest of [`charge_or_flush`](Self::charge_or_flush), out of line as it is only reached when the charge doesn't fit.
+	#[cold]
+	#[inline(never)]
+	fn flush_and_charge(&self, size: usize, tag: usize) -> bool {
+		#[cfg(feature = "quarantine")]
+		if self.quarantine.shrink_to(0, |entry| self.evict(entry)) && self.charge_tagged(size, tag)
+		{
+			return true;
+		}
+		self.wait_to_charge(size, tag)
+	}
+
+	/// Reallocate the block `base` in the wrapped allocator, moving it ourselves if the old block must be [scrubbed](Self::scrubs_resize).
+	unsafe fn realloc_inner(&self, base: *mut u8, old_l: Layout, new_l: Layout) -> *mut u8 {
+		if self.scrubs_resize(old_l, new_l) {
+			let res = self.allocator.alloc(new_l);
+			if !res.is_null() {
+				ptr::copy_nonoverlapping(base, res, old_l.size().min(new_l.size()));
+				self.scrub(base, old_l.size());
+				self.allocator.dealloc(base, old_l);
+			}
+			return res;
+		}
+		self.allocator.realloc(base, old_l, new_l.size())
+	}
+
+	unsafe fn realloc_with(&self, ptr: *mut u8, old_l: Layout, new_s: usize) -> *mut u8 {
+		self.audit_realloc(ptr, old_l, ptr::null_mut(), |old_l| {
+			let res = self.resize_with(ptr, old_l, new_s);
+			(
+				res,
+				res,
+				Layout::from_size_align_unchecked(new_s, old_l.align()),
+			)
+		})
+	}
+
+	unsafe fn resize_with(&self, ptr: *mut u8, old_l: Layout, new_s: usize) -> *mut u8 {
+		let new_l = Layout::from_size_align_unchecked(new_s, old_l.align());
+		let (old_size, new_size) = (self.charged(old_l), self.charged(new_l));
+		let (Some(inner_old_l), Some(inner_new_l)) =
+			(self.inner_layout(old_l), self.inner_layout(new_l))
+		else {
+			return ptr::null_mut();
+		};
+		// Shrinking is never failed, as infallible callers such as `Vec::shrink_to_fit` abort if it is, and `Allocator::shrink` doesn't.
+		if new_s > old_l.size() && (self.inject_failure(new_size) || self.shed()) {
+			return ptr::null_mut();
+		}
+		let (base, tag) = self.detach(ptr, old_l);
+		#[cfg(feature = "budget")]
+		let budget = budget::read(base, inner_old_l);
+		// Decided by the requested rather than the charged sizes, which may be equal when rounded to the granularity.
+		let res = if new_s > old_l.size() {
+			if !self.charge_or_flush(new_size - old_size, tag) {
+				return ptr::null_mut();
+			}
+			#[cfg(feature = "budget")]
+			if !budget::charge(budget, new_size - old_size) {
+				self.release_tagged(new_size - old_size, tag);
+				return ptr::null_mut();
+			}
+			let res = self
+				.retry_inner(new_l, || {
+					ptr::NonNull::new(self.realloc_inner(base, inner_old_l, inner_new_l))
+				})
+				.map_or(ptr::null_mut(), ptr::NonNull::as_ptr);
+			if res.is_null() {
+				self.release_tagged(new_size - old_size, tag);
+				#[cfg(feature = "budget")]
+				budget::release(budget, new_size - old_size);
+			} else {
+				self.count_resize(Resize::Grow);
+			}
+			res
+		} else {
+			let res = self.realloc_inner(base, inner_old_l, inner_new_l);
+			if !res.is_null() {
+				self.release_tagged(old_size - new_size, tag);
+				#[cfg(feature = "budget")]
+				budget::release(budget, old_size - new_size);
+				self.count_resize(Resize::Shrink);
+			}
+			// Although this might just deaalocate, I will still update the stats as if it allocates to be on "the safe side"
+			res
+		};
+		if res.is_null() {
+			return res;
+		}
+		#[cfg(feature = "budget")]
+		budget::write(res, inner_new_l, budget);
+		let res = self.attach(res, new_l, tag);
+		self.count_moved(ptr, res, old_l, new_l);
+		self.update_stats(new_size);
+		self.event(
+			EventKind::Realloc {
+				old_size: old_l.size(),
+			},
+			new_l,
+			tag,
+		);
+		res
+	}
+
+	#[inline]
+	unsafe fn alloc_with(&self, l: Layout, zeroed: bool) -> *mut u8 {
+		let size = self.charged(l);
+		let Some(inner_l) = self.inner_layout(l) else {
+			return ptr::null_mut();
+		};
+		let tag = Self::current_tag();
+		if self.inject_failure(size) || self.shed() || !self.charge_or_flush(size, tag) {
+			return ptr::null_mut();
+		}
+		#[cfg(feature = "budget")]
+		let budget = budget::current();
+		#[cfg(feature = "budget")]
+		if !budget::charge(budget, size) {
+			self.release_tagged(size, tag);
+			return ptr::null_mut();
+		}
+		let res = self.retry_inner(l, || {
+			let res = if zeroed {
+				self.allocator.alloc_zeroed(inner_l)
+			} else {
+				self.allocator.alloc(inner_l)
+			};
+			ptr::NonNull::new(res)
+		});
+		let Some(res) = res else {
+			self.release_tagged(size, tag);
+			#[cfg(feature = "budget")]
+			budget::release(budget, size);
+			return ptr::null_mut();
+		};
+		let res = res.as_ptr();
+		#[cfg(feature = "budget")]
+		budget::attach(res, inner_l, budget);
+		let res = self.attach(res, l, tag);
+		self.audit_alloc(res, l, tag);
+		self.update_stats(size);
+		self.event(EventKind::Alloc, l, tag);
+		res
+	}
+}
+
+/// The dangling, well-aligned pointer zero-sized allocations of `layout` are served with, per the `Allocator` convention, without touching the wrapped allocator or the counters.
+#[cfg(feature = "nightly")]
+fn dangling(layout: Layout) -> ptr::NonNull<[u8]> {
+	// Safe as alignments are nonzero.
+	let ptr = unsafe { ptr::NonNull::new_unchecked(ptr::without_provenance_mut(layout.align())) };
+	ptr::NonNull::slice_from_raw_parts(ptr, 0)
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl<H> Allocator for Cap<H>
+where
+	H: Allocator,
+{
+	fn allocate(&self, l: Layout) -> Result<ptr::NonNull<[u8]>, AllocError> {
+		if l.size() == 0 {
+			return Ok(dangling(l));
+		}
+		let res = self.allocate_with(l, false);
+		self.count_failure(res.is_err(), l);
+		res
+	}
+	fn allocate_zeroed(&self, l: Layout) -> Result<ptr::NonNull<[u8]>, AllocError> {
+		if l.size() == 0 {
+			return Ok(dangling(l));
+		}
+		let res = self.allocate_with(l, true);
+		self.count_failure(res.is_err(), l);
+		res
+	}
+	unsafe fn deallocate(&self, ptr: ptr::NonNull<u8>, l: Layout) {
+		if l.size() == 0 {
+			return;
+		}
+		let Some(l) = self.audit_dealloc(ptr.as_ptr(), l) else {
+			return;
+		};
+		let (base, tag) = self.detach(ptr.as_ptr(), l);
+		self.scrub(ptr.as_ptr(), l.size());
+		let inner_l = self.inner_layout(l).unwrap();
+		#[cfg(feature = "budget")]
+		budget::detach(base, inner_l, self.charged(l));
+		self.allocator
+			.deallocate(ptr::NonNull::new_unchecked(base), inner_l);
+		self.release_tagged(self.charged(l), tag);
+		self.event(EventKind::Dealloc, l, tag);
+	}
+	unsafe fn grow(
+		&self, ptr: ptr::NonNull<u8>, old_l: Layout, new_l: Layout,
+	) -> Result<ptr::NonNull<[u8]>, AllocError> {
+		if old_l.size() == 0 {
+			return self.allocate(new_l);
+		}
+		let res = self.audit_realloc(ptr.as_ptr(), old_l, Err(AllocError), |old_l| {
+			let res = self.resize_slice(ptr, old_l, new_l, false);
+			(
+				res,
+				res.map_or(ptr::null_mut(), |res| res.cast().as_ptr()),
+				new_l,
+			)
+		});
+		self.count_failure(res.is_err(), new_l);
+		res
+	}
+	unsafe fn grow_zeroed(
+		&self, ptr: ptr::NonNull<u8>, old_l: Layout, new_l: Layout,
+	) -> Result<ptr::NonNull<[u8]>, AllocError> {
+		if old_l.size() == 0 {
+			return self.allocate_zeroed(new_l);
+		}
+		let res = self.audit_realloc(ptr.as_ptr(), old_l, Err(AllocError), |old_l| {
+			let res = self.resize_slice(ptr, old_l, new_l, true);
+			(
+				res,
+				res.map_or(ptr::null_mut(), |res| res.cast().as_ptr()),
+				new_l,
+			)
+		});
+		self.count_failure(res.is_err(), new_l);
+		res
+	}
+	unsafe fn shrink(
+		&self, ptr: ptr::NonNull<u8>, old_l: Layout, new_l: Layout,
+	) -> Result<ptr::NonNull<[u8]>, AllocError> {
+		if new_l.size() == 0 {
+			self.deallocate(ptr, old_l);
+			return Ok(dangling(new_l));
+		}
+		let res = self.audit_realloc(ptr.as_ptr(), old_l, Err(AllocError), |old_l| {
+			let res = self.resize_slice(ptr, old_l, new_l, false);
+			(
+				res,
+				res.map_or(ptr::null_mut(), |res| res.cast().as_ptr()),
+				new_l,
+			)
+		});
+		self.count_failure(res.is_err(), new_l);
+		res
+	}
+}
+
+#[cfg(feature = "nightly")]
+impl<H> Cap<H>
+where
+	H: Allocator,
+{
+	fn allocate_with(&self, l: Layout, zeroed: bool) -> Result<ptr::NonNull<[u8]>, AllocError> {
+		let size = self.charged(l);
+		let inner_l = self.inner_layout(l).ok_or(AllocError)?;
+		let tag = Self::current_tag();
+		if self.inject_failure(size) || self.shed() || !self.charge_or_wait(size, tag) {
+			return Err(AllocError);
+		}
+		#[cfg(feature = "budget")]
+		let budget = budget::current();
+		#[cfg(feature = "budget")]
+		if !budget::charge(budget, size) {
+			self.release_tagged(size, tag);
+			return Err(AllocError);
+		}
+		let res = self.retry_inner(l, || {
+			if zeroed {
+				self.allocator.allocate_zeroed(inner_l)
+			} else {
+				self.allocator.allocate(inner_l)
+			}
+			.ok()
+		});
+		let Some(res) = res else {
+			self.release_tagged(size, tag);
+			#[cfg(feature = "budget")]
+			budget::release(budget, size);
+			return Err(AllocError);
+		};
+		#[cfg(feature = "budget")]
+		unsafe {
+			budget::attach(res.cast().as_ptr(), inner_l, budget);
+		}
+		let res = unsafe { self.attach_slice(res, l, tag) };
+		self.audit_alloc(res.cast().as_ptr(), l, tag);
+		self.update_stats(size);
+		self.event(EventKind::Alloc, l, tag);
+		Ok(res)
+	}
+
+	/// Grow or shrink the allocation at `ptr` as `new_l` compares with `old_l`, which the audit mode may have corrected from the layout passed to `grow` or `shrink`.
+	unsafe fn resize_slice(
+		&self, ptr: ptr::NonNull<u8>, old_l: Layout, new_l: Layout, zeroed: bool,
+	) -> Result<ptr::NonNull<[u8]>, AllocError> {
+		if new_l.size() >= old_l.size() {
+			self.grow_with(ptr, old_l, new_l, zeroed)
+		} else {
+			self.shrink_with(ptr, old_l, new_l)
+		}
+	}
+
+	unsafe fn grow_with(
+		&self, ptr: ptr::NonNull<u8>, old_l: Layout, new_l: Layout, zeroed: bool,
+	) -> Result<ptr::NonNull<[u8]>, AllocError> {
+		let (old_size, new_size) = (self.charged(old_l), self.charged(new_l));
+		let (Some(inner_old_l), Some(inner_new_l)) =
+			(self.inner_layout(old_l), self.inner_layout(new_l))
+		else {
+			return Err(AllocError);
+		};
+		let (base, tag) = self.detach(ptr.as_ptr(), old_l);
+		// A smaller alignment may need a smaller front redzone, so even a grow can be charged less.
+		let (charge, refund) = (
+			new_size.saturating_sub(old_size),
+			old_size.saturating_sub(new_size),
+		);
+		if self.inject_failure(new_size) || self.shed() || !self.charge_or_wait(charge, tag) {
+			return Err(AllocError);
+		}
+		#[cfg(feature = "budget")]
+		let budget = budget::read(base, inner_old_l);
+		#[cfg(feature = "budget")]
+		if !budget::charge(budget, charge) {
+			self.release_tagged(charge, tag);
+			return Err(AllocError);
+		}
+		// The old block's trailing redzone, and any suffix after it, which the grown allocation now covers.
+		let tail = inner_old_l.size() - (ptr.as_ptr() as usize - base as usize) - old_l.size();
+		let base = ptr::NonNull::new_unchecked(base);
+		let res = self.retry_inner(new_l, || {
+			if let Some(res) = self.move_inner(base, inner_old_l, inner_new_l, zeroed) {
+				res
+			} else if zeroed {
+				self.allocator.grow_zeroed(base, inner_old_l, inner_new_l)
+			} else {
+				self.allocator.grow(base, inner_old_l, inner_new_l)
+			}
+			.ok()
+		});
+		let Some(res) = res else {
+			self.release_tagged(charge, tag);
+			#[cfg(feature = "budget")]
+			budget::release(budget, charge);
+			return Err(AllocError);
+		};
+		self.release_tagged(refund, tag);
+		#[cfg(feature = "budget")]
+		{
+			budget::release(budget, refund);
+			budget::write(res.cast().as_ptr(), inner_new_l, budget);
+		}
+		let res = self.attach_slice(res, new_l, tag);
+		if zeroed {
+			let grown = new_l.size() - old_l.size();
+			ptr::write_bytes(
+				res.cast::<u8>().as_ptr().add(old_l.size()),
+				0,
+				tail.min(grown),
+			);
+		}
+		self.count_moved(ptr.as_ptr(), res.cast().as_ptr(), old_l, new_l);
+		self.count_resize(if zeroed {
+			Resize::GrowZeroed
+		} else {
+			Resize::Grow
+		});
+		self.update_stats(new_size);
+		self.event(
+			EventKind::Realloc {
+				old_size: old_l.size(),
+			},
+			new_l,
+			tag,
+		);
+		Ok(res)
+	}
+
+	unsafe fn shrink_with(
+		&self, ptr: ptr::NonNull<u8>, old_l: Layout, new_l: Layout,
+	) -> Result<ptr::NonNull<[u8]>, AllocError> {
+		let (old_size, new_size) = (self.charged(old_l), self.charged(new_l));
+		let (Some(inner_old_l), Some(inner_new_l)) =
+			(self.inner_layout(old_l), self.inner_layout(new_l))
+		else {
+			return Err(AllocError);
+		};
+		let (base, tag) = self.detach(ptr.as_ptr(), old_l);
+		// A larger alignment may need a larger front redzone, so even a shrink can be charged more.
+		let (charge, refund) = (
+			new_size.saturating_sub(old_size),
+			old_size.saturating_sub(new_size),
+		);
+		if !self.charge_or_wait(charge, tag) {
+			return Err(AllocError);
+		}
+		#[cfg(feature = "budget")]
+		let budget = budget::read(base, inner_old_l);
+		#[cfg(feature = "budget")]
+		if !budget::charge(budget, charge) {
+			self.release_tagged(charge, tag);
+			return Err(AllocError);
+		}
+		let base = ptr::NonNull::new_unchecked(base);
+		let res = match self.move_inner(base, inner_old_l, inner_new_l, false) {
+			Some(res) => res,
+			None => self.allocator.shrink(base, inner_old_l, inner_new_l),
+		};
+		let Ok(res) = res else {
+			self.release_tagged(charge, tag);
+			#[cfg(feature = "budget")]
+			budget::release(budget, charge);
+			return Err(AllocError);
+		};
+		self.release_tagged(refund, tag);
+		#[cfg(feature = "budget")]
+		{
+			budget::release(budget, refund);
+			budget::write(res.cast().as_ptr(), inner_new_l, budget);
+		}
+		let res = self.attach_slice(res, new_l, tag);
+		self.count_moved(ptr.as_ptr(), res.cast().as_ptr(), old_l, new_l);
+		self.count_resize(Resize::Shrink);
+		self.update_stats(new_size);
+		self.event(
+			EventKind::Realloc {
+				old_size: old_l.size(),
+			},
+			new_l,
+			tag,
+		);
+		Ok(res)
+	}
+
+	/// Move the block `base` to a new block of `new_l` if the old one must be [scrubbed](Self::scrubs_resize), rather than leaving the wrapped allocator to move it. Returns `None` if it should be resized as usual.
+	unsafe fn move_inner(
+		&self, base: ptr::NonNull<u8>, old_l: Layout, new_l: Layout, zeroed: bool,
+	) -> Option<Result<ptr::NonNull<[u8]>, AllocError>> {
+		if self.scrubs_resize(old_l, new_l) {
+			let res = if zeroed {
+				self.allocator.allocate_zeroed(new_l)
+			} else {
+				self.allocator.allocate(new_l)
+			};
+			if let Ok(res) = res {
+				let len = old_l.size().min(new_l.size());
+				ptr::copy_nonoverlapping(base.as_ptr(), res.cast().as_ptr(), len);
+				self.scrub(base.as_ptr(), old_l.size());
+				self.allocator.deallocate(base, old_l);
+			}
+			return Some(res);
+		}
+		None
+	}
+
+	/// Like [`attach`](Self::attach), for the slices returned by [`Allocator`].
+	unsafe fn attach_slice(
+		&self, res: ptr::NonNull<[u8]>, l: Layout, tag: usize,
+	) -> ptr::NonNull<[u8]> {
+		let base = res.cast::<u8>().as_ptr();
+		let ptr = self.attach(base, l, tag);
+		let len = res.len() - (ptr as usize - base as usize) - self.redzones(l).1;
+		// Any excess would cover the budget's suffix, and freeing with a larger layout would misplace it.
+		#[cfg(feature = "budget")]
+		let len = len.min(l.size());
+		ptr::NonNull::slice_from_raw_parts(ptr::NonNull::new_unchecked(ptr), len)
+	}
+}
+
+#[cfg(test)]
+#[allow(
+	clippy::legacy_numeric_constants,
+	clippy::needless_for_each,
+	clippy::unnecessary_semicolon
+)] // lints newer than these tests
+mod tests {
+	#[cfg(all(test, feature = "nightly"))]
+	extern crate test;
+	#[cfg(all(test, feature = "nightly"))]
+	use std::collections::TryReserveErrorKind;
+	use std::{alloc, thread, time::Duration};
+	#[cfg(all(test, feature = "nightly"))]
+	use test::{ShouldPanic, TestDescAndFn, TestFn};
+
+	use super::Cap;
+
+	#[global_allocator]
 	static A: Cap<alloc::System> = Cap::new(alloc::System, usize::max_value());
 
-	#[cfg(all(test, feature = "nightly"))]
-	pub fn runner(tests: &[&TestDescAndFn]) {
-		for test in tests {
-			if let TestFn::StaticTestFn(test_fn) = test.testfn {
-				test_fn();
-			} else {
-				unimplemented!();
+	#[cfg(all(test, feature = "nightly"))]
+	pub fn runner(tests: &[&TestDescAndFn]) {
+		for test in tests {
+			if test.desc.ignore {
+				continue;
+			}
+			if let TestFn::StaticTestFn(test_fn) = test.testfn {
+				if test.desc.should_panic == ShouldPanic::No {
+					test_fn().unwrap();
+				} else {
+					assert!(
+						std::panic::catch_unwind(test_fn).is_err(),
+						"{} didn't panic",
+						test.desc.name
+					);
+				}
+			} else {
+				unimplemented!();
+			}
+		}
+	}
+
+	#[test]
+	fn concurrent() {
+		let allocated = A.allocated();
+		for _ in 0..100 {
+			let threads = (0..100)
+				.map(|_| {
+					thread::spawn(|| {
+						for i in 0..1000 {
+							let _ = (0..i).collect::<Vec<u32>>();
+							let _ = (0..i).flat_map(std::iter::once).collect::<Vec<u32>>();
+						}
+					})
+				})
+				.collect::<Vec<_>>();
+			threads
+				.into_iter()
+				.for_each(|thread| thread.join().unwrap());
+			let allocated2 = A.allocated();
+			#[cfg(feature = "stats")]
+			let total_allocated = A.total_allocated();
+			if cfg!(all(test, feature = "nightly")) {
+				assert_eq!(allocated, allocated2);
+				#[cfg(feature = "stats")]
+				assert!(total_allocated >= allocated);
+			}
+		}
+		#[cfg(feature = "stats")]
+		assert!(A.max_allocated() < A.total_allocated());
+	}
+
+	#[cfg(all(test, not(feature = "nightly")))]
+	#[test]
+	fn limit() {
+		#[cfg(feature = "stats")]
+		let initial = A.allocated();
+		let allocate_amount = 30 * 1024 * 1024;
+		let charged = A.charged(alloc::Layout::from_size_align(allocate_amount, 1).unwrap());
+		A.set_limit(A.allocated() + charged).unwrap();
+		for _ in 0..10 {
+			let mut vec = Vec::<u8>::with_capacity(0);
+			if let Err(_e) = vec.try_reserve_exact(allocate_amount + 1) {
+			} else {
+				A.set_limit(usize::max_value()).unwrap();
+				panic!("{}", A.remaining());
+			};
+			assert_eq!(vec.try_reserve_exact(allocate_amount), Ok(()));
+			let mut vec2 = Vec::<u8>::with_capacity(0);
+			assert!(vec2.try_reserve_exact(1).is_err());
+		}
+		// Might have additional allocations of errors and what not along the way.
+		#[cfg(feature = "stats")]
+		{
+			assert!(A.total_allocated() >= initial + 10 * charged);
+			assert_eq!(A.max_allocated(), initial + charged);
+		}
+	}
+
+	#[cfg(all(test, feature = "nightly"))]
+	#[test]
+	fn limit() {
+		#[cfg(feature = "stats")]
+		let (initial, initial_total) = (A.allocated(), A.total_allocated());
+		let allocate_amount = 30 * 1024 * 1024;
+		let charged = A.charged(alloc::Layout::from_size_align(allocate_amount, 1).unwrap());
+		A.set_limit(A.allocated() + charged).unwrap();
+		for _ in 0..10 {
+			let mut vec = Vec::<u8>::with_capacity(0);
+			if let Err(TryReserveErrorKind::AllocError { .. }) = vec
+				.try_reserve_exact(allocate_amount + 1)
+				.map_err(|e| e.kind())
+			{
+			} else {
+				A.set_limit(usize::max_value()).unwrap();
+				panic!("{}", A.remaining())
+			}
+			assert_eq!(vec.try_reserve_exact(allocate_amount), Ok(()));
+			let mut vec2 = Vec::<u8>::with_capacity(0);
+			assert!(vec2.try_reserve_exact(1).is_err());
+		}
+		#[cfg(feature = "stats")]
+		{
+			assert_eq!(A.total_allocated(), initial_total + 10 * charged);
+			assert_eq!(A.max_allocated(), initial + charged);
+		}
+	}
+
+	#[cfg(all(test, feature = "nightly", feature = "stats"))]
+	#[test]
+	fn allocator() {
+		let cap = Cap::new(alloc::System, usize::MAX);
+		let mut vec = Vec::<u8, _>::with_capacity_in(16, &cap);
+		vec.extend_from_slice(&[0; 8]);
+		vec.reserve_exact(24);
+		let layout = |size| alloc::Layout::from_size_align(size, 1).unwrap();
+		assert_eq!(cap.allocated(), cap.charged(layout(32)));
+		vec.shrink_to_fit();
+		assert_eq!(cap.allocated(), cap.charged(layout(8)));
+		assert_eq!(
+			(
+				cap.grow_count(),
+				cap.grow_zeroed_count(),
+				cap.shrink_count()
+			),
+			(1, 0, 1)
+		);
+		drop(vec);
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	#[cfg(feature = "nightly")]
+	#[test]
+	fn zero_sized() {
+		use std::{
+			alloc::{Allocator, Layout}, ptr
+		};
+		/// Panics if asked for a zero-sized block, as some allocators return non-null for them.
+		#[derive(Debug)]
+		struct NoZeroSized;
+		unsafe impl Allocator for NoZeroSized {
+			fn allocate(&self, layout: Layout) -> Result<ptr::NonNull<[u8]>, alloc::AllocError> {
+				assert_ne!(layout.size(), 0);
+				alloc::System.allocate(layout)
+			}
+			unsafe fn deallocate(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+				assert_ne!(layout.size(), 0);
+				alloc::System.deallocate(ptr, layout);
+			}
+		}
+		let cap = Cap::new(NoZeroSized, usize::MAX).with_granularity(16);
+		let (empty, aligned) = (Layout::new::<()>(), Layout::new::<[u64; 0]>());
+		unsafe {
+			let zst = cap.allocate(aligned).unwrap();
+			assert_eq!(zst.cast::<u8>().as_ptr() as usize % 8, 0);
+			assert_eq!(cap.allocated(), 0);
+			let grown = cap.grow(zst.cast(), aligned, Layout::new::<u64>()).unwrap();
+			assert_eq!(cap.allocated(), cap.charged(Layout::new::<u64>()));
+			let shrunk = cap
+				.shrink(grown.cast(), Layout::new::<u64>(), aligned)
+				.unwrap();
+			assert_eq!(cap.allocated(), 0);
+			cap.deallocate(shrunk.cast(), aligned);
+			cap.deallocate(cap.allocate_zeroed(empty).unwrap().cast(), empty);
+		}
+		let vec = Vec::<(), _>::with_capacity_in(10, &cap);
+		drop(vec);
+		assert_eq!(cap.allocated(), 0);
+		#[cfg(feature = "overhead")]
+		assert_eq!(cap.live_allocations(), 0);
+	}
+
+	#[test]
+	fn ramp_limit() {
+		let cap: &'static Cap<alloc::System> = Box::leak(Box::new(Cap::new(alloc::System, 1000)));
+		let ramp = cap.ramp_limit(100, Duration::from_millis(10));
+		assert_eq!(ramp.join().unwrap(), Ok(()));
+		assert_eq!(cap.limit(), 100);
+		assert_eq!(cap.remaining(), 100);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn control_rss() {
+		let cap: &'static Cap<alloc::System> =
+			Box::leak(Box::new(Cap::new(alloc::System, usize::MAX)));
+		cap.charge(1000).unwrap();
+		let rss = crate::os::rss().unwrap();
+		let thread = cap.control_rss(rss + (1 << 30), Duration::from_millis(1));
+		while cap.limit() == usize::MAX {
+			thread::yield_now();
+		}
+		let limit = cap.limit();
+		assert!(limit > 1 << 29 && limit < 1 << 31, "{}", limit);
+		// Set by other means, so the thread exits.
+		cap.set_limit(usize::MAX).unwrap();
+		thread.join().unwrap();
+		assert_eq!(cap.limit(), usize::MAX);
+	}
+
+	#[cfg(feature = "chaos")]
+	#[test]
+	fn chaos() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		cap.set_failure_probability(1.0, 100);
+		unsafe {
+			let small = cap.alloc(Layout::new::<[u8; 10]>());
+			assert!(!small.is_null());
+			assert!(cap.alloc(Layout::new::<[u8; 200]>()).is_null());
+			assert_eq!(cap.injected_failures(), 1);
+			#[cfg(feature = "stats-counts")]
+			assert_eq!(cap.failure_count(), 1);
+			assert_eq!(cap.allocated(), cap.charged(Layout::new::<[u8; 10]>()));
+			cap.set_failure_probability(0.0, 0);
+			let large = cap.alloc(Layout::new::<[u8; 200]>());
+			assert!(!large.is_null());
+			// Growing reallocations fail, but not shrinking ones.
+			cap.set_failure_probability(1.0, 100);
+			assert!(cap
+				.realloc(large, Layout::new::<[u8; 200]>(), 400)
+				.is_null());
+			let large = cap.realloc(large, Layout::new::<[u8; 200]>(), 150);
+			assert!(!large.is_null());
+			assert_eq!(cap.injected_failures(), 2);
+			cap.set_failure_probability(0.0, 0);
+			cap.dealloc(large, Layout::new::<[u8; 150]>());
+			cap.dealloc(small, Layout::new::<[u8; 10]>());
+		}
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	#[cfg(feature = "chaos")]
+	#[test]
+	fn failure_scope() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		let layout = Layout::new::<[u8; 200]>();
+		cap.set_failure_probability(1.0, 0);
+		cap.set_failure_scope(crate::FailureScope::CurrentThread);
+		let other = thread::scope(|scope| {
+			scope
+				.spawn(|| unsafe { cap.alloc(layout) } as usize)
+				.join()
+				.unwrap() as *mut u8
+		});
+		unsafe {
+			assert!(cap.alloc(layout).is_null());
+			assert!(!other.is_null());
+			cap.dealloc(other, layout);
+		}
+		#[cfg(feature = "tags")]
+		{
+			let tag = crate::Tag::new("failure_scope");
+			cap.set_failure_scope(crate::FailureScope::Tag(tag));
+			unsafe {
+				let outside = cap.alloc(layout);
+				assert!(!outside.is_null());
+				let _guard = tag.enter();
+				assert!(cap.alloc(layout).is_null());
+				cap.dealloc(outside, layout);
+			}
+		}
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	#[test]
+	fn retry_after() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, 0);
+		assert_eq!(cap.retry_after(), None);
+		let layout = Layout::new::<u8>();
+		unsafe {
+			assert!(cap.alloc(layout).is_null());
+			let first = cap.retry_after().unwrap();
+			assert!(first <= Duration::from_millis(1));
+			for _ in 0..20 {
+				assert!(cap.alloc(layout).is_null());
+			}
+		}
+		let backoff = cap.retry_after().unwrap();
+		assert!(backoff > Duration::from_millis(500) && backoff <= Duration::from_secs(1));
+		thread::sleep(Duration::from_millis(2));
+		assert!(cap.retry_after().unwrap() < backoff);
+	}
+
+	#[cfg(feature = "breaker")]
+	#[test]
+	fn circuit_breaker() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, 100);
+		let (small, large) = (Layout::new::<[u8; 50]>(), Layout::new::<[u8; 200]>());
+		cap.set_circuit_breaker(3, Duration::from_secs(1), Duration::MAX);
+		unsafe {
+			// A success breaks the run.
+			for fail in [true, true, false, true, true] {
+				let ptr = cap.alloc(if fail { large } else { small });
+				assert_eq!(ptr.is_null(), fail);
+				if !fail {
+					cap.dealloc(ptr, small);
+				}
+			}
+			assert!(!cap.circuit_open());
+			assert!(cap.alloc(large).is_null());
+			assert!(cap.circuit_open());
+			// Critical allocations are let through, best-effort ones shed.
+			let mut ptr = cap.alloc(small);
+			assert!(!ptr.is_null());
+			{
+				let _best_effort = cap.best_effort();
+				assert!(cap.alloc(small).is_null());
+				assert_eq!(crate::last_rejection(), Some(crate::Rejection::Shed));
+				// Shrinks free memory, so aren't shed.
+				ptr = cap.realloc(ptr, small, 10);
+				assert!(!ptr.is_null());
+			}
+			cap.dealloc(ptr, Layout::new::<[u8; 10]>());
+		}
+		cap.set_circuit_breaker(0, Duration::ZERO, Duration::ZERO);
+		assert!(!cap.circuit_open());
+	}
+
+	#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn fast_path_codegen() {
+		// The generated code of the allocation fast path in examples/fast_path.rs.
+		use std::{fs, process::Command};
+		let target = std::env::temp_dir().join(format!("cap-fast-path-{}", std::process::id()));
+		let status = Command::new(env!("CARGO"))
+			.args([
+				"rustc",
+				"--quiet",
+				"--release",
+				"--example",
+				"fast_path",
+				"--target-dir",
+			])
+			.arg(&target)
+			.args(["--", "--emit", "asm"])
+			.current_dir(env!("CARGO_MANIFEST_DIR"))
+			.status()
+			.unwrap();
+		assert!(status.success());
+		let asm = fs::read_dir(target.join("release").join("examples"))
+			.unwrap()
+			.map(|entry| entry.unwrap().path())
+			.find(|path| path.extension().is_some_and(|extension| extension == "s"))
+			.map(|path| fs::read_to_string(path).unwrap())
+			.unwrap();
+		fs::remove_dir_all(&target).unwrap();
+		// Besides the wrapped allocator, everything called is out of line on a failure path: the charge that didn't fit, the wrapped allocator failing, the rejection, and panics on an invalid layout.
+		let inner = ["malloc", "calloc", "posix_memalign", "memset", "free"];
+		let failure = [
+			"6refund",
+			"13charge_exempt",
+			"16flush_and_charge",
+			"18retry_failed_inner",
+			"12count_failed",
+			"9rejection6reject",
+			"4core9panicking",
+			"13unwrap_failed",
+		];
+		for function in ["cap_fast_path_alloc", "cap_fast_path_dealloc"] {
+			let body = asm.split(&format!("\n{function}:")).nth(1).unwrap();
+			let body = &body[..body.find(".cfi_endproc").unwrap()];
+			let callees = body
+				.lines()
+				.filter(|line| line.trim_start().starts_with("call"))
+				.map(|line| line.split_whitespace().nth(1).unwrap())
+				.collect::<Vec<_>>();
+			assert!(callees
+				.iter()
+				.any(|callee| inner.iter().any(|symbol| callee.contains(symbol))));
+			for callee in &callees {
+				assert!(
+					inner
+						.iter()
+						.chain(&failure)
+						.any(|symbol| callee.contains(symbol)),
+					"{} is called from {}: {:#?}",
+					callee,
+					function,
+					callees
+				);
 			}
 		}
 	}
 
+	#[cfg(feature = "audit")]
 	#[test]
-	fn concurrent() {
-		let allocated = A.allocated();
-		for _ in 0..100 {
-			let threads = (0..100)
+	fn audit() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		cap.set_audit_hook(|_| ());
+		unsafe {
+			let ptr = cap.alloc(Layout::new::<[u8; 16]>());
+			let ptr = cap.realloc(ptr, Layout::new::<[u8; 16]>(), 32);
+			assert_eq!(cap.audit_errors(), 0);
+			cap.dealloc(ptr, Layout::new::<[u8; 16]>());
+			assert_eq!(cap.audit_errors(), 1);
+			let ptr = cap.alloc(Layout::new::<[u8; 16]>());
+			cap.dealloc(ptr, Layout::new::<[u8; 16]>());
+			assert_eq!(cap.audit_errors(), 1);
+		}
+	}
+
+	#[cfg(feature = "audit")]
+	#[test]
+	fn diagnostics_charged() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		let layout = Layout::new::<[u8; 16]>();
+		unsafe {
+			let ptr = cap.alloc(layout);
+			let bytes = cap.diagnostics_allocated();
+			assert!(bytes > 0);
+			assert_eq!(cap.allocated(), cap.charged(layout));
+			cap.set_diagnostics_charged(true).unwrap();
+			assert_eq!(cap.allocated(), cap.charged(layout) + bytes);
+			// Growing the table would exceed the limit, so further allocations go untracked.
+			cap.set_limit(cap.allocated() + 45_000).unwrap();
+			let ptrs = (0..800).map(|_| cap.alloc(layout)).collect::<Vec<_>>();
+			assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+			assert_eq!(cap.diagnostics_allocated(), bytes);
+			cap.set_diagnostics_charged(false).unwrap();
+			for ptr in ptrs.into_iter().chain([ptr]) {
+				cap.dealloc(ptr, layout);
+			}
+		}
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	#[cfg(feature = "audit")]
+	#[test]
+	fn oldest_allocations() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		unsafe {
+			let ptrs =
+				[1, 2, 3, 4].map(|size| cap.alloc(Layout::from_size_align(size, 1).unwrap()));
+			cap.dealloc(ptrs[0], Layout::from_size_align(1, 1).unwrap());
+			let moved = cap.realloc(ptrs[1], Layout::from_size_align(2, 1).unwrap(), 200);
+			let oldest = cap.oldest_allocations(2);
+			let oldest = oldest
+				.iter()
+				.map(|live| (live.id, live.ptr, live.layout.size()));
+			// Reallocation keeps the ID.
+			assert!(oldest.eq([(1, moved as usize, 200), (2, ptrs[2] as usize, 3)]));
+			assert_eq!(cap.oldest_allocations(10).len(), 3);
+			cap.dealloc(moved, Layout::from_size_align(200, 1).unwrap());
+			cap.dealloc(ptrs[2], Layout::from_size_align(3, 1).unwrap());
+			cap.dealloc(ptrs[3], Layout::from_size_align(4, 1).unwrap());
+		}
+		assert!(cap.oldest_allocations(10).is_empty());
+	}
+
+	#[cfg(feature = "audit")]
+	#[test]
+	fn heap_diff() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		unsafe {
+			let before = cap.alloc(Layout::new::<[u8; 10]>());
+			let from = cap.checkpoint();
+			let kept = cap.alloc(Layout::new::<[u8; 20]>());
+			let freed = cap.alloc(Layout::new::<[u8; 40]>());
+			cap.dealloc(freed, Layout::new::<[u8; 40]>());
+			let to = cap.checkpoint();
+			let after = cap.alloc(Layout::new::<[u8; 80]>());
+			let diff = cap.heap_diff(from, to);
+			assert!(diff
+				.iter()
+				.map(|group| (group.count, group.bytes))
+				.eq([(1, 20)]));
+			assert!(cap.heap_diff(to, from).is_empty());
+			cap.dealloc(before, Layout::new::<[u8; 10]>());
+			cap.dealloc(kept, Layout::new::<[u8; 20]>());
+			cap.dealloc(after, Layout::new::<[u8; 80]>());
+		}
+	}
+
+	#[cfg(feature = "audit")]
+	#[test]
+	fn double_free() {
+		use std::{
+			alloc::{GlobalAlloc, Layout}, ptr, sync::atomic::{AtomicUsize, Ordering}
+		};
+		static DOUBLE_FREES: AtomicUsize = AtomicUsize::new(0);
+		let cap = Cap::new(alloc::System, usize::MAX);
+		cap.set_audit_hook(|error| {
+			if let crate::AuditError::DoubleFree { .. } = error {
+				let _ = DOUBLE_FREES.fetch_add(1, Ordering::Relaxed);
+			}
+		});
+		unsafe {
+			let ptr = cap.alloc(Layout::new::<[u8; 16]>());
+			cap.dealloc(ptr, Layout::new::<[u8; 16]>());
+			// Not forwarded, so neither corrupts the heap nor the accounting.
+			cap.dealloc(ptr, Layout::new::<[u8; 16]>());
+			assert_eq!(DOUBLE_FREES.load(Ordering::Relaxed), 1);
+			assert_eq!(cap.allocated(), 0);
+			cap.dealloc(ptr::dangling_mut(), Layout::new::<[u8; 16]>());
+			assert_eq!(cap.audit_errors(), 2);
+			assert_eq!(cap.allocated(), 0);
+			// Nor are reallocations of them.
+			assert!(cap.realloc(ptr, Layout::new::<[u8; 16]>(), 32).is_null());
+			assert!(cap
+				.realloc(ptr::dangling_mut(), Layout::new::<[u8; 16]>(), 32)
+				.is_null());
+			assert_eq!((cap.audit_errors(), cap.allocated()), (4, 0));
+		}
+	}
+
+	#[cfg(feature = "audit")]
+	#[test]
+	fn audit_mismatch() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		cap.set_audit_hook(|_| ());
+		let (layout, wrong) = (Layout::new::<[u8; 64]>(), Layout::new::<[u8; 16]>());
+		unsafe {
+			// Forwarded with the layout allocated with, so that neither the heap nor the accounting is corrupted.
+			let ptr = cap.alloc(layout);
+			let ptr = cap.realloc(ptr, wrong, 128);
+			assert_eq!(cap.allocated(), cap.charged(Layout::new::<[u8; 128]>()));
+			cap.dealloc(ptr, wrong);
+		}
+		assert_eq!((cap.audit_errors(), cap.allocated()), (2, 0));
+	}
+
+	#[cfg(feature = "audit")]
+	#[test]
+	fn audit_report() {
+		use std::{
+			alloc::{GlobalAlloc, Layout}, ptr
+		};
+		// Exhausted, so the default report can only allocate as it is exempt.
+		let cap = Cap::new(alloc::System, 0);
+		unsafe { cap.dealloc(ptr::dangling_mut(), Layout::new::<[u8; 16]>()) };
+		assert_eq!((cap.audit_errors(), cap.allocated()), (1, 0));
+	}
+
+	#[cfg(feature = "audit")]
+	#[test]
+	fn audit_concurrent() {
+		use std::alloc::{GlobalAlloc, Layout};
+		static CAP: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+		CAP.set_audit_hook(|error| panic!("{}", error));
+		// Moving reallocations free addresses that other threads are allocated concurrently.
+		let threads = (0..8)
+			.map(|_| {
+				thread::spawn(|| unsafe {
+					for _ in 0..10_000 {
+						let mut layout = Layout::new::<[u8; 16]>();
+						let mut ptr = CAP.alloc(layout);
+						for size in [128, 512, 64] {
+							ptr = CAP.realloc(ptr, layout, size);
+							layout = Layout::from_size_align(size, 1).unwrap();
+						}
+						CAP.dealloc(ptr, layout);
+					}
+				})
+			})
+			.collect::<Vec<_>>();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+		assert_eq!((CAP.audit_errors(), CAP.allocated()), (0, 0));
+		assert!(CAP.oldest_allocations(1).is_empty());
+	}
+
+	// Tag headers and redzones aren't poisoned, so the wrapped allocator sees them.
+	#[cfg(all(feature = "poison", not(feature = "tags"), not(feature = "redzone")))]
+	#[test]
+	fn poison() {
+		use std::alloc::{GlobalAlloc, Layout};
+		#[derive(Debug)]
+		struct Poisoned;
+		unsafe impl GlobalAlloc for Poisoned {
+			unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+				alloc::System.alloc(layout)
+			}
+			unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+				let freed = std::slice::from_raw_parts(ptr, layout.size());
+				assert!(freed.iter().all(|&byte| byte == crate::POISON));
+				alloc::System.dealloc(ptr, layout);
+			}
+			unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+				let freed = std::slice::from_raw_parts(ptr.add(new_size), layout.size() - new_size);
+				assert!(freed.iter().all(|&byte| byte == crate::POISON));
+				alloc::System.realloc(ptr, layout, new_size)
+			}
+		}
+		let cap = Cap::new(Poisoned, usize::MAX);
+		unsafe {
+			let ptr = cap.alloc_zeroed(Layout::new::<[u8; 64]>());
+			let ptr = cap.realloc(ptr, Layout::new::<[u8; 64]>(), 16);
+			assert_eq!(*ptr, 0);
+			cap.dealloc(ptr, Layout::new::<[u8; 16]>());
+		}
+	}
+
+	// Poisoning overwrites the zeroes, and tag headers and redzones aren't zeroed.
+	#[cfg(all(
+		feature = "zeroize",
+		not(feature = "poison"),
+		not(feature = "tags"),
+		not(feature = "redzone")
+	))]
+	#[test]
+	fn zero_on_free() {
+		use std::alloc::{GlobalAlloc, Layout};
+		#[derive(Debug)]
+		struct Zeroed;
+		unsafe impl GlobalAlloc for Zeroed {
+			unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+				alloc::System.alloc(layout)
+			}
+			unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+				let freed = std::slice::from_raw_parts(ptr, layout.size());
+				assert!(freed.iter().all(|&byte| byte == 0));
+				alloc::System.dealloc(ptr, layout);
+			}
+			unsafe fn realloc(&self, _: *mut u8, _: Layout, _: usize) -> *mut u8 {
+				unreachable!("reallocations are moved by the cap")
+			}
+		}
+		let cap = Cap::new(Zeroed, usize::MAX);
+		cap.set_zero_on_free(true);
+		unsafe {
+			let ptr = cap.alloc(Layout::new::<[u8; 64]>());
+			ptr.write_bytes(0xaa, 64);
+			let ptr = cap.realloc(ptr, Layout::new::<[u8; 64]>(), 128);
+			assert_eq!(*ptr.add(63), 0xaa);
+			let ptr = cap.realloc(ptr, Layout::new::<[u8; 128]>(), 16);
+			assert_eq!((*ptr, cap.allocated()), (0xaa, 16));
+			cap.dealloc(ptr, Layout::new::<[u8; 16]>());
+		}
+	}
+
+	#[cfg(feature = "quarantine")]
+	#[test]
+	fn quarantine() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, 200);
+		cap.set_quarantine(100);
+		let charged = |size| cap.charged(Layout::from_size_align(size, 1).unwrap());
+		unsafe {
+			let small = cap.alloc(Layout::new::<[u8; 40]>());
+			cap.dealloc(small, Layout::new::<[u8; 40]>());
+			assert_eq!(
+				(cap.quarantined(), cap.allocated()),
+				(charged(40), charged(40))
+			);
+			let large = cap.alloc(Layout::new::<[u8; 80]>());
+			cap.dealloc(large, Layout::new::<[u8; 80]>());
+			// The oldest block is evicted to make room.
+			assert_eq!(
+				(cap.quarantined(), cap.allocated()),
+				(charged(80), charged(80))
+			);
+			// Flushed, as it wouldn't otherwise fit.
+			let huge = cap.alloc(Layout::new::<[u8; 150]>());
+			assert!(!huge.is_null());
+			assert_eq!((cap.quarantined(), cap.allocated()), (0, charged(150)));
+			cap.dealloc(huge, Layout::new::<[u8; 150]>());
+			// Too large to be quarantined.
+			assert_eq!((cap.quarantined(), cap.allocated()), (0, 0));
+			let small = cap.alloc(Layout::new::<[u8; 40]>());
+			cap.dealloc(small, Layout::new::<[u8; 40]>());
+			cap.flush_quarantine();
+			assert_eq!((cap.quarantined(), cap.allocated()), (0, 0));
+		}
+	}
+
+	#[cfg(feature = "redzone")]
+	#[test]
+	fn redzone() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX).with_redzone(8);
+		let padded = |layout| Cap::new(alloc::System, usize::MAX).charged(layout) + 16;
+		unsafe {
+			let ptr = cap.alloc(Layout::new::<[u8; 10]>());
+			assert_eq!(cap.allocated(), padded(Layout::new::<[u8; 10]>()));
+			ptr.write_bytes(1, 10);
+			let ptr = cap.realloc(ptr, Layout::new::<[u8; 10]>(), 100);
+			assert_eq!(cap.allocated(), padded(Layout::new::<[u8; 100]>()));
+			assert_eq!(*ptr.add(9), 1);
+			assert_eq!(cap.redzone_errors(), 0);
+			ptr.add(101).write(0);
+			cap.dealloc(ptr, Layout::new::<[u8; 100]>());
+			assert_eq!(cap.redzone_errors(), 1);
+			assert_eq!(cap.allocated(), 0);
+		}
+	}
+
+	#[test]
+	fn granularity() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX).with_granularity(16);
+		let rounded = |layout| {
+			Cap::new(alloc::System, usize::MAX)
+				.charged(layout)
+				.next_multiple_of(16)
+		};
+		unsafe {
+			let ptr = cap.alloc(Layout::new::<[u8; 20]>());
+			assert_eq!(cap.allocated(), rounded(Layout::new::<[u8; 20]>()));
+			let ptr = cap.realloc(ptr, Layout::new::<[u8; 20]>(), 48);
+			assert_eq!(cap.allocated(), rounded(Layout::new::<[u8; 48]>()));
+			cap.dealloc(ptr, Layout::new::<[u8; 48]>());
+		}
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	#[test]
+	fn exempt() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, 100);
+		let (small, large) = (Layout::new::<[u8; 50]>(), Layout::new::<[u8; 200]>());
+		unsafe {
+			let ptr = {
+				let _exempt = cap.exempt();
+				cap.alloc(large)
+			};
+			assert!(!ptr.is_null());
+			assert_eq!((cap.allocated(), cap.remaining()), (cap.charged(large), 0));
+			#[cfg(feature = "stats-counts")]
+			assert_eq!(cap.exempted_bytes(), cap.charged(large) - 100);
+			assert!(cap.alloc(small).is_null());
+			cap.dealloc(ptr, large);
+			assert_eq!((cap.allocated(), cap.remaining()), (0, 100));
+			let ptr = cap.alloc(small);
+			assert!(!ptr.is_null());
+			cap.dealloc(ptr, small);
+		}
+	}
+
+	#[test]
+	fn last_rejection() {
+		use std::alloc::{GlobalAlloc, Layout};
+		#[derive(Debug)]
+		struct Null;
+		unsafe impl GlobalAlloc for Null {
+			unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
+				std::ptr::null_mut()
+			}
+			unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+		}
+		let layout = Layout::new::<[u8; 200]>();
+		unsafe {
+			assert!(Cap::new(alloc::System, 100).alloc(layout).is_null());
+			assert_eq!(crate::last_rejection(), Some(crate::Rejection::Limit));
+			let cap = Cap::new(Null, usize::MAX);
+			assert!(cap.alloc(layout).is_null());
+			assert_eq!(crate::last_rejection(), Some(crate::Rejection::Inner));
+			assert_eq!(cap.allocated(), 0);
+		}
+	}
+
+	#[cfg(feature = "cap-group")]
+	#[test]
+	fn cap_group() {
+		use std::alloc::{GlobalAlloc, Layout};
+		static GROUP: crate::CapGroup = crate::CapGroup::new(1000);
+		let a = Cap::new(alloc::System, 800).with_group(&GROUP);
+		let b = Cap::new(alloc::System, usize::MAX).with_group(&GROUP);
+		let layout = Layout::new::<[u8; 600]>();
+		unsafe {
+			let ptr = a.alloc(layout);
+			assert!(!ptr.is_null());
+			// Within b's own limit, but not the group's.
+			assert!(b.alloc(layout).is_null());
+			assert_eq!(crate::last_rejection(), Some(crate::Rejection::Limit));
+			assert_eq!((GROUP.allocated(), b.allocated()), (a.charged(layout), 0));
+			a.dealloc(ptr, layout);
+			let ptr = b.alloc(layout);
+			assert!(!ptr.is_null());
+			b.dealloc(ptr, layout);
+		}
+		assert_eq!(
+			(GROUP.allocated(), GROUP.max_allocated()),
+			(0, a.charged(layout))
+		);
+		assert!(GROUP.set_limit(500).is_ok());
+		assert_eq!(GROUP.remaining(), 500);
+	}
+
+	#[cfg(feature = "stats-churn")]
+	#[test]
+	fn churn() {
+		use std::{
+			alloc::{GlobalAlloc, Layout}, sync::atomic::{AtomicUsize, Ordering}
+		};
+		/// Moves every reallocation, by way of the default `realloc`.
+		#[derive(Debug)]
+		struct Moving;
+		unsafe impl GlobalAlloc for Moving {
+			unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+				alloc::System.alloc(layout)
+			}
+			unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+				alloc::System.dealloc(ptr, layout);
+			}
+		}
+		static CALLS: AtomicUsize = AtomicUsize::new(0);
+		static COPIED: AtomicUsize = AtomicUsize::new(0);
+		let cap = Cap::new(Moving, usize::MAX);
+		cap.set_churn_hook(3, |churn| {
+			let _ = CALLS.fetch_add(1, Ordering::Relaxed);
+			COPIED.store(churn.copied, Ordering::Relaxed);
+			assert_eq!((churn.copies, churn.size), (3, 64));
+		});
+		unsafe {
+			let mut ptr = cap.alloc(Layout::new::<[u8; 8]>());
+			for size in [16, 32, 64, 128] {
+				ptr = cap.realloc(ptr, Layout::from_size_align(size / 2, 1).unwrap(), size);
+			}
+			ptr = cap.realloc(ptr, Layout::new::<[u8; 128]>(), 8);
+			cap.dealloc(ptr, Layout::new::<[u8; 8]>());
+		}
+		#[cfg(feature = "stats-counts")]
+		assert_eq!(cap.realloc_copied_bytes(), 8 + 16 + 32 + 64 + 8);
+		assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+		assert_eq!(COPIED.load(Ordering::Relaxed), 8 + 16 + 32);
+	}
+
+	#[test]
+	fn realloc_outcomes() {
+		use std::{
+			alloc::{GlobalAlloc, Layout}, sync::atomic::{AtomicBool, Ordering}
+		};
+		/// Fails every allocation and reallocation while `self.0` is set.
+		#[derive(Debug)]
+		struct Failing(AtomicBool);
+		unsafe impl GlobalAlloc for Failing {
+			unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+				if self.0.load(Ordering::Relaxed) {
+					return std::ptr::null_mut();
+				}
+				alloc::System.alloc(layout)
+			}
+			unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+				alloc::System.dealloc(ptr, layout);
+			}
+			unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+				if self.0.load(Ordering::Relaxed) {
+					return std::ptr::null_mut();
+				}
+				alloc::System.realloc(ptr, layout, new_size)
+			}
+		}
+		let cap = Cap::new(Failing(AtomicBool::new(false)), 100).with_granularity(8);
+		let charged = |size| cap.charged(Layout::from_size_align(size, 1).unwrap());
+		let intact =
+			|ptr: *mut u8, len: u8| (0..len).all(|i| unsafe { *ptr.add(usize::from(i)) } == i);
+		unsafe {
+			let mut ptr = cap.alloc(Layout::new::<[u8; 64]>());
+			for i in 0..64 {
+				*ptr.add(usize::from(i)) = i;
+			}
+			// Over the limit.
+			assert!(cap.realloc(ptr, Layout::new::<[u8; 64]>(), 200).is_null());
+			assert_eq!(cap.allocated(), charged(64));
+			// Within the same granule.
+			assert_eq!(charged(60), charged(64));
+			ptr = cap.realloc(ptr, Layout::new::<[u8; 64]>(), 60);
+			assert_eq!(cap.allocated(), charged(64));
+			ptr = cap.realloc(ptr, Layout::new::<[u8; 60]>(), 64);
+			assert_eq!(cap.allocated(), charged(64));
+			assert!(intact(ptr, 60));
+			// The wrapped allocator failing to grow and to shrink.
+			cap.allocator().0.store(true, Ordering::Relaxed);
+			assert!(cap.realloc(ptr, Layout::new::<[u8; 64]>(), 80).is_null());
+			assert!(cap.realloc(ptr, Layout::new::<[u8; 64]>(), 16).is_null());
+			assert_eq!(cap.allocated(), charged(64));
+			assert!(intact(ptr, 60));
+			cap.allocator().0.store(false, Ordering::Relaxed);
+			ptr = cap.realloc(ptr, Layout::new::<[u8; 64]>(), 80);
+			assert_eq!(cap.allocated(), charged(80));
+			ptr = cap.realloc(ptr, Layout::new::<[u8; 80]>(), 16);
+			assert_eq!(cap.allocated(), charged(16));
+			assert!(intact(ptr, 16));
+			cap.dealloc(ptr, Layout::new::<[u8; 16]>());
+		}
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	#[cfg(feature = "consistency")]
+	#[test]
+	fn consistency() {
+		use std::{
+			alloc::{GlobalAlloc, Layout}, sync::Mutex
+		};
+		static FOUND: Mutex<Vec<crate::Inconsistency>> = Mutex::new(Vec::new());
+		let cap = Cap::new(alloc::System, 100);
+		cap.set_inconsistency_action(crate::InconsistencyAction::Hook(|inconsistency| {
+			FOUND.lock().unwrap().push(*inconsistency);
+		}));
+		unsafe {
+			let ptr = cap.alloc(Layout::new::<[u8; 50]>());
+			assert_eq!(cap.check_consistency(), Ok(()));
+			cap.dealloc(ptr, Layout::new::<[u8; 50]>());
+		}
+		assert_eq!(cap.inconsistencies(), 0);
+		cap.uncharge(10);
+		assert_eq!(cap.inconsistencies(), 2);
+		let exceeds = crate::Inconsistency::RemainingExceedsLimit {
+			remaining: 110,
+			limit: 100,
+		};
+		assert_eq!(
+			*FOUND.lock().unwrap(),
+			[
+				crate::Inconsistency::ExternalExceedsAllocated {
+					external: 10_usize.wrapping_neg(),
+					allocated: 0
+				},
+				exceeds
+			]
+		);
+		assert_eq!(cap.check_consistency(), Err(exceeds));
+	}
+
+	#[cfg(feature = "consistency")]
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn consistency_concurrent() {
+		use std::{
+			alloc::{GlobalAlloc, Layout}, sync::atomic::{AtomicBool, Ordering}
+		};
+		// A false positive would abort the tests.
+		let cap = Cap::new(alloc::System, 64 * 1024);
+		cap.set_inconsistency_action(crate::InconsistencyAction::Panic);
+		let stop = AtomicBool::new(false);
+		thread::scope(|scope| {
+			let _ = scope.spawn(|| {
+				for i in 0.. {
+					if stop.load(Ordering::Relaxed) {
+						break;
+					}
+					let _ = cap.set_limit(if i % 2 == 0 { 32 * 1024 } else { 64 * 1024 });
+				}
+			});
+			let threads = (0..4)
 				.map(|_| {
-					thread::spawn(|| {
-						for i in 0..1000 {
-							let _ = (0..i).collect::<Vec<u32>>();
-							let _ = (0..i).flat_map(std::iter::once).collect::<Vec<u32>>();
+					scope.spawn(|| {
+						let layout = Layout::new::<[u8; 4096]>();
+						for _ in 0..10_000 {
+							// Often doesn't fit, wrapping the bytes remaining while it is refunded.
+							let ptrs = [(); 8].map(|()| unsafe { cap.alloc(layout) });
+							for &ptr in ptrs.iter().filter(|ptr| !ptr.is_null()) {
+								unsafe { cap.dealloc(ptr, layout) };
+							}
 						}
 					})
 				})
 				.collect::<Vec<_>>();
-			threads
-				.into_iter()
-				.for_each(|thread| thread.join().unwrap());
-			let allocated2 = A.allocated();
-			#[cfg(feature = "stats")]
-			let total_allocated = A.total_allocated();
-			if cfg!(all(test, feature = "nightly")) {
-				assert_eq!(allocated, allocated2);
-				#[cfg(feature = "stats")]
-				assert!(total_allocated >= allocated);
+			for thread in threads {
+				thread.join().unwrap();
 			}
+			stop.store(true, Ordering::Relaxed);
+		});
+		assert_eq!(cap.inconsistencies(), 0);
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	#[cfg(all(feature = "consistency", debug_assertions))]
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn consistency_panic() {
+		// Aborting, so in a child process running just this test.
+		if std::env::var_os("CAP_CONSISTENCY_PANIC").is_some() {
+			let cap = Cap::new(alloc::System, 100);
+			cap.set_inconsistency_action(crate::InconsistencyAction::Panic);
+			cap.uncharge(10);
+			return;
 		}
-		#[cfg(feature = "stats")]
-		assert!(A.max_allocated() < A.total_allocated());
+		let output = std::process::Command::new(std::env::current_exe().unwrap())
+			.args(["--exact", "tests::consistency_panic", "--nocapture"])
+			.env("CAP_CONSISTENCY_PANIC", "1")
+			.output()
+			.unwrap();
+		assert!(!output.status.success());
+		let stderr = String::from_utf8_lossy(&output.stderr);
+		assert!(
+			stderr.contains("cap: inconsistent accounting"),
+			"{}",
+			stderr
+		);
 	}
 
-	#[cfg(all(test, not(feature = "nightly")))]
 	#[test]
-	fn limit() {
-		#[cfg(feature = "stats")]
-		let initial = A.allocated();
-		let allocate_amount = 30 * 1024 * 1024;
-		A.set_limit(A.allocated() + allocate_amount).unwrap();
-		for _ in 0..10 {
-			let mut vec = Vec::<u8>::with_capacity(0);
-			if let Err(_e) = vec.try_reserve_exact(allocate_amount + 1) {
-			} else {
-				A.set_limit(usize::max_value()).unwrap();
-				panic!("{}", A.remaining());
+	fn on_limit_change() {
+		use std::{
+			alloc::{GlobalAlloc, Layout}, sync::atomic::{AtomicUsize, Ordering}
+		};
+		static CHANGES: AtomicUsize = AtomicUsize::new(0);
+		static CAP: Cap<alloc::System> = Cap::new(alloc::System, 1000);
+		static REPORTED: AtomicUsize = AtomicUsize::new(0);
+		static OTHER: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+		let cap = Cap::new(alloc::System, 1000);
+		cap.on_limit_change(|old, new| {
+			assert_eq!((old, new), (1000, 500));
+			let _ = CHANGES.fetch_add(1, Ordering::Relaxed);
+		});
+		cap.set_limit(500).unwrap();
+		cap.set_limit(500).unwrap();
+		assert_eq!(CHANGES.load(Ordering::Relaxed), 1);
+		// A hook that allocates and changes the limit itself doesn't recurse.
+		CAP.on_limit_change(|_, new| {
+			let _ = CHANGES.fetch_add(1, Ordering::Relaxed);
+			let _ = CAP.set_limit(new - 1);
+			let layout = Layout::new::<[u8; 2000]>();
+			unsafe {
+				let ptr = CAP.alloc(layout);
+				assert!(!ptr.is_null());
+				CAP.dealloc(ptr, layout);
+			}
+		});
+		CAP.set_limit(900).unwrap();
+		// The change to 899 it made itself isn't reported.
+		assert_eq!((CAP.limit(), CHANGES.load(Ordering::Relaxed)), (899, 2));
+		// Changes made by other hooks are.
+		OTHER.on_limit_change(|_, new| REPORTED.store(new, Ordering::Relaxed));
+		OTHER.set_inner_failure_hook(|_| {
+			OTHER.set_limit(1 << 63).unwrap();
+			false
+		});
+		assert!(unsafe { OTHER.alloc(Layout::from_size_align(1 << 62, 1).unwrap()) }.is_null());
+		assert_eq!(
+			(OTHER.limit(), REPORTED.load(Ordering::Relaxed)),
+			(1 << 63, 1 << 63)
+		);
+	}
+
+	#[test]
+	fn can_allocate() {
+		use std::alloc::Layout;
+		let layout = |size| Layout::from_size_align(size, 1).unwrap();
+		let limit = Cap::new(alloc::System, usize::MAX).charged(layout(100));
+		let cap = Cap::new(alloc::System, limit);
+		assert!(cap.can_allocate(layout(100)));
+		assert!(!cap.can_allocate(layout(200)));
+		assert_eq!(cap.would_exceed(layout(100)), None);
+		assert_eq!(
+			cap.would_exceed(layout(150)),
+			Some(cap.charged(layout(150)) - limit)
+		);
+		let huge = layout(isize::MAX as usize);
+		assert_eq!(cap.would_exceed(huge), Some(cap.charged(huge) - limit));
+	}
+
+	#[test]
+	fn advise_cache_budget() {
+		let cap = Cap::new(alloc::System, 2000);
+		cap.set_soft_limit(1000);
+		cap.charge(200).unwrap();
+		assert_eq!(cap.advise_cache_budget(0.5), 400);
+		cap.charge(400).unwrap();
+		// Less a margin for the 400 byte swing.
+		assert_eq!(cap.advise_cache_budget(1.0), 0);
+		cap.uncharge(400);
+		assert_eq!(cap.advise_cache_budget(1.0), 400);
+		assert_eq!(cap.advise_cache_budget(1.0), 450);
+		cap.uncharge(200);
+	}
+
+	#[test]
+	fn inner_failure() {
+		use std::{
+			alloc::{GlobalAlloc, Layout}, sync::atomic::{AtomicUsize, Ordering}
+		};
+		/// Fails the next `self.0` allocations.
+		#[derive(Debug)]
+		struct Flaky(AtomicUsize);
+		unsafe impl GlobalAlloc for Flaky {
+			unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+				let fail = self
+					.0
+					.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+				if fail.is_ok() {
+					return std::ptr::null_mut();
+				}
+				alloc::System.alloc(layout)
+			}
+			unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+				alloc::System.dealloc(ptr, layout);
+			}
+		}
+		let cap = Cap::new(Flaky(AtomicUsize::new(2)), usize::MAX);
+		let layout = Layout::new::<[u8; 16]>();
+		unsafe {
+			assert!(cap.alloc(layout).is_null());
+			cap.set_inner_retries(1);
+			let ptr = cap.alloc(layout);
+			assert!(!ptr.is_null());
+			cap.dealloc(ptr, layout);
+			cap.allocator().0.store(2, Ordering::Relaxed);
+			cap.set_inner_failure_hook(|_| true);
+			let ptr = cap.alloc(layout);
+			assert!(!ptr.is_null());
+			cap.dealloc(ptr, layout);
+		}
+		#[cfg(feature = "stats-counts")]
+		assert_eq!((cap.failure_count(), cap.inner_failure_count()), (1, 1));
+		assert_eq!(cap.allocated(), 0);
+	}
+
+	#[cfg(feature = "tags")]
+	#[test]
+	fn groups() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, 1000);
+		let a = cap.group("a", 1);
+		let b = cap.group("b", 3);
+		assert_eq!((a.share(), b.share()), (250, 750));
+		let layout = Layout::new::<[u8; 300]>();
+		unsafe {
+			let guard = a.enter();
+			// a may exceed its share while the cap isn't under pressure...
+			let x = cap.alloc(layout);
+			assert!(!x.is_null());
+			assert_eq!(a.allocated(), cap.charged(layout));
+			// ...but not once it is.
+			cap.set_group_pressure(0.5);
+			assert!(cap.alloc(layout).is_null());
+			drop(guard);
+			let guard = b.enter();
+			let y = cap.alloc(Layout::new::<[u8; 600]>());
+			assert!(!y.is_null());
+			drop(guard);
+			cap.dealloc(x, layout);
+			cap.dealloc(y, Layout::new::<[u8; 600]>());
+		}
+		assert_eq!((cap.allocated(), a.allocated(), b.allocated()), (0, 0, 0));
+		drop(b);
+		assert_eq!(a.share(), 1000);
+	}
+
+	#[cfg(feature = "tags")]
+	#[test]
+	#[should_panic(expected = "has a weight of 0")]
+	fn groups_misregistered() {
+		use std::panic::{self, AssertUnwindSafe};
+		let cap = Cap::new(alloc::System, 1000);
+		let a = cap.group("misregistered", 1);
+		let _b = cap.group("other", 1);
+		assert!(panic::catch_unwind(AssertUnwindSafe(|| cap.group("misregistered", 3))).is_err());
+		assert_eq!((a.weight(), a.share()), (1, 500));
+		let _ = cap.group("zero", 0);
+	}
+
+	#[cfg(feature = "tags")]
+	#[test]
+	fn tag_limit() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		let tag = crate::Tag::new("tag_limit");
+		cap.set_tag_limit(tag, 100).unwrap();
+		let layout = Layout::new::<[u8; 60]>();
+		unsafe {
+			let (x, y) = {
+				let _guard = tag.enter();
+				(cap.alloc(layout), cap.alloc(layout))
 			};
-			assert_eq!(vec.try_reserve_exact(allocate_amount), Ok(()));
-			let mut vec2 = Vec::<u8>::with_capacity(0);
-			assert!(vec2.try_reserve_exact(1).is_err());
+			assert!(!x.is_null() && y.is_null());
+			assert!(cap.set_tag_limit(tag, 50).is_err());
+			assert_eq!(cap.tag_limit(tag), 100);
+			let y = cap.alloc(layout);
+			assert!(!y.is_null());
+			cap.dealloc(x, layout);
+			cap.dealloc(y, layout);
 		}
-		// Might have additional allocations of errors and what not along the way.
-		#[cfg(feature = "stats")]
-		{
-			assert!(A.total_allocated() >= initial + 10 * allocate_amount);
-			assert_eq!(A.max_allocated(), initial + allocate_amount);
+		assert_eq!(cap.tag_allocated(tag), 0);
+	}
+
+	#[cfg(feature = "overhead")]
+	#[test]
+	fn overhead() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, 1000);
+		let overhead = cap.calibrate_overhead();
+		assert!(overhead <= 256, "{}", overhead);
+		assert_eq!(cap.overhead_per_allocation(), overhead);
+		cap.set_overhead(100);
+		let layout = Layout::new::<[u8; 300]>();
+		unsafe {
+			let x = cap.alloc(layout);
+			let y = cap.alloc(layout);
+			assert!(!x.is_null() && !y.is_null());
+			assert_eq!((cap.live_allocations(), cap.estimated_overhead()), (2, 200));
+			// 400 bytes remain, but 200 of them are estimated to be taken by overhead.
+			assert!(cap.alloc(layout).is_null());
+			cap.dealloc(x, layout);
+			cap.dealloc(y, layout);
 		}
+		assert_eq!(cap.live_allocations(), 0);
 	}
 
-	#[cfg(all(test, feature = "nightly"))]
+	#[cfg(feature = "committed")]
 	#[test]
-	fn limit() {
-		let allocate_amount = 30 * 1024 * 1024;
-		A.set_limit(A.allocated() + allocate_amount).unwrap();
-		for _ in 0..10 {
-			let mut vec = Vec::<u8>::with_capacity(0);
-			if let Err(TryReserveError::AllocError { .. }) =
-				vec.try_reserve_exact(allocate_amount + 1)
+	fn committed_limit() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, 1000);
+		cap.set_committed_limit(1500).unwrap();
+		cap.reserve(1000).unwrap();
+		assert!(cap.reserve(600).is_err());
+		let layout = Layout::new::<[u8; 400]>();
+		unsafe {
+			let x = cap.alloc(layout);
+			assert!(!x.is_null());
+			assert!(cap.charge(200).is_err());
+			assert!(!cap.can_allocate(Layout::new::<[u8; 200]>()));
+			// Nor are jumbo allocations admitted beyond the committed limit.
+			cap.set_jumbo_threshold(100);
+			let jumbo = Layout::new::<[u8; 200]>();
 			{
-			} else {
-				A.set_limit(usize::max_value()).unwrap();
-				panic!("{}", A.remaining())
+				let _guard = crate::wait_for_budget(Duration::from_millis(10));
+				assert!(cap.alloc(jumbo).is_null());
+			}
+			let charged = cap.charged(layout);
+			assert_eq!(
+				(cap.committed(), cap.remaining()),
+				(1000 + charged, 1000 - charged)
+			);
+			assert!(cap.set_committed_limit(1000).is_err());
+			cap.unreserve(1000);
+			assert!(cap.charge(200).is_ok());
+			cap.uncharge(200);
+			cap.dealloc(x, layout);
+		}
+		assert_eq!((cap.committed(), cap.committed_limit()), (0, 1500));
+	}
+
+	#[cfg(feature = "tags")]
+	#[test]
+	fn transfer_tag() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		let layout = Layout::new::<[u8; 100]>();
+		let (producer, consumer) = (
+			crate::Tag::new("transfer_producer"),
+			crate::Tag::new("transfer_consumer"),
+		);
+		unsafe {
+			let x = {
+				let _guard = producer.enter();
+				cap.alloc(layout)
 			};
-			assert_eq!(vec.try_reserve_exact(allocate_amount), Ok(()));
-			let mut vec2 = Vec::<u8>::with_capacity(0);
-			assert!(vec2.try_reserve_exact(1).is_err());
+			cap.set_tag_limit(consumer, 50).unwrap();
+			assert!(cap.transfer_tag(x, layout, Some(consumer)).is_err());
+			cap.set_tag_limit(consumer, usize::MAX).unwrap();
+			cap.transfer_tag(x, layout, Some(consumer)).unwrap();
+			assert_eq!(
+				(cap.tag_allocated(producer), cap.tag_allocated(consumer)),
+				(0, cap.charged(layout))
+			);
+			cap.dealloc(x, layout);
+		}
+		assert_eq!(cap.tag_allocated(consumer), 0);
+	}
+
+	#[cfg(all(feature = "tags", feature = "stats-tags"))]
+	#[test]
+	fn tag_peak() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		let tag = crate::Tag::new("tag_peak");
+		let stats = |cap: &Cap<alloc::System>| {
+			cap.stats_by_tag()
+				.into_iter()
+				.find(|stats| stats.tag == tag)
+				.unwrap()
+		};
+		assert_eq!(stats(&cap).peak_time, None);
+		let before = std::time::SystemTime::now();
+		unsafe {
+			let _guard = tag.enter();
+			let x = cap.alloc(Layout::new::<[u8; 300]>());
+			let y = cap.alloc(Layout::new::<[u8; 100]>());
+			cap.dealloc(x, Layout::new::<[u8; 300]>());
+			let z = cap.alloc(Layout::new::<[u8; 200]>());
+			cap.dealloc(y, Layout::new::<[u8; 100]>());
+			cap.dealloc(z, Layout::new::<[u8; 200]>());
+		}
+		let peak =
+			cap.charged(Layout::new::<[u8; 300]>()) + cap.charged(Layout::new::<[u8; 100]>());
+		let stats = stats(&cap);
+		assert_eq!((stats.allocated, stats.peak), (0, peak));
+		assert!(stats.peak_time.unwrap() >= before);
+		assert_eq!(cap.tag_peak(tag), peak);
+	}
+
+	#[cfg(feature = "tags")]
+	#[test]
+	fn tag_tree() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		let layout = Layout::new::<[u8; 100]>();
+		let (outer, inner) = (crate::Tag::new("tree_outer"), crate::Tag::new("tree_inner"));
+		unsafe {
+			let (x, y) = {
+				let _outer = outer.enter();
+				let x = cap.alloc(layout);
+				let _inner = inner.enter();
+				(x, cap.alloc(layout))
+			};
+			let tree = cap.tag_tree();
+			let node = tree.iter().find(|node| node.tag == outer).unwrap();
+			let charged = cap.charged(layout);
+			assert_eq!(
+				(node.self_bytes, node.total_bytes()),
+				(charged, 2 * charged)
+			);
+			assert_eq!(node.children.len(), 1);
+			assert_eq!(
+				(node.children[0].name, node.children[0].self_bytes),
+				("tree_inner", charged)
+			);
+			assert!(tree.iter().all(|node| node.tag != inner));
+			cap.dealloc(x, layout);
+			cap.dealloc(y, layout);
+		}
+	}
+
+	#[cfg(feature = "tags")]
+	#[test]
+	fn top_tags_report() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		let (small, large) = (
+			crate::Tag::new("report_small"),
+			crate::Tag::new("report_large"),
+		);
+		unsafe {
+			let x = {
+				let _guard = small.enter();
+				cap.alloc(Layout::new::<[u8; 10]>())
+			};
+			let y = {
+				let _guard = large.enter();
+				cap.alloc(Layout::new::<[u8; 100]>())
+			};
+			let expected = format!(
+				"report_large: {}\nreport_small: {}\n",
+				cap.tag_allocated(large),
+				cap.tag_allocated(small)
+			);
+			let mut buf = [0; 256];
+			assert_eq!(cap.top_tags_report(&mut buf), expected);
+			let mut buf = [0; 16];
+			assert_eq!(cap.top_tags_report(&mut buf), &expected[..16]);
+			cap.dealloc(x, Layout::new::<[u8; 10]>());
+			cap.dealloc(y, Layout::new::<[u8; 100]>());
+		}
+	}
+
+	#[cfg(feature = "stats-threads")]
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn thread_stats() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap: &'static Cap<alloc::System> =
+			Box::leak(Box::new(Cap::new(alloc::System, usize::MAX)));
+		thread::spawn(move || unsafe {
+			cap.register_thread("worker").unwrap();
+			let a = cap.alloc(Layout::new::<[u8; 100]>());
+			let b = cap.alloc(Layout::new::<[u8; 50]>());
+			cap.dealloc(a, Layout::new::<[u8; 100]>());
+			cap.dealloc(b, Layout::new::<[u8; 50]>());
+		})
+		.join()
+		.unwrap();
+		let stats = cap.thread_stats();
+		assert_eq!(stats.len(), 1);
+		assert_eq!(
+			(stats[0].name, stats[0].live, stats[0].peak),
+			("worker", 0, 150)
+		);
+	}
+
+	#[cfg(feature = "stats-peaks")]
+	#[test]
+	fn peak_info() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		assert_eq!(cap.peak_info(), None);
+		#[cfg(feature = "tags")]
+		let guard = crate::tag("peak_info");
+		let before = std::time::SystemTime::now();
+		unsafe {
+			let ptr = cap.alloc(Layout::new::<[u8; 100]>());
+			cap.dealloc(ptr, Layout::new::<[u8; 100]>());
+		}
+		#[cfg(feature = "tags")]
+		drop(guard);
+		let peak = cap.peak_info().unwrap();
+		assert_eq!(peak.allocated, cap.charged(Layout::new::<[u8; 100]>()));
+		assert!(peak.time >= before);
+		#[cfg(feature = "tags")]
+		assert_eq!(peak.tag, Some(crate::Tag::new("peak_info")));
+	}
+
+	#[cfg(feature = "stats-peaks")]
+	#[test]
+	fn peak_since_last_call() {
+		use std::alloc::{GlobalAlloc, Layout};
+		let cap = Cap::new(alloc::System, usize::MAX);
+		let (large, small) = (
+			cap.charged(Layout::new::<[u8; 100]>()),
+			cap.charged(Layout::new::<[u8; 10]>()),
+		);
+		unsafe {
+			let ptr = cap.alloc(Layout::new::<[u8; 100]>());
+			cap.dealloc(ptr, Layout::new::<[u8; 100]>());
+			assert_eq!(cap.peak_since_last_call(), large);
+			assert_eq!(cap.peak_since_last_call(), 0);
+			let ptr = cap.alloc(Layout::new::<[u8; 10]>());
+			assert_eq!(cap.peak_since_last_call(), small);
+			assert_eq!(cap.peak_since_last_call(), small);
+			cap.dealloc(ptr, Layout::new::<[u8; 10]>());
 		}
-		assert_eq!(A.total_allocated(), 10 * allocate_amount);
-		assert_eq!(A.max_allocated(), allocate_amount)
+		assert_eq!(cap.max_allocated(), large);
+	}
+
+	#[cfg(feature = "tags")]
+	#[test]
+	fn spawn_tagged() {
+		let tag = crate::Tag::new("spawn_tagged");
+		let _guard = tag.enter();
+		let inherited = crate::spawn_tagged(crate::current_tag).join().unwrap();
+		assert_eq!(inherited, Some(tag));
+		assert_eq!(thread::spawn(crate::current_tag).join().unwrap(), None);
+	}
+
+	#[cfg(feature = "tags")]
+	#[test]
+	fn start_handler() {
+		let tag = crate::Tag::new("start_handler");
+		let handler = tag.start_handler();
+		let current = thread::spawn(move || {
+			handler(0);
+			crate::current_tag()
+		});
+		assert_eq!(current.join().unwrap(), Some(tag));
+		assert_eq!(crate::current_tag(), None);
+	}
+
+	#[cfg(feature = "tags")]
+	#[test]
+	fn instrument() {
+		use std::{
+			future::Future, pin::pin, task::{Context, Poll, Waker}
+		};
+		let tag = crate::Tag::new("instrument");
+		let future = pin!(tag.instrument(async { crate::current_tag() }));
+		let poll = future.poll(&mut Context::from_waker(Waker::noop()));
+		assert_eq!(poll, Poll::Ready(Some(tag)));
+		assert_eq!(crate::current_tag(), None);
 	}
 }
@@ -0,0 +1,71 @@
+//! Windows' low memory resource notification.
+
+use std::{
+	ffi::{c_int, c_void}, io, time::Duration
+};
+
+use crate::{Cap, Monitor, PressureEvent};
+
+const LOW_MEMORY_RESOURCE_NOTIFICATION: c_int = 0;
+const WAIT_OBJECT_0: u32 = 0;
+const WAIT_TIMEOUT: u32 = 0x102;
+
+/// How long, in milliseconds, the monitoring thread waits for a notification at a time, before checking whether it has been stopped.
+const POLL_MILLIS: u32 = 100;
+
+#[link(name = "kernel32")]
+extern "system" {
+	fn CreateMemoryResourceNotification(notification_type: c_int) -> *mut c_void;
+	fn QueryMemoryResourceNotification(handle: *mut c_void, state: *mut c_int) -> c_int;
+	fn WaitForSingleObject(handle: *mut c_void, milliseconds: u32) -> u32;
+	fn CloseHandle(handle: *mut c_void) -> c_int;
+}
+
+struct Handle(*mut c_void);
+
+// Safe as the handle is only waited on and queried, which Windows permits from any thread.
+unsafe impl Send for Handle {}
+
+impl Drop for Handle {
+	fn drop(&mut self) {
+		let _ = unsafe { CloseHandle(self.0) };
+	}
+}
+
+impl<H> Cap<H> {
+	/// Wait on a background thread for Windows to signal that the machine's available physical memory is low, delivering a [`PressureEvent::LowMemory`] to the [pressure callbacks](Self::on_pressure) each time it does.
+	///
+	/// This lets services shed load when the machine, not just the process, is low on memory. While memory remains low, the state is rechecked every `interval` rather than delivering further events; once it recovers, the next episode is waited for. Waiting continues until the returned [`Monitor`] is dropped.
+	pub fn monitor_low_memory(&'static self, interval: Duration) -> io::Result<Monitor>
+	where
+		H: Sync,
+	{
+		let handle = unsafe { CreateMemoryResourceNotification(LOW_MEMORY_RESOURCE_NOTIFICATION) };
+		if handle.is_null() {
+			return Err(io::Error::last_os_error());
+		}
+		let handle = Handle(handle);
+		Ok(Monitor::spawn(move |stop| {
+			let handle = handle;
+			'episodes: loop {
+				match unsafe { WaitForSingleObject(handle.0, POLL_MILLIS) } {
+					WAIT_OBJECT_0 => (),
+					WAIT_TIMEOUT if !stop.is_stopped() => continue,
+					_ => return,
+				}
+				self.notify_pressure(PressureEvent::LowMemory);
+				loop {
+					if !stop.sleep(interval) {
+						break 'episodes;
+					}
+					let mut low = 0;
+					if unsafe { QueryMemoryResourceNotification(handle.0, &mut low) } == 0
+						|| low == 0
+					{
+						break;
+					}
+				}
+			}
+		}))
+	}
+}
@@ -0,0 +1,103 @@
+//! Background threads that run until their guard is dropped.
+
+use std::{
+	fmt, sync::{Arc, Condvar, Mutex, PoisonError}, thread, time::Duration
+};
+
+/// A background thread started by a method such as `Cap::monitor_psi`, which is stopped, and waited for, when this is dropped.
+#[must_use = "the thread is stopped when this is dropped"]
+pub struct Monitor {
+	stop: Arc<Stop>,
+	thread: Option<thread::JoinHandle<()>>,
+}
+
+/// The flag the thread of a [`Monitor`] checks between sleeps, which are cut short when it is set.
+#[derive(Default)]
+pub(crate) struct Stop {
+	stopped: Mutex<bool>,
+	condvar: Condvar,
+}
+
+impl Stop {
+	/// Sleep for `interval`, returning whether the thread is to carry on, which is false as soon as it is stopped.
+	pub(crate) fn sleep(&self, interval: Duration) -> bool {
+		let stopped = self.stopped.lock().unwrap_or_else(PoisonError::into_inner);
+		let (stopped, _) = self
+			.condvar
+			.wait_timeout_while(stopped, interval, |stopped| !*stopped)
+			.unwrap_or_else(PoisonError::into_inner);
+		!*stopped
+	}
+
+	/// Whether the thread has been stopped.
+	#[cfg(any(all(feature = "uds", unix), all(feature = "low-memory", windows)))]
+	pub(crate) fn is_stopped(&self) -> bool {
+		*self.stopped.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+impl Monitor {
+	/// Run `f` on a new thread, passing it the flag that is set once this is dropped.
+	pub(crate) fn spawn(f: impl FnOnce(&Stop) + Send + 'static) -> Self {
+		let stop = Arc::new(Stop::default());
+		let stop_ = stop.clone();
+		let thread = thread::spawn(move || f(&stop_));
+		Self {
+			stop,
+			thread: Some(thread),
+		}
+	}
+}
+
+impl Drop for Monitor {
+	fn drop(&mut self) {
+		*self
+			.stop
+			.stopped
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner) = true;
+		self.stop.condvar.notify_all();
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+impl fmt::Debug for Monitor {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Monitor")
+			.field("thread", &self.thread)
+			.finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		sync::{
+			atomic::{AtomicUsize, Ordering}, Arc
+		}, time::{Duration, Instant}
+	};
+
+	use super::Monitor;
+
+	#[test]
+	fn stop() {
+		let runs = Arc::new(AtomicUsize::new(0));
+		let runs_ = runs.clone();
+		let monitor = Monitor::spawn(move |stop| loop {
+			let _ = runs_.fetch_add(1, Ordering::Relaxed);
+			if !stop.sleep(Duration::from_secs(100)) {
+				break;
+			}
+		});
+		while runs.load(Ordering::Relaxed) == 0 {
+			std::thread::yield_now();
+		}
+		// The sleep is cut short, so dropping doesn't wait out the interval.
+		let stopping = Instant::now();
+		drop(monitor);
+		assert!(stopping.elapsed() < Duration::from_secs(10));
+		assert_eq!(runs.load(Ordering::Relaxed), 1);
+	}
+}
@@ -0,0 +1,151 @@
+//! Queries of the operating system's view of the process's memory.
+
+#[cfg(target_os = "linux")]
+use std::{
+	convert::TryFrom, ffi::{c_int, c_long}, fs, io::{self, Read}, path::Path, str
+};
+
+#[cfg(target_os = "linux")]
+extern "C" {
+	fn sysconf(name: c_int) -> c_long;
+}
+
+#[cfg(target_os = "linux")]
+const SC_PAGESIZE: c_int = 30;
+
+/// Read the small text file `path`, such as those in `/proc`, into `buf`.
+///
+/// Unlike [`fs::read_to_string`] this doesn't allocate, so that monitoring threads keep working while the limit is exhausted, and don't cause allocation failures elsewhere.
+#[cfg(target_os = "linux")]
+pub(crate) fn read_small(path: impl AsRef<Path>, buf: &mut [u8]) -> io::Result<&str> {
+	let mut file = fs::File::open(path)?;
+	let mut len = 0;
+	while len < buf.len() {
+		match file.read(&mut buf[len..])? {
+			0 => break,
+			n => len += n,
+		}
+	}
+	str::from_utf8(&buf[..len]).map_err(|_| io::ErrorKind::InvalidData.into())
+}
+
+/// Return field `field` of `/proc/self/statm` converted from pages to bytes.
+#[cfg(target_os = "linux")]
+fn statm(field: usize) -> Option<usize> {
+	let mut buf = [0; 128];
+	let statm = read_small("/proc/self/statm", &mut buf).ok()?;
+	let pages: usize = statm.split_whitespace().nth(field)?.parse().ok()?;
+	let page_size = usize::try_from(unsafe { sysconf(SC_PAGESIZE) }).ok()?;
+	pages.checked_mul(page_size)
+}
+
+/// Return the resident set size of this process in bytes, if it can be determined on this platform.
+#[cfg(target_os = "linux")]
+pub(crate) fn rss() -> Option<usize> {
+	statm(1)
+}
+
+/// Return the resident set size of this process in bytes, if it can be determined on this platform.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn rss() -> Option<usize> {
+	None
+}
+
+/// Return the virtual memory size of this process in bytes, if it can be determined on this platform.
+#[cfg(all(feature = "compare", target_os = "linux"))]
+pub(crate) fn virtual_size() -> Option<usize> {
+	statm(0)
+}
+
+/// Return the virtual memory size of this process in bytes, if it can be determined on this platform.
+#[cfg(all(feature = "compare", not(target_os = "linux")))]
+pub(crate) fn virtual_size() -> Option<usize> {
+	None
+}
+
+/// Return the path of the file `name` in this process's cgroup v2 directory, if there is one.
+#[cfg(all(
+	target_os = "linux",
+	any(feature = "cgroup", feature = "psi", feature = "k8s")
+))]
+pub(crate) fn cgroup_file(name: &str) -> Option<std::path::PathBuf> {
+	let cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+	let relative = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+	let path = Path::new("/sys/fs/cgroup")
+		.join(relative.trim_start_matches('/'))
+		.join(name);
+	path.exists().then_some(path)
+}
+
+/// Parse the `some` and `full` `avg10` percentages from the contents of a PSI file.
+#[cfg(all(target_os = "linux", any(feature = "psi", feature = "k8s")))]
+pub(crate) fn parse_psi(text: &str) -> Option<(f64, f64)> {
+	let avg10 = |prefix: &str| {
+		text.lines()
+			.find_map(|line| line.strip_prefix(prefix))?
+			.split_whitespace()
+			.find_map(|field| field.strip_prefix("avg10="))?
+			.parse()
+			.ok()
+	};
+	Some((avg10("some ")?, avg10("full ")?))
+}
+
+/// The memory PSI file: the cgroup's, so that pressure from its memory limit is seen, falling back to the system's.
+#[cfg(all(target_os = "linux", any(feature = "psi", feature = "k8s")))]
+pub(crate) fn psi_path() -> std::path::PathBuf {
+	cgroup_file("memory.pressure")
+		.unwrap_or_else(|| std::path::PathBuf::from("/proc/pressure/memory"))
+}
+
+/// Return the memory limit of this process's cgroup in bytes, if it has one.
+#[cfg(all(feature = "k8s", target_os = "linux"))]
+pub(crate) fn cgroup_memory_max() -> Option<usize> {
+	/// cgroup v1 reports no limit as a large page-aligned number, rather than `max`.
+	const V1_UNLIMITED: usize = 1 << 60;
+	let mut buf = [0; 64];
+	if let Some(path) = cgroup_file("memory.max") {
+		return read_small(path, &mut buf).ok()?.trim().parse().ok();
+	}
+	let v1 = read_small("/sys/fs/cgroup/memory/memory.limit_in_bytes", &mut buf).ok()?;
+	v1.trim()
+		.parse()
+		.ok()
+		.filter(|&limit: &usize| limit < V1_UNLIMITED)
+}
+
+/// Return the memory limit of this process's cgroup in bytes, if it has one.
+#[cfg(all(feature = "k8s", not(target_os = "linux")))]
+pub(crate) fn cgroup_memory_max() -> Option<usize> {
+	None
+}
+
+/// Return the memory usage of this process's cgroup in bytes, if it has one.
+#[cfg(all(feature = "k8s", target_os = "linux"))]
+pub(crate) fn cgroup_memory_current() -> Option<usize> {
+	let mut buf = [0; 64];
+	let text = match cgroup_file("memory.current") {
+		Some(path) => read_small(path, &mut buf),
+		None => read_small("/sys/fs/cgroup/memory/memory.usage_in_bytes", &mut buf),
+	};
+	text.ok()?.trim().parse().ok()
+}
+
+/// Return the memory usage of this process's cgroup in bytes, if it has one.
+#[cfg(all(feature = "k8s", not(target_os = "linux")))]
+pub(crate) fn cgroup_memory_current() -> Option<usize> {
+	None
+}
+
+/// Return the percentage of the last 10 seconds in which all non-idle tasks were stalled waiting for memory, if pressure stall information is available.
+#[cfg(all(feature = "k8s", target_os = "linux"))]
+pub(crate) fn memory_pressure_full() -> Option<f64> {
+	let mut buf = [0; 256];
+	parse_psi(read_small(psi_path(), &mut buf).ok()?).map(|(_, full)| full)
+}
+
+/// Return the percentage of the last 10 seconds in which all non-idle tasks were stalled waiting for memory, if pressure stall information is available.
+#[cfg(all(feature = "k8s", not(target_os = "linux")))]
+pub(crate) fn memory_pressure_full() -> Option<f64> {
+	None
+}
@@ -0,0 +1,63 @@
+use std::{
+	sync::atomic::{AtomicU64, AtomicUsize, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+#[cfg(feature = "tags")]
+use crate::Tag;
+
+/// When the peak number of bytes was allocated, and in what context, as returned by [`Cap::peak_info`](crate::Cap::peak_info).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PeakInfo {
+	/// The peak number of bytes allocated, i.e. [`max_allocated`](crate::Cap::max_allocated).
+	pub allocated: usize,
+	/// When it was reached.
+	pub time: SystemTime,
+	/// The tag entered on the thread that reached it, if any.
+	#[cfg(feature = "tags")]
+	pub tag: Option<Tag>,
+	/// An opaque identifier of the thread that reached it, unique among live threads.
+	pub thread: usize,
+}
+
+/// The context of a [`Cap`](crate::Cap)'s peak.
+#[derive(Debug)]
+pub(crate) struct Peak {
+	time: AtomicU64,
+	tag: AtomicUsize,
+	thread: AtomicUsize,
+}
+
+impl Peak {
+	pub(crate) const fn new() -> Self {
+		Self {
+			time: AtomicU64::new(0),
+			tag: AtomicUsize::new(0),
+			thread: AtomicUsize::new(0),
+		}
+	}
+
+	/// Record that a new peak has been reached by the calling thread, with `tag` entered.
+	pub(crate) fn record(&self, tag: usize) {
+		#[allow(clippy::cast_possible_truncation)]
+		let time = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |since| since.as_nanos() as u64);
+		self.time.store(time, Ordering::Relaxed);
+		self.tag.store(tag, Ordering::Relaxed);
+		self.thread
+			.store(crate::events::thread_id(), Ordering::Relaxed);
+	}
+
+	pub(crate) fn info(&self, allocated: usize) -> PeakInfo {
+		#[cfg(not(feature = "tags"))]
+		let _ = &self.tag;
+		PeakInfo {
+			allocated,
+			time: UNIX_EPOCH + Duration::from_nanos(self.time.load(Ordering::Relaxed)),
+			#[cfg(feature = "tags")]
+			tag: Tag::from_index(self.tag.load(Ordering::Relaxed)),
+			thread: self.thread.load(Ordering::Relaxed),
+		}
+	}
+}
@@ -0,0 +1,244 @@
+//! Publication of a [`Cap`]'s counters as Windows performance counters, so that perfmon, and monitoring agents that collect performance counters, can read them.
+
+use std::{ffi::c_void, io, iter, ptr, thread, time::Duration};
+
+use crate::Cap;
+
+/// The GUID of the provider, `{6bc4ddfa-feff-44f0-914c-dc1f347f072b}`.
+const PROVIDER: Guid = Guid(
+	0x6bc4_ddfa,
+	0xfeff,
+	0x44f0,
+	[0x91, 0x4c, 0xdc, 0x1f, 0x34, 0x7f, 0x07, 0x2b],
+);
+/// The GUID of the counter set, `{011a0303-c03d-4256-8f8e-3315f6f508a4}`.
+const COUNTER_SET: Guid = Guid(
+	0x011a_0303,
+	0xc03d,
+	0x4256,
+	[0x8f, 0x8e, 0x33, 0x15, 0xf6, 0xf5, 0x08, 0xa4],
+);
+
+/// The ids of the counters, as in the manifest.
+const ALLOCATED: u32 = 1;
+const LIMIT: u32 = 2;
+const REMAINING: u32 = 3;
+const TOTAL_ALLOCATED: u32 = 4;
+const MAX_ALLOCATED: u32 = 5;
+const COUNTERS: usize = 5;
+
+/// `PERF_COUNTER_LARGE_RAWCOUNT`, a 64-bit gauge.
+const LARGE_RAWCOUNT: u32 = 0x0001_0100;
+/// `PERF_COUNTERSET_MULTI_INSTANCES`.
+const MULTI_INSTANCES: u32 = 2;
+/// `PERF_DETAIL_NOVICE`.
+const DETAIL_NOVICE: u32 = 100;
+
+type Handle = *mut c_void;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Guid(u32, u16, u16, [u8; 8]);
+
+/// `PERF_COUNTERSET_INFO`.
+#[repr(C)]
+struct CounterSetInfo {
+	counter_set: Guid,
+	provider: Guid,
+	counters: u32,
+	instance_type: u32,
+}
+
+/// `PERF_COUNTER_INFO`.
+#[repr(C)]
+struct CounterInfo {
+	id: u32,
+	kind: u32,
+	attributes: u64,
+	size: u32,
+	detail_level: u32,
+	scale: i32,
+	offset: u32,
+}
+
+/// A `PERF_COUNTERSET_INFO` followed by the `PERF_COUNTER_INFO` of each of its counters, as `PerfSetCounterSetInfo` takes them.
+#[repr(C)]
+struct Template {
+	info: CounterSetInfo,
+	counters: [CounterInfo; COUNTERS],
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+	fn PerfStartProvider(
+		provider: *const Guid, callback: *const c_void, handle: *mut Handle,
+	) -> u32;
+	fn PerfStopProvider(handle: Handle) -> u32;
+	fn PerfSetCounterSetInfo(handle: Handle, template: *const CounterSetInfo, size: u32) -> u32;
+	fn PerfCreateInstance(
+		handle: Handle, counter_set: *const Guid, name: *const u16, id: u32,
+	) -> *mut c_void;
+	fn PerfDeleteInstance(handle: Handle, instance: *mut c_void) -> u32;
+	fn PerfSetULongLongCounterValue(
+		handle: Handle, instance: *mut c_void, counter: u32, value: u64,
+	) -> u32;
+}
+
+fn template() -> Template {
+	let counter = |id: u32| CounterInfo {
+		id,
+		kind: LARGE_RAWCOUNT,
+		attributes: 0,
+		size: 8,
+		detail_level: DETAIL_NOVICE,
+		scale: 0,
+		offset: (id - 1) * 8,
+	};
+	Template {
+		info: CounterSetInfo {
+			counter_set: COUNTER_SET,
+			provider: PROVIDER,
+			#[allow(clippy::cast_possible_truncation)]
+			counters: COUNTERS as u32,
+			instance_type: MULTI_INSTANCES,
+		},
+		counters: [
+			counter(ALLOCATED),
+			counter(LIMIT),
+			counter(REMAINING),
+			counter(TOTAL_ALLOCATED),
+			counter(MAX_ALLOCATED),
+		],
+	}
+}
+
+/// Turn a Win32 error code into a `Result`.
+fn check(res: u32) -> io::Result<()> {
+	if res == 0 {
+		Ok(())
+	} else {
+		#[allow(clippy::cast_possible_wrap)]
+		Err(io::Error::from_raw_os_error(res as i32))
+	}
+}
+
+/// A started provider, and the instance of the counter set it publishes, both torn down when dropped.
+struct Provider {
+	handle: Handle,
+	instance: *mut c_void,
+}
+
+// Safe as PerfLib's functions can be called with the handles from any thread.
+unsafe impl Send for Provider {}
+
+impl Provider {
+	fn start(instance: &str) -> io::Result<Self> {
+		let mut handle = ptr::null_mut();
+		check(unsafe { PerfStartProvider(&PROVIDER, ptr::null(), &raw mut handle) })?;
+		let mut provider = Self {
+			handle,
+			instance: ptr::null_mut(),
+		};
+		let template = template();
+		#[allow(clippy::cast_possible_truncation)]
+		check(unsafe {
+			PerfSetCounterSetInfo(
+				handle,
+				(&raw const template).cast(),
+				size_of::<Template>() as u32,
+			)
+		})?;
+		let name = instance
+			.encode_utf16()
+			.chain(iter::once(0))
+			.collect::<Vec<_>>();
+		provider.instance =
+			unsafe { PerfCreateInstance(handle, &COUNTER_SET, name.as_ptr(), std::process::id()) };
+		if provider.instance.is_null() {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(provider)
+	}
+
+	fn set(&self, counter: u32, value: usize) -> io::Result<()> {
+		check(unsafe {
+			PerfSetULongLongCounterValue(self.handle, self.instance, counter, value as u64)
+		})
+	}
+
+	fn update<H>(&self, cap: &Cap<H>) -> io::Result<()> {
+		self.set(ALLOCATED, cap.allocated())?;
+		self.set(LIMIT, cap.limit())?;
+		self.set(REMAINING, cap.remaining())?;
+		#[cfg(feature = "stats-counts")]
+		self.set(TOTAL_ALLOCATED, cap.total_allocated())?;
+		#[cfg(feature = "stats-peaks")]
+		self.set(MAX_ALLOCATED, cap.max_allocated())?;
+		Ok(())
+	}
+}
+
+impl Drop for Provider {
+	fn drop(&mut self) {
+		unsafe {
+			if !self.instance.is_null() {
+				let _ = PerfDeleteInstance(self.handle, self.instance);
+			}
+			let _ = PerfStopProvider(self.handle);
+		}
+	}
+}
+
+impl<H> Cap<H> {
+	/// Publish this allocator's counters as Windows performance counters, in the instance `instance` of the counter set `Cap Memory`, updating them every `interval` on a background thread.
+	///
+	/// The counters are the bytes allocated, the limit, the bytes remaining and, with the `stats-counts` and `stats-peaks` features, the total and peak bytes allocated, for perfmon to read or monitoring agents that collect performance counters to scrape.
+	///
+	/// They are published through version 2 of the performance counter API, so are only visible once the counter set has been registered, typically at install time, by running `lodctr /m:cap.man` with a manifest such as:
+	///
+	/// ```xml
+	/// <instrumentationManifest xmlns="http://schemas.microsoft.com/win/2004/08/events">
+	///   <instrumentation>
+	///     <counters xmlns="http://schemas.microsoft.com/win/2005/12/counters" schemaVersion="2.0">
+	///       <provider providerName="cap" providerGuid="{6bc4ddfa-feff-44f0-914c-dc1f347f072b}" providerType="userMode" applicationIdentity="app.exe" symbol="CapProvider">
+	///         <counterSet guid="{011a0303-c03d-4256-8f8e-3315f6f508a4}" uri="Cap.Memory" name="Cap Memory" description="Memory tracked by cap" symbol="CapMemory" instances="multiple">
+	///           <counter id="1" uri="Cap.Memory.Allocated" name="Bytes Allocated" description="Bytes allocated" type="perf_counter_large_rawcount" detailLevel="standard"/>
+	///           <counter id="2" uri="Cap.Memory.Limit" name="Limit" description="The limit in bytes" type="perf_counter_large_rawcount" detailLevel="standard"/>
+	///           <counter id="3" uri="Cap.Memory.Remaining" name="Bytes Remaining" description="Bytes remaining within the limit" type="perf_counter_large_rawcount" detailLevel="standard"/>
+	///           <counter id="4" uri="Cap.Memory.TotalAllocated" name="Total Bytes Allocated" description="Bytes allocated in total" type="perf_counter_large_rawcount" detailLevel="standard"/>
+	///           <counter id="5" uri="Cap.Memory.MaxAllocated" name="Peak Bytes Allocated" description="The peak bytes allocated" type="perf_counter_large_rawcount" detailLevel="standard"/>
+	///         </counterSet>
+	///       </provider>
+	///     </counters>
+	///   </instrumentation>
+	/// </instrumentationManifest>
+	/// ```
+	///
+	/// where `applicationIdentity` names the executable. The thread exits, withdrawing the instance, if updating the counters fails.
+	pub fn export_perf_counters(
+		&'static self, instance: &str, interval: Duration,
+	) -> io::Result<thread::JoinHandle<()>>
+	where
+		H: Sync,
+	{
+		let provider = Provider::start(instance)?;
+		Ok(thread::spawn(move || {
+			while provider.update(self).is_ok() {
+				thread::sleep(interval);
+			}
+		}))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{CounterInfo, CounterSetInfo, Template, COUNTERS};
+
+	#[test]
+	fn template_layout() {
+		// As in perflib.h.
+		assert_eq!(size_of::<CounterSetInfo>(), 40);
+		assert_eq!(size_of::<CounterInfo>(), 32);
+		assert_eq!(size_of::<Template>(), 40 + 32 * COUNTERS);
+	}
+}
@@ -0,0 +1,228 @@
+#[cfg(feature = "stats-counts")]
+use std::sync::atomic::AtomicU32;
+use std::{
+	fmt, sync::{
+		atomic::{AtomicUsize, Ordering}, Arc, Mutex, PoisonError
+	}
+};
+
+use crate::reentrancy;
+
+/// A signal of memory pressure from outside the allocator, delivered to callbacks registered with [`Cap::on_pressure`](crate::Cap::on_pressure).
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum PressureEvent {
+	/// A sample of the kernel's pressure stall information for memory, taken by [`Cap::monitor_psi`](crate::Cap::monitor_psi).
+	Psi {
+		/// The percentage of the last 10 seconds in which some tasks were stalled waiting for memory.
+		some: f64,
+		/// The percentage of the last 10 seconds in which all non-idle tasks were stalled waiting for memory.
+		full: f64,
+	},
+	/// The kernel has throttled or reclaimed from this process's cgroup, or invoked the OOM killer within it, as seen by [`Cap::monitor_cgroup_events`](crate::Cap::monitor_cgroup_events).
+	Cgroup(CgroupEvents),
+	/// Windows has signalled that the machine's available physical memory is low, as seen by `Cap::monitor_low_memory`.
+	LowMemory,
+	/// macOS's memory pressure level has changed, as seen by `Cap::monitor_memory_pressure`.
+	MemoryPressure(MemoryPressureLevel),
+}
+
+/// The level of macOS's memory pressure, as delivered in [`PressureEvent::MemoryPressure`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryPressureLevel {
+	/// Pressure has returned to normal.
+	Normal,
+	/// The system is under pressure, and processes should free memory they can.
+	Warning,
+	/// The system is under critical pressure, and processes may soon be terminated.
+	Critical,
+}
+
+/// Counts of the events in a cgroup's `memory.events` file, as delivered in [`PressureEvent::Cgroup`].
+///
+/// These are deltas since the previous sample.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CgroupEvents {
+	/// The number of times the cgroup was reclaimed from despite being under its `memory.low` boundary.
+	pub low: u64,
+	/// The number of times the cgroup was throttled and reclaimed from for exceeding its `memory.high` boundary.
+	pub high: u64,
+	/// The number of times the cgroup's usage was about to exceed `memory.max`, forcing reclaim.
+	pub max: u64,
+	/// The number of times the cgroup reached `memory.max` and reclaim failed, invoking the OOM killer.
+	pub oom: u64,
+	/// The number of processes in the cgroup killed by the OOM killer.
+	pub oom_kill: u64,
+}
+
+type Callback = Arc<dyn Fn(&PressureEvent) + Send + Sync>;
+
+/// The callbacks registered with a [`Cap`](crate::Cap) to receive [`PressureEvent`]s.
+pub(crate) struct Callbacks(Mutex<Vec<Callback>>);
+
+impl Callbacks {
+	pub(crate) const fn new() -> Self {
+		Self(Mutex::new(Vec::new()))
+	}
+
+	pub(crate) fn push(&self, callback: Callback) {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.push(callback);
+	}
+
+	/// Call each callback with `event`, as [hooks](crate#hooks) are, on a snapshot of them taken without holding the lock, so that a callback may register another.
+	pub(crate) fn notify(&self, event: &PressureEvent) {
+		let callbacks = self
+			.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.clone();
+		for callback in callbacks {
+			let _ = reentrancy::call(|| callback(event));
+		}
+	}
+}
+
+impl fmt::Debug for Callbacks {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let len = self.0.lock().unwrap_or_else(PoisonError::into_inner).len();
+		f.debug_struct("Callbacks").field("len", &len).finish()
+	}
+}
+
+/// The state behind [`Cap::advise_cache_budget`](crate::Cap::advise_cache_budget).
+#[derive(Debug)]
+pub(crate) struct Advisor {
+	/// The bytes allocated at the previous call, or `usize::MAX` before the first.
+	allocated: AtomicUsize,
+	volatility: AtomicUsize,
+}
+
+impl Advisor {
+	pub(crate) const fn new() -> Self {
+		Self {
+			allocated: AtomicUsize::new(usize::MAX),
+			volatility: AtomicUsize::new(0),
+		}
+	}
+
+	/// Record `allocated`, returning the recent volatility: the largest change in it between calls, decaying by an eighth each call.
+	pub(crate) fn volatility(&self, allocated: usize) -> usize {
+		let previous = self.allocated.swap(allocated, Ordering::Relaxed);
+		let change = if previous == usize::MAX {
+			0
+		} else {
+			allocated.abs_diff(previous)
+		};
+		let mut volatility = self.volatility.load(Ordering::Relaxed);
+		volatility = change.max(volatility - volatility / 8);
+		self.volatility.store(volatility, Ordering::Relaxed);
+		volatility
+	}
+}
+
+/// The state behind [`Cap::pressure`](crate::Cap::pressure).
+#[cfg(feature = "stats-counts")]
+#[derive(Debug)]
+pub(crate) struct Pressure {
+	/// The weights of usage, rate and failures, one per byte.
+	weights: AtomicU32,
+	allocated: AtomicUsize,
+	failures: AtomicUsize,
+}
+
+#[cfg(feature = "stats-counts")]
+impl Pressure {
+	pub(crate) const fn new() -> Self {
+		Self {
+			weights: AtomicU32::new(u32::from_le_bytes([60, 20, 20, 0])),
+			allocated: AtomicUsize::new(0),
+			failures: AtomicUsize::new(0),
+		}
+	}
+
+	pub(crate) fn set_weights(&self, usage: u8, rate: u8, failures: u8) {
+		self.weights.store(
+			u32::from_le_bytes([usage, rate, failures, 0]),
+			Ordering::Relaxed,
+		);
+	}
+
+	/// Score the current state, and start a new interval for the rate and failure components.
+	pub(crate) fn score(&self, allocated: usize, limit: usize, failures: usize) -> u8 {
+		let [usage_weight, rate_weight, failures_weight, _] =
+			self.weights.load(Ordering::Relaxed).to_le_bytes();
+		let previous = self.allocated.swap(allocated, Ordering::Relaxed);
+		let previous_failures = self.failures.swap(failures, Ordering::Relaxed);
+		#[allow(clippy::cast_precision_loss)]
+		let (allocated, limit, previous) = (allocated as f64, limit as f64, previous as f64);
+		let usage = if limit == 0.0 { 1.0 } else { allocated / limit };
+		// The fraction of the headroom that remained at the last call that has since been consumed.
+		let rate = if allocated > previous {
+			(allocated - previous) / (limit - previous).max(1.0)
+		} else {
+			0.0
+		};
+		let failed = if failures > previous_failures {
+			1.0
+		} else {
+			0.0
+		};
+		let total = f64::from(usage_weight) + f64::from(rate_weight) + f64::from(failures_weight);
+		if total == 0.0 {
+			return 0;
+		}
+		let score = (f64::from(usage_weight) * usage.min(1.0)
+			+ f64::from(rate_weight) * rate.min(1.0)
+			+ f64::from(failures_weight) * failed)
+			/ total;
+		#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+		let score = (score * 100.0).round() as u8;
+		score
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{
+		atomic::{AtomicUsize, Ordering}, Arc
+	};
+
+	#[cfg(feature = "stats-counts")]
+	use super::Pressure;
+	use super::{Callbacks, PressureEvent};
+
+	#[cfg(feature = "stats-counts")]
+	#[test]
+	fn score() {
+		let pressure = Pressure::new();
+		assert_eq!(pressure.score(0, 1000, 0), 0);
+		assert_eq!(pressure.score(500, 1000, 0), 40);
+		assert_eq!(pressure.score(500, 1000, 0), 30);
+		assert_eq!(pressure.score(500, 1000, 1), 50);
+		pressure.set_weights(1, 0, 0);
+		assert_eq!(pressure.score(1000, 1000, 1), 100);
+		pressure.set_weights(0, 0, 0);
+		assert_eq!(pressure.score(1000, 1000, 1), 0);
+	}
+
+	#[test]
+	fn register_from_callback() {
+		static CALLS: AtomicUsize = AtomicUsize::new(0);
+		let callbacks = Arc::new(Callbacks::new());
+		let callbacks_ = callbacks.clone();
+		callbacks.push(Arc::new(move |_| {
+			let _ = CALLS.fetch_add(1, Ordering::Relaxed);
+			callbacks_.push(Arc::new(|_| {
+				let _ = CALLS.fetch_add(1, Ordering::Relaxed);
+			}));
+		}));
+		callbacks.notify(&PressureEvent::LowMemory);
+		assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+		callbacks.notify(&PressureEvent::LowMemory);
+		assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+	}
+}
@@ -0,0 +1,99 @@
+//! Monitoring of Linux's pressure stall information.
+
+use std::{fs, io, time::Duration};
+
+use crate::{os, Cap, Monitor, PressureEvent};
+
+impl<H> Cap<H> {
+	/// Sample the kernel's memory pressure stall information every `interval` on a background thread, delivering each sample to the [pressure callbacks](Self::on_pressure) as a [`PressureEvent::Psi`].
+	///
+	/// This catches trouble the byte counter can't see, such as the page cache being squeezed or other processes in the cgroup using memory. The cgroup's `memory.pressure` is read if available, otherwise the system-wide `/proc/pressure/memory`.
+	///
+	/// If `tighten_above` is given, then while the `some` percentage exceeds it the [soft limit](Self::soft_limit) is lowered to the number of bytes allocated, if that is lower, so that consumers of [`over_soft_limit`](Self::over_soft_limit) shed memory. The previous soft limit is restored once pressure subsides.
+	///
+	/// This method will return `Err` if pressure stall information isn't available, as on kernels before 4.20 or without `CONFIG_PSI`. Otherwise sampling continues until the returned [`Monitor`] is dropped, which restores the soft limit if it was lowered.
+	pub fn monitor_psi(
+		&'static self, interval: Duration, tighten_above: Option<f64>,
+	) -> io::Result<Monitor>
+	where
+		H: Sync,
+	{
+		let path = os::psi_path();
+		let _ = fs::read_to_string(&path)?;
+		Ok(Monitor::spawn(move |stop| {
+			let mut restore = None;
+			loop {
+				let mut buf = [0; 256];
+				if let Some((some, full)) =
+					os::read_small(&path, &mut buf).ok().and_then(os::parse_psi)
+				{
+					match (tighten_above, restore) {
+						(Some(threshold), None) if some > threshold => {
+							let soft_limit = self.soft_limit();
+							restore = Some(soft_limit);
+							self.set_soft_limit_with_reason(
+								soft_limit.min(self.allocated()),
+								"psi",
+							);
+						}
+						(Some(threshold), Some(soft_limit)) if some <= threshold => {
+							restore = None;
+							self.set_soft_limit_with_reason(soft_limit, "psi");
+						}
+						_ => (),
+					}
+					self.notify_pressure(PressureEvent::Psi { some, full });
+				}
+				if !stop.sleep(interval) {
+					break;
+				}
+			}
+			if let Some(soft_limit) = restore {
+				self.set_soft_limit_with_reason(soft_limit, "psi");
+			}
+		}))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::System, sync::{
+			atomic::{AtomicBool, Ordering}, Arc
+		}, thread, time::{Duration, Instant}
+	};
+
+	use crate::{Cap, PressureEvent};
+
+	#[test]
+	fn parse() {
+		let text = "some avg10=1.50 avg60=0.00 avg300=0.00 total=0\nfull avg10=0.25 avg60=0.00 avg300=0.00 total=0\n";
+		assert_eq!(crate::os::parse_psi(text), Some((1.5, 0.25)));
+		assert_eq!(crate::os::parse_psi("some avg10=1.50"), None);
+	}
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn monitor_psi() {
+		let cap: &'static Cap<System> = Box::leak(Box::new(Cap::new(System, usize::MAX)));
+		let sampled = Arc::new(AtomicBool::new(false));
+		let sampled_ = sampled.clone();
+		cap.on_pressure(move |event| {
+			assert!(matches!(event, PressureEvent::Psi { .. }));
+			sampled_.store(true, Ordering::Relaxed);
+		});
+		let Ok(monitor) = cap.monitor_psi(Duration::from_millis(100), Some(-1.0)) else {
+			// Pressure stall information isn't available on this kernel.
+			return;
+		};
+		while !sampled.load(Ordering::Relaxed) {
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert_eq!(cap.soft_limit(), 0);
+		// Stopped promptly, rather than after the next sample, restoring the soft limit.
+		let stopping = Instant::now();
+		drop(monitor);
+		assert!(stopping.elapsed() < Duration::from_millis(100));
+		assert_eq!(cap.soft_limit(), usize::MAX);
+	}
+}
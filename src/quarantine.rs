@@ -0,0 +1,141 @@
+//! Delayed release of freed blocks, to catch use-after-free while they are still poisoned.
+
+use std::{
+	alloc::Layout, cell::UnsafeCell, fmt, hint, ptr, sync::atomic::{AtomicBool, AtomicUsize, Ordering}
+};
+
+/// The maximum number of blocks held at once.
+const CAPACITY: usize = 256;
+
+/// A block held in quarantine.
+#[derive(Clone, Copy)]
+pub(crate) struct Entry {
+	/// The block to pass to the wrapped allocator.
+	pub(crate) base: *mut u8,
+	/// The layout to pass alongside it.
+	pub(crate) layout: Layout,
+	/// The number of bytes charged for it.
+	pub(crate) charged: usize,
+	/// The index of the tag it is attributed to.
+	pub(crate) tag: usize,
+}
+
+struct Ring {
+	entries: [Entry; CAPACITY],
+	head: usize,
+	len: usize,
+}
+
+/// A bounded FIFO of freed blocks, that still count against the limit until they are evicted.
+pub(crate) struct Quarantine {
+	locked: AtomicBool,
+	ring: UnsafeCell<Ring>,
+	/// The number of bytes that may be held, or 0 if disabled.
+	pub(crate) budget: AtomicUsize,
+	/// The number of bytes currently held.
+	pub(crate) bytes: AtomicUsize,
+}
+
+unsafe impl Sync for Quarantine {}
+unsafe impl Send for Quarantine {}
+
+impl Quarantine {
+	pub(crate) const fn new() -> Self {
+		Self {
+			locked: AtomicBool::new(false),
+			ring: UnsafeCell::new(Ring {
+				entries: [Entry {
+					base: ptr::null_mut(),
+					layout: Layout::new::<u8>(),
+					charged: 0,
+					tag: 0,
+				}; CAPACITY],
+				head: 0,
+				len: 0,
+			}),
+			budget: AtomicUsize::new(0),
+			bytes: AtomicUsize::new(0),
+		}
+	}
+
+	fn with<R>(&self, f: impl FnOnce(&mut Ring) -> R) -> R {
+		while self
+			.locked
+			.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+			.is_err()
+		{
+			hint::spin_loop();
+		}
+		let ret = f(unsafe { &mut *self.ring.get() });
+		self.locked.store(false, Ordering::Release);
+		ret
+	}
+
+	/// Hold `entry`, evicting the oldest blocks to `free` to stay within the budget. Returns `entry` if it can't be held.
+	///
+	/// Blocks are evicted one at a time and freed after the lock is released, so that the wrapped allocator isn't called while holding it.
+	pub(crate) fn push(&self, entry: Entry, mut free: impl FnMut(Entry)) -> Option<Entry> {
+		let budget = self.budget.load(Ordering::Relaxed);
+		if entry.charged > budget {
+			return Some(entry);
+		}
+		loop {
+			let evicted = self.with(|ring| {
+				let bytes = self.bytes.load(Ordering::Relaxed);
+				if ring.len < CAPACITY && bytes + entry.charged <= budget {
+					ring.entries[(ring.head + ring.len) % CAPACITY] = entry;
+					ring.len += 1;
+					self.bytes.store(bytes + entry.charged, Ordering::Relaxed);
+					return None;
+				}
+				let evicted = ring.pop();
+				self.bytes.store(bytes - evicted.charged, Ordering::Relaxed);
+				Some(evicted)
+			});
+			match evicted {
+				Some(evicted) => free(evicted),
+				None => return None,
+			}
+		}
+	}
+
+	/// Evict blocks to `free`, oldest first, until at most `bytes` are held. Returns whether any were evicted.
+	///
+	/// As with [`push`](Self::push), each block is freed after the lock is released.
+	pub(crate) fn shrink_to(&self, bytes: usize, mut free: impl FnMut(Entry)) -> bool {
+		if self.bytes.load(Ordering::Relaxed) <= bytes {
+			return false;
+		}
+		let mut evicting = false;
+		while let Some(evicted) = self.with(|ring| {
+			let held = self.bytes.load(Ordering::Relaxed);
+			(held > bytes).then(|| {
+				let evicted = ring.pop();
+				self.bytes.store(held - evicted.charged, Ordering::Relaxed);
+				evicted
+			})
+		}) {
+			evicting = true;
+			free(evicted);
+		}
+		evicting
+	}
+}
+
+impl Ring {
+	fn pop(&mut self) -> Entry {
+		let entry = self.entries[self.head];
+		self.head = (self.head + 1) % CAPACITY;
+		self.len -= 1;
+		entry
+	}
+}
+
+impl fmt::Debug for Quarantine {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Quarantine")
+			.field("budget", &self.budget.load(Ordering::Relaxed))
+			.field("bytes", &self.bytes.load(Ordering::Relaxed))
+			.finish_non_exhaustive()
+	}
+}
@@ -0,0 +1,308 @@
+use std::{
+	ptr, slice, str, sync::atomic::{self, AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+/// The number of events retained.
+pub(crate) const CAPACITY: usize = 64;
+/// The number of limit changes retained.
+pub(crate) const LIMIT_CAPACITY: usize = 32;
+
+/// What happened, as recorded in a [`RecentEvent`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecentEventKind {
+	/// An allocation or reallocation failed.
+	Failure,
+	/// An allocation or reallocation of at least the [large allocation threshold](crate::Cap::set_large_threshold) succeeded.
+	Large,
+	/// The number of bytes allocated rose above the [soft limit](crate::Cap::soft_limit).
+	SoftLimitExceeded,
+	/// The number of bytes allocated fell back to within the soft limit.
+	SoftLimitRestored,
+}
+
+impl RecentEventKind {
+	const ALL: [Self; 4] = [
+		Self::Failure,
+		Self::Large,
+		Self::SoftLimitExceeded,
+		Self::SoftLimitRestored,
+	];
+}
+
+/// A significant event, as returned by [`Cap::recent_events`](crate::Cap::recent_events).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RecentEvent {
+	/// What happened.
+	pub kind: RecentEventKind,
+	/// When it happened.
+	pub time: SystemTime,
+	/// The size in bytes of the allocation, or 0 for soft limit crossings.
+	pub size: usize,
+	/// The number of bytes allocated just afterwards.
+	pub allocated: usize,
+}
+
+#[derive(Debug)]
+struct Slot {
+	/// Twice the index of the event written plus one while it is being written, and plus two once it has been.
+	sequence: AtomicU64,
+	kind: AtomicU8,
+	time: AtomicU64,
+	size: AtomicUsize,
+	allocated: AtomicUsize,
+}
+
+/// A lock-free ring buffer of the last [`CAPACITY`] significant events.
+#[derive(Debug)]
+pub(crate) struct Ring {
+	head: AtomicU64,
+	slots: [Slot; CAPACITY],
+	pub(crate) large_threshold: AtomicUsize,
+	/// Whether the allocated bytes were above the soft limit at the last check.
+	pub(crate) over_soft_limit: AtomicBool,
+}
+
+impl Ring {
+	pub(crate) const fn new() -> Self {
+		Self {
+			head: AtomicU64::new(0),
+			slots: [const {
+				Slot {
+					sequence: AtomicU64::new(0),
+					kind: AtomicU8::new(0),
+					time: AtomicU64::new(0),
+					size: AtomicUsize::new(0),
+					allocated: AtomicUsize::new(0),
+				}
+			}; CAPACITY],
+			large_threshold: AtomicUsize::new(usize::MAX),
+			over_soft_limit: AtomicBool::new(false),
+		}
+	}
+
+	pub(crate) fn record(&self, kind: RecentEventKind, size: usize, allocated: usize) {
+		let time = now();
+		let index = self.head.fetch_add(1, Ordering::Relaxed);
+		#[allow(clippy::cast_possible_truncation)]
+		let slot = &self.slots[(index % CAPACITY as u64) as usize];
+		slot.sequence.store(index * 2 + 1, Ordering::Relaxed);
+		atomic::fence(Ordering::Release);
+		slot.kind.store(kind as u8, Ordering::Relaxed);
+		slot.time.store(time, Ordering::Relaxed);
+		slot.size.store(size, Ordering::Relaxed);
+		slot.allocated.store(allocated, Ordering::Relaxed);
+		slot.sequence.store(index * 2 + 2, Ordering::Release);
+	}
+
+	/// Call `f` with each retained event, oldest first, skipping any being overwritten concurrently.
+	pub(crate) fn for_each(&self, mut f: impl FnMut(RecentEvent)) {
+		let head = self.head.load(Ordering::Acquire);
+		for index in head.saturating_sub(CAPACITY as u64)..head {
+			#[allow(clippy::cast_possible_truncation)]
+			let slot = &self.slots[(index % CAPACITY as u64) as usize];
+			if slot.sequence.load(Ordering::Acquire) != index * 2 + 2 {
+				continue;
+			}
+			let event = RecentEvent {
+				kind: RecentEventKind::ALL[usize::from(slot.kind.load(Ordering::Relaxed))],
+				time: UNIX_EPOCH + Duration::from_nanos(slot.time.load(Ordering::Relaxed)),
+				size: slot.size.load(Ordering::Relaxed),
+				allocated: slot.allocated.load(Ordering::Relaxed),
+			};
+			atomic::fence(Ordering::Acquire);
+			if slot.sequence.load(Ordering::Relaxed) == index * 2 + 2 {
+				f(event);
+			}
+		}
+	}
+}
+
+fn now() -> u64 {
+	#[allow(clippy::cast_possible_truncation)]
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |since| since.as_nanos() as u64);
+	now
+}
+
+/// Which limit a [`LimitChange`] changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitKind {
+	/// The [limit](crate::Cap::set_limit).
+	Limit,
+	/// The [soft limit](crate::Cap::set_soft_limit).
+	SoftLimit,
+}
+
+/// A change to a limit, as returned by [`Cap::limit_history`](crate::Cap::limit_history).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LimitChange {
+	/// Which limit changed.
+	pub kind: LimitKind,
+	/// When it changed.
+	pub time: SystemTime,
+	/// The limit beforehand.
+	pub old: usize,
+	/// The limit afterwards.
+	pub new: usize,
+	/// Why, if the caller said.
+	pub reason: Option<&'static str>,
+}
+
+#[derive(Debug)]
+struct LimitSlot {
+	/// As for [`Slot::sequence`].
+	sequence: AtomicU64,
+	soft: AtomicBool,
+	time: AtomicU64,
+	old: AtomicUsize,
+	new: AtomicUsize,
+	reason: (AtomicPtr<u8>, AtomicUsize),
+}
+
+/// A lock-free ring buffer of the last [`LIMIT_CAPACITY`] limit changes.
+#[derive(Debug)]
+pub(crate) struct LimitLog {
+	head: AtomicU64,
+	slots: [LimitSlot; LIMIT_CAPACITY],
+}
+
+impl LimitLog {
+	pub(crate) const fn new() -> Self {
+		Self {
+			head: AtomicU64::new(0),
+			slots: [const {
+				LimitSlot {
+					sequence: AtomicU64::new(0),
+					soft: AtomicBool::new(false),
+					time: AtomicU64::new(0),
+					old: AtomicUsize::new(0),
+					new: AtomicUsize::new(0),
+					reason: (AtomicPtr::new(ptr::null_mut()), AtomicUsize::new(0)),
+				}
+			}; LIMIT_CAPACITY],
+		}
+	}
+
+	pub(crate) fn record(
+		&self, kind: LimitKind, old: usize, new: usize, reason: Option<&'static str>,
+	) {
+		let time = now();
+		let index = self.head.fetch_add(1, Ordering::Relaxed);
+		#[allow(clippy::cast_possible_truncation)]
+		let slot = &self.slots[(index % LIMIT_CAPACITY as u64) as usize];
+		slot.sequence.store(index * 2 + 1, Ordering::Relaxed);
+		atomic::fence(Ordering::Release);
+		slot.soft
+			.store(kind == LimitKind::SoftLimit, Ordering::Relaxed);
+		slot.time.store(time, Ordering::Relaxed);
+		slot.old.store(old, Ordering::Relaxed);
+		slot.new.store(new, Ordering::Relaxed);
+		let reason = reason.map_or((ptr::null_mut(), 0), |reason| {
+			(reason.as_ptr().cast_mut(), reason.len())
+		});
+		slot.reason.0.store(reason.0, Ordering::Relaxed);
+		slot.reason.1.store(reason.1, Ordering::Relaxed);
+		slot.sequence.store(index * 2 + 2, Ordering::Release);
+	}
+
+	/// Return the retained changes, oldest first, skipping any being overwritten concurrently.
+	pub(crate) fn history(&self) -> Vec<LimitChange> {
+		let head = self.head.load(Ordering::Acquire);
+		let mut history = Vec::with_capacity(LIMIT_CAPACITY);
+		for index in head.saturating_sub(LIMIT_CAPACITY as u64)..head {
+			#[allow(clippy::cast_possible_truncation)]
+			let slot = &self.slots[(index % LIMIT_CAPACITY as u64) as usize];
+			if slot.sequence.load(Ordering::Acquire) != index * 2 + 2 {
+				continue;
+			}
+			let (reason, reason_len) = (
+				slot.reason.0.load(Ordering::Relaxed),
+				slot.reason.1.load(Ordering::Relaxed),
+			);
+			let kind = if slot.soft.load(Ordering::Relaxed) {
+				LimitKind::SoftLimit
+			} else {
+				LimitKind::Limit
+			};
+			let time = UNIX_EPOCH + Duration::from_nanos(slot.time.load(Ordering::Relaxed));
+			let (old, new) = (
+				slot.old.load(Ordering::Relaxed),
+				slot.new.load(Ordering::Relaxed),
+			);
+			atomic::fence(Ordering::Acquire);
+			if slot.sequence.load(Ordering::Relaxed) != index * 2 + 2 {
+				continue;
+			}
+			// Safe as it was stored from a `&'static str`, and the sequence shows it wasn't torn.
+			let reason = (!reason.is_null()).then(|| unsafe {
+				str::from_utf8_unchecked(slice::from_raw_parts(reason, reason_len))
+			});
+			history.push(LimitChange {
+				kind,
+				time,
+				old,
+				new,
+				reason,
+			});
+		}
+		history
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::alloc::{GlobalAlloc, Layout, System};
+
+	use super::{LimitKind, RecentEventKind};
+	use crate::Cap;
+
+	#[test]
+	fn limit_history() {
+		let cap = Cap::new(System, 1000);
+		cap.set_limit_with_reason(500, "incident").unwrap();
+		cap.set_limit(500).unwrap();
+		cap.set_soft_limit(400);
+		let history: Vec<_> = cap
+			.limit_history()
+			.into_iter()
+			.map(|change| (change.kind, change.old, change.new, change.reason))
+			.collect();
+		assert_eq!(
+			history,
+			[
+				(LimitKind::Limit, 1000, 500, Some("incident")),
+				(LimitKind::SoftLimit, usize::MAX, 400, None),
+			]
+		);
+	}
+
+	#[test]
+	fn recent_events() {
+		let cap = Cap::new(System, 1000);
+		cap.set_large_threshold(300);
+		cap.set_soft_limit(500);
+		unsafe {
+			let small = cap.alloc(Layout::new::<[u8; 100]>());
+			let large = cap.alloc(Layout::new::<[u8; 600]>());
+			assert!(cap.alloc(Layout::new::<[u8; 600]>()).is_null());
+			cap.dealloc(large, Layout::new::<[u8; 600]>());
+			cap.dealloc(small, Layout::new::<[u8; 100]>());
+		}
+		let mut events = Vec::new();
+		cap.recent_events(|event| events.push((event.kind, event.size, event.allocated)));
+		let small = cap.charged(Layout::new::<[u8; 100]>());
+		let both = small + cap.charged(Layout::new::<[u8; 600]>());
+		assert_eq!(
+			events,
+			[
+				(RecentEventKind::Large, 600, both),
+				(RecentEventKind::SoftLimitExceeded, 0, both),
+				(RecentEventKind::Failure, 600, both),
+				(RecentEventKind::SoftLimitRestored, 0, small),
+			]
+		);
+	}
+}
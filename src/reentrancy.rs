@@ -0,0 +1,90 @@
+//! A guard against user hooks called from within the allocator recursing into it.
+
+use std::{cell::Cell, thread::LocalKey};
+
+thread_local! {
+	static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+	static IN_LIMIT_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Call `hook`, returning its result, unless this thread is already running a hook, in which case it is skipped.
+///
+/// While it runs, allocations on this thread are exempt from the limits, so a hook that allocates can neither fail for lack of memory nor recurse into itself through the allocator. Hooks are also skipped during thread teardown, once thread-local storage is gone.
+pub(crate) fn call<R>(hook: impl FnOnce() -> R) -> Option<R> {
+	if IN_HOOK
+		.try_with(|in_hook| in_hook.replace(true))
+		.unwrap_or(true)
+	{
+		return None;
+	}
+	let _reset = Reset(&IN_HOOK);
+	Some(hook())
+}
+
+/// Call the limit change `hook` as [`call`] does, except that it is only skipped if this thread is already running it, rather than any hook.
+///
+/// Limit changes made by other hooks are thus reported, while those the limit change hook makes itself, which would recurse, aren't.
+pub(crate) fn call_limit_hook(hook: impl FnOnce()) {
+	if IN_LIMIT_HOOK
+		.try_with(|in_hook| in_hook.replace(true))
+		.unwrap_or(true)
+	{
+		return;
+	}
+	let _reset = Reset(&IN_LIMIT_HOOK);
+	if in_hook() {
+		hook();
+	} else {
+		let _ = call(hook);
+	}
+}
+
+/// Marks this thread as no longer running a hook when dropped, even if the hook panicked.
+struct Reset(&'static LocalKey<Cell<bool>>);
+
+impl Drop for Reset {
+	fn drop(&mut self) {
+		self.0.with(|in_hook| in_hook.set(false));
+	}
+}
+
+/// Return whether this thread is running a hook.
+pub(crate) fn in_hook() -> bool {
+	IN_HOOK.try_with(Cell::get).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, sync::atomic::{AtomicUsize, Ordering}
+	};
+
+	use crate::Cap;
+
+	const HUGE: usize = 1 << 62;
+
+	#[test]
+	fn reentrant_hook() {
+		static CAP: Cap<System> = Cap::new(System, HUGE);
+		static CALLS: AtomicUsize = AtomicUsize::new(0);
+		CAP.set_inner_failure_hook(|_| {
+			let _ = CALLS.fetch_add(1, Ordering::Relaxed);
+			unsafe {
+				// Fails without calling the hook again.
+				assert!(CAP
+					.alloc(Layout::from_size_align(HUGE, 1).unwrap())
+					.is_null());
+				// Exempt from the limit, which the failing allocation has taken.
+				let ptr = CAP.alloc(Layout::new::<u64>());
+				assert!(!ptr.is_null());
+				CAP.dealloc(ptr, Layout::new::<u64>());
+			}
+			false
+		});
+		let huge = Layout::from_size_align(HUGE, 1).unwrap();
+		CAP.set_limit(CAP.charged(huge)).unwrap();
+		assert!(unsafe { CAP.alloc(huge) }.is_null());
+		assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+		assert_eq!(CAP.allocated(), 0);
+	}
+}
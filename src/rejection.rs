@@ -0,0 +1,58 @@
+//! Why allocations on each thread were refused.
+
+use std::cell::Cell;
+
+thread_local! {
+	static LAST: Cell<Option<Rejection>> = const { Cell::new(None) };
+}
+
+/// Why an allocation failed, as returned by [`last_rejection`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Rejection {
+	/// It would have exceeded the [limit](crate::Cap::limit), or, with the `cap-group` feature, the combined limit of the [group](crate::CapGroup) its cap belongs to.
+	Limit,
+	/// It would have exceeded the [limit](crate::Cap::set_tag_limit) of the tag it was made under, or the share of its [group](crate::Cap::group) while under pressure.
+	#[cfg(feature = "tags")]
+	TagLimit,
+	/// It would have exceeded the limit of the [budget](crate::Budget) entered, or of one of its ancestors.
+	#[cfg(feature = "budget")]
+	Budget,
+	/// Its size, with any redzones or headers, overflows a [`Layout`](std::alloc::Layout).
+	TooLarge,
+	/// It was failed deliberately, by fault injection or a test plan.
+	Injected,
+	/// It was [best-effort](crate::Cap::best_effort), and shed while the [circuit breaker](crate::Cap::set_circuit_breaker) was open.
+	#[cfg(feature = "breaker")]
+	Shed,
+	/// The wrapped allocator returned null, even after any [retries](crate::Cap::set_inner_retries).
+	Inner,
+}
+
+/// Return why the most recent failed allocation on this thread failed, if any has.
+///
+/// This is not reset by successful allocations, so is only meaningful directly after a failure, such as in an allocation error handler or when a fallible collection method returns an error.
+///
+/// ```
+/// use std::alloc;
+/// use cap::{Cap, Rejection};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     ALLOCATOR.set_limit(ALLOCATOR.allocated() + 1024).unwrap();
+///     let mut buf = Vec::<u8>::new();
+///     assert!(buf.try_reserve(4096).is_err());
+///     assert_eq!(cap::last_rejection(), Some(Rejection::Limit));
+/// }
+/// ```
+pub fn last_rejection() -> Option<Rejection> {
+	LAST.try_with(Cell::get).ok().flatten()
+}
+
+/// Record that an allocation on this thread failed for `reason`.
+#[cold]
+pub(crate) fn reject(reason: Rejection) {
+	let _ = LAST.try_with(|last| last.set(Some(reason)));
+}
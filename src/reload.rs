@@ -0,0 +1,214 @@
+//! Reconfiguration from a file on `SIGHUP`.
+
+use std::{
+	ffi::c_int, fs, io, path::Path, sync::{
+		atomic::{AtomicUsize, Ordering}, Mutex, PoisonError
+	}, time::Duration
+};
+
+use crate::{Cap, Monitor};
+
+extern "C" {
+	fn signal(signum: c_int, handler: usize) -> usize;
+}
+
+const SIGHUP: c_int = 1;
+const SIG_ERR: usize = !0;
+
+/// How often the reloading thread checks whether a `SIGHUP` has arrived.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Incremented by each `SIGHUP`, so that any number of reloading threads can notice it.
+static SIGHUPS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_sighup(_: c_int) {
+	let _ = SIGHUPS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of reloading threads, and the `SIGHUP` handler that was replaced when the first was started, to be restored once the last is stopped.
+static INSTALLED: Mutex<(usize, usize)> = Mutex::new((0, 0));
+
+fn install() -> io::Result<()> {
+	let mut installed = INSTALLED.lock().unwrap_or_else(PoisonError::into_inner);
+	if installed.0 == 0 {
+		let handler: extern "C" fn(c_int) = on_sighup;
+		let previous = unsafe { signal(SIGHUP, handler as usize) };
+		if previous == SIG_ERR {
+			return Err(io::Error::last_os_error());
+		}
+		installed.1 = previous;
+	}
+	installed.0 += 1;
+	Ok(())
+}
+
+fn uninstall() {
+	let mut installed = INSTALLED.lock().unwrap_or_else(PoisonError::into_inner);
+	installed.0 -= 1;
+	if installed.0 == 0 {
+		let _ = unsafe { signal(SIGHUP, installed.1) };
+	}
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Config {
+	limit: Option<usize>,
+	soft_limit: Option<usize>,
+	group_pressure: Option<f64>,
+}
+
+impl Config {
+	fn parse(text: &str) -> Result<Self, String> {
+		let mut config = Config::default();
+		for (number, line) in text.lines().enumerate() {
+			let line = line.split('#').next().unwrap().trim();
+			if line.is_empty() {
+				continue;
+			}
+			let error = |reason: &str| format!("line {}: {}", number + 1, reason);
+			let Some((key, value)) = line.split_once('=') else {
+				return Err(error("expected `key = value`"));
+			};
+			let (key, value) = (key.trim(), value.trim());
+			let bytes = || match value {
+				"unlimited" => Ok(usize::MAX),
+				value => value.parse().map_err(|_| error("invalid number of bytes")),
+			};
+			match key {
+				"limit" => config.limit = Some(bytes()?),
+				"soft_limit" => config.soft_limit = Some(bytes()?),
+				"group_pressure" => {
+					config.group_pressure = Some(
+						value
+							.parse()
+							.ok()
+							.filter(|fraction| (0.0..=1.0).contains(fraction))
+							.ok_or_else(|| error("invalid fraction"))?,
+					);
+				}
+				_ => return Err(error("unknown key")),
+			}
+		}
+		Ok(config)
+	}
+}
+
+impl<H> Cap<H> {
+	/// Apply the configuration file at `path` now, and again each time the process receives `SIGHUP`, on a background thread.
+	///
+	/// The file consists of `key = value` lines, with `#` starting a comment:
+	///
+	/// ```text
+	/// # Bytes, or `unlimited`.
+	/// limit = 1073741824
+	/// soft_limit = 805306368
+	/// # With the `tags` feature; see `Cap::set_group_pressure`.
+	/// group_pressure = 0.9
+	/// ```
+	///
+	/// Keys that are omitted are left unchanged. A file that fails to parse is rejected in its entirety; so is one whose limit is less than the number of bytes already allocated. This method returns such errors for the initial load, and thereafter they are printed to stderr.
+	///
+	/// This installs a `SIGHUP` handler, replacing any existing one until the returned [`Monitor`] is dropped, which stops reloading and, once no other reloading is in progress, restores it.
+	pub fn reload_on_sighup(&'static self, path: impl AsRef<Path>) -> io::Result<Monitor>
+	where
+		H: Sync,
+	{
+		let path = path.as_ref().to_owned();
+		self.reload(&path)?;
+		install()?;
+		let mut seen = SIGHUPS.load(Ordering::Relaxed);
+		Ok(Monitor::spawn(move |stop| {
+			while stop.sleep(POLL_INTERVAL) {
+				let sighups = SIGHUPS.load(Ordering::Relaxed);
+				if sighups != seen {
+					seen = sighups;
+					if let Err(error) = self.reload(&path) {
+						eprintln!("cap: failed to reload {}: {}", path.display(), error);
+					}
+				}
+			}
+			uninstall();
+		}))
+	}
+
+	fn reload(&self, path: &Path) -> io::Result<()> {
+		let config = Config::parse(&fs::read_to_string(path)?)
+			.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+		if let Some(limit) = config.limit {
+			self.set_limit_with_reason(limit, "reload").map_err(|()| {
+				io::Error::new(
+					io::ErrorKind::InvalidInput,
+					"limit is less than the number of bytes allocated",
+				)
+			})?;
+		}
+		if let Some(soft_limit) = config.soft_limit {
+			self.set_soft_limit_with_reason(soft_limit, "reload");
+		}
+		#[cfg(feature = "tags")]
+		if let Some(fraction) = config.group_pressure {
+			self.set_group_pressure(fraction);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{alloc::System, env, ffi::c_int, fs, thread, time::Duration};
+
+	use super::{signal, Config, SIGHUP};
+	use crate::Cap;
+
+	extern "C" {
+		fn raise(signum: c_int) -> c_int;
+	}
+
+	extern "C" fn previous(_: c_int) {}
+
+	#[test]
+	fn parse() {
+		let text = "limit = 1000 # bytes\n\n  soft_limit=unlimited\ngroup_pressure = 0.5\n";
+		assert_eq!(
+			Config::parse(text),
+			Ok(Config {
+				limit: Some(1000),
+				soft_limit: Some(usize::MAX),
+				group_pressure: Some(0.5),
+			})
+		);
+		assert_eq!(
+			Config::parse("limit = 1000\nlimit = -1"),
+			Err("line 2: invalid number of bytes".to_owned())
+		);
+		assert!(Config::parse("group_pressure = 2").is_err());
+		assert!(Config::parse("colour = blue").is_err());
+	}
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn reload_on_sighup() {
+		let cap: &'static Cap<System> = Box::leak(Box::new(Cap::new(System, usize::MAX)));
+		let path = env::temp_dir().join(format!("cap-reload-{}", std::process::id()));
+		fs::write(&path, "limit = 1000").unwrap();
+		let handler: extern "C" fn(c_int) = previous;
+		let _ = unsafe { signal(SIGHUP, handler as usize) };
+		let monitor = cap.reload_on_sighup(&path).unwrap();
+		assert_eq!(cap.limit(), 1000);
+		fs::write(&path, "soft_limit = 500").unwrap();
+		assert_eq!(unsafe { raise(SIGHUP) }, 0);
+		thread::sleep(Duration::from_millis(500));
+		assert_eq!((cap.limit(), cap.soft_limit()), (1000, 500));
+		// Stopped, restoring the previous handler, after which `SIGHUP` no longer reloads.
+		drop(monitor);
+		fs::write(&path, "soft_limit = 250").unwrap();
+		assert_eq!(
+			unsafe { signal(SIGHUP, handler as usize) },
+			handler as usize
+		);
+		assert_eq!(unsafe { raise(SIGHUP) }, 0);
+		thread::sleep(Duration::from_millis(500));
+		fs::remove_file(&path).unwrap();
+		assert_eq!(cap.soft_limit(), 500);
+	}
+}
@@ -0,0 +1,261 @@
+//! A small arena, held within a [`Cap`](crate::Cap), that allocations made by hooks can be served from.
+
+use std::{
+	alloc::{GlobalAlloc, Layout}, cell::UnsafeCell, fmt, ptr, sync::atomic::{AtomicUsize, Ordering}
+};
+
+use crate::{reentrancy, Cap};
+
+/// The size of the reserve arena in bytes.
+pub const RESERVE_SIZE: usize = 8 * 1024;
+
+/// The low bits of the arena's state, holding the offset of its first free byte. The rest count its live allocations, of which there can't be more than `RESERVE_SIZE` as none are zero-sized.
+const OFFSET_BITS: u32 = 16;
+const OFFSET_MASK: usize = (1 << OFFSET_BITS) - 1;
+
+/// How allocations made by hooks, such as the [inner failure hook](crate::Cap::set_inner_failure_hook), are served, as set by [`Cap::set_hook_allocation`](crate::Cap::set_hook_allocation).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HookAllocation {
+	/// From the wrapped allocator, exempt from the limits.
+	Exempt,
+	/// From the wrapped allocator, exempt from the limits, falling back on the reserve arena if it fails. This is the default.
+	Fallback,
+	/// From the reserve arena, so that hooks don't overdraw the limit, and once it is full from the wrapped allocator, exempt from the limits.
+	Reserve,
+}
+
+#[repr(align(16))]
+struct Bytes(UnsafeCell<[u8; RESERVE_SIZE]>);
+
+/// A bump allocator over `RESERVE_SIZE` bytes, reset once all it has handed out are freed.
+pub(crate) struct Arena {
+	bytes: Bytes,
+	/// The live allocations, above `OFFSET_BITS`, and the offset of the first free byte.
+	state: AtomicUsize,
+	mode: AtomicUsize,
+}
+
+// Safe as the blocks handed out are disjoint, and only accessed through the pointers to them.
+unsafe impl Sync for Arena {}
+
+impl Arena {
+	pub(crate) const fn new() -> Self {
+		Self {
+			bytes: Bytes(UnsafeCell::new([0; RESERVE_SIZE])),
+			state: AtomicUsize::new(0),
+			mode: AtomicUsize::new(HookAllocation::Fallback as usize),
+		}
+	}
+
+	pub(crate) fn mode(&self) -> HookAllocation {
+		match self.mode.load(Ordering::Relaxed) {
+			0 => HookAllocation::Exempt,
+			1 => HookAllocation::Fallback,
+			_ => HookAllocation::Reserve,
+		}
+	}
+
+	pub(crate) fn set_mode(&self, mode: HookAllocation) {
+		self.mode.store(mode as usize, Ordering::Relaxed);
+	}
+
+	fn base(&self) -> *mut u8 {
+		self.bytes.0.get().cast()
+	}
+
+	/// Allocate `layout` from the arena, returning null if it doesn't fit.
+	pub(crate) fn alloc(&self, layout: Layout, zeroed: bool) -> *mut u8 {
+		let base = self.base() as usize;
+		let mut start = 0;
+		let res = self
+			.state
+			.fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+				let free = base + (state & OFFSET_MASK);
+				start = free.checked_next_multiple_of(layout.align())? - base;
+				let end = start.checked_add(layout.size())?;
+				(end <= RESERVE_SIZE).then(|| ((state >> OFFSET_BITS) + 1) << OFFSET_BITS | end)
+			});
+		if res.is_err() {
+			return ptr::null_mut();
+		}
+		// Safe as the block is within the arena and handed out to no one else.
+		unsafe {
+			let ptr = self.base().add(start);
+			if zeroed {
+				ptr::write_bytes(ptr, 0, layout.size());
+			}
+			ptr
+		}
+	}
+
+	/// Return whether `ptr` was allocated from the arena.
+	pub(crate) fn contains(&self, ptr: *mut u8) -> bool {
+		let base = self.base() as usize;
+		(base..base + RESERVE_SIZE).contains(&(ptr as usize))
+	}
+
+	/// Free a block allocated from the arena, resetting it if it was the last live one.
+	pub(crate) fn free(&self) {
+		let _ = self
+			.state
+			.fetch_update(Ordering::Release, Ordering::Relaxed, |state| {
+				let live = (state >> OFFSET_BITS) - 1;
+				Some(if live == 0 {
+					0
+				} else {
+					live << OFFSET_BITS | state & OFFSET_MASK
+				})
+			});
+	}
+
+	/// The bytes of the arena that can't be allocated until its live allocations are freed.
+	pub(crate) fn used(&self) -> usize {
+		self.state.load(Ordering::Relaxed) & OFFSET_MASK
+	}
+}
+
+impl fmt::Debug for Arena {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Arena")
+			.field("mode", &self.mode())
+			.field("used", &self.used())
+			.finish_non_exhaustive()
+	}
+}
+
+impl<H> Cap<H> {
+	/// Set how allocations made by hooks are served, from the wrapped allocator or from a reserve arena of 8 KiB held within the cap.
+	///
+	/// Hooks are typically called when memory is short, to log, report or release it, so their allocations are exempt from the limits. The arena guarantees them memory even when the wrapped allocator is out of it too, or, with [`HookAllocation::Reserve`], without their overdrawing the limit at all. Blocks from the arena aren't counted as [allocated](Self::allocated), and are only served through [`GlobalAlloc`].
+	pub fn set_hook_allocation(&self, mode: HookAllocation) {
+		self.reserve.set_mode(mode);
+	}
+
+	/// Return the bytes of the reserve arena in use by allocations made by hooks. The arena is reclaimed once they are all freed.
+	pub fn hook_reserve_used(&self) -> usize {
+		self.reserve.used()
+	}
+}
+
+impl<H> Cap<H>
+where
+	H: GlobalAlloc,
+{
+	/// Like [`alloc_with`](Self::alloc_with), serving allocations made by hooks from the reserve arena as [set](Self::set_hook_allocation).
+	pub(crate) unsafe fn alloc_with_reserve(&self, layout: Layout, zeroed: bool) -> *mut u8 {
+		let mode = self.reserve.mode();
+		if mode == HookAllocation::Reserve && reentrancy::in_hook() {
+			let res = self.reserve.alloc(layout, zeroed);
+			if !res.is_null() {
+				return res;
+			}
+		}
+		let res = self.alloc_with(layout, zeroed);
+		if res.is_null() && mode == HookAllocation::Fallback && reentrancy::in_hook() {
+			return self.reserve.alloc(layout, zeroed);
+		}
+		res
+	}
+
+	/// Like [`realloc_with`](Self::realloc_with), moving blocks from the reserve arena out of it, and those of hooks into it if they can't otherwise be resized.
+	pub(crate) unsafe fn realloc_with_reserve(
+		&self, ptr: *mut u8, old_l: Layout, new_s: usize,
+	) -> *mut u8 {
+		let new_l = Layout::from_size_align_unchecked(new_s, old_l.align());
+		if self.reserve.contains(ptr) {
+			let res = self.alloc_with_reserve(new_l, false);
+			if !res.is_null() {
+				ptr::copy(ptr, res, old_l.size().min(new_s));
+				self.reserve.free();
+			}
+			return res;
+		}
+		let res = self.realloc_with(ptr, old_l, new_s);
+		if res.is_null() && self.reserve.mode() != HookAllocation::Exempt && reentrancy::in_hook() {
+			let res = self.reserve.alloc(new_l, false);
+			if !res.is_null() {
+				ptr::copy_nonoverlapping(ptr, res, old_l.size().min(new_s));
+				self.dealloc(ptr, old_l);
+			}
+			return res;
+		}
+		res
+	}
+
+	/// Free `ptr` if it was allocated from the reserve arena, returning whether it was.
+	pub(crate) fn dealloc_reserved(&self, ptr: *mut u8) -> bool {
+		let reserved = self.reserve.contains(ptr);
+		if reserved {
+			self.reserve.free();
+		}
+		reserved
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, ptr
+	};
+
+	use super::{HookAllocation, RESERVE_SIZE};
+	use crate::{reentrancy, Cap};
+
+	/// An allocator that is out of memory.
+	#[derive(Debug)]
+	struct Exhausted;
+	unsafe impl GlobalAlloc for Exhausted {
+		unsafe fn alloc(&self, _: Layout) -> *mut u8 {
+			ptr::null_mut()
+		}
+		unsafe fn dealloc(&self, _: *mut u8, _: Layout) {
+			unreachable!();
+		}
+	}
+
+	#[test]
+	fn reserve() {
+		let cap = Cap::new(Exhausted, usize::MAX);
+		let small = Layout::from_size_align(100, 64).unwrap();
+		unsafe {
+			// Outside of hooks, the arena isn't touched.
+			assert!(cap.alloc(small).is_null());
+			reentrancy::call(|| {
+				let ptr = cap.alloc_zeroed(small);
+				assert!(!ptr.is_null());
+				assert_eq!(ptr as usize % 64, 0);
+				assert_eq!(*ptr, 0);
+				let ptr = cap.realloc(ptr, small, 200);
+				assert!(!ptr.is_null());
+				assert!(cap
+					.alloc(Layout::from_size_align(RESERVE_SIZE, 1).unwrap())
+					.is_null());
+				assert_eq!(cap.allocated(), 0);
+				cap.dealloc(ptr, Layout::from_size_align(200, 64).unwrap());
+				assert_eq!(cap.hook_reserve_used(), 0);
+				cap.set_hook_allocation(HookAllocation::Exempt);
+				assert!(cap.alloc(small).is_null());
+			})
+			.unwrap();
+		}
+		// Served from the arena first, without overdrawing the exhausted limit.
+		let cap = Cap::new(System, 0);
+		cap.set_hook_allocation(HookAllocation::Reserve);
+		unsafe {
+			reentrancy::call(|| {
+				let ptr = cap.alloc(Layout::new::<[u64; 4]>());
+				assert!(!ptr.is_null());
+				assert_eq!((cap.allocated(), cap.hook_reserve_used()), (0, 32));
+				let large = Layout::from_size_align(RESERVE_SIZE, 1).unwrap();
+				let overdrawn = cap.alloc(large);
+				assert!(!overdrawn.is_null());
+				assert_eq!(cap.allocated(), cap.charged(large));
+				cap.dealloc(overdrawn, large);
+				cap.dealloc(ptr, Layout::new::<[u64; 4]>());
+			})
+			.unwrap();
+		}
+		assert_eq!((cap.allocated(), cap.hook_reserve_used()), (0, 0));
+	}
+}
@@ -0,0 +1,320 @@
+//! Measurement of the memory allocated by a unit of work, such as handling a request.
+
+use std::{
+	cell::Cell, future::Future, marker::PhantomData, pin::Pin, ptr, sync::{
+		atomic::{AtomicIsize, AtomicUsize, Ordering}, Arc
+	}, task::{Context, Poll}
+};
+
+thread_local! {
+	static CURRENT: Current = const { Current(Cell::new(ptr::null())) };
+	/// The cap the innermost [`PeakScope`] on this thread measures, and its net and peak bytes.
+	static PEAK: Cell<(*const (), isize, isize)> = const { Cell::new((ptr::null(), 0, 0)) };
+}
+
+/// The scope entered on this thread, of which it holds a strong reference, so that it stays alive however the guards that entered it are dropped or forgotten.
+struct Current(Cell<*const MemoryScope>);
+
+impl Drop for Current {
+	fn drop(&mut self) {
+		release_ref(self.0.get());
+	}
+}
+
+/// Release a strong reference to `scope`, if it isn't null.
+fn release_ref(scope: *const MemoryScope) {
+	if !scope.is_null() {
+		unsafe { Arc::decrement_strong_count(scope) };
+	}
+}
+
+/// Counts of the bytes allocated and deallocated by a [`Cap`](crate::Cap) while a scope is entered.
+///
+/// Entering a scope, directly with [`enter`](Self::enter) or for each poll of a future with [`instrument`](Self::instrument), lets a server measure what each request costs:
+///
+/// ```
+/// use std::{alloc, sync::Arc};
+/// use cap::{Cap, MemoryScope};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     let scope = Arc::new(MemoryScope::new());
+///     let handled = {
+///         let _guard = scope.enter();
+///         let scratch = vec![0u8; 1000];
+///         let response = vec![0u8; 100];
+///         drop(scratch);
+///         response
+///     };
+///     assert_eq!((scope.net(), scope.peak()), (100, 1100));
+///     // e.g. recorded in the response's extensions, or an `x-memory-peak` header, for logging.
+/// #   drop(handled);
+/// }
+/// ```
+///
+/// A middleware for a web framework wraps each request's handler future with [`instrument`](Self::instrument), and reads the scope once the response is produced. Allocations are attributed to the innermost entered scope; memory deallocated while it is entered is subtracted even if it was allocated earlier.
+#[derive(Debug, Default)]
+pub struct MemoryScope {
+	current: AtomicIsize,
+	peak: AtomicIsize,
+	allocated: AtomicUsize,
+}
+
+impl MemoryScope {
+	/// Create a new scope, with nothing yet allocated in it.
+	#[must_use]
+	pub const fn new() -> Self {
+		Self {
+			current: AtomicIsize::new(0),
+			peak: AtomicIsize::new(0),
+			allocated: AtomicUsize::new(0),
+		}
+	}
+
+	/// Attribute allocations and deallocations on this thread to this scope, until the returned guard is dropped.
+	///
+	/// The thread holds a reference to the scope while it is entered, so it stays alive even if the guard is forgotten.
+	#[must_use]
+	pub fn enter(self: &Arc<Self>) -> ScopeGuard {
+		let entered = Arc::into_raw(Arc::clone(self));
+		let previous = CURRENT.with(|current| current.0.replace(entered));
+		ScopeGuard { previous }
+	}
+
+	/// Wrap `future` so that it is attributed to this scope each time it is polled.
+	pub fn instrument<F>(self: Arc<Self>, future: F) -> Scoped<F>
+	where
+		F: Future,
+	{
+		Scoped {
+			scope: self,
+			future,
+		}
+	}
+
+	/// Return the bytes allocated less the bytes deallocated while this scope was entered.
+	pub fn net(&self) -> isize {
+		self.current.load(Ordering::Relaxed)
+	}
+
+	/// Return the highest the [net](Self::net) bytes have been.
+	pub fn peak(&self) -> isize {
+		self.peak.load(Ordering::Relaxed)
+	}
+
+	/// Return the total bytes allocated while this scope was entered, ignoring deallocations.
+	pub fn total_allocated(&self) -> usize {
+		self.allocated.load(Ordering::Relaxed)
+	}
+}
+
+/// A guard returned by [`MemoryScope::enter`] that restores the previously entered scope when dropped.
+#[derive(Debug)]
+pub struct ScopeGuard {
+	/// The scope entered before, of which the guard holds the thread's reference.
+	previous: *const MemoryScope,
+}
+
+impl Drop for ScopeGuard {
+	fn drop(&mut self) {
+		// What is replaced is whatever is entered now, which if guards are dropped out of order isn't the scope this guard entered.
+		match CURRENT.try_with(|current| current.0.replace(self.previous)) {
+			Ok(entered) => release_ref(entered),
+			// The thread is exiting, and has released what was entered.
+			Err(_) => release_ref(self.previous),
+		}
+	}
+}
+
+/// A future returned by [`MemoryScope::instrument`] that runs its wrapped future in a scope.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Scoped<F> {
+	scope: Arc<MemoryScope>,
+	future: F,
+}
+
+impl<F> Scoped<F> {
+	/// Return the scope the future is attributed to.
+	pub fn scope(&self) -> &Arc<MemoryScope> {
+		&self.scope
+	}
+}
+
+impl<F> Future for Scoped<F>
+where
+	F: Future,
+{
+	type Output = F::Output;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+		let _guard = self.scope.enter();
+		// Safe as `future` is structurally pinned: it is never moved out of `self`.
+		unsafe { self.map_unchecked_mut(|scoped| &mut scoped.future) }.poll(cx)
+	}
+}
+
+/// A guard returned by [`Cap::peak_scope`](crate::Cap::peak_scope) that measures the peak bytes allocated through a cap by this thread while it is held.
+///
+/// Unlike [`max_allocated`](crate::Cap::max_allocated), this isn't polluted by other threads, so suits benchmarks:
+///
+/// ```
+/// use std::alloc;
+/// use cap::Cap;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     let scope = ALLOCATOR.peak_scope();
+///     let a = vec![0u8; 1000];
+///     drop(a);
+///     let b = vec![0u8; 100];
+///     assert_eq!((scope.net(), scope.peak()), (100, 1000));
+/// #   drop(b);
+/// }
+/// ```
+///
+/// Scopes nest: an outer scope sees the peak reached within an inner one. For a task that moves between threads, use a [`MemoryScope`] instead.
+#[derive(Debug)]
+pub struct PeakScope<'a> {
+	previous: (*const (), isize, isize),
+	_cap: PhantomData<&'a ()>,
+}
+
+impl PeakScope<'_> {
+	pub(crate) fn new(cap: *const ()) -> Self {
+		Self {
+			previous: PEAK.with(|peak| peak.replace((cap, 0, 0))),
+			_cap: PhantomData,
+		}
+	}
+
+	/// Return the bytes allocated less the bytes deallocated by this thread since the scope began.
+	pub fn net(&self) -> isize {
+		PEAK.with(Cell::get).1
+	}
+
+	/// Return the highest the [net](Self::net) bytes have been since the scope began.
+	pub fn peak(&self) -> usize {
+		#[allow(clippy::cast_sign_loss)]
+		let peak = PEAK.with(Cell::get).2 as usize;
+		peak
+	}
+}
+
+impl Drop for PeakScope<'_> {
+	fn drop(&mut self) {
+		let (cap, net, peak) = PEAK.with(Cell::get);
+		let (previous_cap, previous_net, previous_peak) = self.previous;
+		let restored = if ptr::eq(cap, previous_cap) {
+			(
+				cap,
+				previous_net + net,
+				previous_peak.max(previous_net + peak),
+			)
+		} else {
+			self.previous
+		};
+		PEAK.with(|current| current.set(restored));
+	}
+}
+
+/// Attribute the freeing of `freed` bytes and allocation of `allocated` bytes through `cap` to the [`PeakScope`] on this thread, if it measures `cap`.
+pub(crate) fn record_peak(cap: *const (), freed: usize, allocated: usize) {
+	let _ = PEAK.try_with(|current| {
+		let (scope_cap, net, peak) = current.get();
+		if ptr::eq(scope_cap, cap) {
+			#[allow(clippy::cast_possible_wrap)]
+			let net = net + allocated.wrapping_sub(freed) as isize;
+			current.set((cap, net, peak.max(net)));
+		}
+	});
+}
+
+/// Attribute the freeing of `freed` bytes and allocation of `allocated` bytes to the scope entered on this thread, if any.
+pub(crate) fn record(freed: usize, allocated: usize) {
+	let scope = CURRENT
+		.try_with(|current| current.0.get())
+		.unwrap_or(ptr::null());
+	// Safe as the scope is kept alive by the thread that entered it.
+	let Some(scope) = (unsafe { scope.as_ref() }) else {
+		return;
+	};
+	#[allow(clippy::cast_possible_wrap)]
+	let delta = allocated.wrapping_sub(freed) as isize;
+	let current = scope.current.fetch_add(delta, Ordering::Relaxed) + delta;
+	let _ = scope.peak.fetch_max(current, Ordering::Relaxed);
+	let _ = scope
+		.allocated
+		.fetch_add(allocated.saturating_sub(freed), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, future::Future, pin::pin, sync::Arc, task::{Context, Poll, Waker}, thread
+	};
+
+	use super::MemoryScope;
+	use crate::Cap;
+
+	#[test]
+	fn peak_scope() {
+		let cap = Cap::new(System, usize::MAX);
+		let (small, large) = (Layout::new::<[u8; 100]>(), Layout::new::<[u8; 1000]>());
+		unsafe {
+			let outer = cap.peak_scope();
+			let a = cap.alloc(small);
+			{
+				let inner = cap.peak_scope();
+				let b = cap.alloc(large);
+				cap.dealloc(b, large);
+				assert_eq!((inner.net(), inner.peak()), (0, 1000));
+			}
+			assert_eq!((outer.net(), outer.peak()), (100, 1100));
+			cap.dealloc(a, small);
+			assert_eq!((outer.net(), outer.peak()), (0, 1100));
+		}
+	}
+
+	#[test]
+	fn guard_forgotten() {
+		static CAP: Cap<System> = Cap::new(System, usize::MAX);
+		let layout = Layout::new::<[u8; 100]>();
+		let scope = Arc::new(MemoryScope::new());
+		let weak = Arc::downgrade(&scope);
+		let entered = weak.clone();
+		thread::spawn(move || {
+			std::mem::forget(scope.enter());
+			drop(scope);
+			unsafe { CAP.dealloc(CAP.alloc(layout), layout) };
+			assert_eq!(entered.upgrade().unwrap().total_allocated(), 100);
+		})
+		.join()
+		.unwrap();
+		// Kept alive until the thread exited.
+		assert!(weak.upgrade().is_none());
+	}
+
+	#[test]
+	fn instrument() {
+		let cap = Cap::new(System, usize::MAX);
+		let (outer, inner) = (Arc::new(MemoryScope::new()), Arc::new(MemoryScope::new()));
+		let layout = Layout::new::<[u8; 100]>();
+		let _guard = outer.enter();
+		let future = Arc::clone(&inner).instrument(async { unsafe { cap.alloc(layout) } });
+		let Poll::Ready(ptr) = pin!(future).poll(&mut Context::from_waker(Waker::noop())) else {
+			unreachable!()
+		};
+		assert_eq!((inner.net(), outer.net()), (100, 0));
+		unsafe { cap.dealloc(ptr, layout) };
+		assert_eq!(
+			(inner.net(), inner.peak(), inner.total_allocated()),
+			(100, 100, 100)
+		);
+		assert_eq!((outer.net(), outer.peak()), (-100, 0));
+	}
+}
@@ -0,0 +1,267 @@
+//! A self-test of a [`Cap`]'s accounting against the allocator it wraps.
+
+use std::alloc::{GlobalAlloc, Layout};
+
+use crate::Cap;
+
+/// The layouts exercised, from the smallest to a large, page-aligned one.
+const LAYOUTS: [(usize, usize); 4] = [(1, 1), (24, 8), (4096, 64), (1 << 20, 4096)];
+/// The most failures recorded, so that recording them doesn't allocate during the test.
+const MAX_FAILURES: usize = 16;
+
+/// The outcome of [`Cap::self_test`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SelfTest {
+	/// The number of checks made.
+	pub checks: usize,
+	/// The checks that failed, up to 16 of them.
+	pub failures: Vec<SelfTestFailure>,
+}
+
+impl SelfTest {
+	/// Return whether every check passed.
+	#[must_use]
+	pub fn passed(&self) -> bool {
+		self.failures.is_empty()
+	}
+}
+
+/// A failed check of [`Cap::self_test`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SelfTestFailure {
+	/// The step returned null.
+	Null {
+		/// The step, such as `"alloc"` or `"realloc grow"`.
+		step: &'static str,
+		/// The layout requested.
+		layout: Layout,
+	},
+	/// The bytes or allocations counted after the step weren't as expected.
+	Accounting {
+		/// The step, with the counter checked, such as `"alloc: allocated"`.
+		step: &'static str,
+		/// The layout requested.
+		layout: Layout,
+		/// The value expected of the counter.
+		expected: usize,
+		/// The value found.
+		actual: usize,
+	},
+	/// The contents of the allocation weren't preserved, or weren't zeroed.
+	Contents {
+		/// The step.
+		step: &'static str,
+		/// The layout requested.
+		layout: Layout,
+	},
+}
+
+/// The checks made so far, and the failures among them, held inline.
+struct Checks {
+	made: usize,
+	failures: [Option<SelfTestFailure>; MAX_FAILURES],
+	len: usize,
+}
+
+impl Checks {
+	fn check(&mut self, passed: bool, failure: impl FnOnce() -> SelfTestFailure) -> bool {
+		self.made += 1;
+		if !passed && self.len < MAX_FAILURES {
+			self.failures[self.len] = Some(failure());
+			self.len += 1;
+		}
+		passed
+	}
+
+	fn count(&mut self, step: &'static str, layout: Layout, expected: usize, actual: usize) {
+		let _ = self.check(expected == actual, || SelfTestFailure::Accounting {
+			step,
+			layout,
+			expected,
+			actual,
+		});
+	}
+}
+
+impl<H> Cap<H>
+where
+	H: GlobalAlloc,
+{
+	/// Allocate, reallocate and free a series of blocks of various sizes and alignments, checking that the counters track each step exactly and return to their baseline, and that contents are preserved.
+	///
+	/// This is a canary for interactions between the wrapped allocator and the enabled features, suited to CI and service startup. The blocks are counted against the limit as usual. As the checks compare counters across steps, they are only reliable while no other thread allocates through this cap, and while this thread holds no carve-out. With the `quarantine` feature, the quarantine is flushed, so that the freed blocks are released.
+	///
+	/// ```
+	/// use std::alloc;
+	/// use cap::Cap;
+	///
+	/// #[global_allocator]
+	/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+	///
+	/// fn main() {
+	///     let report = ALLOCATOR.self_test();
+	///     assert!(report.passed(), "{:?}", report.failures);
+	/// }
+	/// ```
+	pub fn self_test(&self) -> SelfTest {
+		let mut checks = Checks {
+			made: 0,
+			failures: [None; MAX_FAILURES],
+			len: 0,
+		};
+		#[cfg(feature = "overhead")]
+		let live = self.live_allocations();
+		#[cfg(not(feature = "overhead"))]
+		let live = 0;
+		let allocated = self.allocated();
+		for (size, align) in LAYOUTS {
+			// Safe as the layouts are valid.
+			unsafe {
+				let layout = Layout::from_size_align_unchecked(size, align);
+				self.self_test_layout(&mut checks, layout, allocated, live);
+			}
+		}
+		#[cfg(feature = "quarantine")]
+		self.flush_quarantine();
+		checks.count(
+			"baseline: allocated",
+			Layout::new::<()>(),
+			allocated,
+			self.allocated(),
+		);
+		#[cfg(feature = "overhead")]
+		checks.count(
+			"baseline: live allocations",
+			Layout::new::<()>(),
+			live,
+			self.live_allocations(),
+		);
+		SelfTest {
+			checks: checks.made,
+			failures: checks.failures.iter().flatten().copied().collect(),
+		}
+	}
+
+	unsafe fn self_test_layout(
+		&self, checks: &mut Checks, layout: Layout, allocated: usize, live: usize,
+	) {
+		let grown = Layout::from_size_align_unchecked(layout.size() * 2, layout.align());
+		let shrunk = Layout::from_size_align_unchecked((layout.size() / 2).max(1), layout.align());
+		#[allow(clippy::cast_possible_truncation)]
+		let pattern = |i: usize| (i % 251) as u8;
+		let intact = |ptr: *mut u8, len: usize| (0..len).all(|i| *ptr.add(i) == pattern(i));
+		#[cfg(not(feature = "overhead"))]
+		let _ = live;
+
+		let ptr = self.alloc(layout);
+		if !checks.check(!ptr.is_null(), || SelfTestFailure::Null {
+			step: "alloc",
+			layout,
+		}) {
+			return;
+		}
+		checks.count(
+			"alloc: allocated",
+			layout,
+			allocated + self.charged(layout),
+			self.allocated(),
+		);
+		#[cfg(feature = "overhead")]
+		checks.count(
+			"alloc: live allocations",
+			layout,
+			live + 1,
+			self.live_allocations(),
+		);
+		for i in 0..layout.size() {
+			ptr.add(i).write(pattern(i));
+		}
+
+		let mut ptr = ptr;
+		for (step, from, to) in [
+			("realloc grow", layout, grown),
+			("realloc shrink", grown, shrunk),
+		] {
+			let res = self.realloc(ptr, from, to.size());
+			if !checks.check(!res.is_null(), || SelfTestFailure::Null {
+				step,
+				layout: to,
+			}) {
+				self.dealloc(ptr, from);
+				return;
+			}
+			ptr = res;
+			checks.count(step, to, allocated + self.charged(to), self.allocated());
+			let len = from.size().min(to.size());
+			let _ = checks.check(intact(ptr, len), || SelfTestFailure::Contents {
+				step,
+				layout: to,
+			});
+		}
+		self.dealloc(ptr, shrunk);
+		#[cfg(feature = "quarantine")]
+		self.flush_quarantine();
+		checks.count("dealloc: allocated", layout, allocated, self.allocated());
+		#[cfg(feature = "overhead")]
+		checks.count(
+			"dealloc: live allocations",
+			layout,
+			live,
+			self.live_allocations(),
+		);
+
+		let ptr = self.alloc_zeroed(layout);
+		if !checks.check(!ptr.is_null(), || SelfTestFailure::Null {
+			step: "alloc_zeroed",
+			layout,
+		}) {
+			return;
+		}
+		let zeroed = (0..layout.size()).all(|i| *ptr.add(i) == 0);
+		let _ = checks.check(zeroed, || SelfTestFailure::Contents {
+			step: "alloc_zeroed",
+			layout,
+		});
+		checks.count(
+			"alloc_zeroed: allocated",
+			layout,
+			allocated + self.charged(layout),
+			self.allocated(),
+		);
+		self.dealloc(ptr, layout);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::alloc::{Layout, System};
+
+	use super::SelfTestFailure;
+	use crate::Cap;
+
+	#[test]
+	fn self_test() {
+		let report = Cap::new(System, usize::MAX)
+			.with_granularity(64)
+			.self_test();
+		assert!(report.passed(), "{:?}", report.failures);
+		let checks = if cfg!(feature = "overhead") {
+			4 * 14 + 2
+		} else {
+			4 * 12 + 1
+		};
+		assert_eq!(report.checks, checks);
+		// Too little to allocate the largest layout.
+		let report = Cap::new(System, 1 << 16).self_test();
+		assert!(!report.passed());
+		assert_eq!(
+			report.failures,
+			[SelfTestFailure::Null {
+				step: "alloc",
+				layout: Layout::from_size_align(1 << 20, 4096).unwrap(),
+			}]
+		);
+	}
+}
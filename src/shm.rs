@@ -0,0 +1,188 @@
+//! Mirroring of a [`Cap`]'s counters into a shared-memory region, so that other processes can read them.
+
+use std::{
+	ffi::{c_int, c_long, c_void}, fs, io, os::unix::io::AsRawFd, path::Path, ptr, sync::atomic::{self, AtomicU32, AtomicU64, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+use crate::{Cap, Monitor};
+
+extern "C" {
+	// `offset` is an `off_t`, which is a `long` on the targets this supports, including 32-bit Linux without large file support.
+	fn mmap(
+		addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: c_long,
+	) -> *mut c_void;
+	fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 1;
+const MAP_FAILED: usize = !0;
+
+/// The layout of the region written by [`Cap::export_shared`].
+///
+/// All fields are native-endian and naturally aligned. Readers should:
+///
+/// 1. check that `magic` is [`SharedStats::MAGIC`] and `version` is one they understand (fields are only ever appended, growing `size`);
+/// 2. read `sequence`, then the counters, then `sequence` again, retrying if the two differ or are odd, as the region is being updated.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SharedStats {
+	/// Always [`SharedStats::MAGIC`].
+	pub magic: u64,
+	/// The version of this layout, currently [`SharedStats::VERSION`].
+	pub version: u32,
+	/// The size in bytes of this layout.
+	pub size: u32,
+	/// Incremented before and after each update, so odd while one is in progress.
+	pub sequence: AtomicU64,
+	/// The result of [`Cap::allocated`].
+	pub allocated: AtomicU64,
+	/// The result of [`Cap::limit`].
+	pub limit: AtomicU64,
+	/// The result of [`Cap::total_allocated`], or 0 without the `stats-counts` feature.
+	pub total_allocated: AtomicU64,
+	/// The result of [`Cap::max_allocated`], or 0 without the `stats-peaks` feature.
+	pub max_allocated: AtomicU64,
+	/// The time of the last update, in milliseconds since the Unix epoch.
+	pub updated: AtomicU64,
+	/// The interval between updates, in milliseconds.
+	pub interval: AtomicU32,
+	_reserved: u32,
+}
+
+impl SharedStats {
+	/// The bytes `b"cap\0stat"`, read as a native-endian `u64`.
+	pub const MAGIC: u64 = u64::from_ne_bytes(*b"cap\0stat");
+	/// The current version of the layout.
+	pub const VERSION: u32 = 1;
+}
+
+/// A mapping of a [`SharedStats`] region, unmapped when dropped.
+struct Mapping(*mut SharedStats);
+
+// The region is only written through atomics once initialised.
+unsafe impl Send for Mapping {}
+
+impl Drop for Mapping {
+	fn drop(&mut self) {
+		let _ = unsafe { munmap(self.0.cast(), size_of::<SharedStats>()) };
+	}
+}
+
+impl<H> Cap<H> {
+	/// Mirror this allocator's counters into the file `path`, every `interval`, on a background thread.
+	///
+	/// The file is created if necessary and memory-mapped with the layout of [`SharedStats`]. Placing it on a `tmpfs` such as `/dev/shm` means updates never touch disk; sidecars can then `mmap` it to scrape usage without the process exposing any endpoint.
+	///
+	/// The counters are mirrored once before this returns, and then until the returned [`Monitor`] is dropped, which unmaps the file but leaves it in place.
+	pub fn export_shared(
+		&'static self, path: impl AsRef<Path>, interval: Duration,
+	) -> io::Result<Monitor>
+	where
+		H: Sync,
+	{
+		let size = size_of::<SharedStats>();
+		// Not truncated, so that readers with the file already mapped don't fault.
+		let file = fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(path)?;
+		file.set_len(size as u64)?;
+		let region = unsafe {
+			mmap(
+				ptr::null_mut(),
+				size,
+				PROT_READ | PROT_WRITE,
+				MAP_SHARED,
+				file.as_raw_fd(),
+				0,
+			)
+		};
+		if region as usize == MAP_FAILED {
+			return Err(io::Error::last_os_error());
+		}
+		// The mapping outlives the file descriptor.
+		let mapping = Mapping(region.cast());
+		let shared = mapping.0;
+		unsafe {
+			(&raw mut (*shared).magic).write(SharedStats::MAGIC);
+			(&raw mut (*shared).version).write(SharedStats::VERSION);
+			#[allow(clippy::cast_possible_truncation)]
+			(&raw mut (*shared).size).write(size as u32);
+		}
+		let shared = unsafe { &*shared };
+		#[allow(clippy::cast_possible_truncation)]
+		shared
+			.interval
+			.store(interval.as_millis() as u32, Ordering::Relaxed);
+		self.mirror(shared);
+		Ok(Monitor::spawn(move |stop| {
+			let shared = unsafe { &*mapping.0 };
+			while stop.sleep(interval) {
+				self.mirror(shared);
+			}
+		}))
+	}
+
+	fn mirror(&self, shared: &SharedStats) {
+		let _ = shared.sequence.fetch_add(1, Ordering::Relaxed);
+		atomic::fence(Ordering::Release);
+		shared
+			.allocated
+			.store(self.allocated() as u64, Ordering::Relaxed);
+		shared.limit.store(self.limit() as u64, Ordering::Relaxed);
+		#[cfg(feature = "stats-counts")]
+		shared
+			.total_allocated
+			.store(self.total_allocated() as u64, Ordering::Relaxed);
+		#[cfg(feature = "stats-peaks")]
+		shared
+			.max_allocated
+			.store(self.max_allocated() as u64, Ordering::Relaxed);
+		#[allow(clippy::cast_possible_truncation)]
+		let updated = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |since| since.as_millis() as u64);
+		shared.updated.store(updated, Ordering::Relaxed);
+		let _ = shared.sequence.fetch_add(1, Ordering::Release);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{alloc::System, convert::TryInto, env, fs, thread, time::Duration};
+
+	use super::SharedStats;
+	use crate::Cap;
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn export_shared() {
+		let cap: &'static Cap<System> = Box::leak(Box::new(Cap::new(System, 1000)));
+		cap.charge(100).unwrap();
+		let path = env::temp_dir().join(format!("cap-shm-{}", std::process::id()));
+		let monitor = cap
+			.export_shared(&path, Duration::from_millis(100))
+			.unwrap();
+		let read = || {
+			let bytes = fs::read(&path).unwrap();
+			assert_eq!(bytes.len(), size_of::<SharedStats>());
+			let word =
+				|offset: usize| u64::from_ne_bytes(bytes[offset..offset + 8].try_into().unwrap());
+			assert_eq!(word(0), SharedStats::MAGIC);
+			(word(24), word(32))
+		};
+		assert_eq!(read(), (100, 1000));
+		cap.charge(100).unwrap();
+		while read() != (200, 1000) {
+			thread::sleep(Duration::from_millis(10));
+		}
+		drop(monitor);
+		// Unmapped, leaving the last update in the file.
+		assert_eq!(read(), (200, 1000));
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,141 @@
+//! Detection of allocations made from within signal handlers.
+
+use std::{
+	alloc::Layout, cell::Cell, mem, sync::atomic::{AtomicPtr, AtomicUsize, Ordering}
+};
+
+use crate::{reentrancy, Cap};
+
+#[cfg(unix)]
+extern "C" {
+	fn write(fd: std::ffi::c_int, buf: *const u8, count: usize) -> isize;
+}
+
+thread_local! {
+	static IN_HANDLER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `handler`, the body of a signal handler, marking this thread as handling a signal so that [`Cap`]s report allocations it makes.
+///
+/// The allocator isn't async-signal-safe, so a handler that allocates can deadlock or corrupt the heap if the signal interrupted an allocation on the same thread. Such allocations still go ahead, as most of the time they get away with it, but each is reported by the cap it is made through, as set by [`Cap::set_signal_allocation_hook`].
+///
+/// ```
+/// use std::alloc;
+/// use cap::Cap;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// extern "C" fn on_signal(_: i32) {
+///     cap::signal_handler(|| {
+///         // Only async-signal-safe work here.
+///     });
+/// }
+/// # fn main() {}
+/// ```
+pub fn signal_handler<R>(handler: impl FnOnce() -> R) -> R {
+	let previous = IN_HANDLER
+		.try_with(|in_handler| in_handler.replace(true))
+		.unwrap_or(true);
+	let _reset = Reset(previous);
+	handler()
+}
+
+/// Restores whether this thread was handling a signal when dropped, even if the handler panicked.
+struct Reset(bool);
+
+impl Drop for Reset {
+	fn drop(&mut self) {
+		let _ = IN_HANDLER.try_with(|in_handler| in_handler.set(self.0));
+	}
+}
+
+/// Return whether this thread is running a signal handler wrapped with [`signal_handler`].
+pub(crate) fn in_handler() -> bool {
+	IN_HANDLER.try_with(Cell::get).unwrap_or(false)
+}
+
+/// The allocations made from within signal handlers, and how they are reported.
+#[derive(Debug)]
+pub(crate) struct Detector {
+	count: AtomicUsize,
+	hook: AtomicPtr<()>,
+}
+
+impl Detector {
+	pub(crate) const fn new() -> Self {
+		Self {
+			count: AtomicUsize::new(0),
+			hook: AtomicPtr::new(std::ptr::null_mut()),
+		}
+	}
+
+	/// Report an allocation of `layout` from within a signal handler.
+	fn report(&self, layout: Layout) {
+		let _ = self.count.fetch_add(1, Ordering::Relaxed);
+		let hook = self.hook.load(Ordering::Acquire);
+		if hook.is_null() {
+			// Directly, as the standard library's stderr takes a lock.
+			#[cfg(unix)]
+			{
+				const MESSAGE: &[u8] = b"cap: allocation from within a signal handler\n";
+				let _ = unsafe { write(2, MESSAGE.as_ptr(), MESSAGE.len()) };
+			}
+		} else {
+			let hook = unsafe { mem::transmute::<*mut (), fn(Layout)>(hook) };
+			let _ = reentrancy::call(|| hook(layout));
+		}
+	}
+}
+
+impl<H> Cap<H> {
+	/// Set a function to be called with the layout of each allocation made from within a [signal handler](signal_handler), in place of the default of writing a line to stderr.
+	///
+	/// The function is called within the signal handler, so should itself be async-signal-safe, such as by incrementing a counter.
+	pub fn set_signal_allocation_hook(&self, hook: fn(Layout)) {
+		self.signals.hook.store(hook as *mut (), Ordering::Release);
+	}
+
+	/// Return the number of allocations, reallocations and failed attempts at either that have been made from within [signal handlers](signal_handler).
+	pub fn signal_allocations(&self) -> usize {
+		self.signals.count.load(Ordering::Relaxed)
+	}
+
+	/// Report an allocation of `layout` if it was made from within a signal handler.
+	pub(crate) fn check_signal(&self, layout: Layout) {
+		if in_handler() {
+			self.signals.report(layout);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, sync::atomic::{AtomicUsize, Ordering}
+	};
+
+	use super::signal_handler;
+	use crate::Cap;
+
+	#[test]
+	fn signal_allocations() {
+		static CAP: Cap<System> = Cap::new(System, usize::MAX);
+		static REPORTED: AtomicUsize = AtomicUsize::new(0);
+		CAP.set_signal_allocation_hook(|layout| {
+			let _ = REPORTED.fetch_add(layout.size(), Ordering::Relaxed);
+		});
+		let layout = Layout::new::<[u8; 100]>();
+		unsafe {
+			let ptr = CAP.alloc(layout);
+			let ptr = signal_handler(|| {
+				// Nested, as when a second signal arrives while handling the first.
+				signal_handler(|| ());
+				CAP.realloc(ptr, layout, 200)
+			});
+			CAP.dealloc(ptr, Layout::new::<[u8; 200]>());
+		}
+		assert_eq!(CAP.signal_allocations(), 1);
+		assert_eq!(REPORTED.load(Ordering::Relaxed), 200);
+	}
+}
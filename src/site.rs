@@ -0,0 +1,191 @@
+//! Per-call-site allocation counters, as registered by [`count_site!`](crate::count_site).
+
+use std::{
+	cell::Cell, marker::PhantomData, ptr, sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering}
+};
+
+/// The most recently registered site, heading a list linked through each site's `next`.
+static SITES: AtomicPtr<Site> = AtomicPtr::new(ptr::null_mut());
+
+thread_local! {
+	static CURRENT: Cell<*const Site> = const { Cell::new(ptr::null()) };
+}
+
+/// A call site whose allocations are counted, as declared by [`count_site!`](crate::count_site).
+///
+/// Allocations made through any [`Cap`](crate::Cap) on a thread while it is within a site are counted against that site, so hot paths can be attributed at the cost of one thread-local access per allocation.
+#[derive(Debug)]
+pub struct Site {
+	file: &'static str,
+	line: u32,
+	column: u32,
+	allocations: AtomicUsize,
+	bytes: AtomicUsize,
+	registered: AtomicBool,
+	next: AtomicPtr<Site>,
+}
+
+impl Site {
+	#[doc(hidden)]
+	#[must_use]
+	pub const fn new(file: &'static str, line: u32, column: u32) -> Self {
+		Self {
+			file,
+			line,
+			column,
+			allocations: AtomicUsize::new(0),
+			bytes: AtomicUsize::new(0),
+			registered: AtomicBool::new(false),
+			next: AtomicPtr::new(ptr::null_mut()),
+		}
+	}
+
+	/// Count allocations on this thread against this site until the returned guard is dropped, registering it if this is the first time.
+	#[doc(hidden)]
+	pub fn enter(&'static self) -> SiteGuard {
+		if !self.registered.swap(true, Ordering::AcqRel) {
+			let mut head = SITES.load(Ordering::Acquire);
+			loop {
+				self.next.store(head, Ordering::Relaxed);
+				match SITES.compare_exchange_weak(
+					head,
+					ptr::from_ref(self).cast_mut(),
+					Ordering::AcqRel,
+					Ordering::Acquire,
+				) {
+					Ok(_) => break,
+					Err(current) => head = current,
+				}
+			}
+		}
+		SiteGuard {
+			previous: CURRENT.with(|current| current.replace(self)),
+			_not_send: PhantomData,
+		}
+	}
+
+	/// Return the sites that have been entered, most recently registered first.
+	pub fn all() -> impl Iterator<Item = &'static Site> {
+		let mut next = SITES.load(Ordering::Acquire);
+		std::iter::from_fn(move || {
+			// Safe as sites are statics, and only added to the list once initialised.
+			let site = unsafe { next.as_ref() }?;
+			next = site.next.load(Ordering::Relaxed);
+			Some(site)
+		})
+	}
+
+	/// Return the file the site is in.
+	#[must_use]
+	pub fn file(&self) -> &'static str {
+		self.file
+	}
+
+	/// Return the line the site is on.
+	#[must_use]
+	pub fn line(&self) -> u32 {
+		self.line
+	}
+
+	/// Return the column the site starts at.
+	#[must_use]
+	pub fn column(&self) -> u32 {
+		self.column
+	}
+
+	/// Return the number of allocations made within the site.
+	#[must_use]
+	pub fn allocations(&self) -> usize {
+		self.allocations.load(Ordering::Relaxed)
+	}
+
+	/// Return the number of bytes allocated within the site, including the growth of reallocations.
+	#[must_use]
+	pub fn bytes(&self) -> usize {
+		self.bytes.load(Ordering::Relaxed)
+	}
+}
+
+/// A guard returned by entering a [`Site`] that restores the previous site when dropped.
+#[derive(Debug)]
+pub struct SiteGuard {
+	previous: *const Site,
+	_not_send: PhantomData<*const ()>,
+}
+
+impl Drop for SiteGuard {
+	fn drop(&mut self) {
+		CURRENT.with(|current| current.set(self.previous));
+	}
+}
+
+/// Count an allocation of `allocated` bytes, or a reallocation growing by them, against the site this thread is within, if any.
+pub(crate) fn record(allocation: bool, allocated: usize) {
+	let site = CURRENT.try_with(Cell::get).unwrap_or(ptr::null());
+	// Safe as sites are statics.
+	if let Some(site) = unsafe { site.as_ref() } {
+		if allocation {
+			let _ = site.allocations.fetch_add(1, Ordering::Relaxed);
+		}
+		let _ = site.bytes.fetch_add(allocated, Ordering::Relaxed);
+	}
+}
+
+/// Count the allocations made while evaluating an expression against this call site.
+///
+/// This registers a static per-call-site [`Site`], listed by [`Site::all`], far cheaper than sampling backtraces:
+///
+/// ```
+/// use std::alloc;
+/// use cap::{count_site, Cap, Site};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     for _ in 0..10 {
+///         let buffer = count_site!(vec![0u8; 100]);
+/// #       drop(buffer);
+///     }
+///     let site = Site::all().next().unwrap();
+///     assert_eq!((site.allocations(), site.bytes()), (10, 1000));
+/// }
+/// ```
+#[macro_export]
+macro_rules! count_site {
+	($expr:expr) => {{
+		static SITE: $crate::Site = $crate::Site::new(file!(), line!(), column!());
+		let _guard = SITE.enter();
+		$expr
+	}};
+}
+
+#[cfg(test)]
+mod tests {
+	use std::alloc::{GlobalAlloc, Layout, System};
+
+	use super::Site;
+	use crate::Cap;
+
+	#[test]
+	fn count_site() {
+		let cap = Cap::new(System, usize::MAX);
+		let layout = Layout::new::<[u8; 100]>();
+		let (outer, inner) = unsafe {
+			count_site!({
+				let outer = cap.alloc(layout);
+				let inner = count_site!(cap.realloc(cap.alloc(layout), layout, 150));
+				(outer, inner)
+			})
+		};
+		unsafe {
+			cap.dealloc(outer, layout);
+			cap.dealloc(inner, Layout::new::<[u8; 150]>());
+		}
+		// The inner site was registered after the outer one.
+		let sites = Site::all()
+			.filter(|site| site.file() == file!())
+			.map(|site| (site.allocations(), site.bytes()));
+		assert!(sites.eq([(1, 150), (1, 100)]));
+	}
+}
@@ -0,0 +1,126 @@
+//! Human-readable sizes, parsed at compile time.
+
+const UNITS: [(&str, u64); 9] = [
+	("B", 1),
+	("KB", 1000),
+	("MB", 1000 * 1000),
+	("GB", 1000 * 1000 * 1000),
+	("TB", 1000 * 1000 * 1000 * 1000),
+	("KiB", 1 << 10),
+	("MiB", 1 << 20),
+	("GiB", 1 << 30),
+	("TiB", 1 << 40),
+];
+
+/// Parse a human-readable number of bytes: an integer optionally followed by a unit, such as `"4096"`, `"64 KB"` or `"256MiB"`.
+///
+/// The units are `B`, the decimal `KB`, `MB`, `GB` and `TB`, and the binary `KiB`, `MiB`, `GiB` and `TiB`. Returns `None` if `size` isn't of that form or overflows a `usize`.
+///
+/// As it is a `const fn`, [`bytes!`](crate::bytes) can use it to check sizes at compile time.
+#[must_use]
+pub const fn parse_size(size: &str) -> Option<usize> {
+	let size = size.as_bytes();
+	let mut i = 0;
+	let mut number: u64 = 0;
+	while i < size.len() && size[i].is_ascii_digit() {
+		number = match number.checked_mul(10) {
+			Some(number) => match number.checked_add((size[i] - b'0') as u64) {
+				Some(number) => number,
+				None => return None,
+			},
+			None => return None,
+		};
+		i += 1;
+	}
+	if i == 0 {
+		return None;
+	}
+	if i < size.len() && size[i] == b' ' {
+		i += 1;
+	}
+	let unit = size.split_at(i).1;
+	let mut multiplier = if unit.is_empty() { Some(1) } else { None };
+	let mut u = 0;
+	while u < UNITS.len() {
+		if eq(unit, UNITS[u].0.as_bytes()) {
+			multiplier = Some(UNITS[u].1);
+		}
+		u += 1;
+	}
+	match multiplier {
+		Some(multiplier) => match number.checked_mul(multiplier) {
+			#[allow(clippy::cast_possible_truncation)] // checked to fit
+			Some(bytes) if bytes <= usize::MAX as u64 => Some(bytes as usize),
+			_ => None,
+		},
+		None => None,
+	}
+}
+
+const fn eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+	let mut i = 0;
+	while i < a.len() {
+		if a[i] != b[i] {
+			return false;
+		}
+		i += 1;
+	}
+	true
+}
+
+/// Evaluate to the number of bytes in a human-readable size, such as `bytes!("256MiB")`, failing the build if it is invalid.
+///
+/// See [`parse_size`](crate::parse_size) for the format accepted.
+///
+/// ```
+/// use std::alloc;
+/// use cap::{bytes, Cap};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new_checked(alloc::System, bytes!("256MiB"));
+///
+/// fn main() {
+///     assert_eq!(ALLOCATOR.limit(), 256 * 1024 * 1024);
+/// }
+/// ```
+///
+/// ```compile_fail
+/// const LIMIT: usize = cap::bytes!("256 MiBs");
+/// ```
+#[macro_export]
+macro_rules! bytes {
+	($size:expr) => {{
+		const BYTES: usize = match $crate::parse_size($size) {
+			Some(bytes) => bytes,
+			None => panic!("cap: invalid size"),
+		};
+		BYTES
+	}};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::parse_size;
+
+	#[test]
+	fn parse() {
+		assert_eq!(parse_size("4096"), Some(4096));
+		assert_eq!(parse_size("64 KB"), Some(64_000));
+		assert_eq!(parse_size("256MiB"), Some(256 << 20));
+		assert_eq!(parse_size("1TiB"), Some(1 << 40));
+		assert_eq!(bytes!("2 GiB"), 2 << 30);
+		for invalid in [
+			"",
+			"MiB",
+			"1.5GiB",
+			"1  MiB",
+			"1 mib",
+			"99999999999999999999",
+		] {
+			assert_eq!(parse_size(invalid), None, "{invalid}");
+		}
+	}
+}
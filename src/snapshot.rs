@@ -0,0 +1,501 @@
+//! A compact, versioned binary snapshot of a [`Cap`]'s usage, for external tools to consume.
+//!
+//! A snapshot begins with the 8-byte magic `CAPSNAP\0` and a little-endian `u16` version, currently 1. A sequence of sections follows, each a `u8` kind, a little-endian `u32` length and that many bytes of payload. All integers are little-endian, and counts of bytes are `u64`, with `u64::MAX` meaning a statistic isn't available. Readers skip sections of kinds they don't know, so that sections can be added without bumping the version:
+//!
+//! * Kind 1, the statistics: `time` (seconds since the Unix epoch), `pid`, `allocated`, `limit`, `soft_limit`, `external`, `peak`, `total_allocated` and `failures`, each a `u64`.
+//! * Kind 2, the tags: a `u32` count, then for each a `u16` length and that many bytes of UTF-8 name, followed by `allocated` and `limit` as `u64`s.
+//!
+//! ```
+//! use std::alloc::System;
+//! use cap::{snapshot::Snapshot, Cap};
+//!
+//! let cap = Cap::new(System, 1024);
+//! let mut dump = Vec::new();
+//! cap.export_snapshot(&mut dump).unwrap();
+//! let snapshot = Snapshot::parse(&dump).unwrap();
+//! assert_eq!((snapshot.allocated, snapshot.limit), (0, 1024));
+//! ```
+
+use std::{
+	fmt, io::{self, Read, Write}, process, time::{SystemTime, UNIX_EPOCH}
+};
+
+use crate::Cap;
+
+/// The bytes every snapshot begins with.
+pub const MAGIC: [u8; 8] = *b"CAPSNAP\0";
+/// The version of the format written.
+pub const VERSION: u16 = 1;
+
+const STATS: u8 = 1;
+const TAGS: u8 = 2;
+
+/// A point-in-time record of a [`Cap`]'s usage, as written by [`Cap::export_snapshot`] and read by [`Snapshot::parse`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Snapshot {
+	/// When it was taken, in seconds since the Unix epoch.
+	pub time: u64,
+	/// The id of the process it was taken in.
+	pub pid: u64,
+	/// The bytes [allocated](Cap::allocated).
+	pub allocated: u64,
+	/// The [limit](Cap::limit).
+	pub limit: u64,
+	/// The [soft limit](Cap::soft_limit).
+	pub soft_limit: u64,
+	/// The bytes [charged externally](Cap::external).
+	pub external: u64,
+	/// The [peak](Cap::max_allocated) bytes allocated, if the `stats-peaks` feature was enabled.
+	pub peak: Option<u64>,
+	/// The [total](Cap::total_allocated) bytes allocated, if the `stats-counts` feature was enabled.
+	pub total_allocated: Option<u64>,
+	/// The number of [failed](Cap::failure_count) allocations, if the `stats-counts` feature was enabled.
+	pub failures: Option<u64>,
+	/// The registered tags, if the `tags` feature was enabled.
+	pub tags: Vec<TagSnapshot>,
+}
+
+/// A tag's usage in a [`Snapshot`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TagSnapshot {
+	/// The tag's name.
+	pub name: String,
+	/// The bytes allocated while attributed to it.
+	pub allocated: u64,
+	/// Its [limit](Cap::tag_limit).
+	pub limit: u64,
+}
+
+/// Why a snapshot couldn't be parsed, as returned by [`Snapshot::parse`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SnapshotError {
+	/// It doesn't begin with [`MAGIC`].
+	BadMagic,
+	/// It is of a version newer than this crate understands.
+	UnsupportedVersion(u16),
+	/// It ends partway through a section.
+	Truncated,
+	/// A tag's name isn't valid UTF-8.
+	InvalidName,
+}
+
+impl fmt::Display for SnapshotError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SnapshotError::BadMagic => f.write_str("not a cap snapshot"),
+			SnapshotError::UnsupportedVersion(version) => {
+				write!(f, "unsupported snapshot version {version}")
+			}
+			SnapshotError::Truncated => f.write_str("truncated snapshot"),
+			SnapshotError::InvalidName => f.write_str("tag name isn't valid UTF-8"),
+		}
+	}
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl Snapshot {
+	/// Take a snapshot of `cap`'s usage.
+	pub fn capture<H>(cap: &Cap<H>) -> Self {
+		#[cfg(feature = "stats-peaks")]
+		let peak = Some(cap.max_allocated() as u64);
+		#[cfg(not(feature = "stats-peaks"))]
+		let peak = None;
+		#[cfg(feature = "stats-counts")]
+		let (total_allocated, failures) = (
+			Some(cap.total_allocated() as u64),
+			Some(cap.failure_count() as u64),
+		);
+		#[cfg(not(feature = "stats-counts"))]
+		let (total_allocated, failures) = (None, None);
+		#[cfg(feature = "tags")]
+		let tags = cap
+			.stats_by_tag()
+			.into_iter()
+			.map(|stats| TagSnapshot {
+				name: stats.tag.name().to_owned(),
+				allocated: stats.allocated as u64,
+				limit: stats.limit as u64,
+			})
+			.collect();
+		#[cfg(not(feature = "tags"))]
+		let tags = Vec::new();
+		Self {
+			time: SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map_or(0, |since| since.as_secs()),
+			pid: u64::from(process::id()),
+			allocated: cap.allocated() as u64,
+			limit: cap.limit() as u64,
+			soft_limit: cap.soft_limit() as u64,
+			external: cap.external() as u64,
+			peak,
+			total_allocated,
+			failures,
+			tags,
+		}
+	}
+
+	/// Write this snapshot to `writer` in the binary format.
+	pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+		let mut out = Vec::with_capacity(256);
+		out.extend_from_slice(&MAGIC);
+		out.extend_from_slice(&VERSION.to_le_bytes());
+		let stats = [
+			self.time,
+			self.pid,
+			self.allocated,
+			self.limit,
+			self.soft_limit,
+			self.external,
+			self.peak.unwrap_or(u64::MAX),
+			self.total_allocated.unwrap_or(u64::MAX),
+			self.failures.unwrap_or(u64::MAX),
+		];
+		section(&mut out, STATS, |out| {
+			for stat in stats {
+				out.extend_from_slice(&stat.to_le_bytes());
+			}
+		});
+		if !self.tags.is_empty() {
+			section(&mut out, TAGS, |out| {
+				#[allow(clippy::cast_possible_truncation)]
+				out.extend_from_slice(&(self.tags.len() as u32).to_le_bytes());
+				for tag in &self.tags {
+					let name = &tag.name.as_bytes()[..tag.name.len().min(u16::MAX.into())];
+					#[allow(clippy::cast_possible_truncation)]
+					out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+					out.extend_from_slice(name);
+					out.extend_from_slice(&tag.allocated.to_le_bytes());
+					out.extend_from_slice(&tag.limit.to_le_bytes());
+				}
+			});
+		}
+		writer.write_all(&out)
+	}
+
+	/// Read a snapshot from the whole of `reader`.
+	pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+		let mut bytes = Vec::new();
+		let _ = reader.read_to_end(&mut bytes)?;
+		Self::parse(&bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+	}
+
+	/// Parse a snapshot from `bytes`.
+	pub fn parse(bytes: &[u8]) -> Result<Self, SnapshotError> {
+		let mut input = Input(bytes);
+		if input.take(MAGIC.len()) != Some(&MAGIC[..]) {
+			return Err(SnapshotError::BadMagic);
+		}
+		let version = input.u16()?;
+		if version > VERSION {
+			return Err(SnapshotError::UnsupportedVersion(version));
+		}
+		let mut snapshot = Self::default();
+		while !input.0.is_empty() {
+			let kind = input.u8()?;
+			let len = input.u32()? as usize;
+			let mut payload = Input(input.take(len).ok_or(SnapshotError::Truncated)?);
+			match kind {
+				STATS => {
+					let available = |stat: u64| (stat != u64::MAX).then_some(stat);
+					snapshot.time = payload.u64()?;
+					snapshot.pid = payload.u64()?;
+					snapshot.allocated = payload.u64()?;
+					snapshot.limit = payload.u64()?;
+					snapshot.soft_limit = payload.u64()?;
+					snapshot.external = payload.u64()?;
+					snapshot.peak = available(payload.u64()?);
+					snapshot.total_allocated = available(payload.u64()?);
+					snapshot.failures = available(payload.u64()?);
+				}
+				TAGS => {
+					for _ in 0..payload.u32()? {
+						let len = payload.u16()?.into();
+						let name = payload.take(len).ok_or(SnapshotError::Truncated)?;
+						snapshot.tags.push(TagSnapshot {
+							name: String::from_utf8(name.to_owned())
+								.map_err(|_| SnapshotError::InvalidName)?,
+							allocated: payload.u64()?,
+							limit: payload.u64()?,
+						});
+					}
+				}
+				_ => (),
+			}
+		}
+		Ok(snapshot)
+	}
+}
+
+impl Snapshot {
+	/// Return the `n` tags with the most bytes allocated, largest first.
+	#[must_use]
+	pub fn top_tags(&self, n: usize) -> Vec<&TagSnapshot> {
+		let mut tags = self.tags.iter().collect::<Vec<_>>();
+		tags.sort_by_key(|tag| std::cmp::Reverse(tag.allocated));
+		tags.truncate(n);
+		tags
+	}
+
+	/// Return what changed between this snapshot and the `later` one.
+	#[must_use]
+	pub fn diff(&self, later: &Snapshot) -> SnapshotDiff {
+		let mut tags = later
+			.tags
+			.iter()
+			.map(|tag| {
+				let before = self.tags.iter().find(|before| before.name == tag.name);
+				(
+					tag.name.clone(),
+					delta(before.map_or(0, |before| before.allocated), tag.allocated),
+				)
+			})
+			.chain(
+				self.tags
+					.iter()
+					.filter(|tag| later.tags.iter().all(|after| after.name != tag.name))
+					.map(|tag| (tag.name.clone(), delta(tag.allocated, 0))),
+			)
+			.filter(|&(_, delta)| delta != 0)
+			.collect::<Vec<_>>();
+		tags.sort_by_key(|(_, delta)| std::cmp::Reverse(delta.unsigned_abs()));
+		SnapshotDiff {
+			elapsed: later.time.saturating_sub(self.time),
+			allocated: delta(self.allocated, later.allocated),
+			limit: delta(self.limit, later.limit),
+			failures: self
+				.failures
+				.zip(later.failures)
+				.map(|(before, after)| delta(before, after)),
+			tags,
+		}
+	}
+}
+
+/// The change from `before` to `after`.
+fn delta(before: u64, after: u64) -> i128 {
+	i128::from(after) - i128::from(before)
+}
+
+impl fmt::Display for Snapshot {
+	/// Print the statistics, one per line, followed by the 10 largest tags.
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let unlimited = |limit: u64| {
+			if limit == u64::MAX {
+				"unlimited".to_owned()
+			} else {
+				limit.to_string()
+			}
+		};
+		writeln!(f, "time:            {}", self.time)?;
+		writeln!(f, "pid:             {}", self.pid)?;
+		writeln!(f, "allocated:       {}", self.allocated)?;
+		writeln!(f, "limit:           {}", unlimited(self.limit))?;
+		writeln!(f, "soft limit:      {}", unlimited(self.soft_limit))?;
+		writeln!(f, "external:        {}", self.external)?;
+		for (name, stat) in [
+			("peak:           ", self.peak),
+			("total allocated:", self.total_allocated),
+			("failures:       ", self.failures),
+		] {
+			if let Some(stat) = stat {
+				writeln!(f, "{name} {stat}")?;
+			}
+		}
+		let top = self.top_tags(10);
+		if !top.is_empty() {
+			writeln!(f, "top tags:")?;
+		}
+		for tag in top {
+			writeln!(
+				f,
+				"  {}: {} (limit {})",
+				tag.name,
+				tag.allocated,
+				unlimited(tag.limit)
+			)?;
+		}
+		Ok(())
+	}
+}
+
+/// The changes between two snapshots, as returned by [`Snapshot::diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SnapshotDiff {
+	/// The seconds between them.
+	pub elapsed: u64,
+	/// The change in bytes allocated.
+	pub allocated: i128,
+	/// The change in the limit.
+	pub limit: i128,
+	/// The change in the number of failed allocations, if both record it.
+	pub failures: Option<i128>,
+	/// The change in bytes allocated by each tag whose allocation changed, largest change first. Tags missing from one of the snapshots count as having nothing allocated in it.
+	pub tags: Vec<(String, i128)>,
+}
+
+impl fmt::Display for SnapshotDiff {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "elapsed:   {}s", self.elapsed)?;
+		writeln!(f, "allocated: {:+}", self.allocated)?;
+		if self.limit != 0 {
+			writeln!(f, "limit:     {:+}", self.limit)?;
+		}
+		if let Some(failures) = self.failures {
+			writeln!(f, "failures:  {failures:+}")?;
+		}
+		if !self.tags.is_empty() {
+			writeln!(f, "tags:")?;
+		}
+		for (name, delta) in &self.tags {
+			writeln!(f, "  {name}: {delta:+}")?;
+		}
+		Ok(())
+	}
+}
+
+/// Append a section of `kind` to `out`, with the payload written by `f`.
+fn section(out: &mut Vec<u8>, kind: u8, f: impl FnOnce(&mut Vec<u8>)) {
+	out.push(kind);
+	let start = out.len();
+	out.extend_from_slice(&[0; 4]);
+	f(out);
+	#[allow(clippy::cast_possible_truncation)]
+	let len = (out.len() - start - 4) as u32;
+	out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+/// The unparsed remainder of a snapshot.
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+	fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+		if self.0.len() < len {
+			return None;
+		}
+		let (taken, rest) = self.0.split_at(len);
+		self.0 = rest;
+		Some(taken)
+	}
+
+	fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+		let taken = self.take(N).ok_or(SnapshotError::Truncated)?;
+		let mut array = [0; N];
+		array.copy_from_slice(taken);
+		Ok(array)
+	}
+
+	fn u8(&mut self) -> Result<u8, SnapshotError> {
+		Ok(self.array::<1>()?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16, SnapshotError> {
+		self.array().map(u16::from_le_bytes)
+	}
+
+	fn u32(&mut self) -> Result<u32, SnapshotError> {
+		self.array().map(u32::from_le_bytes)
+	}
+
+	fn u64(&mut self) -> Result<u64, SnapshotError> {
+		self.array().map(u64::from_le_bytes)
+	}
+}
+
+impl<H> Cap<H> {
+	/// Write a [`Snapshot`] of this allocator's usage to `writer`, in the [binary format](crate::snapshot).
+	pub fn export_snapshot(&self, writer: &mut impl Write) -> io::Result<()> {
+		Snapshot::capture(self).write(writer)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Snapshot, SnapshotError, TagSnapshot, MAGIC};
+
+	fn tag(name: &str, allocated: u64) -> TagSnapshot {
+		TagSnapshot {
+			name: name.to_owned(),
+			allocated,
+			limit: u64::MAX,
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		let snapshot = Snapshot {
+			time: 1,
+			pid: 2,
+			allocated: 3,
+			limit: 4,
+			soft_limit: 5,
+			external: 0,
+			peak: Some(6),
+			total_allocated: None,
+			failures: Some(0),
+			tags: vec![TagSnapshot {
+				name: "cache".to_owned(),
+				allocated: 7,
+				limit: u64::MAX,
+			}],
+		};
+		let mut dump = Vec::new();
+		snapshot.write(&mut dump).unwrap();
+		assert_eq!(Snapshot::parse(&dump), Ok(snapshot.clone()));
+		// Sections of unknown kinds are skipped.
+		dump.extend_from_slice(&[99, 2, 0, 0, 0, 1, 2]);
+		assert_eq!(Snapshot::parse(&dump), Ok(snapshot));
+		assert_eq!(
+			Snapshot::parse(&dump[..dump.len() - 1]),
+			Err(SnapshotError::Truncated)
+		);
+		assert_eq!(Snapshot::parse(b"CAPSNAP"), Err(SnapshotError::BadMagic));
+		let mut future = MAGIC.to_vec();
+		future.extend_from_slice(&2_u16.to_le_bytes());
+		assert_eq!(
+			Snapshot::parse(&future),
+			Err(SnapshotError::UnsupportedVersion(2))
+		);
+	}
+
+	#[test]
+	fn diff() {
+		let before = Snapshot {
+			time: 10,
+			allocated: 1000,
+			tags: vec![tag("cache", 600), tag("gone", 100), tag("same", 50)],
+			..Snapshot::default()
+		};
+		let after = Snapshot {
+			time: 70,
+			allocated: 1400,
+			tags: vec![tag("cache", 900), tag("new", 200), tag("same", 50)],
+			..Snapshot::default()
+		};
+		assert_eq!(
+			after
+				.top_tags(2)
+				.iter()
+				.map(|tag| tag.name.as_str())
+				.collect::<Vec<_>>(),
+			["cache", "new"]
+		);
+		let diff = before.diff(&after);
+		assert_eq!((diff.elapsed, diff.allocated), (60, 400));
+		assert_eq!(
+			diff.tags,
+			[
+				("cache".to_owned(), 300),
+				("new".to_owned(), 200),
+				("gone".to_owned(), -100)
+			]
+		);
+		assert!(diff.to_string().contains("cache: +300"));
+		assert!(after.to_string().contains("  new: 200 (limit unlimited)"));
+	}
+}
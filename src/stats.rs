@@ -0,0 +1,86 @@
+//! The groups of statistics a [`Cap`](crate::Cap) keeps, each of which can be left out.
+
+use std::ops::BitOr;
+
+/// A set of the groups of statistics a [`Cap`](crate::Cap) keeps, as selected with [`Cap::with_stats`](crate::Cap::with_stats).
+///
+/// Each group is compiled in by its own feature, `stats-counts`, `stats-peaks`, `stats-histograms`, `stats-threads`, `stats-tags` or `stats-churn`, all of which `stats` enables, and costs some work on every allocation or reallocation. Of those compiled in, a cap keeps only the groups selected, so that one on a hot path can keep, say, its peaks, but not pay for a histogram. Statistics of groups not kept read as 0. Failure counts and [exempted bytes](crate::Cap::exempted_bytes) are only counted off the success path, so are always kept with `stats-counts`.
+///
+/// ```
+/// use std::alloc;
+/// use cap::{Cap, Stats};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> =
+///     Cap::new(alloc::System, usize::MAX).with_stats(Stats::PEAKS.union(Stats::COUNTS));
+///
+/// fn main() {
+///     assert!(ALLOCATOR.stats().contains(Stats::PEAKS));
+///     assert!(!ALLOCATOR.stats().contains(Stats::HISTOGRAMS));
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats(u8);
+
+impl Stats {
+	/// None of the groups.
+	pub const NONE: Self = Self(0);
+	/// Totals and counts, with `stats-counts`: [`total_allocated`](crate::Cap::total_allocated), the counts of grows and shrinks, and [`realloc_copied_bytes`](crate::Cap::realloc_copied_bytes).
+	pub const COUNTS: Self = Self(1);
+	/// Peaks, with `stats-peaks`: [`max_allocated`](crate::Cap::max_allocated), [`peak_info`](crate::Cap::peak_info) and [`peak_since_last_call`](crate::Cap::peak_since_last_call).
+	pub const PEAKS: Self = Self(1 << 1);
+	/// A histogram of sizes, with `stats-histograms`: [`size_histogram`](crate::Cap::size_histogram).
+	pub const HISTOGRAMS: Self = Self(1 << 2);
+	/// Per-thread accounting, with `stats-threads`: [`thread_stats`](crate::Cap::thread_stats).
+	pub const THREADS: Self = Self(1 << 3);
+	/// Per-tag peaks, with `stats-tags` and `tags`: [`tag_peak`](crate::Cap::tag_peak) and the peaks of [`stats_by_tag`](crate::Cap::stats_by_tag).
+	pub const TAGS: Self = Self(1 << 4);
+	/// The tracking of chains of moving reallocations, with `stats-churn`, for the [churn hook](crate::Cap::set_churn_hook).
+	pub const CHURN: Self = Self(1 << 5);
+	/// All of the groups. This is the default.
+	pub const ALL: Self = Self(0b11_1111);
+
+	/// Return the groups in either set.
+	#[must_use]
+	pub const fn union(self, other: Self) -> Self {
+		Self(self.0 | other.0)
+	}
+
+	/// Return whether all of the groups of `other` are in this set.
+	#[must_use]
+	pub const fn contains(self, other: Self) -> bool {
+		self.0 & other.0 == other.0
+	}
+}
+
+impl BitOr for Stats {
+	type Output = Self;
+
+	fn bitor(self, other: Self) -> Self {
+		self.union(other)
+	}
+}
+
+#[cfg(all(test, feature = "stats-counts", feature = "stats-peaks"))]
+mod tests {
+	use std::alloc::{GlobalAlloc, Layout, System};
+
+	use super::Stats;
+	use crate::Cap;
+
+	#[test]
+	fn with_stats() {
+		let cap = Cap::new(System, usize::MAX).with_stats(Stats::PEAKS);
+		assert_eq!(cap.stats(), Stats::PEAKS);
+		assert!(Stats::ALL.contains(Stats::COUNTS | Stats::CHURN));
+		assert!(!Stats::PEAKS.contains(Stats::COUNTS));
+		let layout = Layout::new::<[u8; 100]>();
+		unsafe {
+			let ptr = cap.alloc(layout);
+			let ptr = cap.realloc(ptr, layout, 200);
+			cap.dealloc(ptr, Layout::new::<[u8; 200]>());
+		}
+		assert_eq!(cap.max_allocated(), cap.charged(Layout::new::<[u8; 200]>()));
+		assert_eq!((cap.total_allocated(), cap.grow_count()), (0, 0));
+	}
+}
@@ -0,0 +1,243 @@
+//! A machine-readable summary of a [`Cap`]'s usage, optionally persisted at process exit.
+
+use std::{
+	fmt::{self, Write as _}, fs, io::{self, Write}, mem, panic, path::Path, process, sync::{Mutex, Once, PoisonError}, time::{SystemTime, UNIX_EPOCH}
+};
+
+use crate::Cap;
+
+extern "C" {
+	fn atexit(f: extern "C" fn()) -> std::ffi::c_int;
+}
+
+/// The number of tags included in a summary.
+#[cfg(feature = "tags")]
+const TOP_TAGS: usize = 10;
+
+type Pending = Vec<Box<dyn FnOnce() + Send>>;
+
+/// Summaries to be written at exit.
+static PENDING: Mutex<Pending> = Mutex::new(Vec::new());
+
+extern "C" fn at_exit() {
+	let pending = mem::take(&mut *PENDING.lock().unwrap_or_else(PoisonError::into_inner));
+	for write in pending {
+		write();
+	}
+}
+
+/// Append `s` to `out` as a JSON string.
+#[cfg_attr(not(feature = "tags"), allow(dead_code))]
+fn json_string(out: &mut String, s: &str) {
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			c if u32::from(c) < 0x20 => write!(out, "\\u{:04x}", u32::from(c)).unwrap(),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+}
+
+/// A [`Cap`]'s memory usage for crash reports, as returned by [`Cap::panic_report`]. It is rendered each time it is formatted, so reflects usage at the time of the panic.
+///
+/// Panic-report crates take extra sections as [`Display`](fmt::Display) values, such as `color-eyre`'s `HookBuilder::panic_section`, so it can be passed to them directly. To print it to stderr after whichever panic hook is installed, use [`Cap::install_panic_report`].
+pub struct PanicReport<'a, H> {
+	cap: &'a Cap<H>,
+}
+
+impl<H> fmt::Display for PanicReport<'_, H> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		// The limit may well be why the program panicked.
+		let _exempt = self.cap.exempt();
+		write!(
+			f,
+			"Memory: {} bytes allocated of a limit of {}",
+			self.cap.allocated(),
+			self.cap.limit()
+		)?;
+		#[cfg(feature = "stats-peaks")]
+		write!(f, "\nPeak: {} bytes", self.cap.max_allocated())?;
+		#[cfg(feature = "stats-counts")]
+		write!(
+			f,
+			"{}{} allocated in total, {} allocations failed",
+			if cfg!(feature = "stats-peaks") {
+				", "
+			} else {
+				"\n"
+			},
+			self.cap.total_allocated(),
+			self.cap.failure_count()
+		)?;
+		#[cfg(feature = "tags")]
+		{
+			let mut tags = self.cap.stats_by_tag();
+			tags.sort_by_key(|stats| std::cmp::Reverse(stats.allocated));
+			f.write_str("\nTags:")?;
+			for (i, stats) in tags.iter().take(TOP_TAGS).enumerate() {
+				let separator = if i == 0 { " " } else { ", " };
+				write!(f, "{}{} {}", separator, stats.tag.name(), stats.allocated)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl<H> fmt::Debug for PanicReport<'_, H> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("PanicReport").finish_non_exhaustive()
+	}
+}
+
+impl<H> Cap<H> {
+	/// Return this allocator's memory usage, formatted for inclusion in crash reports: the bytes allocated and the limit, with the `stats-peaks` feature the peak, with `stats-counts` the total and failures, and with the `tags` feature the largest tags.
+	///
+	/// ```
+	/// use std::alloc;
+	/// use cap::Cap;
+	///
+	/// #[global_allocator]
+	/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+	///
+	/// fn main() {
+	///     let report = ALLOCATOR.panic_report().to_string();
+	///     assert!(report.starts_with("Memory: "));
+	/// }
+	/// ```
+	pub fn panic_report(&self) -> PanicReport<'_, H> {
+		PanicReport { cap: self }
+	}
+
+	/// Install a panic hook that runs the one currently installed, then prints this allocator's [memory usage](Self::panic_report) to stderr.
+	///
+	/// The usage follows whatever the previous hook printed, such as the default hook's message or `human-panic`'s, but isn't added to report files a hook writes, so isn't in `human-panic`'s. To get it into a crash report, pass [`panic_report`](Self::panic_report) as a section to a crate that takes them instead.
+	///
+	/// Install it after any other panic hook, as setting a hook replaces the previous one.
+	pub fn install_panic_report(&'static self)
+	where
+		H: Sync,
+	{
+		let previous = panic::take_hook();
+		panic::set_hook(Box::new(move |info| {
+			previous(info);
+			eprintln!("{}", self.panic_report());
+		}));
+	}
+
+	/// Write a summary of this allocator's usage to `writer`, as one line of JSON.
+	///
+	/// The object has the keys `time` (seconds since the Unix epoch), `pid`, `allocated`, `limit` and, with the `stats-peaks` feature, `peak` and, with `stats-counts`, `total_allocated` and `failures`. With the `tags` feature, `tags` is an array of up to 10 `{"name", "allocated"}` objects, largest first.
+	pub fn write_summary(&self, writer: &mut impl Write) -> io::Result<()> {
+		let time = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |since| since.as_secs());
+		let mut out = format!(
+			"{{\"time\":{},\"pid\":{},\"allocated\":{},\"limit\":{}",
+			time,
+			process::id(),
+			self.allocated(),
+			self.limit()
+		);
+		#[cfg(feature = "stats-peaks")]
+		write!(out, ",\"peak\":{}", self.max_allocated()).unwrap();
+		#[cfg(feature = "stats-counts")]
+		write!(
+			out,
+			",\"total_allocated\":{},\"failures\":{}",
+			self.total_allocated(),
+			self.failure_count()
+		)
+		.unwrap();
+		#[cfg(feature = "tags")]
+		{
+			let mut tags = self.stats_by_tag();
+			tags.sort_by_key(|stats| std::cmp::Reverse(stats.allocated));
+			out.push_str(",\"tags\":[");
+			for (i, stats) in tags.iter().take(TOP_TAGS).enumerate() {
+				if i != 0 {
+					out.push(',');
+				}
+				out.push_str("{\"name\":");
+				json_string(&mut out, stats.tag.name());
+				write!(out, ",\"allocated\":{}}}", stats.allocated).unwrap();
+			}
+			out.push(']');
+		}
+		out.push_str("}\n");
+		writer.write_all(out.as_bytes())
+	}
+
+	/// Write a [summary](Self::write_summary) of this allocator's usage to the file `path` when the process exits.
+	///
+	/// If `append` is true the summary is appended, so that a file shared by many runs of a batch job accumulates their history, one line each; otherwise the file is replaced. Errors writing it are printed to stderr.
+	///
+	/// The summary is written by an `atexit` handler, so isn't written if the process is killed or aborts.
+	pub fn persist_summary(&'static self, path: impl AsRef<Path>, append: bool)
+	where
+		H: Sync,
+	{
+		static REGISTER: Once = Once::new();
+		REGISTER.call_once(|| {
+			let _ = unsafe { atexit(at_exit) };
+		});
+		let path = path.as_ref().to_owned();
+		PENDING
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.push(Box::new(move || {
+				let res = fs::OpenOptions::new()
+					.create(true)
+					.write(true)
+					.append(append)
+					.truncate(!append)
+					.open(&path)
+					.and_then(|mut file| self.write_summary(&mut file));
+				if let Err(error) = res {
+					eprintln!(
+						"cap: failed to write summary to {}: {}",
+						path.display(),
+						error
+					);
+				}
+			}));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{alloc::System, str};
+
+	use crate::Cap;
+
+	#[test]
+	fn write_summary() {
+		let cap = Cap::new(System, 1000);
+		cap.charge(100).unwrap();
+		let mut summary = Vec::new();
+		cap.write_summary(&mut summary).unwrap();
+		let summary = str::from_utf8(&summary).unwrap();
+		assert!(summary.starts_with("{\"time\":"));
+		assert!(summary.contains(",\"allocated\":100,\"limit\":1000"));
+		assert!(summary.ends_with("}\n"));
+	}
+
+	#[test]
+	fn panic_report() {
+		let cap = Cap::new(System, 1000);
+		cap.charge(100).unwrap();
+		let report = cap.panic_report().to_string();
+		assert!(report.starts_with("Memory: 100 bytes allocated of a limit of 1000"));
+		#[cfg(feature = "stats-peaks")]
+		assert!(report.contains("\nPeak: "));
+	}
+
+	#[test]
+	fn json_string() {
+		let mut out = String::new();
+		super::json_string(&mut out, "a \"b\"\\\n");
+		assert_eq!(out, r#""a \"b\"\\\u000a""#);
+	}
+}
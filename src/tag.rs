@@ -0,0 +1,490 @@
+//! Attribution of allocations to named tags.
+
+use std::{
+	alloc::Layout, cell::Cell, fmt, future::Future, hint, marker::PhantomData, pin::Pin, ptr, slice, str, sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering}, task::{Context, Poll}, thread
+};
+#[cfg(feature = "stats-tags")]
+use std::{
+	sync::atomic::AtomicU64, time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+#[cfg(feature = "stats-tags")]
+use crate::now;
+
+/// The maximum number of distinct tags, including the implicit untagged one.
+pub(crate) const MAX_TAGS: usize = 64;
+
+/// The parent of a tag that hasn't yet been entered.
+const NO_PARENT: usize = usize::MAX;
+
+struct Registry {
+	locked: AtomicBool,
+	len: AtomicUsize,
+	names: [(AtomicPtr<u8>, AtomicUsize); MAX_TAGS],
+	/// The index of the tag each was first entered within, or 0 if none.
+	parents: [AtomicUsize; MAX_TAGS],
+}
+
+static REGISTRY: Registry = Registry {
+	locked: AtomicBool::new(false),
+	len: AtomicUsize::new(1),
+	names: [const { (AtomicPtr::new(ptr::null_mut()), AtomicUsize::new(0)) }; MAX_TAGS],
+	parents: [const { AtomicUsize::new(NO_PARENT) }; MAX_TAGS],
+};
+
+thread_local! {
+	static CURRENT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A name that allocations can be attributed to.
+///
+/// Tags are cheap to copy and compare. Allocations made while a tag is [entered](Tag::enter) are attributed to it for their whole lifetime, whichever thread deallocates them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tag(usize);
+
+impl Tag {
+	/// Get the tag with the given name, registering it if necessary.
+	///
+	/// # Panics
+	///
+	/// Panics if 63 distinct tags have already been registered.
+	pub fn new(name: &'static str) -> Self {
+		while REGISTRY
+			.locked
+			.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+			.is_err()
+		{
+			hint::spin_loop();
+		}
+		let len = REGISTRY.len.load(Ordering::Relaxed);
+		let index = (1..len)
+			.find(|&i| Tag(i).name() == name)
+			.unwrap_or_else(|| {
+				if len < MAX_TAGS {
+					let (ptr, name_len) = &REGISTRY.names[len];
+					ptr.store(name.as_ptr().cast_mut(), Ordering::Relaxed);
+					name_len.store(name.len(), Ordering::Relaxed);
+					REGISTRY.len.store(len + 1, Ordering::Release);
+				}
+				len
+			});
+		REGISTRY.locked.store(false, Ordering::Release);
+		assert!(index < MAX_TAGS, "cap: too many tags");
+		Tag(index)
+	}
+
+	/// Return the name of this tag.
+	#[must_use]
+	pub fn name(self) -> &'static str {
+		let (ptr, len) = &REGISTRY.names[self.0];
+		let (ptr, len) = (ptr.load(Ordering::Relaxed), len.load(Ordering::Relaxed));
+		// Safe as names are only ever set from a &'static str, before len is published.
+		unsafe { str::from_utf8_unchecked(slice::from_raw_parts(ptr, len)) }
+	}
+
+	/// Attribute allocations made on this thread to this tag, until the returned guard is dropped.
+	///
+	/// The tag that is current when a tag is first entered becomes its parent in [`tag_tree`](crate::Cap::tag_tree).
+	#[must_use = "the tag is exited when the guard is dropped"]
+	pub fn enter(self) -> TagGuard {
+		let previous = CURRENT.with(|current| current.replace(self.0));
+		let parent = &REGISTRY.parents[self.0];
+		if parent.load(Ordering::Relaxed) == NO_PARENT && previous != self.0 {
+			let _ =
+				parent.compare_exchange(NO_PARENT, previous, Ordering::Relaxed, Ordering::Relaxed);
+		}
+		TagGuard {
+			previous,
+			_not_send: PhantomData,
+		}
+	}
+
+	/// Return a handler that attributes all allocations on the thread it is called on to this tag, for the rest of the thread's life.
+	///
+	/// This is intended for thread pools that accept a start handler, such as rayon's, so that the data-parallel portion of a workload is attributed to a tag in [`stats_by_tag`](crate::Cap::stats_by_tag):
+	///
+	/// ```ignore
+	/// let pool = rayon::ThreadPoolBuilder::new()
+	///     .start_handler(cap::Tag::new("rayon").start_handler())
+	///     .build()
+	///     .unwrap();
+	/// ```
+	///
+	/// Tags entered by jobs running on the pool still take precedence while they are entered.
+	pub fn start_handler(self) -> impl Fn(usize) + Send + Sync + 'static {
+		move |_index| CURRENT.with(|current| current.set(self.0))
+	}
+
+	/// Wrap `future` so that allocations made while it is polled are attributed to this tag.
+	///
+	/// The tag is entered on each poll and exited when it returns, so this works with any executor, including ones that move tasks between threads:
+	///
+	/// ```ignore
+	/// smol::spawn(cap::Tag::new("request").instrument(async {
+	///     // ...
+	/// }))
+	/// ```
+	pub fn instrument<F>(self, future: F) -> Tagged<F>
+	where
+		F: Future,
+	{
+		Tagged { tag: self, future }
+	}
+
+	pub(crate) fn index(self) -> usize {
+		self.0
+	}
+
+	/// The tag with the given index, or `None` for the untagged index 0.
+	pub(crate) fn from_index(index: usize) -> Option<Self> {
+		Some(index).filter(|&index| index != 0).map(Tag)
+	}
+
+	fn registered() -> impl Iterator<Item = Tag> {
+		(1..REGISTRY.len.load(Ordering::Acquire)).map(Tag)
+	}
+}
+
+impl fmt::Debug for Tag {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_tuple("Tag").field(&self.name()).finish()
+	}
+}
+
+/// Attribute allocations made on this thread to the tag `name`, until the returned guard is dropped.
+///
+/// ```
+/// # use std::alloc;
+/// # use cap::Cap;
+/// # #[global_allocator]
+/// # static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+/// let _guard = cap::tag("decompression");
+/// let buf = vec![0_u8; 1024];
+/// assert!(ALLOCATOR.tag_allocated(cap::Tag::new("decompression")) >= 1024);
+/// ```
+///
+/// This is shorthand for `Tag::new(name).enter()`.
+#[must_use = "the tag is exited when the guard is dropped"]
+pub fn tag(name: &'static str) -> TagGuard {
+	Tag::new(name).enter()
+}
+
+/// Return the tag that allocations on this thread are currently attributed to, if any.
+#[must_use]
+pub fn current_tag() -> Option<Tag> {
+	Tag::from_index(current())
+}
+
+/// Spawn a new thread, like [`thread::spawn`], that inherits the calling thread's current tag.
+pub fn spawn_tagged<F, T>(f: F) -> thread::JoinHandle<T>
+where
+	F: FnOnce() -> T + Send + 'static,
+	T: Send + 'static,
+{
+	thread::spawn(capture_tag(f))
+}
+
+/// Wrap `f` so that, wherever it is called, it runs under the calling thread's current tag.
+///
+/// This is useful to retain attribution for work handed to a thread pool:
+///
+/// ```
+/// # use std::{sync::mpsc, thread};
+/// let (sender, receiver) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+/// let worker = thread::spawn(move || receiver.into_iter().for_each(|job| job()));
+///
+/// let _guard = cap::tag("compaction");
+/// sender.send(Box::new(cap::capture_tag(|| {
+///     assert_eq!(cap::current_tag(), Some(cap::Tag::new("compaction")));
+/// }))).unwrap();
+/// # drop(sender);
+/// # worker.join().unwrap();
+/// ```
+pub fn capture_tag<F, T>(f: F) -> impl FnOnce() -> T
+where
+	F: FnOnce() -> T,
+{
+	let tag = current_tag();
+	move || {
+		let _guard = tag.map(Tag::enter);
+		f()
+	}
+}
+
+/// A future returned by [`Tag::instrument`] that runs its wrapped future under a tag.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Tagged<F> {
+	tag: Tag,
+	future: F,
+}
+
+impl<F> Future for Tagged<F>
+where
+	F: Future,
+{
+	type Output = F::Output;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+		let _guard = self.tag.enter();
+		// Safe as `future` is structurally pinned: it is never moved out of `self`.
+		unsafe { self.map_unchecked_mut(|tagged| &mut tagged.future) }.poll(cx)
+	}
+}
+
+/// The index of the tag allocations on this thread are currently attributed to, or 0 if none.
+pub(crate) fn current() -> usize {
+	CURRENT.try_with(Cell::get).unwrap_or(0)
+}
+
+/// A guard returned by [`tag`] and [`Tag::enter`] that restores the previously entered tag when dropped.
+#[derive(Debug)]
+pub struct TagGuard {
+	previous: usize,
+	_not_send: PhantomData<*const ()>,
+}
+
+impl Drop for TagGuard {
+	fn drop(&mut self) {
+		CURRENT.with(|current| current.set(self.previous));
+	}
+}
+
+/// Per-tag statistics, as returned by [`Cap::stats_by_tag`](crate::Cap::stats_by_tag).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TagStats {
+	/// The tag.
+	pub tag: Tag,
+	/// The number of bytes currently allocated while attributed to it.
+	pub allocated: usize,
+	/// The maximum number of bytes that may be allocated while attributed to it.
+	pub limit: usize,
+	/// The most bytes that have been allocated while attributed to it at once.
+	#[cfg(feature = "stats-tags")]
+	pub peak: usize,
+	/// When `peak` was reached, or `None` if nothing has been allocated while attributed to it.
+	#[cfg(feature = "stats-tags")]
+	pub peak_time: Option<SystemTime>,
+}
+
+/// A tag and the tags first entered within it, as returned by [`Cap::tag_tree`](crate::Cap::tag_tree).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TagNode {
+	/// The tag.
+	pub tag: Tag,
+	/// The tag's name.
+	pub name: &'static str,
+	/// The number of bytes currently allocated while attributed to the tag itself, excluding its children.
+	pub self_bytes: usize,
+	/// The tags first entered while this one was current.
+	pub children: Vec<TagNode>,
+}
+
+impl TagNode {
+	/// Return the number of bytes currently allocated while attributed to this tag or any of its descendants.
+	pub fn total_bytes(&self) -> usize {
+		self.self_bytes
+			+ self
+				.children
+				.iter()
+				.map(TagNode::total_bytes)
+				.sum::<usize>()
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct Slot {
+	pub(crate) allocated: AtomicUsize,
+	pub(crate) limit: AtomicUsize,
+	pub(crate) weight: AtomicUsize,
+	#[cfg(feature = "stats-tags")]
+	pub(crate) peak: AtomicUsize,
+	/// When `peak` was reached, in nanoseconds since the epoch, or 0 if it hasn't been.
+	#[cfg(feature = "stats-tags")]
+	peak_time: AtomicU64,
+}
+
+impl Slot {
+	/// Record that `allocated` bytes are attributed to the tag, which may be a new peak.
+	#[cfg(feature = "stats-tags")]
+	pub(crate) fn record_peak(&self, allocated: usize) {
+		if self.peak.fetch_max(allocated, Ordering::Relaxed) < allocated {
+			self.peak_time.store(now(), Ordering::Relaxed);
+		}
+	}
+}
+
+/// The denominator of [`Table::pressure`].
+pub(crate) const PRESSURE_SCALE: u32 = 1 << 16;
+
+/// A [`Cap`](crate::Cap)'s per-tag accounting.
+#[derive(Debug)]
+pub(crate) struct Table {
+	pub(crate) slots: [Slot; MAX_TAGS],
+	pub(crate) total_weight: AtomicUsize,
+	/// The fraction of the limit, over `PRESSURE_SCALE`, above which groups are held to their shares.
+	pub(crate) pressure: AtomicUsize,
+}
+
+impl Table {
+	pub(crate) const fn new() -> Self {
+		Self {
+			slots: [const {
+				Slot {
+					allocated: AtomicUsize::new(0),
+					limit: AtomicUsize::new(usize::MAX),
+					weight: AtomicUsize::new(0),
+					#[cfg(feature = "stats-tags")]
+					peak: AtomicUsize::new(0),
+					#[cfg(feature = "stats-tags")]
+					peak_time: AtomicU64::new(0),
+				}
+			}; MAX_TAGS],
+			total_weight: AtomicUsize::new(0),
+			pressure: AtomicUsize::new(PRESSURE_SCALE as usize / 8 * 7),
+		}
+	}
+
+	pub(crate) fn stats(&self) -> Vec<TagStats> {
+		Tag::registered()
+			.map(|tag| {
+				let slot = &self.slots[tag.0];
+				#[cfg(feature = "stats-tags")]
+				let peak_time = slot.peak_time.load(Ordering::Relaxed);
+				TagStats {
+					tag,
+					allocated: slot.allocated.load(Ordering::Relaxed),
+					limit: slot.limit.load(Ordering::Relaxed),
+					#[cfg(feature = "stats-tags")]
+					peak: slot.peak.load(Ordering::Relaxed),
+					#[cfg(feature = "stats-tags")]
+					peak_time: (peak_time != 0)
+						.then(|| UNIX_EPOCH + Duration::from_nanos(peak_time)),
+				}
+			})
+			.collect()
+	}
+
+	/// Build the tree of registered tags, with each under the tag it was first entered within.
+	pub(crate) fn tree(&self) -> Vec<TagNode> {
+		let parents: Vec<(Tag, usize)> = Tag::registered()
+			.map(|tag| (tag, REGISTRY.parents[tag.0].load(Ordering::Relaxed)))
+			.collect();
+		// A bit per tag, so that a cycle, possible via `Tag::start_handler`, is broken rather than recursed into.
+		let mut visited = 1_u64;
+		let mut roots = self.children(0, &parents, &mut visited);
+		for &(tag, _) in &parents {
+			if visited & (1 << tag.0) == 0 {
+				visited |= 1 << tag.0;
+				roots.push(self.node(tag, &parents, &mut visited));
+			}
+		}
+		roots
+	}
+
+	fn children(&self, parent: usize, parents: &[(Tag, usize)], visited: &mut u64) -> Vec<TagNode> {
+		let mut children = Vec::new();
+		for &(tag, tag_parent) in parents {
+			let orphan = tag_parent == NO_PARENT && parent == 0;
+			if (tag_parent == parent || orphan) && *visited & (1 << tag.0) == 0 {
+				*visited |= 1 << tag.0;
+				children.push(self.node(tag, parents, visited));
+			}
+		}
+		children
+	}
+
+	fn node(&self, tag: Tag, parents: &[(Tag, usize)], visited: &mut u64) -> TagNode {
+		TagNode {
+			tag,
+			name: tag.name(),
+			self_bytes: self.slots[tag.0].allocated.load(Ordering::Relaxed),
+			children: self.children(tag.0, parents, visited),
+		}
+	}
+
+	/// Write up to `n` registered tags with the most bytes allocated, ignoring those with none, largest first, as `name: bytes` lines into `buf`, returning the part written.
+	///
+	/// Nothing is allocated, so this can be called from within the allocator. The report is truncated if `buf` is too small.
+	pub(crate) fn report<'a>(&self, n: usize, buf: &'a mut [u8]) -> &'a str {
+		let mut top = [(0, 0); MAX_TAGS];
+		let mut len = 0;
+		for tag in Tag::registered() {
+			let allocated = self.slots[tag.0].allocated.load(Ordering::Relaxed);
+			if allocated == 0 {
+				continue;
+			}
+			let at = top[..len].partition_point(|&(_, other)| other >= allocated);
+			if at < n {
+				top.copy_within(at..len.min(n - 1), at + 1);
+				top[at] = (tag.0, allocated);
+				len = (len + 1).min(n);
+			}
+		}
+		let mut writer = SliceWriter { buf, len: 0 };
+		for &(tag, allocated) in &top[..len] {
+			if fmt::Write::write_fmt(
+				&mut writer,
+				format_args!("{}: {}\n", Tag(tag).name(), allocated),
+			)
+			.is_err()
+			{
+				break;
+			}
+		}
+		let SliceWriter { buf, len } = writer;
+		str::from_utf8(&buf[..len]).unwrap()
+	}
+}
+
+/// A [`fmt::Write`] into a fixed buffer, that writes as much as fits and then fails.
+struct SliceWriter<'a> {
+	buf: &'a mut [u8],
+	len: usize,
+}
+
+impl fmt::Write for SliceWriter<'_> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let mut fits = s.len().min(self.buf.len() - self.len);
+		while !s.is_char_boundary(fits) {
+			fits -= 1;
+		}
+		self.buf[self.len..self.len + fits].copy_from_slice(&s.as_bytes()[..fits]);
+		self.len += fits;
+		if fits == s.len() {
+			Ok(())
+		} else {
+			Err(fmt::Error)
+		}
+	}
+}
+
+const HEADER: usize = size_of::<usize>();
+
+/// The size of the prefix recording the tag of an allocation aligned to `align`.
+pub(crate) fn header_size(align: usize) -> usize {
+	align.max(HEADER)
+}
+
+/// The layout requested of the wrapped allocator: `layout` with a prefix recording the tag.
+pub(crate) fn inner_layout(layout: Layout) -> Option<Layout> {
+	let size = layout.size().checked_add(header_size(layout.align()))?;
+	Layout::from_size_align(size, layout.align().max(align_of::<usize>())).ok()
+}
+
+/// Record `index` in the prefix of the block `base`, returning the pointer to hand out.
+#[allow(clippy::cast_ptr_alignment)] // the header is a multiple of usize's alignment
+pub(crate) unsafe fn attach(base: *mut u8, layout: Layout, index: usize) -> *mut u8 {
+	let ptr = base.add(header_size(layout.align()));
+	ptr.cast::<usize>().sub(1).write(index);
+	ptr
+}
+
+/// Recover the block and tag index from a pointer returned by [`attach`].
+#[allow(clippy::cast_ptr_alignment)]
+pub(crate) unsafe fn detach(ptr: *mut u8, layout: Layout) -> (*mut u8, usize) {
+	let index = ptr.cast::<usize>().sub(1).read();
+	(ptr.sub(header_size(layout.align())), index)
+}
@@ -0,0 +1,393 @@
+//! Helpers to exercise out-of-memory handling under randomized limits and failure injection.
+//!
+//! [`explore`] runs a closure repeatedly against a [`Cap`], each time under a random [`Schedule`] of allocations to fail and a random limit. If the closure panics, the schedule is shrunk to a minimal one that still makes it panic, which can be [replayed](replay) as a regression test. Schedules can also be built directly, for example from fuzzer input with [`Schedule::from_bytes`] or by a property-testing strategy.
+//!
+//! The closure should be deterministic, and nothing else should allocate through the `Cap` while it runs, as allocations are identified by the order in which they are made.
+//!
+//! ```
+//! use std::alloc::{GlobalAlloc, Layout, System};
+//! use cap::{testing, Cap};
+//!
+//! let cap = Cap::new(System, usize::MAX);
+//! let layout = Layout::new::<[u64; 16]>();
+//! let schedule = testing::explore(&cap, 0, 100, || unsafe {
+//!     let ptr = cap.alloc(layout);
+//!     assert!(!ptr.is_null(), "out-of-memory isn't handled");
+//!     cap.dealloc(ptr, layout);
+//! })
+//! .unwrap_err();
+//! assert_eq!(schedule.failures, [0]);
+//! ```
+
+use std::{
+	alloc::GlobalAlloc, panic::{self, AssertUnwindSafe}, sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}
+};
+
+use crate::Cap;
+#[cfg(all(feature = "stats-counts", feature = "stats-peaks"))]
+use crate::Stats;
+
+/// The number of allocations, counted from the start of each run, that can be failed.
+pub const MAX_FAILURES: usize = 256;
+
+const WORDS: usize = MAX_FAILURES / 64;
+
+/// The allocations to fail, and the limit to apply, during one run of a closure.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Schedule {
+	/// The number of bytes that may be allocated on top of those already allocated when the run starts, or `None` to leave the limit unchanged.
+	pub headroom: Option<usize>,
+	/// The indices, counted from the start of the run, of the allocations and growing reallocations to fail. Indices of at least [`MAX_FAILURES`] are ignored.
+	pub failures: Vec<usize>,
+}
+
+impl Schedule {
+	/// Create a schedule from its headroom and the allocations to fail.
+	#[must_use]
+	pub fn new(headroom: Option<usize>, failures: Vec<usize>) -> Self {
+		Self { headroom, failures }
+	}
+
+	/// Decode a schedule from arbitrary bytes, such as fuzzer input.
+	///
+	/// The first byte chooses whether there is a headroom and the next four give it, little-endian; each subsequent byte is the index of an allocation to fail.
+	#[must_use]
+	pub fn from_bytes(bytes: &[u8]) -> Self {
+		let (headroom, failures) = match bytes.split_first() {
+			Some((&flag, rest)) if flag & 1 == 1 && rest.len() >= 4 => {
+				let (headroom, failures) = rest.split_at(4);
+				let headroom =
+					u32::from_le_bytes([headroom[0], headroom[1], headroom[2], headroom[3]]);
+				(Some(headroom as usize), failures)
+			}
+			Some((_, rest)) => (None, rest),
+			None => (None, bytes),
+		};
+		Self::new(headroom, failures.iter().map(|&i| usize::from(i)).collect())
+	}
+}
+
+/// A [`Cap`]'s schedule state while a closure is being run under it.
+#[derive(Debug)]
+pub(crate) struct State {
+	active: AtomicBool,
+	count: AtomicUsize,
+	peak: AtomicUsize,
+	failures: [AtomicU64; WORDS],
+}
+
+impl State {
+	pub(crate) const fn new() -> Self {
+		Self {
+			active: AtomicBool::new(false),
+			count: AtomicUsize::new(0),
+			peak: AtomicUsize::new(0),
+			failures: [const { AtomicU64::new(0) }; WORDS],
+		}
+	}
+
+	/// Count an allocation, with `allocated` bytes allocated beforehand, returning whether the schedule fails it.
+	pub(crate) fn fail(&self, allocated: usize) -> bool {
+		if !self.active.load(Ordering::Relaxed) {
+			return false;
+		}
+		let _ = self.peak.fetch_max(allocated, Ordering::Relaxed);
+		let index = self.count.fetch_add(1, Ordering::Relaxed);
+		index < MAX_FAILURES
+			&& self.failures[index / 64].load(Ordering::Relaxed) & (1 << (index % 64)) != 0
+	}
+}
+
+/// Run `f` under `schedule`, returning whether it completed without panicking.
+///
+/// The limit is restored afterwards.
+pub fn replay<H>(cap: &Cap<H>, schedule: &Schedule, f: impl FnMut()) -> bool
+where
+	H: GlobalAlloc,
+{
+	run(cap, schedule, f).0
+}
+
+/// Run `f` against a fresh, unlimited [`Cap`] wrapping `allocator`, then check its accounting with [`assert_accounting`].
+///
+/// This is what [`test_matrix!`](crate::test_matrix) runs for each allocator.
+pub fn run_checked<H>(name: &str, allocator: H, f: impl FnOnce(&Cap<H>))
+where
+	H: GlobalAlloc,
+{
+	let cap = Cap::new(allocator, usize::MAX);
+	f(&cap);
+	assert_accounting(&cap, name);
+}
+
+/// Assert that `cap`'s accounting is consistent and that everything allocated through it has been freed, naming `allocator` in the panic message if not.
+///
+/// # Panics
+///
+/// If any bytes remain allocated or charged, the bytes remaining don't add up to the limit, or, with the `consistency` feature, an inconsistency is found.
+pub fn assert_accounting<H>(cap: &Cap<H>, allocator: &str) {
+	let (allocated, remaining, limit) = (cap.allocated(), cap.remaining(), cap.limit());
+	assert_eq!(allocated, 0, "{allocator}: {allocated} bytes leaked");
+	assert_eq!(
+		remaining, limit,
+		"{allocator}: {remaining} bytes remaining of a limit of {limit} with nothing allocated"
+	);
+	#[cfg(all(feature = "stats-counts", feature = "stats-peaks"))]
+	assert!(
+		!cap.stats().contains(Stats::COUNTS | Stats::PEAKS)
+			|| cap.max_allocated() <= cap.total_allocated(),
+		"{allocator}: peak of {} bytes exceeds the {} allocated in total",
+		cap.max_allocated(),
+		cap.total_allocated()
+	);
+	#[cfg(feature = "consistency")]
+	{
+		if let Err(inconsistency) = cap.check_consistency() {
+			panic!("{}: {}", allocator, inconsistency);
+		}
+		assert_eq!(
+			cap.inconsistencies(),
+			0,
+			"{allocator}: inconsistencies found while running"
+		);
+	}
+}
+
+/// Run a test body against a [`Cap`](crate::Cap) wrapping each of several allocators in turn, [checking its accounting](crate::testing::assert_accounting) after each.
+///
+/// The body is given `&Cap<_>` for a fresh, unlimited cap, bound to the name before it, and must free everything it allocates. [`System`](std::alloc::System) is always tested. Further allocators, given as paths to unit structs such as jemalloc's and mimalloc's, can be listed in brackets beforehand, each behind whatever `cfg` attributes the caller's features require, so that the matrix follows the features a test is built with:
+///
+/// ```
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// // Standing in for an allocator such as `tikv_jemallocator::Jemalloc`, which would be listed behind `#[cfg(feature = "jemalloc")]`.
+/// struct Forwarding;
+/// unsafe impl GlobalAlloc for Forwarding {
+///     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+///         System.alloc(layout)
+///     }
+///     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+///         System.dealloc(ptr, layout)
+///     }
+/// }
+///
+/// cap::test_matrix!([
+///     #[cfg(not(miri))] Forwarding,
+/// ] |cap| unsafe {
+///     let layout = Layout::new::<[u64; 16]>();
+///     let ptr = cap.alloc(layout);
+///     assert!(!ptr.is_null());
+///     assert!(cap.allocated() >= layout.size());
+///     cap.dealloc(ptr, layout);
+/// });
+/// ```
+///
+/// As the body is expanded once per allocator, it can use methods that need the allocator's concrete type.
+#[macro_export]
+macro_rules! test_matrix {
+	(|$cap:ident| $body:expr) => {
+		$crate::test_matrix!([] |$cap| $body)
+	};
+	([$($(#[$meta:meta])* $allocator:path),* $(,)?] |$cap:ident| $body:expr) => {{
+		$crate::testing::run_checked("System", ::std::alloc::System, |$cap| {
+			let _ = $body;
+		});
+		$(
+			$(#[$meta])*
+			$crate::testing::run_checked(stringify!($allocator), $allocator, |$cap| {
+				let _ = $body;
+			});
+		)*
+	}};
+}
+
+/// Run `f` under `schedule`, returning whether it passed, the number of allocations it made and the peak bytes allocated above the starting point.
+fn run<H>(cap: &Cap<H>, schedule: &Schedule, mut f: impl FnMut()) -> (bool, usize, usize) {
+	let state = &cap.testing;
+	let mut failures = [0_u64; WORDS];
+	for &index in schedule
+		.failures
+		.iter()
+		.filter(|&&index| index < MAX_FAILURES)
+	{
+		failures[index / 64] |= 1 << (index % 64);
+	}
+	for (word, &failures) in state.failures.iter().zip(&failures) {
+		word.store(failures, Ordering::Relaxed);
+	}
+	let start = cap.allocated();
+	let limit = cap.limit();
+	if let Some(headroom) = schedule.headroom {
+		let _ = cap.set_limit(start.saturating_add(headroom).min(limit));
+	}
+	state.count.store(0, Ordering::Relaxed);
+	state.peak.store(start, Ordering::Relaxed);
+	state.active.store(true, Ordering::Relaxed);
+	let passed = panic::catch_unwind(AssertUnwindSafe(&mut f)).is_ok();
+	state.active.store(false, Ordering::Relaxed);
+	let _ = cap.set_limit(limit);
+	let peak = state.peak.load(Ordering::Relaxed).saturating_sub(start);
+	(passed, state.count.load(Ordering::Relaxed), peak)
+}
+
+/// Run `f` `runs` times against `cap`, each under a random schedule drawn from `seed`, returning a minimal schedule under which it panics, if any.
+///
+/// A schedule is minimal in that removing any of its failures, or raising its headroom, makes `f` pass. `f` is first run under no schedule to measure how many allocations it makes and how many bytes it needs, and if it panics then the empty schedule is returned.
+pub fn explore<H>(cap: &Cap<H>, seed: u64, runs: usize, mut f: impl FnMut()) -> Result<(), Schedule>
+where
+	H: GlobalAlloc,
+{
+	let (passed, count, peak) = run(cap, &Schedule::default(), &mut f);
+	if !passed {
+		return Err(Schedule::default());
+	}
+	let mut rng = seed;
+	let mut next = move |bound: usize| {
+		// splitmix64
+		rng = rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = rng;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		z ^= z >> 31;
+		#[allow(clippy::cast_possible_truncation)]
+		let z = z as usize;
+		z % bound.max(1)
+	};
+	let count = count.clamp(1, MAX_FAILURES);
+	for _ in 0..runs {
+		let headroom = (next(2) == 0).then(|| next(peak.saturating_add(1)));
+		let failures = (0..next(3)).map(|_| next(count)).collect();
+		let schedule = Schedule::new(headroom, failures);
+		if !run(cap, &schedule, &mut f).0 {
+			return Err(shrink(cap, schedule, peak, &mut f));
+		}
+	}
+	Ok(())
+}
+
+/// Shrink `schedule`, under which `f` panics, to a minimal one under which it still does.
+fn shrink<H>(cap: &Cap<H>, mut schedule: Schedule, peak: usize, f: &mut impl FnMut()) -> Schedule {
+	schedule.failures.sort_unstable();
+	schedule.failures.dedup();
+	let mut i = 0;
+	while i < schedule.failures.len() {
+		let mut candidate = schedule.clone();
+		let _ = candidate.failures.remove(i);
+		if run(cap, &candidate, &mut *f).0 {
+			i += 1;
+		} else {
+			schedule = candidate;
+		}
+	}
+	if let Some(headroom) = schedule.headroom {
+		let unlimited = Schedule::new(None, schedule.failures.clone());
+		if run(cap, &unlimited, &mut *f).0 {
+			// Find the largest headroom under which it still panics.
+			let (mut failing, mut passing) = (headroom, peak.max(headroom).saturating_add(1));
+			while passing - failing > 1 {
+				let mid = failing.midpoint(passing);
+				if run(
+					cap,
+					&Schedule::new(Some(mid), schedule.failures.clone()),
+					&mut *f,
+				)
+				.0
+				{
+					passing = mid;
+				} else {
+					failing = mid;
+				}
+			}
+			schedule.headroom = Some(failing);
+		} else {
+			schedule = unlimited;
+		}
+	}
+	schedule
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, panic
+	};
+
+	use super::{explore, replay, Schedule};
+	use crate::Cap;
+
+	/// Allocates three blocks, panicking if the second fails or if fewer than two succeed.
+	fn fragile(cap: &Cap<System>) {
+		let layout = Layout::new::<[u8; 50]>();
+		let blocks = unsafe { [cap.alloc(layout), cap.alloc(layout), cap.alloc(layout)] };
+		for block in blocks.iter().filter(|block| !block.is_null()) {
+			unsafe { cap.dealloc(*block, layout) };
+		}
+		assert!(!blocks[1].is_null());
+		assert!(!blocks[0].is_null() || !blocks[2].is_null());
+	}
+
+	#[test]
+	fn explore_shrinks() {
+		let hook = panic::take_hook();
+		panic::set_hook(Box::new(|_| ()));
+		let cap = Cap::new(System, usize::MAX);
+		let schedule = explore(&cap, 1, 1000, || fragile(&cap)).unwrap_err();
+		panic::set_hook(hook);
+		// Just too little for the first two blocks.
+		let headroom = 2 * cap.charged(Layout::new::<[u8; 50]>()) - 1;
+		assert!(
+			schedule == Schedule::new(None, vec![1])
+				|| schedule == Schedule::new(Some(headroom), vec![]),
+			"{:?}",
+			schedule
+		);
+		assert!(!replay(&cap, &schedule, || fragile(&cap)));
+		assert!(replay(&cap, &Schedule::new(None, vec![0]), || fragile(
+			&cap
+		)));
+		assert_eq!(cap.allocated(), 0);
+		assert_eq!(cap.limit(), usize::MAX);
+	}
+
+	#[test]
+	fn test_matrix() {
+		let mut runs = 0;
+		crate::test_matrix!(
+			[
+				System,
+				#[cfg(any())]
+				Missing
+			] | cap | unsafe {
+				runs += 1;
+				let layout = Layout::new::<[u8; 50]>();
+				let ptr = cap.alloc(layout);
+				assert_eq!(cap.allocated(), cap.charged(layout));
+				cap.dealloc(ptr, layout);
+			}
+		);
+		assert_eq!(runs, 2);
+		let hook = panic::take_hook();
+		panic::set_hook(Box::new(|_| ()));
+		let leaked = panic::catch_unwind(|| {
+			crate::test_matrix!(|cap| unsafe {
+				let _ = cap.alloc(Layout::new::<u8>());
+			});
+		});
+		panic::set_hook(hook);
+		assert!(leaked.is_err());
+	}
+
+	#[test]
+	fn from_bytes() {
+		assert_eq!(Schedule::from_bytes(&[]), Schedule::default());
+		assert_eq!(
+			Schedule::from_bytes(&[0, 3, 4]),
+			Schedule::new(None, vec![3, 4])
+		);
+		assert_eq!(
+			Schedule::from_bytes(&[1, 1, 1, 0, 0, 7]),
+			Schedule::new(Some(257), vec![7])
+		);
+	}
+}
@@ -0,0 +1,105 @@
+use std::{
+	cell::Cell, ptr, slice, str, sync::atomic::{AtomicIsize, AtomicPtr, AtomicUsize, Ordering}
+};
+
+/// The maximum number of threads that can be registered with each [`Cap`](crate::Cap).
+pub(crate) const MAX_THREADS: usize = 64;
+
+thread_local! {
+	/// The table this thread is registered with, and its slot in it.
+	static REGISTERED: Cell<(*const Threads, usize)> = const { Cell::new((ptr::null(), 0)) };
+}
+
+/// Statistics for a thread registered with [`Cap::register_thread`](crate::Cap::register_thread), as returned by [`Cap::thread_stats`](crate::Cap::thread_stats).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ThreadStats {
+	/// The name it was registered with.
+	pub name: &'static str,
+	/// The bytes it has allocated less the bytes it has deallocated. This is negative if it has deallocated more than it allocated, such as memory handed to it by other threads.
+	pub live: isize,
+	/// The highest `live` has been.
+	pub peak: isize,
+}
+
+#[derive(Debug)]
+struct Slot {
+	name: (AtomicPtr<u8>, AtomicUsize),
+	live: AtomicIsize,
+	peak: AtomicIsize,
+}
+
+/// A [`Cap`](crate::Cap)'s per-thread accounting.
+#[derive(Debug)]
+pub(crate) struct Threads {
+	len: AtomicUsize,
+	slots: [Slot; MAX_THREADS],
+}
+
+impl Threads {
+	pub(crate) const fn new() -> Self {
+		Self {
+			len: AtomicUsize::new(0),
+			slots: [const {
+				Slot {
+					name: (AtomicPtr::new(ptr::null_mut()), AtomicUsize::new(0)),
+					live: AtomicIsize::new(0),
+					peak: AtomicIsize::new(0),
+				}
+			}; MAX_THREADS],
+		}
+	}
+
+	/// Register the calling thread as `name`, returning `Err` if the table is full.
+	pub(crate) fn register(&self, name: &'static str) -> Result<(), ()> {
+		let index = self
+			.len
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+				(len < MAX_THREADS).then_some(len + 1)
+			})
+			.map_err(|_| ())?;
+		let slot = &self.slots[index];
+		slot.name.1.store(name.len(), Ordering::Relaxed);
+		slot.name
+			.0
+			.store(name.as_ptr().cast_mut(), Ordering::Release);
+		REGISTERED.with(|registered| registered.set((self, index)));
+		Ok(())
+	}
+
+	/// Attribute the freeing of `freed` bytes and allocation of `allocated` bytes to the calling thread, if it is registered with this table.
+	pub(crate) fn record(&self, freed: usize, allocated: usize) {
+		let Ok((table, index)) = REGISTERED.try_with(Cell::get) else {
+			return;
+		};
+		if !ptr::eq(table, self) {
+			return;
+		}
+		let slot = &self.slots[index];
+		#[allow(clippy::cast_possible_wrap)]
+		let delta = allocated.wrapping_sub(freed) as isize;
+		let live = slot.live.fetch_add(delta, Ordering::Relaxed) + delta;
+		let _ = slot.peak.fetch_max(live, Ordering::Relaxed);
+	}
+
+	pub(crate) fn stats(&self) -> Vec<ThreadStats> {
+		self.slots[..self.len.load(Ordering::Relaxed)]
+			.iter()
+			.filter_map(|slot| {
+				let ptr = slot.name.0.load(Ordering::Acquire);
+				let len = slot.name.1.load(Ordering::Relaxed);
+				// Not yet initialised by a concurrent registration.
+				if ptr.is_null() {
+					return None;
+				}
+				// Safe as it was stored from a `&'static str`.
+				let name = unsafe { str::from_utf8_unchecked(slice::from_raw_parts(ptr, len)) };
+				Some(ThreadStats {
+					name,
+					live: slot.live.load(Ordering::Relaxed),
+					peak: slot.peak.load(Ordering::Relaxed),
+				})
+			})
+			.collect()
+	}
+}
@@ -0,0 +1,75 @@
+use std::{fmt, sync::atomic::Ordering};
+
+use crate::Cap;
+
+/// A provisional charge against a [`Cap`]'s limit, created by [`Cap::transaction`].
+///
+/// The estimate is charged up front, reserving that much budget. Once the actual amount is known, [`commit`](Transaction::commit) reconciles the charge to it; otherwise, [`rollback`](Transaction::rollback) or dropping the transaction releases the estimate.
+///
+/// ```
+/// use std::alloc;
+/// use cap::Cap;
+///
+/// let cap = Cap::new(alloc::System, 1024);
+/// let transaction = cap.transaction(800).unwrap();
+/// // Only 224 bytes remain while the estimate is reserved.
+/// assert!(cap.transaction(800).is_err());
+/// // The operator turned out to need only 600 bytes.
+/// transaction.commit(600).unwrap();
+/// assert_eq!(cap.external(), 600);
+/// // ... and once it's finished with them:
+/// cap.uncharge(600);
+/// assert_eq!(cap.allocated(), 0);
+/// ```
+#[must_use = "dropping a transaction rolls it back"]
+pub struct Transaction<'a, H> {
+	cap: &'a Cap<H>,
+	estimate: usize,
+}
+
+impl<'a, H> Transaction<'a, H> {
+	pub(crate) fn new(cap: &'a Cap<H>, estimate: usize) -> Self {
+		Self { cap, estimate }
+	}
+
+	/// Return the number of bytes currently charged by this transaction.
+	#[must_use]
+	pub fn estimate(&self) -> usize {
+		self.estimate
+	}
+
+	/// Settle the charge at `actual` bytes, releasing the excess of the estimate or charging the shortfall.
+	///
+	/// The committed bytes remain charged as external memory until released with [`Cap::uncharge`].
+	///
+	/// This method will return `Err`, and roll back the transaction, if `actual` exceeds the estimate by more than the bytes remaining within the limit.
+	pub fn commit(mut self, actual: usize) -> Result<(), ()> {
+		if actual > self.estimate {
+			if !self.cap.charge_bytes(actual - self.estimate) {
+				return Err(());
+			}
+		} else {
+			self.cap.release(self.estimate - actual);
+		}
+		let _ = self.cap.external.fetch_add(actual, Ordering::Relaxed);
+		self.estimate = 0;
+		Ok(())
+	}
+
+	/// Release the estimate.
+	pub fn rollback(self) {}
+}
+
+impl<H> Drop for Transaction<'_, H> {
+	fn drop(&mut self) {
+		self.cap.release(self.estimate);
+	}
+}
+
+impl<H> fmt::Debug for Transaction<'_, H> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Transaction")
+			.field("estimate", &self.estimate)
+			.finish()
+	}
+}
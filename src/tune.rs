@@ -0,0 +1,134 @@
+//! Auto-tuning of the soft limit from the bursts of allocation observed.
+
+use std::{sync::atomic::Ordering, thread, time::Duration};
+
+use crate::Cap;
+#[cfg(feature = "recent")]
+use crate::LimitKind;
+
+/// The number of recent bursts the soft limit is tuned from.
+const WINDOW: usize = 128;
+
+/// The recent increases in the bytes allocated between samples.
+struct Bursts {
+	window: [usize; WINDOW],
+	len: usize,
+	next: usize,
+	last: Option<usize>,
+}
+
+impl Bursts {
+	const fn new() -> Self {
+		Self {
+			window: [0; WINDOW],
+			len: 0,
+			next: 0,
+			last: None,
+		}
+	}
+
+	/// Record a sample of `allocated` bytes, counting the increase since the previous sample, if any, as a burst.
+	fn record(&mut self, allocated: usize) {
+		if let Some(last) = self.last.replace(allocated) {
+			self.window[self.next] = allocated.saturating_sub(last);
+			self.next = (self.next + 1) % WINDOW;
+			self.len = (self.len + 1).min(WINDOW);
+		}
+	}
+
+	/// Return the `quantile` of the recorded bursts, or `None` if there are none.
+	fn quantile(&self, quantile: f64) -> Option<usize> {
+		if self.len == 0 {
+			return None;
+		}
+		let mut sorted = self.window;
+		let sorted = &mut sorted[..self.len];
+		sorted.sort_unstable();
+		#[allow(
+			clippy::cast_possible_truncation,
+			clippy::cast_sign_loss,
+			clippy::cast_precision_loss
+		)]
+		let rank = ((self.len - 1) as f64 * quantile.clamp(0.0, 1.0)).ceil() as usize;
+		Some(sorted[rank])
+	}
+}
+
+impl<H> Cap<H> {
+	/// Tune the [soft limit](Self::set_soft_limit) every `interval` on a background thread, keeping it below the limit by the `quantile`, such as `0.99`, of the bursts of allocation observed.
+	///
+	/// A burst is the increase in the bytes allocated over an interval, and the last 128 are considered, so backpressure from [`over_soft_limit`](Self::over_soft_limit) engages early enough for the typical burst to fit below the limit, and follows the workload as it shifts. The thread exits, leaving the soft limit as it is, once the soft limit is set by other means.
+	pub fn auto_tune_soft_limit(
+		&'static self, interval: Duration, quantile: f64,
+	) -> thread::JoinHandle<()>
+	where
+		H: Sync,
+	{
+		thread::spawn(move || {
+			let mut bursts = Bursts::new();
+			let mut tuned = self.soft_limit();
+			loop {
+				thread::sleep(interval);
+				bursts.record(self.allocated());
+				let next = bursts
+					.quantile(quantile)
+					.map_or(tuned, |burst| self.limit().saturating_sub(burst));
+				// Only if it hasn't been set by other means since it was last tuned.
+				if self
+					.soft_limit
+					.compare_exchange(tuned, next, Ordering::Relaxed, Ordering::Relaxed)
+					.is_err()
+				{
+					break;
+				}
+				#[cfg(feature = "recent")]
+				if next != tuned {
+					self.limit_log
+						.record(LimitKind::SoftLimit, tuned, next, Some("auto-tuned"));
+				}
+				tuned = next;
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{alloc::System, thread, time::Duration};
+
+	use super::Bursts;
+	use crate::Cap;
+
+	#[test]
+	fn bursts() {
+		let mut bursts = Bursts::new();
+		assert_eq!(bursts.quantile(0.99), None);
+		let mut allocated = 0;
+		for i in 0..200 {
+			// Mostly steady growth, with a larger burst every tenth sample, and a free after each.
+			allocated += if i % 10 == 0 { 1000 } else { 10 };
+			bursts.record(allocated);
+			bursts.record(allocated - 5);
+			allocated -= 5;
+		}
+		assert_eq!(bursts.len, 128);
+		assert_eq!(bursts.quantile(0.0), Some(0));
+		assert_eq!(bursts.quantile(0.4), Some(0));
+		assert_eq!(bursts.quantile(0.8), Some(10));
+		assert_eq!(bursts.quantile(1.0), Some(1000));
+	}
+
+	#[test]
+	fn auto_tune_soft_limit() {
+		static CAP: Cap<System> = Cap::new(System, 10_000);
+		let tuner = CAP.auto_tune_soft_limit(Duration::from_millis(1), 1.0);
+		CAP.charge(3000).unwrap();
+		while CAP.soft_limit() == usize::MAX {
+			thread::yield_now();
+		}
+		// Handing control back.
+		CAP.set_soft_limit(5000);
+		tuner.join().unwrap();
+		assert_eq!(CAP.soft_limit(), 5000);
+	}
+}
@@ -0,0 +1,175 @@
+//! A line-based stats and control server on a Unix domain socket.
+
+use std::{
+	fs, io::{self, BufRead, BufReader, Write}, os::unix::{
+		fs::{FileTypeExt, PermissionsExt}, net::{UnixListener, UnixStream}
+	}, path::Path, time::Duration
+};
+
+use crate::{monitor::Stop, Cap, Monitor};
+
+/// How often the serving thread checks whether it has been stopped, while waiting for a connection or a request.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl<H> Cap<H> {
+	/// Serve stats and control requests on a Unix domain socket at `path`, on a background thread.
+	///
+	/// This lets operators inspect and adjust a running process, for example with `socat - UNIX-CONNECT:path`. Each request is a line, and each response is zero or more lines followed by `ok` or `err <reason>`:
+	///
+	/// * `stats`: the current counters, as `<name> <value>` lines;
+	/// * `limit <bytes>`: [set the limit](Self::set_limit);
+	/// * `dump`: the counters followed, with the `tags` feature, by a `tag <name> <allocated> <limit>` line per tag.
+	///
+	/// The socket is only accessible to the user running the process. A socket left at `path` by a previous process is replaced if nothing is listening on it; this method will return `Err` if something is. Connections are served one at a time, until the returned [`Monitor`] is dropped, which removes the socket.
+	pub fn serve_uds(&'static self, path: impl AsRef<Path>) -> io::Result<Monitor>
+	where
+		H: Sync,
+	{
+		let path = path.as_ref();
+		if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+			&& UnixStream::connect(path).is_err()
+		{
+			fs::remove_file(path)?;
+		}
+		let listener = UnixListener::bind(path)?;
+		let path = path.to_owned();
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+		listener.set_nonblocking(true)?;
+		Ok(Monitor::spawn(move |stop| {
+			loop {
+				match listener.accept() {
+					Ok((stream, _)) => {
+						let _ = self.serve_connection(stream, stop);
+					}
+					Err(_) => {
+						if !stop.sleep(POLL_INTERVAL) {
+							break;
+						}
+					}
+				}
+			}
+			let _ = fs::remove_file(&path);
+		}))
+	}
+
+	fn serve_connection(&self, stream: UnixStream, stop: &Stop) -> io::Result<()> {
+		stream.set_nonblocking(false)?;
+		stream.set_read_timeout(Some(POLL_INTERVAL))?;
+		let mut writer = stream.try_clone()?;
+		let mut reader = BufReader::new(stream);
+		let mut line = String::new();
+		loop {
+			match reader.read_line(&mut line) {
+				Ok(0) => return Ok(()),
+				Ok(_) => {
+					self.respond(&line, &mut writer)?;
+					line.clear();
+				}
+				// Any partial line is kept in `line`, to be completed by the next read.
+				Err(err)
+					if matches!(
+						err.kind(),
+						io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+					) =>
+				{
+					if stop.is_stopped() {
+						return Ok(());
+					}
+				}
+				Err(err) => return Err(err),
+			}
+		}
+	}
+
+	fn respond(&self, line: &str, writer: &mut impl Write) -> io::Result<()> {
+		let mut words = line.split_whitespace();
+		match (words.next(), words.next(), words.next()) {
+			(Some("stats"), None, _) => {
+				self.write_stats(writer)?;
+				writeln!(writer, "ok")?;
+			}
+			(Some("limit"), Some(limit), None) => match limit.parse() {
+				Ok(limit) => match self.set_limit_with_reason(limit, "uds") {
+					Ok(()) => writeln!(writer, "ok")?,
+					Err(()) => writeln!(writer, "err limit is less than allocated")?,
+				},
+				Err(_) => writeln!(writer, "err invalid number of bytes")?,
+			},
+			(Some("dump"), None, _) => {
+				self.write_stats(writer)?;
+				#[cfg(feature = "tags")]
+				for stats in self.stats_by_tag() {
+					writeln!(
+						writer,
+						"tag {} {} {}",
+						stats.tag.name(),
+						stats.allocated,
+						stats.limit
+					)?;
+				}
+				writeln!(writer, "ok")?;
+			}
+			(None, ..) => (),
+			_ => writeln!(writer, "err unknown request")?,
+		}
+		Ok(())
+	}
+
+	fn write_stats(&self, writer: &mut impl Write) -> io::Result<()> {
+		writeln!(writer, "allocated {}", self.allocated())?;
+		writeln!(writer, "limit {}", self.limit())?;
+		writeln!(writer, "remaining {}", self.remaining())?;
+		writeln!(writer, "external {}", self.external())?;
+		#[cfg(feature = "stats-counts")]
+		writeln!(writer, "total_allocated {}", self.total_allocated())?;
+		#[cfg(feature = "stats-peaks")]
+		writeln!(writer, "max_allocated {}", self.max_allocated())?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::System, env, fs, io::{BufRead, BufReader, Write}, os::unix::{fs::PermissionsExt, net::UnixStream}
+	};
+
+	use crate::Cap;
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn serve_uds() {
+		let cap: &'static Cap<System> = Box::leak(Box::new(Cap::new(System, 1000)));
+		cap.charge(100).unwrap();
+		let path = env::temp_dir().join(format!("cap-uds-{}", std::process::id()));
+		let monitor = cap.serve_uds(&path).unwrap();
+		assert_eq!(
+			fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+			0o600
+		);
+		// A socket that's being served isn't replaced.
+		assert!(cap.serve_uds(&path).is_err());
+		let mut stream = UnixStream::connect(&path).unwrap();
+		stream.write_all(b"limit 50\nlimit 500\nstats\n").unwrap();
+		let lines = BufReader::new(&stream)
+			.lines()
+			.map(Result::unwrap)
+			.take(6)
+			.collect::<Vec<_>>();
+		assert_eq!(
+			lines,
+			[
+				"err limit is less than allocated",
+				"ok",
+				"allocated 100",
+				"limit 500",
+				"remaining 400",
+				"external 100"
+			]
+		);
+		assert_eq!(cap.limit(), 500);
+		// Stopped even while a connection is open, removing the socket.
+		drop(monitor);
+		assert!(fs::symlink_metadata(&path).is_err());
+	}
+}
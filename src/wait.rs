@@ -0,0 +1,153 @@
+//! Cooperative waiting for budget, rather than failing allocations immediately.
+
+use std::{
+	cell::Cell, marker::PhantomData, thread, time::{Duration, Instant}
+};
+
+/// How often the limit is rechecked while waiting.
+const POLL: Duration = Duration::from_millis(1);
+
+thread_local! {
+	static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Make allocations on this thread that would exceed the limit wait up to `timeout` for memory to be freed, until the returned guard is dropped.
+///
+/// This suits batch pipelines, where a short stall while other work completes is preferable to an error. The deadline is shared by all allocations made while the guard is held, so in total they wait at most `timeout`.
+///
+/// ```
+/// use std::{alloc, time::Duration};
+/// use cap::Cap;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     let _guard = cap::wait_for_budget(Duration::from_millis(100));
+///     let batch = vec![0u8; 1000];
+/// #   drop(batch);
+/// }
+/// ```
+#[must_use = "allocations stop waiting when the guard is dropped"]
+pub fn wait_for_budget(timeout: Duration) -> WaitGuard {
+	let deadline = Instant::now().checked_add(timeout);
+	WaitGuard {
+		previous: DEADLINE.with(|current| current.replace(deadline)),
+		_not_send: PhantomData,
+	}
+}
+
+/// A guard returned by [`wait_for_budget`] that restores the previous waiting behaviour when dropped.
+#[derive(Debug)]
+pub struct WaitGuard {
+	previous: Option<Instant>,
+	_not_send: PhantomData<*const ()>,
+}
+
+impl Drop for WaitGuard {
+	fn drop(&mut self) {
+		DEADLINE.with(|current| current.set(self.previous));
+	}
+}
+
+/// Call `retry` until it succeeds or this thread's deadline passes, returning whether it succeeded. Returns false immediately if this thread isn't waiting for budget.
+pub(crate) fn wait(mut retry: impl FnMut() -> bool) -> bool {
+	let Some(deadline) = DEADLINE.try_with(Cell::get).ok().flatten() else {
+		return false;
+	};
+	loop {
+		let now = Instant::now();
+		if now >= deadline {
+			return false;
+		}
+		thread::sleep(POLL.min(deadline - now));
+		if retry() {
+			return true;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, sync::atomic::{AtomicBool, Ordering}, thread, time::Duration
+	};
+
+	use crate::Cap;
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn wait_for_budget() {
+		let cap: &'static Cap<System> = Box::leak(Box::new(Cap::new(System, 100)));
+		let layout = Layout::new::<[u8; 60]>();
+		let held = unsafe { cap.alloc(layout) } as usize;
+		assert!(unsafe { cap.alloc(layout) }.is_null());
+		let freer = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(20));
+			unsafe { cap.dealloc(held as *mut u8, layout) };
+		});
+		let ptr = {
+			let _guard = super::wait_for_budget(Duration::from_secs(10));
+			unsafe { cap.alloc(layout) }
+		};
+		assert!(!ptr.is_null());
+		freer.join().unwrap();
+		unsafe { cap.dealloc(ptr, layout) };
+	}
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn jumbo() {
+		let cap: &'static Cap<System> = Box::leak(Box::new(Cap::new(System, 100)));
+		cap.set_jumbo_threshold(80);
+		let (small, large) = (Layout::new::<[u8; 30]>(), Layout::new::<[u8; 80]>());
+		let held = unsafe { [cap.alloc(small), cap.alloc(small)] }.map(|ptr| ptr as usize);
+		let freer = thread::spawn(move || {
+			for ptr in held {
+				thread::sleep(Duration::from_millis(10));
+				unsafe { cap.dealloc(ptr as *mut u8, small) };
+			}
+		});
+		let ptr = {
+			let _guard = super::wait_for_budget(Duration::from_secs(10));
+			unsafe { cap.alloc(large) }
+		};
+		assert!(!ptr.is_null());
+		assert_eq!(cap.allocated(), cap.charged(large));
+		freer.join().unwrap();
+		unsafe { cap.dealloc(ptr, large) };
+		assert_eq!(cap.remaining(), 100);
+	}
+
+	#[test]
+	#[cfg_attr(miri, ignore)]
+	fn jumbo_racing_failures() {
+		static STOP: AtomicBool = AtomicBool::new(false);
+		let cap: &'static Cap<System> = Box::leak(Box::new(Cap::new(System, 1000)));
+		cap.set_jumbo_threshold(500);
+		let large = Layout::from_size_align(600, 1).unwrap();
+		cap.charge(900).unwrap();
+		// A charge that doesn't fit momentarily wraps the bytes remaining, which mustn't be gathered. Only one races, as another charge made meanwhile would fit.
+		let racer = thread::spawn(move || {
+			while !STOP.load(Ordering::Relaxed) {
+				assert!(cap.charge(2000).is_err());
+			}
+		});
+		let ptr = {
+			let _guard = super::wait_for_budget(Duration::from_millis(200));
+			unsafe { cap.alloc(large) }
+		};
+		assert!(ptr.is_null());
+		cap.uncharge(900);
+		let ptr = {
+			let _guard = super::wait_for_budget(Duration::from_secs(10));
+			unsafe { cap.alloc(large) }
+		};
+		assert!(!ptr.is_null());
+		STOP.store(true, Ordering::Relaxed);
+		racer.join().unwrap();
+		assert_eq!(cap.allocated(), cap.charged(large));
+		unsafe { cap.dealloc(ptr, large) };
+		assert_eq!(cap.remaining(), 1000);
+	}
+}
@@ -0,0 +1,310 @@
+//! Subscriptions to the bytes allocated crossing a threshold, that unregister when dropped.
+
+use std::{
+	convert::TryFrom, fmt, mem, ptr, sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+use crate::now;
+
+/// The number of watches that can be registered with a [`Cap`](crate::Cap) at once.
+const SLOTS: usize = 16;
+
+const FREE: u8 = 0;
+const CLAIMED: u8 = 1;
+const ACTIVE: u8 = 2;
+
+/// Which crossings of its threshold a [`Watch`] reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Crossing {
+	/// The bytes allocated rising to or above the threshold.
+	Rising,
+	/// The bytes allocated falling back below the threshold.
+	Falling,
+	/// Either.
+	Both,
+}
+
+impl Crossing {
+	fn from_u8(crossing: u8) -> Self {
+		match crossing {
+			0 => Crossing::Rising,
+			1 => Crossing::Falling,
+			_ => Crossing::Both,
+		}
+	}
+
+	fn matches(self, rising: bool) -> bool {
+		match self {
+			Crossing::Rising => rising,
+			Crossing::Falling => !rising,
+			Crossing::Both => true,
+		}
+	}
+}
+
+/// A crossing of a [`Watch`]'s threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WatchEvent {
+	/// Whether the bytes allocated rose to or above the threshold, rather than falling below it.
+	pub rising: bool,
+	/// The bytes allocated just after the crossing.
+	pub allocated: usize,
+	/// When the crossing happened.
+	pub time: SystemTime,
+}
+
+struct Slot {
+	state: AtomicU8,
+	threshold: AtomicUsize,
+	crossing: AtomicU8,
+	debounce: AtomicU64,
+	above: AtomicBool,
+	/// When the last event was reported, in nanoseconds since the Unix epoch, or 0 if none has been.
+	fired: AtomicU64,
+	events: AtomicUsize,
+	last_rising: AtomicBool,
+	last_allocated: AtomicUsize,
+	callback: AtomicPtr<()>,
+}
+
+impl Slot {
+	const fn new() -> Self {
+		Self {
+			state: AtomicU8::new(FREE),
+			threshold: AtomicUsize::new(0),
+			crossing: AtomicU8::new(0),
+			debounce: AtomicU64::new(0),
+			above: AtomicBool::new(false),
+			fired: AtomicU64::new(0),
+			events: AtomicUsize::new(0),
+			last_rising: AtomicBool::new(false),
+			last_allocated: AtomicUsize::new(0),
+			callback: AtomicPtr::new(ptr::null_mut()),
+		}
+	}
+
+	fn check(&self, allocated: usize, publish: &impl Fn(WatchEvent)) {
+		let above = allocated >= self.threshold.load(Ordering::Relaxed);
+		if self.above.swap(above, Ordering::Relaxed) == above
+			|| !Crossing::from_u8(self.crossing.load(Ordering::Relaxed)).matches(above)
+		{
+			return;
+		}
+		let now = now();
+		let fired = self.fired.load(Ordering::Relaxed);
+		if fired != 0 && now.saturating_sub(fired) < self.debounce.load(Ordering::Relaxed) {
+			return;
+		}
+		if self
+			.fired
+			.compare_exchange(fired, now.max(1), Ordering::Relaxed, Ordering::Relaxed)
+			.is_err()
+		{
+			return;
+		}
+		self.last_rising.store(above, Ordering::Relaxed);
+		self.last_allocated.store(allocated, Ordering::Relaxed);
+		let _ = self.events.fetch_add(1, Ordering::Release);
+		let event = self.last_event(now);
+		let callback = self.callback.load(Ordering::Acquire);
+		if !callback.is_null() {
+			let callback = unsafe { mem::transmute::<*mut (), fn(&WatchEvent)>(callback) };
+			let _ = crate::reentrancy::call(|| callback(&event));
+		}
+		publish(event);
+	}
+
+	fn last_event(&self, fired: u64) -> WatchEvent {
+		WatchEvent {
+			rising: self.last_rising.load(Ordering::Relaxed),
+			allocated: self.last_allocated.load(Ordering::Relaxed),
+			time: UNIX_EPOCH + Duration::from_nanos(fired),
+		}
+	}
+}
+
+/// The watches registered with a [`Cap`](crate::Cap).
+pub(crate) struct Table {
+	slots: [Slot; SLOTS],
+	active: AtomicUsize,
+}
+
+impl Table {
+	pub(crate) const fn new() -> Self {
+		Self {
+			slots: [const { Slot::new() }; SLOTS],
+			active: AtomicUsize::new(0),
+		}
+	}
+
+	pub(crate) fn register(
+		&self, threshold: usize, crossing: Crossing, debounce: Duration, allocated: usize,
+	) -> Result<Watch<'_>, ()> {
+		let (index, slot) = self
+			.slots
+			.iter()
+			.enumerate()
+			.find(|(_, slot)| {
+				slot.state
+					.compare_exchange(FREE, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+					.is_ok()
+			})
+			.ok_or(())?;
+		slot.threshold.store(threshold, Ordering::Relaxed);
+		slot.crossing.store(crossing as u8, Ordering::Relaxed);
+		slot.debounce.store(
+			u64::try_from(debounce.as_nanos()).unwrap_or(u64::MAX),
+			Ordering::Relaxed,
+		);
+		slot.above.store(allocated >= threshold, Ordering::Relaxed);
+		slot.fired.store(0, Ordering::Relaxed);
+		slot.events.store(0, Ordering::Relaxed);
+		slot.callback.store(ptr::null_mut(), Ordering::Relaxed);
+		slot.state.store(ACTIVE, Ordering::Release);
+		let _ = self.active.fetch_add(1, Ordering::Release);
+		Ok(Watch { table: self, index })
+	}
+
+	/// Report any crossings of registered thresholds, now that `allocated` returns the bytes allocated, passing each event reported to `publish`.
+	///
+	/// `allocated` is only called if a watch is registered, as it sits under every allocation.
+	#[inline]
+	pub(crate) fn check(&self, allocated: impl FnOnce() -> usize, publish: impl Fn(WatchEvent)) {
+		if self.active.load(Ordering::Relaxed) == 0 {
+			return;
+		}
+		let allocated = allocated();
+		for slot in &self.slots {
+			if slot.state.load(Ordering::Acquire) == ACTIVE {
+				slot.check(allocated, &publish);
+			}
+		}
+	}
+}
+
+/// A subscription to the bytes allocated by a [`Cap`](crate::Cap) crossing a threshold, as returned by [`Cap::watch`](crate::Cap::watch). It is unregistered when dropped.
+///
+/// Crossings are detected as allocations and deallocations are made. Those in the direction watched for are recorded, and passed to the function set with [`on_event`](Self::on_event), unless within the debounce period of the last one reported, so that usage hovering around the threshold doesn't produce a flood of events.
+///
+/// ```
+/// use std::{alloc, time::Duration};
+/// use cap::{Cap, Crossing};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+///
+/// fn main() {
+///     let watch = ALLOCATOR
+///         .watch(ALLOCATOR.allocated() + 1024 * 1024, Crossing::Rising, Duration::from_secs(1))
+///         .unwrap();
+///     let buffer = vec![0u8; 2 * 1024 * 1024];
+///     assert!(watch.last_event().unwrap().rising);
+/// #   drop(buffer);
+/// }
+/// ```
+pub struct Watch<'a> {
+	table: &'a Table,
+	index: usize,
+}
+
+impl Watch<'_> {
+	fn slot(&self) -> &Slot {
+		&self.table.slots[self.index]
+	}
+
+	/// Return the threshold watched.
+	#[must_use]
+	pub fn threshold(&self) -> usize {
+		self.slot().threshold.load(Ordering::Relaxed)
+	}
+
+	/// Set a function to be called with each event reported. It is [called from within the allocator](crate#hooks).
+	pub fn on_event(&self, callback: fn(&WatchEvent)) {
+		self.slot()
+			.callback
+			.store(callback as *mut (), Ordering::Release);
+	}
+
+	/// Return the number of events reported.
+	#[must_use]
+	pub fn events(&self) -> usize {
+		self.slot().events.load(Ordering::Acquire)
+	}
+
+	/// Return the last event reported, if any.
+	#[must_use]
+	pub fn last_event(&self) -> Option<WatchEvent> {
+		let fired = self.slot().fired.load(Ordering::Acquire);
+		(fired != 0).then(|| self.slot().last_event(fired))
+	}
+}
+
+impl Drop for Watch<'_> {
+	fn drop(&mut self) {
+		let _ = self.table.active.fetch_sub(1, Ordering::Release);
+		self.slot().state.store(FREE, Ordering::Release);
+	}
+}
+
+impl fmt::Debug for Table {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Table")
+			.field("active", &self.active.load(Ordering::Relaxed))
+			.finish_non_exhaustive()
+	}
+}
+
+impl fmt::Debug for Watch<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Watch")
+			.field("threshold", &self.threshold())
+			.field("events", &self.events())
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System}, sync::atomic::{AtomicUsize, Ordering}, time::Duration
+	};
+
+	use super::{Crossing, WatchEvent};
+	use crate::Cap;
+
+	#[test]
+	fn watch() {
+		static CALLED: AtomicUsize = AtomicUsize::new(0);
+		fn callback(event: &WatchEvent) {
+			assert!(!event.rising);
+			let _ = CALLED.fetch_add(1, Ordering::Relaxed);
+		}
+		let cap = Cap::new(System, usize::MAX);
+		let layout = Layout::new::<[u8; 100]>();
+		let rising = cap.watch(150, Crossing::Rising, Duration::ZERO).unwrap();
+		let falling = cap.watch(150, Crossing::Falling, Duration::MAX).unwrap();
+		falling.on_event(callback);
+		unsafe {
+			let a = cap.alloc(layout);
+			assert!(rising.last_event().is_none());
+			for _ in 0..2 {
+				let b = cap.alloc(layout);
+				cap.dealloc(b, layout);
+			}
+			assert_eq!(rising.events(), 2);
+			let event = rising.last_event().unwrap();
+			assert!(event.rising && event.allocated == 2 * cap.charged(layout));
+			// The second fall is within the debounce period.
+			assert_eq!((falling.events(), CALLED.load(Ordering::Relaxed)), (1, 1));
+			cap.dealloc(a, layout);
+		}
+		let watches = (0..14)
+			.map(|_| cap.watch(0, Crossing::Both, Duration::ZERO).unwrap())
+			.collect::<Vec<_>>();
+		assert!(cap.watch(0, Crossing::Both, Duration::ZERO).is_err());
+		drop(rising);
+		assert!(cap.watch(0, Crossing::Both, Duration::ZERO).is_ok());
+		drop((watches, falling));
+	}
+}